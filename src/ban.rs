@@ -0,0 +1,71 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Blame an address may collect before it is banned, one full point being a bad piece
+/// it sent entirely by itself
+pub const BAN_THRESHOLD: f64 = 2.0;
+
+/// Addresses that sent data failing hash verification, kept for the rest of the session
+#[derive(Debug, Default)]
+pub struct BanList {
+    blame: HashMap<IpAddr, f64>,
+    banned: HashSet<IpAddr>,
+}
+
+pub type SharedBanList = Arc<Mutex<BanList>>;
+
+impl BanList {
+    pub fn shared() -> SharedBanList {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    /// Splits the blame for a bad piece evenly across the addresses that sent blocks of it,
+    /// returning the ones that just crossed `BAN_THRESHOLD`
+    pub fn blame(&mut self, contributors: &HashSet<IpAddr>) -> Vec<IpAddr> {
+        if contributors.is_empty() {
+            return Vec::new();
+        }
+        let share = 1.0 / contributors.len() as f64;
+        let mut newly_banned = Vec::new();
+        for ip in contributors {
+            let blame = self.blame.entry(*ip).or_default();
+            *blame += share;
+            if *blame >= BAN_THRESHOLD && self.banned.insert(*ip) {
+                newly_banned.push(*ip);
+            }
+        }
+        newly_banned
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) {
+        self.banned.insert(ip);
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    pub fn banned(&self) -> &HashSet<IpAddr> {
+        &self.banned
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_bans_repeat_offenders() {
+        let mut bans = BanList::default();
+        let alone: IpAddr = [10, 0, 0, 1].into();
+        let other: IpAddr = [10, 0, 0, 2].into();
+        assert!(bans.blame(&HashSet::from([alone])).is_empty());
+        assert!(bans.blame(&HashSet::from([alone, other])).is_empty());
+        assert_eq!(vec![alone], bans.blame(&HashSet::from([alone, other])));
+        assert!(bans.is_banned(&alone));
+        assert!(!bans.is_banned(&other));
+    }
+}
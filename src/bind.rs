@@ -0,0 +1,125 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// Where outgoing peer and tracker connections must leave from, typically a VPN.
+/// Connections fail rather than take another route when it's gone
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    Address(IpAddr),
+    /// Interface name like `wg0` or `tun0`, bound with `SO_BINDTODEVICE`
+    Interface(String),
+}
+
+impl FromStr for Bind {
+    type Err = anyhow::Error;
+
+    /// Anything that doesn't parse as an address is taken as an interface name
+    fn from_str(bind: &str) -> Result<Self> {
+        if bind.is_empty() {
+            return Err(anyhow!("Empty bind address"));
+        }
+        Ok(bind
+            .parse()
+            .map(Self::Address)
+            .unwrap_or_else(|_| Self::Interface(bind.to_string())))
+    }
+}
+
+impl Bind {
+    /// A TCP connection to `remote` through the bound address or interface
+    pub async fn connect(&self, remote: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if remote.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        self.bind_socket(&socket, remote)?;
+        socket.connect(remote).await
+    }
+
+    /// Binds `socket` before it connects to `remote`, for callers that set it up further
+    pub fn bind_socket(&self, socket: &TcpSocket, remote: SocketAddr) -> io::Result<()> {
+        match self {
+            Self::Address(ip) if ip.is_ipv4() != remote.is_ipv4() => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("Bound to {} which can't reach {}", ip, remote),
+            )),
+            Self::Address(ip) => socket.bind(SocketAddr::new(*ip, 0)),
+            Self::Interface(interface) => bind_device(socket, interface),
+        }
+    }
+
+    /// The local address connections to `remote` leave from, for clients that can only
+    /// bind to an address
+    pub async fn local_ip(&self, remote: SocketAddr) -> Result<IpAddr> {
+        let interface = match self {
+            Self::Address(ip) => return Ok(*ip),
+            Self::Interface(interface) => interface,
+        };
+        let unspecified = if remote.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+        bind_udp_device(&socket, interface)?;
+        // Connecting a UDP socket sends nothing, the OS only picks our address on the interface
+        socket
+            .connect(remote)
+            .await
+            .map_err(|error| anyhow!("{} can't reach {}: {}", interface, remote, error))?;
+        Ok(socket.local_addr()?.ip())
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_udp_device(socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_: &TcpSocket, interface: &str) -> io::Result<()> {
+    Err(unsupported(interface))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_udp_device(_: &UdpSocket, interface: &str) -> io::Result<()> {
+    Err(unsupported(interface))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn unsupported(interface: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Can't bind to {} here, bind to its address instead",
+            interface
+        ),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_tells_addresses_from_interfaces() {
+        assert_eq!(
+            Bind::Address(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2))),
+            "10.8.0.2".parse().unwrap()
+        );
+        assert_eq!(Bind::Interface("wg0".into()), "wg0".parse().unwrap());
+        assert!("".parse::<Bind>().is_err());
+    }
+}
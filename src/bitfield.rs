@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Result};
+
+/// One bit per piece, most significant bit of the first byte being piece 0
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    /// Parses a bitfield received from a peer, which must have exactly enough bytes
+    /// for `len` pieces and no spare bits set
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(anyhow!(
+                "Bitfield of {} bytes for {} pieces",
+                bytes.len(),
+                len
+            ));
+        }
+        let bitfield = Self {
+            bytes: bytes.to_vec(),
+            len,
+        };
+        let spare_bits = bitfield.bytes.len() * 8 - len;
+        if spare_bits > 0 && bitfield.bytes[bitfield.bytes.len() - 1] & ((1 << spare_bits) - 1) != 0
+        {
+            return Err(anyhow!("Bitfield has spare bits set"));
+        }
+        Ok(bitfield)
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|index| self.has(*index))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bitfield_from_peer_bytes() {
+        let bitfield = Bitfield::from_bytes(&[0b1010_0000, 0b0100_0000], 10).unwrap();
+        assert_eq!(bitfield.pieces().collect::<Vec<_>>(), vec![0, 2, 9]);
+        assert!(Bitfield::from_bytes(&[0xFF], 10).is_err());
+        assert!(Bitfield::from_bytes(&[0xFF, 0xFF], 10).is_err());
+        assert_eq!(Bitfield::full(10).as_bytes(), &[0xFF, 0xC0]);
+    }
+}
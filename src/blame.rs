@@ -0,0 +1,124 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use sha1::{Digest, Sha1};
+
+use crate::tracker::Peer;
+
+/// A block of a piece that failed its hash check, kept until the right data shows
+#[derive(Debug, Clone)]
+struct Suspect {
+    begin: u32,
+    length: usize,
+    peer: Peer,
+    digest: [u8; 20],
+}
+
+/// Who sent each block of the pieces being downloaded. When a piece made of several
+/// peers' blocks fails, the blocks are remembered so the peers that sent wrong ones can be
+/// told apart from the rest once the piece passes
+#[derive(Debug, Default)]
+pub struct BlockBlame {
+    /// Supplier and length of each block received, by piece and offset
+    suppliers: HashMap<u32, BTreeMap<u32, (Peer, usize)>>,
+    suspects: HashMap<u32, Vec<Suspect>>,
+}
+
+impl BlockBlame {
+    pub fn record(&mut self, piece: u32, begin: u32, length: usize, peer: Peer) {
+        self.suppliers
+            .entry(piece)
+            .or_default()
+            .insert(begin, (peer, length));
+    }
+
+    /// Peers that sent blocks of `piece` since it was last checked
+    pub fn contributors(&self, piece: u32) -> HashSet<Peer> {
+        self.suppliers
+            .get(&piece)
+            .into_iter()
+            .flat_map(|blocks| blocks.values().map(|(peer, _)| peer.clone()))
+            .collect()
+    }
+
+    /// Notes that `piece`, whose bad data is `content`, failed, returning who to blame
+    /// right away: the peer that sent all of it, or everyone involved if the piece failed
+    /// before. Otherwise nobody is blamed until `passed` finds the wrong blocks
+    pub fn failed(&mut self, piece: u32, content: Option<&[u8]>) -> HashSet<Peer> {
+        let contributors = self.contributors(piece);
+        let blocks = self.suppliers.remove(&piece).unwrap_or_default();
+        let failed_before = self.suspects.remove(&piece).is_some();
+        let content = match content {
+            Some(content) if contributors.len() > 1 && !failed_before => content,
+            _ => return contributors,
+        };
+        let suspects = blocks
+            .into_iter()
+            .filter_map(|(begin, (peer, length))| {
+                let data = content.get(begin as usize..begin as usize + length)?;
+                Some(Suspect {
+                    begin,
+                    length,
+                    peer,
+                    digest: Sha1::digest(data).into(),
+                })
+            })
+            .collect();
+        self.suspects.insert(piece, suspects);
+        HashSet::new()
+    }
+
+    /// Notes that `piece` passed with `content`, returning the peers that sent blocks of it
+    /// and those whose blocks of an earlier, failed attempt differ from it
+    pub fn passed(&mut self, piece: u32, content: Option<&[u8]>) -> (HashSet<Peer>, HashSet<Peer>) {
+        let contributors = self.contributors(piece);
+        self.suppliers.remove(&piece);
+        let suspects = self.suspects.remove(&piece).unwrap_or_default();
+        let culprits = match content {
+            Some(content) => suspects
+                .into_iter()
+                .filter(|suspect| {
+                    let start = suspect.begin as usize;
+                    content
+                        .get(start..start + suspect.length)
+                        .is_none_or(|data| Sha1::digest(data).as_slice() != suspect.digest)
+                })
+                .map(|suspect| suspect.peer)
+                .collect(),
+            None => HashSet::new(),
+        };
+        (contributors, culprits)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_blames_the_peers_of_the_wrong_blocks() {
+        let good = Peer::from_socket_addr("10.0.0.1:6881".parse().unwrap());
+        let bad = Peer::from_socket_addr("10.0.0.2:6881".parse().unwrap());
+        let mut blame = BlockBlame::default();
+        blame.record(0, 0, 4, good.clone());
+        blame.record(0, 4, 4, bad.clone());
+        assert!(blame.failed(0, Some(b"abcdXXXX")).is_empty());
+        blame.record(0, 0, 4, good.clone());
+        blame.record(0, 4, 4, good.clone());
+        let (contributors, culprits) = blame.passed(0, Some(b"abcdefgh"));
+        assert_eq!(HashSet::from([good.clone()]), contributors);
+        assert_eq!(HashSet::from([bad.clone()]), culprits);
+
+        // Alone, or failing twice, there is no telling good blocks from bad ones
+        blame.record(1, 0, 4, bad.clone());
+        assert_eq!(HashSet::from([bad.clone()]), blame.failed(1, Some(b"XXXX")));
+        blame.record(2, 0, 4, good.clone());
+        blame.record(2, 4, 4, bad.clone());
+        assert!(blame.failed(2, Some(b"abcdXXXX")).is_empty());
+        blame.record(2, 0, 4, good.clone());
+        blame.record(2, 4, 4, bad.clone());
+        assert_eq!(
+            HashSet::from([good, bad]),
+            blame.failed(2, Some(b"abcdXXXX"))
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{anyhow, Result};
+
+/// Bytes of pieces kept in memory unless told otherwise
+pub const DEFAULT_CACHE_BYTES: usize = 32 * 1024 * 1024;
+
+/// Whole pieces kept in memory after being written or read for a peer, so seeding the
+/// same pieces over and over doesn't go back to the disk each time. The least recently
+/// used ones make room once `budget` bytes are taken
+#[derive(Debug)]
+pub struct PieceCache {
+    budget: usize,
+    used: usize,
+    pieces: HashMap<u32, Vec<u8>>,
+    /// Most recently used last
+    recency: VecDeque<u32>,
+}
+
+impl Default for PieceCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_BYTES)
+    }
+}
+
+impl PieceCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            pieces: HashMap::new(),
+            recency: VecDeque::new(),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Shrinking evicts right away, a budget of 0 turns caching off
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict(0);
+    }
+
+    /// Whether a piece of `length` bytes is worth caching at all
+    pub fn fits(&self, length: usize) -> bool {
+        length <= self.budget
+    }
+
+    fn touch(&mut self, piece: u32) {
+        if let Some(position) = self.recency.iter().position(|cached| *cached == piece) {
+            self.recency.remove(position);
+        }
+        self.recency.push_back(piece);
+    }
+
+    /// Drops the least recently used pieces until `length` more bytes fit
+    fn evict(&mut self, length: usize) {
+        while self.used + length > self.budget {
+            let Some(piece) = self.recency.pop_front() else {
+                break;
+            };
+            if let Some(content) = self.pieces.remove(&piece) {
+                self.used -= content.len();
+            }
+        }
+    }
+
+    pub fn insert(&mut self, piece: u32, content: Vec<u8>) {
+        if !self.fits(content.len()) {
+            return;
+        }
+        self.remove(piece);
+        self.evict(content.len());
+        self.used += content.len();
+        self.pieces.insert(piece, content);
+        self.touch(piece);
+    }
+
+    pub fn remove(&mut self, piece: u32) {
+        if let Some(content) = self.pieces.remove(&piece) {
+            self.used -= content.len();
+            self.recency.retain(|cached| *cached != piece);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pieces.clear();
+        self.recency.clear();
+        self.used = 0;
+    }
+
+    pub fn get(&mut self, piece: u32) -> Option<&[u8]> {
+        if !self.pieces.contains_key(&piece) {
+            return None;
+        }
+        self.touch(piece);
+        self.pieces.get(&piece).map(Vec::as_slice)
+    }
+
+    /// The cached content of `piece`, loading and caching it first when it isn't
+    pub fn get_or_load(
+        &mut self,
+        piece: u32,
+        load: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<&[u8]> {
+        if !self.pieces.contains_key(&piece) {
+            self.insert(piece, load()?);
+        } else {
+            self.touch(piece);
+        }
+        match self.pieces.get(&piece) {
+            Some(content) => Ok(content),
+            None => Err(anyhow!("Piece {} doesn't fit in the cache", piece)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_evicts_the_least_recently_used() {
+        let mut cache = PieceCache::new(10);
+        cache.insert(0, vec![0; 4]);
+        cache.insert(1, vec![1; 4]);
+        assert!(cache.get(0).is_some());
+        cache.insert(2, vec![2; 4]);
+        assert_eq!(8, cache.used());
+        assert!(cache.get(1).is_none());
+        assert_eq!(&[0; 4], cache.get_or_load(0, || unreachable!()).unwrap());
+        assert_eq!(&[3; 4], cache.get_or_load(3, || Ok(vec![3; 4])).unwrap());
+        assert!(cache.get(2).is_none());
+        cache.insert(4, vec![4; 11]);
+        assert!(cache.get(4).is_none());
+        cache.set_budget(4);
+        assert_eq!(4, cache.used());
+        assert!(cache.get(3).is_some());
+    }
+}
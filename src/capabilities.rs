@@ -0,0 +1,41 @@
+use bitflags::bitflags;
+
+bitflags! {
+    /// The reserved bytes of the handshake read as one big endian integer
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PeerCapabilities: u64 {
+        /// BEP 5
+        const DHT = 0x01;
+        /// BEP 6
+        const FAST = 0x04;
+        /// BEP 52
+        const V2 = 0x10;
+        /// BEP 10, bit 0x10 of the sixth reserved byte
+        const EXTENDED = 0x10 << 16;
+    }
+}
+
+impl PeerCapabilities {
+    pub fn from_reserved(reserved: [u8; 8]) -> Self {
+        Self::from_bits_retain(u64::from_be_bytes(reserved))
+    }
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        self.bits().to_be_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reserved_bytes_round_trip() {
+        let capabilities =
+            PeerCapabilities::EXTENDED | PeerCapabilities::FAST | PeerCapabilities::DHT;
+        assert_eq!([0, 0, 0, 0, 0, 0x10, 0, 0x05], capabilities.to_reserved());
+        let parsed = PeerCapabilities::from_reserved([0, 0, 0, 0, 0, 0x10, 0, 0x15]);
+        assert!(parsed.contains(PeerCapabilities::V2 | PeerCapabilities::EXTENDED));
+        assert_eq!(parsed & capabilities, capabilities);
+    }
+}
@@ -0,0 +1,127 @@
+use std::{cmp::Reverse, time::Duration};
+
+use rand::{seq::SliceRandom, Rng};
+
+/// How often the unchoked set is re-evaluated
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// Peers unchoked at once, the optimistic unchoke included
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+/// Once seeding there is no download to reciprocate, so more peers can share our upload
+pub const DEFAULT_SEED_UPLOAD_SLOTS: usize = 8;
+/// How long an optimistic unchoke lasts before the slot moves to another peer
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+/// Peers connected for less than this are favored by optimistic unchokes
+pub const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+/// How much likelier a new peer is to get the optimistic unchoke than an older one
+const NEW_PEER_WEIGHT: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChokeCandidate {
+    pub index: usize,
+    /// Rate at which the peer sent us bytes, or we sent it bytes while seeding
+    pub transferred: u64,
+    pub interested: bool,
+    /// Seeds never download from us so an unchoke slot would be lost on them
+    pub upload_only: bool,
+    /// Connected for less than `NEW_PEER_AGE`
+    pub newly_connected: bool,
+    /// Sat on our requests without sending anything, so it only gets the optimistic slot
+    pub snubbed: bool,
+    /// On our own network, ranked ahead of remote peers when LAN preference is on
+    pub local: bool,
+}
+
+/// Tit-for-tat: the interested peers that transferred the most get the upload slots,
+/// local ones first, and peers snubbing us don't whatever they sent before
+pub fn choose_unchoked(candidates: &[ChokeCandidate], slots: usize) -> Vec<usize> {
+    let mut ranked: Vec<&ChokeCandidate> = candidates
+        .iter()
+        .filter(|candidate| candidate.interested && !candidate.upload_only && !candidate.snubbed)
+        .collect();
+    ranked.sort_by_key(|candidate| Reverse((candidate.local, candidate.transferred)));
+    ranked
+        .into_iter()
+        .take(slots)
+        .map(|candidate| candidate.index)
+        .collect()
+}
+
+/// Picks a random interested peer outside `unchoked` for the optimistic slot, which lets
+/// peers with nothing to offer yet bootstrap and uncovers partners faster than ours
+pub fn choose_optimistic(
+    candidates: &[ChokeCandidate],
+    unchoked: &[usize],
+    rng: &mut impl Rng,
+) -> Option<usize> {
+    let choked: Vec<&ChokeCandidate> = candidates
+        .iter()
+        .filter(|candidate| {
+            candidate.interested && !candidate.upload_only && !unchoked.contains(&candidate.index)
+        })
+        .collect();
+    choked
+        .choose_weighted(rng, |candidate| {
+            if candidate.newly_connected {
+                NEW_PEER_WEIGHT
+            } else {
+                1
+            }
+        })
+        .ok()
+        .map(|candidate| candidate.index)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn candidate(index: usize, transferred: u64, interested: bool) -> ChokeCandidate {
+        ChokeCandidate {
+            index,
+            transferred,
+            interested,
+            upload_only: false,
+            newly_connected: false,
+            snubbed: false,
+            local: false,
+        }
+    }
+
+    #[test]
+    fn it_unchokes_the_fastest_interested_peers() {
+        let mut seed = candidate(4, 1000, true);
+        seed.upload_only = true;
+        let candidates = [
+            candidate(0, 10, true),
+            candidate(1, 500, false),
+            candidate(2, 300, true),
+            candidate(3, 20, true),
+            seed,
+        ];
+        assert_eq!(vec![2, 3], choose_unchoked(&candidates, 2));
+
+        let mut snubbing = candidate(2, 300, true);
+        snubbing.snubbed = true;
+        let candidates = [candidate(0, 10, true), snubbing];
+        assert_eq!(vec![0], choose_unchoked(&candidates, 2));
+        let mut rng = rand::thread_rng();
+        assert_eq!(Some(2), choose_optimistic(&candidates, &[0], &mut rng));
+
+        let mut local = candidate(0, 10, true);
+        local.local = true;
+        let candidates = [local, candidate(1, 300, true)];
+        assert_eq!(vec![0], choose_unchoked(&candidates, 1));
+    }
+
+    #[test]
+    fn it_optimistically_unchokes_a_choked_interested_peer() {
+        let candidates = [
+            candidate(0, 10, true),
+            candidate(1, 500, false),
+            candidate(2, 300, true),
+        ];
+        let mut rng = rand::thread_rng();
+        assert_eq!(Some(0), choose_optimistic(&candidates, &[2], &mut rng));
+        assert_eq!(None, choose_optimistic(&candidates, &[0, 2], &mut rng));
+    }
+}
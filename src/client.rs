@@ -0,0 +1,191 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use futures::future::try_join_all;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::{
+    ban::{BanList, SharedBanList},
+    download::Download,
+    parse_torrent::TorrentFile,
+    peers::{
+        ConnectionManager, Listener, DEFAULT_DIAL_RATE, DEFAULT_GLOBAL_MAX_CONNECTIONS,
+        DEFAULT_HALF_OPEN_LIMIT,
+    },
+    ports::PortRange,
+    queue::{keep_queued, QueueLimits},
+    ratelimit::{GlobalRateLimits, SharedBucket, TokenBucket},
+    socket::SocketOptions,
+    tracker::get_info_hash,
+};
+
+/// Every torrent of a session, sharing one listening port, the connection and half-open
+/// limits, the bandwidth budget and the ban list
+pub struct Client<'a> {
+    peer_id: String,
+    torrents: Vec<ConnectionManager<'a>>,
+    listener: Option<Listener>,
+    global_slots: Arc<Semaphore>,
+    half_open: Arc<Semaphore>,
+    dial_rate: SharedBucket,
+    rate_limits: GlobalRateLimits,
+    bans: SharedBanList,
+    socket_options: SocketOptions,
+    queue_limits: QueueLimits,
+}
+
+impl<'a> Client<'a> {
+    pub fn new(peer_id: String) -> Self {
+        Self {
+            peer_id,
+            torrents: Vec::new(),
+            listener: None,
+            global_slots: Arc::new(Semaphore::new(DEFAULT_GLOBAL_MAX_CONNECTIONS)),
+            half_open: Arc::new(Semaphore::new(DEFAULT_HALF_OPEN_LIMIT)),
+            dial_rate: TokenBucket::shared(DEFAULT_DIAL_RATE),
+            rate_limits: GlobalRateLimits::default(),
+            bans: BanList::shared(),
+            socket_options: SocketOptions::default(),
+            queue_limits: QueueLimits::default(),
+        }
+    }
+
+    /// Starts managing the peers of `torrent`. Its manager can still be configured before
+    /// the client runs, the shared limits are already in place
+    pub fn add_torrent(
+        &mut self,
+        torrent: &'a TorrentFile,
+        download: Download,
+    ) -> Result<&mut ConnectionManager<'a>> {
+        let info_hash = get_info_hash(&torrent.info)?;
+        for existing in &self.torrents {
+            if get_info_hash(&existing.torrent().info)? == info_hash {
+                return Err(anyhow!("Torrent {} added twice", hex::encode(&info_hash)));
+            }
+        }
+        let mut manager = ConnectionManager::new(torrent, download, self.peer_id.clone());
+        manager.share_global_slots(self.global_slots.clone());
+        manager.share_half_open_limit(self.half_open.clone());
+        manager.share_dial_rate(self.dial_rate.clone());
+        manager.set_socket_options(self.socket_options);
+        manager.share_ban_list(self.bans.clone());
+        manager.limit_upload(self.rate_limits.upload.clone());
+        manager.limit_download(self.rate_limits.download.clone());
+        if let Some(listener) = &self.listener {
+            manager.attach(listener)?;
+        }
+        self.torrents.push(manager);
+        Ok(self.torrents.last_mut().unwrap())
+    }
+
+    /// Accepts inbound peers on `port` for every torrent, added already or later
+    pub async fn listen(&mut self, port: u16) -> Result<SocketAddr> {
+        self.share_listener(Listener::bind(port, self.socket_options).await?)
+    }
+
+    /// Like `listen` on the first free port of `range`, trackers are told whichever it is
+    pub async fn listen_range(
+        &mut self,
+        range: &PortRange,
+        randomized: bool,
+    ) -> Result<SocketAddr> {
+        let listener = Listener::bind_range(range, randomized, self.socket_options).await?;
+        self.share_listener(listener)
+    }
+
+    fn share_listener(&mut self, listener: Listener) -> Result<SocketAddr> {
+        for manager in &mut self.torrents {
+            manager.attach(&listener)?;
+        }
+        let address = listener.local_addr();
+        self.listener = Some(listener);
+        Ok(address)
+    }
+
+    pub fn torrents(&self) -> &[ConnectionManager<'a>] {
+        &self.torrents
+    }
+
+    pub fn torrents_mut(&mut self) -> &mut [ConnectionManager<'a>] {
+        &mut self.torrents
+    }
+
+    /// Tuning of every peer connection, for torrents added and a listener bound from now on
+    pub fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket_options = socket_options;
+    }
+
+    /// Handle on the session wide upload and download limits, which keeps working while
+    /// the client runs
+    pub fn rate_limits(&self) -> GlobalRateLimits {
+        self.rate_limits.clone()
+    }
+
+    /// Connection attempts per second across every torrent, 0 for no limit
+    pub fn set_dial_rate(&self, dials_per_second: u64) {
+        self.dial_rate.lock().unwrap().set_rate(dials_per_second);
+    }
+
+    /// At most `limits` torrents download and seed at once, the others are paused in the
+    /// order they were added until a slot frees up
+    pub fn set_queue_limits(&mut self, limits: QueueLimits) {
+        self.queue_limits = limits;
+    }
+
+    /// Runs every torrent until one of them fails
+    pub async fn run(&mut self) -> Result<()> {
+        let queued = self
+            .torrents
+            .iter()
+            .map(|manager| manager.queued())
+            .collect();
+        let queue = keep_queued(queued, self.queue_limits);
+        let torrents = try_join_all(self.torrents.iter_mut().map(|manager| manager.run()));
+        tokio::select! {
+            result = torrents => {
+                result?;
+            }
+            _ = queue => {}
+        }
+        Ok(())
+    }
+
+    /// Shuts every torrent down, even when some fail to, returning the first failure
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.listener = None;
+        let mut first_error = None;
+        for manager in &mut self.torrents {
+            if let Err(error) = manager.shutdown().await {
+                warn!(
+                    "Could not shut {} down cleanly: {:?}",
+                    manager.torrent().info.name,
+                    error
+                );
+                first_error.get_or_insert(error);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_torrent::parse_torrent;
+
+    #[tokio::test]
+    async fn it_shares_the_listener_between_torrents() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut client = Client::new("-FU0001-000000000000".into());
+        let address = client.listen(0).await.unwrap();
+        client
+            .add_torrent(&torrent, Download::from(&torrent))
+            .unwrap();
+        assert!(client
+            .add_torrent(&torrent, Download::from(&torrent))
+            .is_err());
+        assert_eq!(1, client.torrents().len());
+        assert_eq!(address, client.torrents()[0].listen_address().unwrap());
+    }
+}
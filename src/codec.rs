@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Error};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::disconnect::{Disconnect, DisconnectReason};
+use crate::messages::{Block, Message, MessageType};
+
+/// Frames above this size are treated as a protocol violation. The largest legit
+/// messages are bitfields of huge torrents, pieces are only 16 KiB + header.
+pub const MAX_FRAME_LENGTH: usize = 1 << 20;
+
+/// Length-prefixed framing of the peer wire protocol, used once the handshake is done
+pub struct PeerCodec {
+    max_frame_length: usize,
+    /// Wire bytes of the frames decoded since the last `take_bytes_read`
+    bytes_read: usize,
+}
+
+impl PeerCodec {
+    pub fn new(max_frame_length: usize) -> Self {
+        Self {
+            max_frame_length,
+            bytes_read: 0,
+        }
+    }
+
+    pub fn take_bytes_read(&mut self) -> usize {
+        std::mem::take(&mut self.bytes_read)
+    }
+}
+
+impl Default for PeerCodec {
+    fn default() -> Self {
+        Self::new(MAX_FRAME_LENGTH)
+    }
+}
+
+impl Decoder for PeerCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(src[..4].try_into()?) as usize;
+        if len > self.max_frame_length {
+            return Err(Disconnect::new(
+                DisconnectReason::FrameTooLarge,
+                format!(
+                    "Frame of {} bytes exceeds limit of {}",
+                    len, self.max_frame_length
+                ),
+            )
+            .into());
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        self.bytes_read += 4 + len;
+        if len == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+        let mut payload = src.split_to(len).freeze();
+        let id = payload.get_u8();
+        Message::from_frame(id, payload).map(Some)
+    }
+}
+
+/// Encodes messages as built by `Message`, which already carry their length prefix
+impl Encoder<Bytes> for PeerCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message: Bytes, dst: &mut BytesMut) -> Result<(), Error> {
+        if message.len() < 4 {
+            return Err(anyhow!("Message without length prefix"));
+        }
+        let len = u32::from_be_bytes(message[..4].try_into()?) as usize;
+        if len != message.len() - 4 || len > self.max_frame_length {
+            return Err(anyhow!(
+                "Invalid frame: declared {} bytes, got {}",
+                len,
+                message.len() - 4
+            ));
+        }
+        dst.extend_from_slice(&message);
+        Ok(())
+    }
+}
+
+/// Writes piece messages header first and the block data straight after it,
+/// so uploaded blocks are copied only once into the socket buffer
+impl Encoder<Block> for PeerCodec {
+    type Error = Error;
+
+    fn encode(&mut self, block: Block, dst: &mut BytesMut) -> Result<(), Error> {
+        let len = block.data.len() + 9;
+        if len > self.max_frame_length {
+            return Err(anyhow!("Block of {} bytes is too big", block.data.len()));
+        }
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        dst.put_u8(MessageType::Piece as u8);
+        dst.put_u32(block.index);
+        dst.put_u32(block.begin);
+        dst.extend_from_slice(&block.data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_decodes_frames_split_across_reads() {
+        let mut codec = PeerCodec::default();
+        let mut buffer = BytesMut::new();
+        codec.encode(Message::have_all(), &mut buffer).unwrap();
+        buffer.extend_from_slice(&[0, 0, 0, 0]);
+        let piece = Message::piece(0, 0, &[1, 2, 3]);
+        buffer.extend_from_slice(&piece[..6]);
+
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Message::HaveAll));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(Message::KeepAlive));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(&piece[6..]);
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(Message::Piece(Block {
+                index: 0,
+                begin: 0,
+                data: Bytes::from_static(&[1, 2, 3])
+            }))
+        );
+    }
+
+    #[test]
+    fn it_encodes_blocks_like_piece_messages() {
+        let mut codec = PeerCodec::default();
+        let mut buffer = BytesMut::new();
+        let block = Block {
+            index: 4,
+            begin: 16384,
+            data: Bytes::from_static(&[9; 32]),
+        };
+        let expected = block.to_message();
+        codec.encode(block, &mut buffer).unwrap();
+        assert_eq!(buffer.freeze(), expected);
+    }
+
+    #[test]
+    fn it_rejects_oversized_frames() {
+        let mut codec = PeerCodec::new(16);
+        let mut buffer = BytesMut::from(&[0, 0, 0, 17][..]);
+        assert!(codec.decode(&mut buffer).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::{timeout_at, Instant},
+};
+
+use tracing::debug;
+
+use crate::tracker::{parse_compact_peers, parse_compact_peers6, Peer};
+
+/// Nodes a lookup starts from when no peer told us about any yet
+pub const BOOTSTRAP_NODES: &[&str] = &["router.bittorrent.com:6881", "dht.transmissionbt.com:6881"];
+/// How often each torrent asks the DHT for more peers
+pub const LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Nodes queried at the same time in each round of a lookup
+const ALPHA: usize = 8;
+const MAX_LOOKUP_ROUNDS: usize = 4;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Upper bound of nodes kept around: 160 buckets of 8 nodes like a full Kademlia table
+pub const MAX_NODES: usize = 8 * 160;
+
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    nodes: Vec<SocketAddr>,
+}
+
+impl RoutingTable {
+    /// Returns false when the node was already known or the table is full
+    pub fn add_node(&mut self, addr: SocketAddr) -> bool {
+        if self.nodes.len() >= MAX_NODES || self.nodes.contains(&addr) {
+            return false;
+        }
+        self.nodes.push(addr);
+        true
+    }
+
+    pub fn nodes(&self) -> &[SocketAddr] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Dht {
+    /// UDP port our DHT node listens on, advertised to peers with PORT messages
+    pub port: u16,
+    pub node_id: [u8; 20],
+    pub routing_table: RoutingTable,
+}
+
+impl Dht {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            node_id: rand::random(),
+            routing_table: RoutingTable::default(),
+        }
+    }
+}
+
+/// Arguments of the `get_peers` and `announce_peer` queries of BEP 5
+#[derive(Debug, Serialize, Deserialize)]
+struct QueryArguments {
+    id: ByteBuf,
+    info_hash: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    id: ByteBuf,
+    #[serde(default)]
+    token: Option<ByteBuf>,
+    /// Compact peers, 6 bytes for IPv4 and 18 for IPv6
+    #[serde(default)]
+    values: Option<Vec<ByteBuf>>,
+    /// Compact node infos: 20 bytes of id followed by a compact IPv4 address
+    #[serde(default)]
+    nodes: Option<ByteBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KrpcMessage {
+    t: ByteBuf,
+    y: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    a: Option<QueryArguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<Response>,
+}
+
+impl KrpcMessage {
+    fn query(transaction: u16, name: &str, arguments: QueryArguments) -> Self {
+        Self {
+            t: ByteBuf::from(transaction.to_be_bytes().to_vec()),
+            y: "q".to_string(),
+            q: Some(name.to_string()),
+            a: Some(arguments),
+            r: None,
+        }
+    }
+}
+
+/// What a `get_peers` lookup found
+#[derive(Debug, Default)]
+pub struct Lookup {
+    pub peers: Vec<Peer>,
+    /// Nodes that answered, worth keeping in the routing table
+    pub nodes: Vec<SocketAddr>,
+    /// Write tokens of the nodes that answered, needed to announce to them
+    pub tokens: Vec<(SocketAddr, Vec<u8>)>,
+}
+
+fn distance(a: &[u8; 20], b: &[u8]) -> [u8; 20] {
+    let mut distance = [0; 20];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = a[i] ^ b.get(i).copied().unwrap_or(0);
+    }
+    distance
+}
+
+fn parse_compact_nodes(bytes: &[u8]) -> Vec<([u8; 20], SocketAddr)> {
+    bytes
+        .chunks_exact(26)
+        .map(|chunk| {
+            let id: [u8; 20] = chunk[..20].try_into().unwrap();
+            let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+            let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+            (id, SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        })
+        .collect()
+}
+
+async fn bootstrap_nodes() -> Vec<SocketAddr> {
+    let mut nodes = Vec::new();
+    for host in BOOTSTRAP_NODES {
+        if let Ok(addresses) = lookup_host(host).await {
+            nodes.extend(addresses.filter(SocketAddr::is_ipv4));
+        }
+    }
+    nodes
+}
+
+/// Iterative `get_peers`: asks the nodes closest to `info_hash` for peers, moving closer
+/// with the nodes each answer points to. Starts from the bootstrap nodes if `known` is empty.
+pub async fn get_peers(
+    node_id: [u8; 20],
+    info_hash: [u8; 20],
+    known: Vec<SocketAddr>,
+) -> Result<Lookup> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    // The socket is IPv4, nodes learned from IPv6 peers can't be reached from it
+    let known: Vec<SocketAddr> = known.into_iter().filter(SocketAddr::is_ipv4).collect();
+    let known = if known.is_empty() {
+        bootstrap_nodes().await
+    } else {
+        known
+    };
+    // Nodes we only know the address of sort first, after them the closest to the info hash
+    let mut frontier: Vec<([u8; 20], SocketAddr)> =
+        known.into_iter().map(|addr| (info_hash, addr)).collect();
+    let mut queried = HashSet::new();
+    let mut peers = HashSet::new();
+    let mut lookup = Lookup::default();
+    let mut transaction: u16 = 0;
+    for _ in 0..MAX_LOOKUP_ROUNDS {
+        frontier.sort_by_key(|(id, _)| distance(id, &info_hash));
+        let round: Vec<SocketAddr> = frontier
+            .iter()
+            .map(|(_, addr)| *addr)
+            .filter(|addr| addr.is_ipv4() && !queried.contains(addr))
+            .take(ALPHA)
+            .collect();
+        if round.is_empty() {
+            break;
+        }
+        let mut sent = 0;
+        for addr in &round {
+            queried.insert(*addr);
+            transaction = transaction.wrapping_add(1);
+            let query = KrpcMessage::query(
+                transaction,
+                "get_peers",
+                QueryArguments {
+                    id: ByteBuf::from(node_id.to_vec()),
+                    info_hash: ByteBuf::from(info_hash.to_vec()),
+                    port: None,
+                    token: None,
+                    implied_port: None,
+                },
+            );
+            match socket
+                .send_to(&serde_bencode::to_bytes(&query)?, addr)
+                .await
+            {
+                Ok(_) => sent += 1,
+                Err(error) => debug!("Could not query DHT node {}: {:?}", addr, error),
+            }
+        }
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut answered = 0;
+        let mut buffer = [0; 2048];
+        while answered < sent {
+            let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buffer)).await else {
+                break;
+            };
+            let (length, from) = received?;
+            let Ok(KrpcMessage {
+                r: Some(response), ..
+            }) = serde_bencode::from_bytes::<KrpcMessage>(&buffer[..length])
+            else {
+                continue;
+            };
+            answered += 1;
+            lookup.nodes.push(from);
+            if let Some(token) = response.token {
+                lookup.tokens.push((from, token.into_vec()));
+            }
+            for value in response.values.unwrap_or_default() {
+                peers.extend(parse_value(&value));
+            }
+            if let Some(nodes) = response.nodes {
+                frontier.extend(parse_compact_nodes(&nodes));
+            }
+        }
+    }
+    lookup.peers = peers.into_iter().collect();
+    Ok(lookup)
+}
+
+/// One entry of a `values` list, a compact IPv4 or IPv6 peer told apart by its length
+fn parse_value(value: &[u8]) -> Vec<Peer> {
+    match value.len() {
+        6 => parse_compact_peers(value),
+        18 => parse_compact_peers6(value),
+        _ => Vec::new(),
+    }
+}
+
+/// Tells the nodes that handed us a token that we take connections for `info_hash` on `port`
+pub async fn announce_peer(
+    node_id: [u8; 20],
+    info_hash: [u8; 20],
+    port: u16,
+    tokens: &[(SocketAddr, Vec<u8>)],
+) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    for (transaction, (addr, token)) in tokens.iter().enumerate() {
+        if !addr.is_ipv4() {
+            continue;
+        }
+        let query = KrpcMessage::query(
+            transaction as u16,
+            "announce_peer",
+            QueryArguments {
+                id: ByteBuf::from(node_id.to_vec()),
+                info_hash: ByteBuf::from(info_hash.to_vec()),
+                port: Some(port),
+                token: Some(ByteBuf::from(token.clone())),
+                implied_port: Some(0),
+            },
+        );
+        if let Err(error) = socket
+            .send_to(&serde_bencode::to_bytes(&query)?, addr)
+            .await
+        {
+            debug!("Could not announce to DHT node {}: {:?}", addr, error);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn routing_table_ignores_known_nodes() {
+        let mut table = RoutingTable::default();
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        assert!(table.add_node(addr));
+        assert!(!table.add_node(addr));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn it_parses_values_by_their_length() {
+        let v4 = parse_value(&[10, 0, 0, 1, 0x1a, 0xe1]);
+        assert_eq!(
+            vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()],
+            addrs(&v4)
+        );
+        let mut value = vec![0; 16];
+        value[15] = 1;
+        value.extend_from_slice(&[0x1a, 0xe1]);
+        assert_eq!(
+            vec!["[::1]:6881".parse::<SocketAddr>().unwrap()],
+            addrs(&parse_value(&value))
+        );
+        assert!(parse_value(&[0; 12]).is_empty());
+    }
+
+    fn addrs(peers: &[Peer]) -> Vec<SocketAddr> {
+        peers.iter().map(|peer| peer.addr).collect()
+    }
+
+    #[test]
+    fn it_encodes_get_peers_queries() {
+        let query = KrpcMessage::query(
+            1,
+            "get_peers",
+            QueryArguments {
+                id: ByteBuf::from(vec![b'a'; 20]),
+                info_hash: ByteBuf::from(vec![b'b'; 20]),
+                port: None,
+                token: None,
+                implied_port: None,
+            },
+        );
+        let expected = format!(
+            "d1:ad2:id20:{}9:info_hash20:{}e1:q9:get_peers1:t2:\\x00\\x011:y1:qe",
+            "a".repeat(20),
+            "b".repeat(20)
+        );
+        assert_eq!(
+            expected,
+            serde_bencode::to_bytes(&query)
+                .unwrap()
+                .escape_ascii()
+                .to_string()
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::{fmt, time::Duration};
+
+/// How long a peer gets to complete the handshake once the connection is up
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Why we dropped a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    HandshakeTimeout,
+    InvalidHandshake,
+    /// A frame declared a length above the codec limit
+    FrameTooLarge,
+    MalformedMessage,
+    /// The peer sent a block we never asked for, nor cancelled
+    UnrequestedPiece,
+    ConnectionClosed,
+    /// Dropped to make room for a more promising peer
+    Evicted,
+    /// No blocks went either way for the idle timeout while candidates were waiting
+    Idle,
+    /// The connection is plaintext but our policy for the peer requires encryption
+    EncryptionRequired,
+    /// The address sent too much data that failed hash verification
+    Banned,
+    /// Another connection to the same peer id won
+    Duplicate,
+    /// The peer turned out to be ourselves
+    SelfConnection,
+}
+
+/// Error carrying the reason a peer has to be disconnected, so it survives being passed
+/// around as an `anyhow::Error` and can be told apart from other failures
+#[derive(Debug)]
+pub struct Disconnect {
+    pub reason: DisconnectReason,
+    pub detail: String,
+}
+
+impl Disconnect {
+    pub fn new(reason: DisconnectReason, detail: impl Into<String>) -> Self {
+        Self {
+            reason,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.reason, self.detail)
+    }
+}
+
+impl std::error::Error for Disconnect {}
+
+impl DisconnectReason {
+    /// Errors that don't say otherwise are socket failures or payloads we couldn't parse
+    pub fn of(error: &anyhow::Error) -> Self {
+        if let Some(disconnect) = error.downcast_ref::<Disconnect>() {
+            disconnect.reason
+        } else if error.downcast_ref::<std::io::Error>().is_some() {
+            DisconnectReason::ConnectionClosed
+        } else {
+            DisconnectReason::MalformedMessage
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_classifies_errors() {
+        let error = anyhow::Error::new(Disconnect::new(DisconnectReason::FrameTooLarge, "big"));
+        assert_eq!(
+            DisconnectReason::FrameTooLarge,
+            DisconnectReason::of(&error)
+        );
+        let error = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+        assert_eq!(
+            DisconnectReason::ConnectionClosed,
+            DisconnectReason::of(&error)
+        );
+        assert_eq!(
+            DisconnectReason::MalformedMessage,
+            DisconnectReason::of(&anyhow::anyhow!("Bad bitfield"))
+        );
+    }
+}
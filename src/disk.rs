@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+        Semaphore,
+    },
+    task::spawn_blocking,
+};
+
+use crate::storage::Storage;
+
+/// Verified pieces waiting for the disk before new ones are refused
+pub const DISK_QUEUE_DEPTH: usize = 16;
+/// Pieces written at once, each on its own blocking thread
+pub const DISK_WRITERS: usize = 4;
+
+pub struct WriteJob {
+    pub piece: u32,
+    pub data: Vec<u8>,
+}
+
+/// A write that finished, in whatever order the disk got through them
+#[derive(Debug)]
+pub struct WriteDone {
+    pub piece: u32,
+    pub result: Result<()>,
+}
+
+/// Writes pieces off the runtime: jobs queue up in a bounded channel, a task hands them to
+/// blocking threads and reports each as it completes. A full queue is the signal to stop
+/// requesting blocks until the disk catches up
+pub struct DiskWriter {
+    storage: Arc<dyn Storage>,
+    /// Started with the first job, so a writer can be made outside the runtime
+    jobs: Option<Sender<WriteJob>>,
+    done: (UnboundedSender<WriteDone>, UnboundedReceiver<WriteDone>),
+    /// Submitted pieces not reported back yet
+    writing: usize,
+}
+
+impl DiskWriter {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            jobs: None,
+            done: mpsc::unbounded_channel(),
+            writing: 0,
+        }
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// Whether pieces are still on their way to the disk
+    pub fn is_writing(&self) -> bool {
+        self.writing > 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.writing >= DISK_QUEUE_DEPTH
+    }
+
+    fn jobs(&mut self) -> &Sender<WriteJob> {
+        let storage = &self.storage;
+        let done = &self.done.0;
+        self.jobs.get_or_insert_with(|| {
+            let (jobs, queue) = mpsc::channel(DISK_QUEUE_DEPTH);
+            tokio::spawn(write_jobs(storage.clone(), queue, done.clone()));
+            jobs
+        })
+    }
+
+    /// Queues `data` to be written as `piece`, handing it back when the queue is full
+    pub fn submit(&mut self, piece: u32, data: Vec<u8>) -> Result<(), WriteJob> {
+        let job = WriteJob { piece, data };
+        if self.is_full() {
+            return Err(job);
+        }
+        match self.jobs().try_send(job) {
+            Ok(()) => {
+                self.writing += 1;
+                Ok(())
+            }
+            Err(TrySendError::Full(job) | TrySendError::Closed(job)) => Err(job),
+        }
+    }
+
+    /// The writes that finished since the last call
+    pub fn completed(&mut self) -> Vec<WriteDone> {
+        let mut completed = Vec::new();
+        while let Ok(done) = self.done.1.try_recv() {
+            completed.push(done);
+        }
+        self.writing -= completed.len();
+        completed
+    }
+
+    /// Waits for every submitted write
+    pub async fn flush(&mut self) -> Vec<WriteDone> {
+        let mut completed = Vec::new();
+        while completed.len() < self.writing {
+            match self.done.1.recv().await {
+                Some(done) => completed.push(done),
+                None => break,
+            }
+        }
+        self.writing -= completed.len();
+        completed
+    }
+
+    /// Has the storage make every finished write durable, off the runtime
+    pub async fn sync(&self) -> Result<()> {
+        let storage = self.storage.clone();
+        spawn_blocking(move || storage.flush()).await?
+    }
+}
+
+async fn write_jobs(
+    storage: Arc<dyn Storage>,
+    mut queue: Receiver<WriteJob>,
+    done: UnboundedSender<WriteDone>,
+) {
+    let writers = Arc::new(Semaphore::new(DISK_WRITERS));
+    while let Some(job) = queue.recv().await {
+        let Ok(permit) = writers.clone().acquire_owned().await else {
+            return;
+        };
+        let (storage, done) = (storage.clone(), done.clone());
+        tokio::spawn(async move {
+            let piece = job.piece;
+            let result =
+                spawn_blocking(move || storage.write_block(job.piece as usize, 0, &job.data))
+                    .await
+                    .unwrap_or_else(|error| Err(anyhow!("Writer failed: {}", error)));
+            drop(permit);
+            let _ = done.send(WriteDone { piece, result });
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse_torrent::TorrentFile, storage::MemoryStorage};
+
+    #[tokio::test]
+    async fn it_reports_every_write() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod6:lengthi12e4:name7:disk.rs12:piece lengthi4e6:pieces0:ee",
+        )
+        .unwrap();
+        let storage = Arc::new(MemoryStorage::new(&torrent));
+        let mut writer = DiskWriter::new(storage.clone());
+        for piece in 0..3 {
+            let data = vec![b'a' + piece as u8; 4];
+            assert!(writer.submit(piece, data).is_ok());
+        }
+        let mut written: Vec<u32> = writer
+            .flush()
+            .await
+            .into_iter()
+            .map(|done| {
+                done.result.unwrap();
+                done.piece
+            })
+            .collect();
+        written.sort();
+        assert_eq!(vec![0, 1, 2], written);
+        assert!(!writer.is_writing());
+        assert_eq!(b"aaaabbbbcccc", &storage.read_block(0, 0, 12).unwrap()[..]);
+    }
+}
@@ -1,4 +1,44 @@
-use crate::parse_torrent::TorrentFile;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::bitfield::Bitfield;
+use crate::merkle::{hash_block, root, verify_proof, Hash, MERKLE_BLOCK_SIZE};
+use crate::messages::{Block, BlockRequest, HashRequest, BLOCK_BYTES, MAX_REQUEST_BYTES};
+use crate::parse_torrent::{file_spans, total_length, v2_files, FileSpan, TorrentFile};
+
+/// Pieces fetched in order past the first missing one in sequential mode
+pub const SEQUENTIAL_LOOKAHEAD: usize = 8;
+
+/// How the next piece to fetch from a peer is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PickOrder {
+    /// Rarest pieces first, which keeps the swarm healthy
+    #[default]
+    RarestFirst,
+    /// Pieces in order, so media can be previewed or streamed while downloading. Only
+    /// the `SEQUENTIAL_LOOKAHEAD` pieces from the first missing one are fetched in order,
+    /// peers without any of them stay busy with the rarest pieces further on
+    Sequential,
+}
+
+/// How much a file of the torrent is wanted, pieces get the highest priority of the
+/// files they overlap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum FilePriority {
+    /// Not downloaded, unless a piece is shared with a wanted file
+    Skip,
+    #[default]
+    Normal,
+    /// Picked before any normal piece
+    High,
+}
+
 pub enum PieceStatus {
     NotStarted,
     Downloading,
@@ -11,25 +51,1105 @@ pub struct Piece {
     pub content: Option<Vec<u8>>,
     pub status: PieceStatus,
     pub original_sha1: Vec<u8>,
+    pub length: usize,
+    /// Size of the blocks the piece is requested in, fixed until it is reset
+    block_size: u32,
+    /// Blocks that arrived
+    received: Bitfield,
+    /// Blocks requested and not received yet, by block
+    in_flight: HashMap<usize, InFlight>,
+    received_bytes: usize,
+}
+
+/// How many copies of each piece the connected peers hold between them, ours not counted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SwarmAvailability {
+    /// Copies of the rarest piece, 0 while some piece is nowhere to be found
+    pub rarest: usize,
+    /// Number of pieces by number of copies
+    pub histogram: BTreeMap<usize, usize>,
+    /// `rarest` plus the share of pieces with more copies than that, the "distributed
+    /// copies" other clients show
+    pub distributed_copies: f64,
+}
+
+/// Who was asked for a block still on its way, and since when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InFlight {
+    /// More than one peer in endgame
+    pub peers: Vec<SocketAddr>,
+    pub requested_at: Instant,
+}
+
+impl Piece {
+    fn new(original_sha1: Vec<u8>, length: usize, block_size: u32) -> Self {
+        Self {
+            content: None,
+            status: PieceStatus::NotStarted,
+            original_sha1,
+            length,
+            block_size,
+            received: Bitfield::new(length.div_ceil(block_size as usize)),
+            in_flight: HashMap::new(),
+            received_bytes: 0,
+        }
+    }
+
+    /// The block starting at `begin`, if `begin` is where one starts
+    fn block_at(&self, begin: u32) -> Option<usize> {
+        let aligned = begin.is_multiple_of(self.block_size) && (begin as usize) < self.length;
+        aligned.then_some((begin / self.block_size) as usize)
+    }
+
+    fn block_length(&self, block: usize) -> usize {
+        (self.length - block * self.block_size as usize).min(self.block_size as usize)
+    }
+
+    /// Every block of the piece, `index` being the piece's
+    pub fn requests(&self, index: u32) -> Vec<BlockRequest> {
+        BlockRequest::for_piece(index, self.length as u32, self.block_size)
+    }
+
+    /// Blocks neither received nor requested from anyone
+    pub fn missing_requests(&self, index: u32) -> Vec<BlockRequest> {
+        self.requests(index)
+            .into_iter()
+            .enumerate()
+            .filter(|(block, _)| !self.received.has(*block) && !self.in_flight.contains_key(block))
+            .map(|(_, request)| request)
+            .collect()
+    }
+
+    fn has_missing_blocks(&self) -> bool {
+        (0..self.received.len())
+            .any(|block| !self.received.has(block) && !self.in_flight.contains_key(&block))
+    }
+
+    pub fn received_bytes(&self) -> usize {
+        self.received_bytes
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    pub fn received_blocks(&self) -> &Bitfield {
+        &self.received
+    }
+
+    /// Offset and data of every block that arrived
+    pub fn received_data(&self) -> impl Iterator<Item = (u32, &[u8])> + '_ {
+        self.received.pieces().filter_map(|block| {
+            let begin = block * self.block_size as usize;
+            let data = self.content.as_deref()?;
+            Some((
+                begin as u32,
+                data.get(begin..begin + self.block_length(block))?,
+            ))
+        })
+    }
+
+    /// Copies a block into the piece buffer, returning true once every byte of the piece arrived
+    pub fn add_block(&mut self, begin: u32, data: &[u8]) -> Result<bool> {
+        let begin_offset = begin as usize;
+        if begin_offset + data.len() > self.length {
+            return Err(anyhow!(
+                "Block at {} of {} bytes overflows piece of {} bytes",
+                begin,
+                data.len(),
+                self.length
+            ));
+        }
+        let block = self
+            .block_at(begin)
+            .filter(|block| self.block_length(*block) == data.len())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Block at {} of {} bytes isn't one of the {} byte blocks we request",
+                    begin,
+                    data.len(),
+                    self.block_size
+                )
+            })?;
+        let length = self.length;
+        let content = self.content.get_or_insert_with(|| vec![0; length]);
+        self.in_flight.remove(&block);
+        if !self.received.has(block) {
+            content[begin_offset..begin_offset + data.len()].copy_from_slice(data);
+            self.received.set(block);
+            self.received_bytes += data.len();
+        }
+        if self.received_bytes >= self.length {
+            self.status = PieceStatus::Downloaded;
+            Ok(true)
+        } else {
+            self.status = PieceStatus::Downloading;
+            Ok(false)
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.content = None;
+        self.status = PieceStatus::NotStarted;
+        self.received = Bitfield::new(self.received.len());
+        self.in_flight.clear();
+        self.received_bytes = 0;
+    }
+}
+
+/// Where the pieces of a v2 file are and the hashes to check them against
+struct MerkleFile {
+    pieces_root: Hash,
+    first_piece: usize,
+    pieces: usize,
+    length: u64,
+    /// Roots of the piece sized subtrees, proven against `pieces_root`. Empty for files
+    /// of a single piece, whose blocks hash up to `pieces_root` directly
+    piece_layer: Vec<Hash>,
+}
+
+impl MerkleFile {
+    fn of(torrent: &TorrentFile) -> Vec<Self> {
+        let piece_length = torrent.info.piece_length as u64;
+        let piece_padding = root(&[], blocks_per_piece(piece_length as usize), [0; 32]);
+        let mut first_piece = 0;
+        let mut files = Vec::new();
+        for file in v2_files(torrent) {
+            let Some(pieces_root) = file.pieces_root else {
+                continue;
+            };
+            let pieces = file.length.div_ceil(piece_length) as usize;
+            let piece_layer = torrent
+                .piece_layers
+                .as_ref()
+                .and_then(|layers| layers.get(&ByteBuf::from(pieces_root.to_vec())))
+                .map(|layer| {
+                    layer
+                        .chunks_exact(32)
+                        .map(|hash| hash.try_into().unwrap())
+                        .collect::<Vec<Hash>>()
+                })
+                .filter(|layer| {
+                    layer.len() == pieces
+                        && root(layer, pieces.next_power_of_two(), piece_padding) == pieces_root
+                })
+                .unwrap_or_default();
+            files.push(Self {
+                pieces_root,
+                first_piece,
+                pieces,
+                length: file.length,
+                piece_layer,
+            });
+            first_piece += pieces;
+        }
+        files
+    }
+}
+
+fn blocks_per_piece(piece_length: usize) -> usize {
+    (piece_length / MERKLE_BLOCK_SIZE).max(1)
 }
 
 pub struct Download {
     pub pieces: Vec<Piece>,
+    /// Pieces we have and can advertise to peers
+    pub have: Bitfield,
+    /// v2 Merkle tree hashes received from peers and proven against their file's
+    /// pieces root, by pieces root and layer
+    pub verified_hashes: HashMap<(Hash, u32), BTreeMap<u32, Hash>>,
+    /// Connected peers holding each piece, from their bitfields and haves
+    availability: Vec<usize>,
+    order: PickOrder,
+    piece_length: usize,
+    files: Vec<FileSpan>,
+    file_priorities: Vec<FilePriority>,
+    piece_priorities: Vec<FilePriority>,
+    /// Pieces wanted by a given time, picked before any other
+    deadlines: HashMap<usize, Instant>,
+    /// Files of v2 and hybrid torrents, empty for v1 ones
+    merkle_files: Vec<MerkleFile>,
+    /// Peers initial seeding told about each piece
+    revealed: HashMap<usize, HashSet<SocketAddr>>,
+    /// Pieces that showed up at peers they were never revealed to, i.e. that the swarm
+    /// passes on without our help
+    spread: Bitfield,
+    paused: bool,
 }
 
 impl Download {
     pub fn from(torrent: &TorrentFile) -> Self {
+        let piece_length = torrent.info.piece_length as usize;
+        let mut remaining = total_length(torrent) as usize;
+        let number_of_pieces = torrent.info.pieces.len() / 20;
+        let files = file_spans(torrent);
         Self {
+            have: Bitfield::new(number_of_pieces),
+            verified_hashes: HashMap::new(),
+            availability: vec![0; number_of_pieces],
+            order: PickOrder::default(),
+            piece_length,
+            file_priorities: vec![FilePriority::default(); files.len()],
+            files,
+            piece_priorities: vec![FilePriority::default(); number_of_pieces],
+            deadlines: HashMap::new(),
+            merkle_files: MerkleFile::of(torrent),
+            revealed: HashMap::new(),
+            spread: Bitfield::new(number_of_pieces),
+            paused: false,
             pieces: torrent
                 .info
                 .pieces
                 .chunks(20)
-                .map(|sha1| Piece {
-                    content: None,
-                    original_sha1: sha1.to_owned(),
-                    status: PieceStatus::NotStarted,
+                .map(|sha1| {
+                    let length = remaining.min(piece_length);
+                    remaining -= length;
+                    Piece::new(sha1.to_owned(), length, BLOCK_BYTES)
                 })
                 .collect(),
         }
     }
+
+    pub fn files(&self) -> &[FileSpan] {
+        &self.files
+    }
+
+    pub fn file_priorities(&self) -> &[FilePriority] {
+        &self.file_priorities
+    }
+
+    /// Changes how much the file at `index`, in the order of the torrent, is wanted
+    pub fn set_file_priority(&mut self, index: usize, priority: FilePriority) -> Result<()> {
+        let file = self
+            .file_priorities
+            .get_mut(index)
+            .ok_or_else(|| anyhow!("No file {} in the torrent", index))?;
+        *file = priority;
+        self.piece_priorities = vec![FilePriority::Skip; self.pieces.len()];
+        let piece_length = self.piece_length as u64;
+        for (span, priority) in self.files.iter().zip(&self.file_priorities) {
+            if span.length == 0 {
+                continue;
+            }
+            let first = (span.offset / piece_length) as usize;
+            let last = ((span.offset + span.length - 1) / piece_length) as usize;
+            for piece in &mut self.piece_priorities[first..=last] {
+                *piece = (*piece).max(*priority);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `available` has a piece of a file we want that we don't have yet, which is
+    /// never the case once we're finished
+    pub fn is_interested_in(&self, available: &Bitfield) -> bool {
+        available
+            .pieces()
+            .any(|piece| !self.have.has(piece) && self.piece_priority(piece) != FilePriority::Skip)
+    }
+
+    pub fn piece_priority(&self, piece: usize) -> FilePriority {
+        self.piece_priorities
+            .get(piece)
+            .copied()
+            .unwrap_or(FilePriority::Skip)
+    }
+
+    /// Stops `pick` from handing out pieces until `resume`, what was received is kept
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether every piece of the files we want is there, which is all of them unless
+    /// some files are skipped
+    pub fn is_finished(&self) -> bool {
+        (0..self.pieces.len())
+            .all(|piece| self.have.has(piece) || self.piece_priority(piece) == FilePriority::Skip)
+    }
+
+    /// Bytes of the wanted pieces still missing, what trackers get as `left`
+    pub fn left(&self) -> u64 {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                !self.have.has(*index) && self.piece_priority(*index) != FilePriority::Skip
+            })
+            .map(|(_, piece)| piece.length as u64)
+            .sum()
+    }
+
+    /// Verified bytes of each file, in the order of the torrent
+    pub fn file_progress(&self) -> Vec<u64> {
+        let piece_length = self.piece_length as u64;
+        self.files
+            .iter()
+            .map(|span| {
+                if span.length == 0 {
+                    return 0;
+                }
+                let first = span.offset / piece_length;
+                let last = (span.offset + span.length - 1) / piece_length;
+                (first..=last)
+                    .filter(|piece| self.have.has(*piece as usize))
+                    .map(|piece| {
+                        let start = (piece * piece_length).max(span.offset);
+                        let end = ((piece + 1) * piece_length).min(span.offset + span.length);
+                        end - start
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    pub fn mark_have(&mut self, index: usize) {
+        self.have.set(index);
+        self.deadlines.remove(&index);
+    }
+
+    /// Wants `piece` within `milliseconds`, e.g. because a player is about to play it.
+    /// It is picked before anything else, even in a skipped file, until it arrives
+    pub fn set_piece_deadline(&mut self, piece: usize, milliseconds: u64) {
+        if piece < self.pieces.len() && !self.have.has(piece) {
+            let deadline = Instant::now() + std::time::Duration::from_millis(milliseconds);
+            self.deadlines.insert(piece, deadline);
+        }
+    }
+
+    pub fn reset_piece_deadline(&mut self, piece: usize) {
+        self.deadlines.remove(&piece);
+    }
+
+    pub fn clear_piece_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    /// Throws away a piece we had, e.g. after it failed a disk check, so it gets downloaded again
+    pub fn discard(&mut self, index: usize) {
+        if let Some(piece) = self.pieces.get_mut(index) {
+            piece.reset();
+            self.have.unset(index);
+        }
+    }
+
+    /// Size of the blocks of pieces not started yet, bytes
+    pub fn set_block_size(&mut self, block_size: u32) {
+        for piece in &mut self.pieces {
+            if matches!(piece.status, PieceStatus::NotStarted) && piece.in_flight.is_empty() {
+                *piece = Piece::new(
+                    std::mem::take(&mut piece.original_sha1),
+                    piece.length,
+                    block_size,
+                );
+            }
+        }
+    }
+
+    /// Puts back the `blocks` of `piece` received before a restart, reading their data at
+    /// an offset of the piece with `load`. Returns false, leaving the piece as it was, when
+    /// a block can't be read
+    pub fn restore_partial(
+        &mut self,
+        piece: usize,
+        block_size: u32,
+        blocks: Bitfield,
+        mut load: impl FnMut(u64, usize) -> Result<Vec<u8>>,
+    ) -> bool {
+        let Some(restored) = self.pieces.get_mut(piece) else {
+            return false;
+        };
+        let mut partial = Piece::new(
+            restored.original_sha1.clone(),
+            restored.length,
+            block_size.max(1),
+        );
+        let count = partial.received.len();
+        for block in blocks.pieces().filter(|block| *block < count) {
+            let begin = block * partial.block_size as usize;
+            let Ok(data) = load(begin as u64, partial.block_length(block)) else {
+                return false;
+            };
+            if partial.add_block(begin as u32, &data).is_err() {
+                return false;
+            }
+        }
+        // A complete piece would never be checked, it's downloaded again instead
+        if partial.received_bytes == 0 || partial.received_bytes >= partial.length {
+            return false;
+        }
+        *restored = partial;
+        true
+    }
+
+    /// Blocks of `piece` neither received nor requested from anyone
+    pub fn missing_requests(&self, piece: u32) -> Vec<BlockRequest> {
+        self.pieces
+            .get(piece as usize)
+            .map(|missing| missing.missing_requests(piece))
+            .unwrap_or_default()
+    }
+
+    /// Every block of `piece`
+    pub fn block_requests(&self, piece: u32) -> Result<Vec<BlockRequest>> {
+        self.pieces
+            .get(piece as usize)
+            .map(|known| known.requests(piece))
+            .ok_or_else(|| anyhow!("Unknown piece {}", piece))
+    }
+
+    fn block_of_mut(&mut self, request: &BlockRequest) -> Option<(&mut Piece, usize)> {
+        let piece = self.pieces.get_mut(request.index as usize)?;
+        let block = piece.block_at(request.begin)?;
+        Some((piece, block))
+    }
+
+    /// Notes that `peer` was asked for the block of `request`
+    pub fn mark_requested(&mut self, request: &BlockRequest, peer: SocketAddr) {
+        let Some((piece, block)) = self.block_of_mut(request) else {
+            return;
+        };
+        if piece.received.has(block) {
+            return;
+        }
+        let in_flight = piece.in_flight.entry(block).or_insert_with(|| InFlight {
+            peers: Vec::new(),
+            requested_at: Instant::now(),
+        });
+        if !in_flight.peers.contains(&peer) {
+            in_flight.peers.push(peer);
+        }
+    }
+
+    /// Forgets that `peer` was asked for the block of `request`, because the request was
+    /// cancelled, timed out or the peer left
+    pub fn mark_cancelled(&mut self, request: &BlockRequest, peer: SocketAddr) {
+        let Some((piece, block)) = self.block_of_mut(request) else {
+            return;
+        };
+        if let Some(in_flight) = piece.in_flight.get_mut(&block) {
+            in_flight.peers.retain(|asked| *asked != peer);
+            if in_flight.peers.is_empty() {
+                piece.in_flight.remove(&block);
+            }
+        }
+    }
+
+    /// Who the block of `request` is expected from, and since when
+    pub fn in_flight(&self, request: &BlockRequest) -> Option<&InFlight> {
+        let piece = self.pieces.get(request.index as usize)?;
+        piece.in_flight.get(&piece.block_at(request.begin)?)
+    }
+
+    /// Bytes of the content we have, counting blocks of pieces still downloading
+    pub fn received_bytes(&self) -> u64 {
+        self.pieces
+            .iter()
+            .enumerate()
+            .map(|(index, piece)| {
+                if self.have.has(index) {
+                    piece.length as u64
+                } else {
+                    piece.received_bytes as u64
+                }
+            })
+            .sum()
+    }
+
+    pub fn availability(&self) -> &[usize] {
+        &self.availability
+    }
+
+    pub fn swarm_availability(&self) -> SwarmAvailability {
+        let mut histogram = BTreeMap::new();
+        for copies in &self.availability {
+            *histogram.entry(*copies).or_default() += 1;
+        }
+        let rarest = self.availability.iter().copied().min().unwrap_or(0);
+        let above = self
+            .availability
+            .iter()
+            .filter(|copies| **copies > rarest)
+            .count();
+        SwarmAvailability {
+            rarest,
+            histogram,
+            distributed_copies: rarest as f64
+                + above as f64 / self.availability.len().max(1) as f64,
+        }
+    }
+
+    /// Notes that initial seeding told `peer` we have `piece`
+    pub fn mark_revealed(&mut self, piece: usize, peer: SocketAddr) {
+        self.revealed.entry(piece).or_default().insert(peer);
+    }
+
+    /// Notes that `peer` announced `piece`, returning whether that means the piece spread:
+    /// it was revealed, just not to this peer, which got it from someone else
+    pub fn observe_have(&mut self, piece: usize, peer: SocketAddr) -> bool {
+        let spread = self
+            .revealed
+            .get(&piece)
+            .is_some_and(|revealed| !revealed.contains(&peer));
+        if spread {
+            self.spread.set(piece);
+        }
+        spread
+    }
+
+    pub fn times_revealed(&self, piece: usize) -> usize {
+        self.revealed.get(&piece).map_or(0, HashSet::len)
+    }
+
+    pub fn has_spread(&self, piece: usize) -> bool {
+        self.spread.has(piece)
+    }
+
+    /// How many pieces the swarm passed on after initial seeding revealed them
+    pub fn spread_pieces(&self) -> usize {
+        self.spread.count()
+    }
+
+    /// Counts the pieces of a peer that connected or sent its bitfield
+    pub fn add_available(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.pieces() {
+            self.add_available_piece(piece);
+        }
+    }
+
+    /// Forgets the pieces of a peer that left or replaced its bitfield
+    pub fn remove_available(&mut self, bitfield: &Bitfield) {
+        for piece in bitfield.pieces() {
+            self.remove_available_piece(piece);
+        }
+    }
+
+    pub fn add_available_piece(&mut self, piece: usize) {
+        if let Some(count) = self.availability.get_mut(piece) {
+            *count += 1;
+        }
+    }
+
+    pub fn remove_available_piece(&mut self, piece: usize) {
+        if let Some(count) = self.availability.get_mut(piece) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    /// Whether the piece is missing, belongs to a file we want and some of its blocks neither
+    /// arrived nor are on their way
+    pub fn wants(&self, piece: usize) -> bool {
+        self.piece_priority(piece) != FilePriority::Skip && self.lacks(piece)
+    }
+
+    /// Whether some blocks of the piece neither arrived nor are on their way
+    fn lacks(&self, piece: usize) -> bool {
+        !self.have.has(piece)
+            && self.pieces.get(piece).is_some_and(|missing| {
+                matches!(
+                    missing.status,
+                    PieceStatus::NotStarted | PieceStatus::Downloading
+                ) && missing.has_missing_blocks()
+            })
+    }
+
+    pub fn order(&self) -> PickOrder {
+        self.order
+    }
+
+    pub fn set_order(&mut self, order: PickOrder) {
+        self.order = order;
+    }
+
+    /// The next piece to fetch from `peer`: the one with the earliest deadline, then
+    /// following the pick order, see `pick_rarest`
+    pub fn pick(&self, peer: &Bitfield, skip: impl Fn(u32) -> bool) -> Option<u32> {
+        if self.paused {
+            return None;
+        }
+        let urgent = self
+            .deadlines
+            .iter()
+            .filter(|(piece, _)| peer.has(**piece) && self.lacks(**piece) && !skip(**piece as u32))
+            .min_by_key(|(piece, deadline)| (**deadline, **piece));
+        if let Some((piece, _)) = urgent {
+            return Some(*piece as u32);
+        }
+        if self.order == PickOrder::Sequential {
+            let first_missing = (0..self.pieces.len()).find(|&piece| !self.have.has(piece))?;
+            let window =
+                first_missing..(first_missing + SEQUENTIAL_LOOKAHEAD).min(self.pieces.len());
+            let next = window
+                .into_iter()
+                .find(|&piece| peer.has(piece) && self.wants(piece) && !skip(piece as u32));
+            if let Some(piece) = next {
+                return Some(piece as u32);
+            }
+        }
+        self.pick_rarest(peer, skip)
+    }
+
+    /// The piece we want that the fewest connected peers have, among those `peer` has and
+    /// `skip` doesn't rule out, e.g. because another peer is fetching it. Pieces of high
+    /// priority files come first, ties are broken at random so peers don't all start on
+    /// the same piece
+    pub fn pick_rarest(&self, peer: &Bitfield, skip: impl Fn(u32) -> bool) -> Option<u32> {
+        let mut rng = rand::thread_rng();
+        let mut rarest = None;
+        let mut ties = 0;
+        for piece in peer.pieces() {
+            if !self.wants(piece) || skip(piece as u32) {
+                continue;
+            }
+            // Higher priorities sort first
+            let rank = (
+                std::cmp::Reverse(self.piece_priority(piece)),
+                self.availability[piece],
+            );
+            match rarest {
+                Some((_, best)) if rank > best => continue,
+                Some((_, best)) if rank == best => {
+                    // Keeps each of the equally rare pieces with the same odds
+                    ties += 1;
+                    if rng.gen_range(0..ties) == 0 {
+                        rarest = Some((piece, rank));
+                    }
+                }
+                _ => {
+                    rarest = Some((piece, rank));
+                    ties = 1;
+                }
+            }
+        }
+        rarest.map(|(piece, _)| piece as u32)
+    }
+
+    /// Checks the hashes of a BEP 52 hashes message against the pieces root they
+    /// claim to belong to and keeps them for verifying blocks of that file
+    pub fn add_hashes(&mut self, request: &HashRequest, hashes: &[Hash]) -> Result<()> {
+        let length = request.length as usize;
+        if hashes.len() < length {
+            return Err(anyhow!("Got {} hashes out of {}", hashes.len(), length));
+        }
+        let (layer, proof) = hashes.split_at(length);
+        verify_proof(layer, request.index, proof, &request.pieces_root)?;
+        let known = self
+            .verified_hashes
+            .entry((request.pieces_root, request.base_layer))
+            .or_default();
+        for (offset, hash) in layer.iter().enumerate() {
+            known.insert(request.index + offset as u32, *hash);
+        }
+        Ok(())
+    }
+
+    /// Whether a peer may be sent what it asked for: we have the piece and the request
+    /// stays inside it
+    pub fn can_serve(&self, request: &BlockRequest) -> bool {
+        let index = request.index as usize;
+        self.have.has(index)
+            && request.length <= MAX_REQUEST_BYTES
+            && (request.begin as usize)
+                .checked_add(request.length as usize)
+                .is_some_and(|end| end <= self.pieces[index].length)
+    }
+
+    /// The bytes a peer asked for, if we can serve them and the piece is still in memory
+    pub fn block(&self, request: &BlockRequest) -> Option<&[u8]> {
+        if !self.can_serve(request) {
+            return None;
+        }
+        let content = self.pieces[request.index as usize].content.as_deref()?;
+        let begin = request.begin as usize;
+        content.get(begin..begin + request.length as usize)
+    }
+
+    /// Whether a completed piece matches its SHA-1 from the torrent, and for v2 and hybrid
+    /// torrents the Merkle tree of its file too
+    pub fn verify_piece(&self, index: usize) -> bool {
+        let Some(piece) = self.pieces.get(index) else {
+            return false;
+        };
+        match &piece.content {
+            Some(content) if content.len() == piece.length => {
+                Sha1::digest(content).as_slice() == piece.original_sha1
+                    && self.matches_merkle_tree(index, content)
+            }
+            _ => false,
+        }
+    }
+
+    fn matches_merkle_tree(&self, index: usize, content: &[u8]) -> bool {
+        let Some(file) = self.merkle_file(index) else {
+            return true;
+        };
+        let start = (index - file.first_piece) as u64 * self.piece_length as u64;
+        // Hybrid torrents pad the last piece of a file, the tree only covers the file
+        let in_file = content.len().min((file.length - start) as usize);
+        let leaves: Vec<Hash> = content[..in_file]
+            .chunks(MERKLE_BLOCK_SIZE)
+            .map(hash_block)
+            .collect();
+        if file.pieces == 1 {
+            return root(&leaves, leaves.len().next_power_of_two(), [0; 32]) == file.pieces_root;
+        }
+        match file.piece_layer.get(index - file.first_piece) {
+            Some(expected) => {
+                root(&leaves, blocks_per_piece(self.piece_length), [0; 32]) == *expected
+            }
+            None => true,
+        }
+    }
+
+    fn merkle_file(&self, piece: usize) -> Option<&MerkleFile> {
+        self.merkle_files
+            .iter()
+            .find(|file| (file.first_piece..file.first_piece + file.pieces).contains(&piece))
+    }
+
+    pub fn is_v2(&self) -> bool {
+        !self.merkle_files.is_empty()
+    }
+
+    /// Asks for the leaf hashes of `piece` with the proof up to the pieces root of its file,
+    /// so its blocks can be checked one by one as they arrive. None for v1 pieces and
+    /// pieces whose leaves we know already
+    pub fn leaf_hash_request(&self, piece: usize) -> Option<HashRequest> {
+        let file = self.merkle_file(piece)?;
+        let width = file
+            .length
+            .div_ceil(MERKLE_BLOCK_SIZE as u64)
+            .next_power_of_two() as usize;
+        let length = blocks_per_piece(self.piece_length).min(width);
+        let index = (piece - file.first_piece) * blocks_per_piece(self.piece_length);
+        let known = self
+            .verified_hashes
+            .get(&(file.pieces_root, 0))
+            .is_some_and(|leaves| {
+                (index..index + length).all(|leaf| leaves.contains_key(&(leaf as u32)))
+            });
+        if known {
+            return None;
+        }
+        Some(HashRequest {
+            pieces_root: file.pieces_root,
+            base_layer: 0,
+            index: index as u32,
+            length: length as u32,
+            proof_layers: (width / length).trailing_zeros(),
+        })
+    }
+
+    /// Whether the 16 KiB blocks of `block` match the leaf hashes we got from peers. Blocks
+    /// of v1 pieces, with unknown leaves or not aligned on leaves pass, the piece check
+    /// still catches them
+    pub fn block_matches_hashes(&self, block: &Block) -> bool {
+        let Some(file) = self.merkle_file(block.index as usize) else {
+            return true;
+        };
+        let Some(leaves) = self.verified_hashes.get(&(file.pieces_root, 0)) else {
+            return true;
+        };
+        let piece_start =
+            (block.index as usize - file.first_piece) as u64 * self.piece_length as u64;
+        block
+            .data
+            .chunks(MERKLE_BLOCK_SIZE)
+            .enumerate()
+            .all(|(chunk, data)| {
+                let offset = piece_start + block.begin as u64 + (chunk * MERKLE_BLOCK_SIZE) as u64;
+                if offset >= file.length || !offset.is_multiple_of(MERKLE_BLOCK_SIZE as u64) {
+                    return true;
+                }
+                let leaf_length = (file.length - offset).min(MERKLE_BLOCK_SIZE as u64) as usize;
+                if data.len() < leaf_length {
+                    return true;
+                }
+                let leaf = (offset / MERKLE_BLOCK_SIZE as u64) as u32;
+                leaves
+                    .get(&leaf)
+                    .is_none_or(|expected| hash_block(&data[..leaf_length]) == *expected)
+            })
+    }
+
+    /// Whether the block was already received, from someone else or as part of a piece we have
+    pub fn has_block(&self, block: &Block) -> bool {
+        let index = block.index as usize;
+        self.have.has(index)
+            || self.pieces.get(index).is_some_and(|piece| {
+                piece
+                    .block_at(block.begin)
+                    .is_some_and(|at| piece.received.has(at))
+            })
+    }
+
+    /// Stores a block received from a peer, returning true if it completed its piece
+    pub fn add_block(&mut self, block: &Block) -> Result<bool> {
+        let piece = self
+            .pieces
+            .get_mut(block.index as usize)
+            .ok_or_else(|| anyhow!("Block for unknown piece {}", block.index))?;
+        piece.add_block(block.begin, &block.data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_torrent::parse_torrent;
+    use bytes::Bytes;
+
+    #[test]
+    fn it_reassembles_blocks_into_pieces() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        let piece = Bytes::from(vec![7_u8; torrent.info.piece_length as usize]);
+        let blocks = Block::split(0, &piece, 16384);
+        let (last, rest) = blocks.split_last().unwrap();
+        for block in rest {
+            assert!(!download.add_block(block).unwrap());
+        }
+        assert!(download.has_block(&rest[0]) && !download.has_block(last));
+        assert!(download.add_block(last).unwrap());
+        assert_eq!(download.pieces[0].content.as_deref(), Some(&piece[..]));
+
+        let request = |begin, length| BlockRequest {
+            index: 0,
+            begin,
+            length,
+        };
+        assert_eq!(None, download.block(&request(0, 16384)));
+        download.mark_have(0);
+        assert_eq!(
+            Some(&piece[16384..32768]),
+            download.block(&request(16384, 16384))
+        );
+        let past_the_end = request(torrent.info.piece_length as u32, 1);
+        assert_eq!(None, download.block(&past_the_end));
+        download.discard(0);
+        assert!(!download.have.has(0));
+        assert!(download.pieces[0].content.is_none());
+        assert!(!download.add_block(last).unwrap());
+    }
+
+    #[test]
+    fn it_tracks_blocks_in_flight() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        download.set_block_size(65536);
+        let requests = download.block_requests(1).unwrap();
+        assert_eq!(4, requests.len());
+        let (first, second) = (
+            SocketAddr::from(([10, 0, 0, 1], 6881)),
+            SocketAddr::from(([10, 0, 0, 2], 6881)),
+        );
+        download.mark_requested(&requests[0], first);
+        download.mark_requested(&requests[0], second);
+        download.mark_requested(&requests[1], first);
+        assert_eq!(
+            vec![first, second],
+            download.in_flight(&requests[0]).unwrap().peers
+        );
+        assert_eq!(&requests[2..], download.pieces[1].missing_requests(1));
+
+        download.mark_cancelled(&requests[1], first);
+        assert_eq!(None, download.in_flight(&requests[1]));
+        let block = Block {
+            index: 1,
+            begin: 0,
+            data: Bytes::from(vec![1; 65536]),
+        };
+        download.add_block(&block).unwrap();
+        assert_eq!(None, download.in_flight(&requests[0]));
+        assert_eq!(&requests[1..], download.pieces[1].missing_requests(1));
+        assert_eq!(65536, download.received_bytes());
+        let misaligned = Block {
+            begin: 16384,
+            ..block
+        };
+        assert!(download.add_block(&misaligned).is_err());
+    }
+
+    #[test]
+    fn it_sizes_the_last_piece_and_its_last_block() {
+        let mut bencode =
+            b"d4:infod6:lengthi40000e4:name1:a12:piece lengthi32768e6:pieces40:".to_vec();
+        bencode.extend_from_slice(&[0; 40]);
+        bencode.extend_from_slice(b"ee");
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencode).unwrap();
+        let mut download = Download::from(&torrent);
+        assert_eq!(1, crate::parse_torrent::bitfield_size(&torrent));
+        assert_eq!(7232, download.pieces[1].length);
+        let lengths = |piece| -> Vec<u32> {
+            let requests = download.block_requests(piece).unwrap();
+            requests.iter().map(|request| request.length).collect()
+        };
+        assert_eq!(vec![16384, 16384], lengths(0));
+        assert_eq!(vec![7232], lengths(1));
+
+        let piece = Bytes::from(vec![7_u8; 7232]);
+        download.pieces[1].original_sha1 = Sha1::digest(&piece).to_vec();
+        assert!(download.add_block(&block(1, 0, &piece[..7000])).is_err());
+        assert!(download.add_block(&block(1, 0, &[7; 16384])).is_err());
+        assert!(download.add_block(&block(1, 0, &piece)).unwrap());
+        assert!(download.verify_piece(1));
+    }
+
+    fn block(index: u32, begin: u32, data: &[u8]) -> Block {
+        Block {
+            index,
+            begin,
+            data: Bytes::copy_from_slice(data),
+        }
+    }
+
+    #[test]
+    fn it_checks_completed_pieces_against_their_hash() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        let piece = Bytes::from(vec![7_u8; torrent.info.piece_length as usize]);
+        download.pieces[0].original_sha1 = Sha1::digest(&piece).to_vec();
+        assert!(!download.verify_piece(0));
+        for block in Block::split(0, &piece, 16384) {
+            download.add_block(&block).unwrap();
+        }
+        assert!(download.verify_piece(0));
+        download.pieces[0].content.as_mut().unwrap()[0] = 8;
+        assert!(!download.verify_piece(0));
+    }
+
+    #[test]
+    fn it_rejects_single_blocks_of_v2_files() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        assert!(!download.is_v2());
+        let leaves = [hash_block(&[1; 16384]), hash_block(&[2; 3616])];
+        let pieces_root = root(&leaves, 2, [0; 32]);
+        download.merkle_files.push(MerkleFile {
+            pieces_root,
+            first_piece: 0,
+            pieces: 1,
+            length: 16384 + 3616,
+            piece_layer: Vec::new(),
+        });
+        let request = download.leaf_hash_request(0).unwrap();
+        assert_eq!(
+            (0, 0, 2, 0),
+            (
+                request.base_layer,
+                request.index,
+                request.length,
+                request.proof_layers
+            )
+        );
+        download.add_hashes(&request, &leaves).unwrap();
+        assert_eq!(None, download.leaf_hash_request(0));
+
+        let block = |begin, data: Vec<u8>| Block {
+            index: 0,
+            begin,
+            data: Bytes::from(data),
+        };
+        assert!(download.block_matches_hashes(&block(0, vec![1; 16384])));
+        assert!(!download.block_matches_hashes(&block(0, vec![9; 16384])));
+        assert!(download.block_matches_hashes(&block(16384, vec![2; 3616])));
+        assert!(!download.block_matches_hashes(&block(16384, vec![3; 3616])));
+    }
+
+    #[test]
+    fn it_only_wants_pieces_of_wanted_files() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        let everything = Bitfield::full(download.pieces.len());
+        let total = download.left();
+        assert_eq!(total_length(&torrent) as u64, total);
+        download.mark_have(0);
+        assert_eq!(total - torrent.info.piece_length as u64, download.left());
+
+        download.set_file_priority(0, FilePriority::Skip).unwrap();
+        assert!(download.set_file_priority(1, FilePriority::High).is_err());
+        assert_eq!(0, download.left());
+        assert!(download.is_finished() && !download.have.is_complete());
+        assert_eq!(None, download.pick(&everything, |_| false));
+        download.set_file_priority(0, FilePriority::High).unwrap();
+        assert!(download.pick(&everything, |_| false).is_some());
+        download.pause();
+        assert_eq!(None, download.pick(&everything, |_| false));
+        download.resume();
+        assert!(download.pick(&everything, |_| false).is_some());
+    }
+
+    #[test]
+    fn it_picks_pieces_with_a_deadline_first() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        let everything = Bitfield::full(download.pieces.len());
+        download.set_order(PickOrder::Sequential);
+        download.set_piece_deadline(40, 2000);
+        download.set_piece_deadline(30, 1000);
+        assert_eq!(Some(30), download.pick(&everything, |_| false));
+        download.mark_have(30);
+        assert_eq!(Some(40), download.pick(&everything, |_| false));
+        download.set_file_priority(0, FilePriority::Skip).unwrap();
+        assert_eq!(Some(40), download.pick(&everything, |_| false));
+        download.clear_piece_deadlines();
+        assert_eq!(None, download.pick(&everything, |_| false));
+    }
+
+    #[test]
+    fn it_picks_the_rarest_piece_first() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        let pieces = download.pieces.len();
+        let everything = Bitfield::full(pieces);
+        download.add_available(&everything);
+        download.add_available(&everything);
+        download.remove_available_piece(3);
+        download.remove_available_piece(5);
+        download.remove_available_piece(5);
+        assert_eq!(Some(5), download.pick_rarest(&everything, |_| false));
+        assert_eq!(
+            Some(3),
+            download.pick_rarest(&everything, |piece| piece == 5)
+        );
+        download.mark_have(3);
+        download.mark_have(5);
+        assert_ne!(Some(3), download.pick_rarest(&everything, |_| false));
+
+        download.remove_available(&everything);
+        assert_eq!(1, download.availability()[0]);
+        let swarm = download.swarm_availability();
+        assert_eq!(0, swarm.rarest);
+        assert_eq!(Some(&2), swarm.histogram.get(&0));
+        assert_eq!(Some(&(pieces - 2)), swarm.histogram.get(&1));
+        assert_eq!(
+            (pieces - 2) as f64 / pieces as f64,
+            swarm.distributed_copies
+        );
+        download.set_order(PickOrder::Sequential);
+        assert_eq!(Some(0), download.pick(&everything, |_| false));
+        assert_eq!(Some(4), download.pick(&everything, |piece| piece < 3));
+        let mut edge = Bitfield::new(pieces);
+        edge.set(SEQUENTIAL_LOOKAHEAD - 1);
+        edge.set(SEQUENTIAL_LOOKAHEAD + 1);
+        assert_eq!(
+            Some(SEQUENTIAL_LOOKAHEAD as u32 - 1),
+            download.pick(&edge, |_| false)
+        );
+        assert_eq!(
+            Some(SEQUENTIAL_LOOKAHEAD as u32 + 1),
+            download.pick(&edge, |piece| piece == SEQUENTIAL_LOOKAHEAD as u32 - 1)
+        );
+        assert_eq!(
+            None,
+            download.pick_rarest(&Bitfield::new(pieces), |_| false)
+        );
+    }
 }
@@ -0,0 +1,32 @@
+use std::path::PathBuf;
+
+use crate::{disconnect::DisconnectReason, tracker::Peer};
+
+/// Events kept for subscribers that fall behind, older ones are dropped past this
+pub const EVENT_CAPACITY: usize = 256;
+
+/// What happened to the peers of a torrent, broadcast to every subscriber of its manager
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The handshake completed and the peer joined the torrent
+    Connected { peer: Peer },
+    /// Dialing the peer, or its inbound handshake, failed before it joined
+    HandshakeFailed {
+        peer: Peer,
+        reason: DisconnectReason,
+    },
+    /// The peer stopped serving our requests
+    Choked { peer: Peer },
+    /// Every block of a piece arrived, it still has to be verified. Web seeds send
+    /// pieces without a peer
+    PieceReceived { peer: Option<Peer>, piece: u32 },
+    /// A finished file doesn't match the `md5sum` of the torrent although all of its
+    /// pieces matched their hashes, e.g. a torrent made from a file that changed
+    FileMismatch { file: usize },
+    /// The finished files were moved out of the incomplete directory into `to`
+    ContentMoved { to: PathBuf },
+    Disconnected {
+        peer: Peer,
+        reason: DisconnectReason,
+    },
+}
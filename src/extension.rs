@@ -0,0 +1,107 @@
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+/// Extended message id reserved for the extension handshake itself
+pub const HANDSHAKE_ID: u8 = 0;
+/// Extended message id we ask peers to use when sending us ut_pex messages
+pub const UT_PEX_ID: u8 = 1;
+pub const UT_HOLEPUNCH_ID: u8 = 2;
+pub const LT_DONTHAVE_ID: u8 = 3;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Maps extension names to the message id the sender wants to receive them with
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    #[serde(default)]
+    pub p: Option<u16>,
+    #[serde(default)]
+    pub v: Option<String>,
+    #[serde(default)]
+    pub reqq: Option<u32>,
+    /// Set to 1 by peers that only upload, i.e. seeds
+    #[serde(default)]
+    pub upload_only: Option<u8>,
+    /// Our address as the peer sees it, 4 or 16 bytes
+    #[serde(default)]
+    pub yourip: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    /// Peer exchange is left out of the advertised extensions for private torrents (BEP 27)
+    pub fn new(port: u16, private: bool, upload_only: bool) -> Self {
+        let mut m = BTreeMap::new();
+        if !private {
+            m.insert("ut_pex".to_string(), UT_PEX_ID);
+        }
+        m.insert("ut_holepunch".to_string(), UT_HOLEPUNCH_ID);
+        m.insert("lt_donthave".to_string(), LT_DONTHAVE_ID);
+        Self {
+            m,
+            p: Some(port),
+            v: Some(format!("furia {}", env!("CARGO_PKG_VERSION"))),
+            reqq: None,
+            upload_only: upload_only.then_some(1),
+            yourip: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    pub fn is_upload_only(&self) -> bool {
+        self.upload_only.is_some_and(|upload_only| upload_only != 0)
+    }
+
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let yourip: &[u8] = self.yourip.as_ref()?;
+        if let Ok(ip) = <[u8; 4]>::try_from(yourip) {
+            Some(IpAddr::V4(Ipv4Addr::from(ip)))
+        } else {
+            <[u8; 16]>::try_from(yourip)
+                .ok()
+                .map(|ip| IpAddr::V6(Ipv6Addr::from(ip)))
+        }
+    }
+
+    /// The id the peer expects for the given extension, if it supports it
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extended_handshake_round_trip() {
+        let handshake = ExtendedHandshake::new(6881, false, true);
+        let decoded = ExtendedHandshake::from_bytes(&handshake.to_bytes().unwrap()).unwrap();
+        assert_eq!(Some(UT_PEX_ID), decoded.extension_id("ut_pex"));
+        assert_eq!(Some(6881), decoded.p);
+        assert_eq!(None, decoded.extension_id("ut_metadata"));
+        assert!(decoded.is_upload_only());
+        assert!(!ExtendedHandshake::new(6881, false, false).is_upload_only());
+        let seen = ExtendedHandshake {
+            yourip: Some(ByteBuf::from(vec![203, 0, 113, 7])),
+            ..Default::default()
+        };
+        let decoded = ExtendedHandshake::from_bytes(&seen.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))),
+            decoded.your_ip()
+        );
+    }
+}
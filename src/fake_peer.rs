@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio_util::codec::Framed;
+
+use crate::{codec::PeerCodec, messages::Message};
+
+/// One move of a scripted peer
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// An encoded message, e.g. from `Message::unchoke()`
+    Send(Bytes),
+    /// The next message received, keep-alives aside, has to be this one
+    Expect(Message),
+}
+
+/// The remote end of `PeerConnection::in_memory`, playing a peer message by message so
+/// connection flows can be tested without sockets or timing
+pub struct FakePeer {
+    stream: Framed<DuplexStream, PeerCodec>,
+}
+
+impl FakePeer {
+    pub fn new(stream: DuplexStream) -> Self {
+        Self {
+            stream: Framed::new(stream, PeerCodec::default()),
+        }
+    }
+
+    /// Exchanges plain handshakes without any extension bit, returning the other side's
+    pub async fn handshake(&mut self, info_hash: &[u8], peer_id: &[u8; 20]) -> Result<[u8; 68]> {
+        let mut handshake = vec![19];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(info_hash);
+        handshake.extend_from_slice(peer_id);
+        // Nothing went through the codec yet, so the raw stream is safe to use
+        let stream = self.stream.get_mut();
+        stream.write_all(&handshake).await?;
+        let mut theirs = [0; 68];
+        stream.read_exact(&mut theirs).await?;
+        Ok(theirs)
+    }
+
+    pub async fn send(&mut self, message: Bytes) -> Result<()> {
+        self.stream.send(message).await
+    }
+
+    pub async fn receive(&mut self) -> Result<Message> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Message::KeepAlive)) => continue,
+                Some(message) => return message,
+                None => return Err(anyhow!("Connection closed")),
+            }
+        }
+    }
+
+    /// Plays `script` in order, failing at the first unexpected message
+    pub async fn play(&mut self, script: Vec<Step>) -> Result<()> {
+        for step in script {
+            match step {
+                Step::Send(message) => self.send(message).await?,
+                Step::Expect(expected) => {
+                    let received = self.receive().await?;
+                    if received != expected {
+                        return Err(anyhow!("Expected {:?}, got {:?}", expected, received));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use std::net::Ipv4Addr;
+
+use sha1::{Digest, Sha1};
+
+/// Number of pieces we let a choked peer request from us, as suggested by BEP 6
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+/// Canonical allowed fast set from BEP 6: derived from the peer's /24 and the
+/// info hash, so every client ends up granting the same pieces to the same peer
+pub fn allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: &[u8],
+    number_of_pieces: u32,
+    k: usize,
+) -> Vec<u32> {
+    let mut allowed = Vec::new();
+    if number_of_pieces == 0 {
+        return allowed;
+    }
+    let k = k.min(number_of_pieces as usize);
+    let mut x = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while allowed.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks(4) {
+            if allowed.len() >= k {
+                break;
+            }
+            let index = u32::from_be_bytes(chunk.try_into().unwrap()) % number_of_pieces;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_matches_the_bep_6_allowed_fast_vectors() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xAA; 20];
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+/// Two letter client codes used in Azureus-style peer ids, e.g. `-qB4630-...`
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FU", "furia"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent (Rasterbar)"),
+    ("lt", "libTorrent (rakshasa)"),
+    ("qB", "qBittorrent"),
+    ("TR", "Transmission"),
+    ("UT", "µTorrent"),
+    ("UM", "µTorrent Mac"),
+    ("WW", "WebTorrent"),
+];
+
+/// Single letter client codes used in Shadow-style peer ids, e.g. `S58B-----...`
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Characters a Shadow-style version digit is drawn from, its index is the digit value
+const SHADOW_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientFingerprint {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl ClientFingerprint {
+    /// Prefers the extended handshake `v` field, which clients fill in with their full
+    /// name and version, over what can be decoded from the peer id
+    pub fn identify(peer_id: &[u8], v: Option<&str>) -> Option<Self> {
+        v.and_then(Self::from_extended_version)
+            .or_else(|| Self::from_peer_id(peer_id))
+    }
+
+    pub fn from_extended_version(v: &str) -> Option<Self> {
+        let v = v.trim();
+        if v.is_empty() {
+            return None;
+        }
+        Some(match v.rsplit_once(' ') {
+            Some((name, version)) if version.starts_with(|c: char| c.is_ascii_digit()) => Self {
+                name: name.to_string(),
+                version: Some(version.to_string()),
+            },
+            _ => Self {
+                name: v.to_string(),
+                version: None,
+            },
+        })
+    }
+
+    pub fn from_peer_id(peer_id: &[u8]) -> Option<Self> {
+        if peer_id.len() != 20 {
+            return None;
+        }
+        Self::azureus(peer_id).or_else(|| Self::shadow(peer_id))
+    }
+
+    fn azureus(peer_id: &[u8]) -> Option<Self> {
+        if peer_id[0] != b'-' || peer_id[7] != b'-' {
+            return None;
+        }
+        let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+        let version = std::str::from_utf8(&peer_id[3..7]).ok()?;
+        if !version.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        let name = AZUREUS_CLIENTS
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map_or_else(|| code.to_string(), |(_, name)| name.to_string());
+        let version = version
+            .chars()
+            .map(|c| c.to_digit(36).unwrap_or(0).to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        Some(Self {
+            name,
+            version: Some(version),
+        })
+    }
+
+    fn shadow(peer_id: &[u8]) -> Option<Self> {
+        let (_, name) = SHADOW_CLIENTS
+            .iter()
+            .find(|(code, _)| *code == peer_id[0])?;
+        let digits: Vec<usize> = peer_id[1..6]
+            .iter()
+            .take_while(|c| **c != b'-')
+            .map(|c| SHADOW_DIGITS.iter().position(|digit| digit == c))
+            .collect::<Option<_>>()?;
+        Some(Self {
+            name: name.to_string(),
+            version: (!digits.is_empty()).then(|| {
+                digits
+                    .iter()
+                    .map(|digit| digit.to_string())
+                    .collect::<Vec<_>>()
+                    .join(".")
+            }),
+        })
+    }
+}
+
+impl fmt::Display for ClientFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_identifies_clients() {
+        let qbittorrent = ClientFingerprint::from_peer_id(b"-qB4630-abcdefghijkl").unwrap();
+        assert_eq!("qBittorrent 4.6.3.0", qbittorrent.to_string());
+        let shadow = ClientFingerprint::from_peer_id(b"S58B-----abcdefghijk").unwrap();
+        assert_eq!("Shadow 5.8.11", shadow.to_string());
+        assert_eq!(None, ClientFingerprint::from_peer_id(&[0xff; 20]));
+
+        let by_version = ClientFingerprint::identify(b"-qB4630-abcdefghijkl", Some("Deluge 2.1.1"));
+        assert_eq!("Deluge 2.1.1", by_version.unwrap().to_string());
+    }
+}
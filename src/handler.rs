@@ -0,0 +1,146 @@
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::{
+    merkle::Hash,
+    messages::{Block, BlockRequest, HashRequest, Message},
+};
+
+/// One callback per wire message, all ignoring the message by default so implementors
+/// only spell out what they care about. `dispatch` routes decoded messages to them.
+#[allow(async_fn_in_trait)]
+pub trait MessageHandler {
+    async fn on_keep_alive(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_choke(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_unchoke(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_interested(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_not_interested(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_have(&mut self, _index: u32) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_bitfield(&mut self, _bitfield: Bytes) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_request(&mut self, _request: BlockRequest) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_piece(&mut self, _block: Block) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_cancel(&mut self, _request: BlockRequest) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_port(&mut self, _port: u16) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_suggest_piece(&mut self, _index: u32) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_have_all(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_have_none(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_reject_request(&mut self, _request: BlockRequest) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_allowed_fast(&mut self, _index: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// `id` is the extended message id we assigned in our extended handshake
+    async fn on_extended(&mut self, _id: u8, _payload: Bytes) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_hash_request(&mut self, _request: HashRequest) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_hashes(&mut self, _request: HashRequest, _hashes: Vec<Hash>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_hash_reject(&mut self, _request: HashRequest) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_unknown(&mut self, _id: u8, _payload: Bytes) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub async fn dispatch<H: MessageHandler>(handler: &mut H, message: Message) -> Result<()> {
+    match message {
+        Message::KeepAlive => handler.on_keep_alive().await,
+        Message::Choke => handler.on_choke().await,
+        Message::Unchoke => handler.on_unchoke().await,
+        Message::Interested => handler.on_interested().await,
+        Message::NotInterested => handler.on_not_interested().await,
+        Message::Have(index) => handler.on_have(index).await,
+        Message::Bitfield(bitfield) => handler.on_bitfield(bitfield).await,
+        Message::Request(request) => handler.on_request(request).await,
+        Message::Piece(block) => handler.on_piece(block).await,
+        Message::Cancel(request) => handler.on_cancel(request).await,
+        Message::Port(port) => handler.on_port(port).await,
+        Message::SuggestPiece(index) => handler.on_suggest_piece(index).await,
+        Message::HaveAll => handler.on_have_all().await,
+        Message::HaveNone => handler.on_have_none().await,
+        Message::RejectRequest(request) => handler.on_reject_request(request).await,
+        Message::AllowedFast(index) => handler.on_allowed_fast(index).await,
+        Message::Extended(id, payload) => handler.on_extended(id, payload).await,
+        Message::HashRequest(request) => handler.on_hash_request(request).await,
+        Message::Hashes(request, hashes) => handler.on_hashes(request, hashes).await,
+        Message::HashReject(request) => handler.on_hash_reject(request).await,
+        Message::Unknown(id, payload) => handler.on_unknown(id, payload).await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Default)]
+    struct Haves(Vec<u32>);
+
+    impl MessageHandler for Haves {
+        async fn on_have(&mut self, index: u32) -> Result<()> {
+            self.0.push(index);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_dispatches_to_the_matching_callback() {
+        let mut haves = Haves::default();
+        dispatch(&mut haves, Message::Have(4)).await.unwrap();
+        dispatch(&mut haves, Message::Choke).await.unwrap();
+        assert_eq!(vec![4], haves.0);
+    }
+}
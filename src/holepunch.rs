@@ -0,0 +1,131 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchType {
+    /// Asks the relay to introduce us to the given peer
+    Rendezvous = 0,
+    /// Sent by the relay to both parties, who then connect to each other at once
+    Connect = 1,
+    Error = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    None = 0,
+    NoSuchPeer = 1,
+    NotConnected = 2,
+    NoSupport = 3,
+    NoSelf = 4,
+}
+
+/// ut_holepunch message from BEP 55, a binary payload rather than a bencoded one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HolepunchMessage {
+    pub kind: HolepunchType,
+    pub addr: SocketAddr,
+    pub error: HolepunchError,
+}
+
+impl HolepunchMessage {
+    pub fn rendezvous(addr: SocketAddr) -> Self {
+        Self {
+            kind: HolepunchType::Rendezvous,
+            addr,
+            error: HolepunchError::None,
+        }
+    }
+
+    pub fn connect(addr: SocketAddr) -> Self {
+        Self {
+            kind: HolepunchType::Connect,
+            addr,
+            error: HolepunchError::None,
+        }
+    }
+
+    pub fn error(addr: SocketAddr, error: HolepunchError) -> Self {
+        Self {
+            kind: HolepunchType::Error,
+            addr,
+            error,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut message = BytesMut::with_capacity(24);
+        message.put_u8(self.kind as u8);
+        match self.addr.ip() {
+            IpAddr::V4(ip) => {
+                message.put_u8(0);
+                message.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                message.put_u8(1);
+                message.extend_from_slice(&ip.octets());
+            }
+        }
+        message.put_u16(self.addr.port());
+        message.put_u32(self.error as u32);
+        message.freeze()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (ip, rest): (IpAddr, &[u8]) = match bytes.get(1) {
+            Some(0) if bytes.len() >= 12 => {
+                (<[u8; 4]>::try_from(&bytes[2..6])?.into(), &bytes[6..])
+            }
+            Some(1) if bytes.len() >= 24 => {
+                (<[u8; 16]>::try_from(&bytes[2..18])?.into(), &bytes[18..])
+            }
+            _ => return Err(anyhow!("Malformed holepunch message")),
+        };
+        let kind = match bytes[0] {
+            0 => HolepunchType::Rendezvous,
+            1 => HolepunchType::Connect,
+            2 => HolepunchType::Error,
+            kind => return Err(anyhow!("Unknown holepunch message type {}", kind)),
+        };
+        let error = match u32::from_be_bytes(rest[2..6].try_into()?) {
+            0 => HolepunchError::None,
+            1 => HolepunchError::NoSuchPeer,
+            2 => HolepunchError::NotConnected,
+            3 => HolepunchError::NoSupport,
+            4 => HolepunchError::NoSelf,
+            error => return Err(anyhow!("Unknown holepunch error {}", error)),
+        };
+        Ok(Self {
+            kind,
+            addr: SocketAddr::new(ip, u16::from_be_bytes(rest[..2].try_into()?)),
+            error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn holepunch_message_round_trip() {
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let message = HolepunchMessage::connect(addr);
+        assert_eq!(
+            &message.to_bytes()[..],
+            &[1, 0, 10, 0, 0, 1, 0x1A, 0xE1, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            HolepunchMessage::from_bytes(&message.to_bytes()).unwrap(),
+            message
+        );
+
+        let addr: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        let message = HolepunchMessage::error(addr, HolepunchError::NotConnected);
+        assert_eq!(
+            HolepunchMessage::from_bytes(&message.to_bytes()).unwrap(),
+            message
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{anyhow, Result};
+
+/// eMule filters block entries with an access level up to this one
+const EMULE_MAX_BLOCKED_LEVEL: u32 = 127;
+
+/// Address ranges peers must not come from, loaded from PeerGuardian (`name:first-last`),
+/// eMule `.dat` (`first - last , level , name`) or CIDR (`10.0.0.0/8`) list files
+#[derive(Debug, Default, Clone)]
+pub struct IpFilter {
+    /// Sorted, non-overlapping inclusive ranges
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    pub fn load(path: &str) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Lines that are blank or start with `#` or `//` are skipped, anything else unreadable is an error
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut filter = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            let range = parse_line(line)
+                .ok_or_else(|| anyhow!("Invalid IP filter line {}: {}", number + 1, line))?;
+            if let Some((first, last)) = range {
+                filter.add_range(first, last)?;
+            }
+        }
+        filter.v4 = merge(std::mem::take(&mut filter.v4));
+        filter.v6 = merge(std::mem::take(&mut filter.v6));
+        Ok(filter)
+    }
+
+    fn add_range(&mut self, first: IpAddr, last: IpAddr) -> Result<()> {
+        match (first, last) {
+            (IpAddr::V4(first), IpAddr::V4(last)) => {
+                self.v4.push(ordered(u32::from(first), u32::from(last)))
+            }
+            (IpAddr::V6(first), IpAddr::V6(last)) => {
+                self.v6.push(ordered(u128::from(first), u128::from(last)))
+            }
+            _ => return Err(anyhow!("Range from {} to {} mixes families", first, last)),
+        }
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        self.contains(ip)
+    }
+
+    /// Whether `ip` falls in one of the ranges, for lists that are not about blocking
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(*ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, u32::from(ip)),
+                None => contains(&self.v6, u128::from(*ip)),
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+/// `None` for unreadable lines, `Some(None)` for entries that don't block anything
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    if let Some((range, rest)) = line.split_once(',') {
+        let level: u32 = rest.split(',').next()?.trim().parse().ok()?;
+        if level > EMULE_MAX_BLOCKED_LEVEL {
+            return Some(None);
+        }
+        return parse_range(range).map(Some);
+    }
+    if let Some((network, prefix)) = line.split_once('/') {
+        return parse_cidr(parse_ip(network)?, prefix.trim().parse().ok()?).map(Some);
+    }
+    // PeerGuardian names may contain anything, the range is what follows the last colon
+    parse_range(line)
+        .or_else(|| parse_range(&line[line.rfind(':')? + 1..]))
+        .map(Some)
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let (first, last) = range.split_once('-')?;
+    Some((parse_ip(first)?, parse_ip(last)?))
+}
+
+/// Unlike `IpAddr::from_str`, accepts the zero padded octets eMule lists use
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let ip = ip.trim();
+    if ip.contains(':') {
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let octets: Vec<u8> = ip
+        .split('.')
+        .map(|octet| octet.parse().ok())
+        .collect::<Option<_>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn parse_cidr(network: IpAddr, prefix: u32) -> Option<(IpAddr, IpAddr)> {
+    match network {
+        IpAddr::V4(network) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let first = u32::from(network) & mask;
+            Some((
+                Ipv4Addr::from(first).into(),
+                Ipv4Addr::from(first | !mask).into(),
+            ))
+        }
+        IpAddr::V6(network) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let first = u128::from(network) & mask;
+            Some((
+                Ipv6Addr::from(first).into(),
+                Ipv6Addr::from(first | !mask).into(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn ordered<T: Ord>(first: T, last: T) -> (T, T) {
+    if first <= last {
+        (first, last)
+    } else {
+        (last, first)
+    }
+}
+
+fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some((_, end)) if first <= *end => *end = (*end).max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let after = ranges.partition_point(|(first, _)| *first <= ip);
+    after > 0 && ranges[after - 1].1 >= ip
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_reads_every_list_format() {
+        let filter = IpFilter::parse(
+            "# comment\n\
+             Bad: network-1:1.2.3.0-1.2.3.255\n\
+             010.000.000.000 - 010.000.000.255 , 000 , eMule entry\n\
+             011.000.000.000 - 011.000.000.255 , 200 , allowed\n\
+             192.168.0.0/16\n\
+             2001:db8::/32\n",
+        )
+        .unwrap();
+        let blocked = |ip: &str| filter.is_blocked(&ip.parse().unwrap());
+        assert!(blocked("1.2.3.4"));
+        assert!(blocked("10.0.0.255"));
+        assert!(!blocked("11.0.0.1"));
+        assert!(blocked("192.168.255.1"));
+        assert!(blocked("::ffff:192.168.1.1"));
+        assert!(blocked("2001:db8::1"));
+        assert!(!blocked("8.8.8.8"));
+        assert!(IpFilter::parse("not an address").is_err());
+    }
+}
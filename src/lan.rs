@@ -0,0 +1,94 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+use crate::ipfilter::IpFilter;
+
+/// Prefix length of the subnet we assume peers on our own link share with us
+const IPV4_SUBNET_PREFIX: u32 = 24;
+const IPV6_SUBNET_PREFIX: u32 = 64;
+
+/// Peers on our own network, preferred for dials and unchokes since local links are
+/// fast and free. Off by default: on shared or hostile networks neighbours are nobody
+/// to favour
+#[derive(Debug, Default, Clone)]
+pub struct LanPreference {
+    enabled: bool,
+    /// Configured CIDRs counted as local in addition to our own subnets
+    networks: IpFilter,
+    /// Our own addresses, whose subnets are local
+    local_ips: Vec<IpAddr>,
+}
+
+impl LanPreference {
+    pub fn new(networks: IpFilter) -> Self {
+        Self {
+            enabled: true,
+            networks,
+            local_ips: Vec::new(),
+        }
+    }
+
+    /// Adds the addresses of the interfaces our default routes go through
+    pub fn detect_local_ips(&mut self) {
+        // Connecting UDP sockets sends nothing, it only picks the interface to route through
+        for (bind, remote) in [
+            ("0.0.0.0:0", "198.51.100.1:9"),
+            ("[::]:0", "[2001:db8::1]:9"),
+        ] {
+            let local = UdpSocket::bind(bind).and_then(|socket| {
+                socket.connect(remote)?;
+                socket.local_addr()
+            });
+            if let Ok(local) = local {
+                self.add_local_ip(local.ip());
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Our address as seen on an established connection
+    pub fn add_local_ip(&mut self, ip: IpAddr) {
+        if !ip.is_unspecified() && !self.local_ips.contains(&ip) {
+            self.local_ips.push(ip);
+        }
+    }
+
+    pub fn is_local(&self, address: &SocketAddr) -> bool {
+        let ip = address.ip();
+        self.enabled
+            && (self.networks.contains(&ip)
+                || self.local_ips.iter().any(|local| same_subnet(*local, ip)))
+    }
+}
+
+pub fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            u32::from(a) >> (32 - IPV4_SUBNET_PREFIX) == u32::from(b) >> (32 - IPV4_SUBNET_PREFIX)
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            u128::from(a) >> (128 - IPV6_SUBNET_PREFIX)
+                == u128::from(b) >> (128 - IPV6_SUBNET_PREFIX)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_counts_our_subnets_and_configured_networks_as_local() {
+        let mut lan = LanPreference::new(IpFilter::parse("10.8.0.0/16").unwrap());
+        lan.add_local_ip("192.168.1.20".parse().unwrap());
+        let local = |address: &str| lan.is_local(&address.parse().unwrap());
+        assert!(local("192.168.1.77:6881"));
+        assert!(!local("192.168.2.77:6881"));
+        assert!(local("10.8.200.1:6881"));
+        assert!(!local("203.0.113.5:6881"));
+        assert!(!LanPreference::default().is_local(&"192.168.1.77:6881".parse().unwrap()));
+    }
+}
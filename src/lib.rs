@@ -0,0 +1,54 @@
+pub mod ban;
+pub mod bind;
+pub mod bitfield;
+pub mod blame;
+pub mod cache;
+pub mod capabilities;
+pub mod choker;
+pub mod client;
+pub mod codec;
+pub mod dht;
+pub mod disconnect;
+pub mod disk;
+pub mod download;
+pub mod events;
+pub mod extension;
+pub mod fake_peer;
+pub mod fast;
+pub mod fingerprint;
+pub mod handler;
+pub mod holepunch;
+pub mod ipfilter;
+pub mod lan;
+pub mod md5;
+pub mod merkle;
+pub mod messages;
+pub mod metrics;
+#[cfg(unix)]
+pub mod mmap;
+pub mod mse;
+pub mod natpmp;
+pub mod parse_torrent;
+pub mod peerlist;
+pub mod peers;
+pub mod pex;
+pub mod portmap;
+pub mod ports;
+pub mod progress;
+pub mod queue;
+pub mod ratelimit;
+pub mod recheck;
+pub mod resume;
+pub mod retry;
+pub mod schedule;
+pub mod score;
+pub mod session;
+pub mod socket;
+pub mod stats;
+pub mod storage;
+pub mod superseed;
+pub mod trace;
+pub mod tracker;
+pub mod transport;
+pub mod upnp;
+pub mod webseed;
@@ -1,30 +1,270 @@
-mod parse_torrent;
-mod tracker;
-mod peers;
-mod messages;
-use crate::download::Download;
+use anyhow::{anyhow, Result};
+use furia::bind::Bind;
+use furia::client::Client;
+use furia::download::{Download, FilePriority, PickOrder};
+use furia::ipfilter::IpFilter;
+use furia::metrics::{serve, Metrics};
+use furia::parse_torrent::parse_torrent;
+use furia::peerlist::render_table;
+use furia::portmap::{keep_mapped, PortMapper, Protocol};
+use furia::ports::PortRange;
+use furia::recheck::{check_files, recheck, DEFAULT_RECHECK_WORKERS};
+use furia::schedule::{follow_schedule, BandwidthSchedule, Rates};
+use furia::storage::{Allocation, FileAccess, FileStorage};
+use furia::tracker::{generate_peer_id, request_tracker, DEFAULT_PORT};
 use std::env;
-use parse_torrent::parse_torrent;
-use tracker::request_tracker;
-use peers::ConnectionManager;
-use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+use tracing_subscriber::EnvFilter;
 
-pub mod download;
+/// How often `furia peers` prints the connected peers
+const PEER_LIST_INTERVAL: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+    let mut args: Vec<String> = env::args().collect();
+    // `furia peers <torrent file>` downloads as usual and keeps printing who we're connected to
+    let list_peers = args.get(1).is_some_and(|command| command == "peers");
+    if list_peers {
+        args.remove(1);
+    }
+    // `furia verify <torrent file> [--dir <content dir>]` only checks what is on disk
+    if args.len() > 2 && args[1] == "verify" {
+        return verify(&args[2], flag_value(&args, "--dir")?.unwrap_or(".")).await;
+    }
     if args.len() < 2 {
-        println!("Usage: {} <torrent file>", args[0]);
+        println!(
+            "Usage: {} verify <torrent file> [--dir <content dir>]",
+            args[0]
+        );
+        println!(
+            "Usage: {} [peers] <torrent file> [--bind <address or interface>] [--prefer-lan] \
+             [--metrics <port>] [--port <port or first-last>] [--random-port] \
+             [--upload-rate <bytes/s>] [--download-rate <bytes/s>] [--sequential] \
+             [--resume <file>] [--skip-files <index,...>] [--preallocate] [--mmap] \
+             [--cache <bytes>] [--move-to <dir>] [--ignore-free-space] \
+             [--schedule <HH:MM-HH:MM=bytes/s[/upload bytes/s],...>]",
+            args[0]
+        );
         return Ok(());
     }
     let torrent = parse_torrent(&args[1]);
-    let tracker_response = request_tracker(&torrent).await?;
+    let bind: Option<Bind> = flag_value(&args, "--bind")?.map(str::parse).transpose()?;
+    let ports = match flag_value(&args, "--port")? {
+        Some(ports) => ports.parse()?,
+        None => PortRange::default(),
+    };
+    let peer_id = generate_peer_id();
     let download = Download::from(&torrent);
 
-    let mut connection_manager = ConnectionManager::new(&torrent, download);
-    connection_manager.add_peer(tracker_response.peers[0].clone())?;
-    connection_manager.connect_to_peers()?;
+    let mut client = Client::new(peer_id.clone());
+    let port = match client
+        .listen_range(&ports, args.iter().any(|arg| arg == "--random-port"))
+        .await
+    {
+        Ok(address) => address.port(),
+        Err(error) => {
+            warn!("Not accepting incoming peers: {}", error);
+            DEFAULT_PORT
+        }
+    };
+    if let Some(rate) = flag_value(&args, "--upload-rate")? {
+        client.rate_limits().set_upload_rate(rate.parse()?);
+    }
+    if let Some(rate) = flag_value(&args, "--download-rate")? {
+        client.rate_limits().set_download_rate(rate.parse()?);
+    }
+    // Outside of the scheduled windows the rates above, if any, still apply
+    let schedule = match flag_value(&args, "--schedule")? {
+        Some(rules) => {
+            let mut schedule: BandwidthSchedule = rules.parse()?;
+            let limits = client.rate_limits();
+            schedule.set_otherwise(Rates {
+                upload: limits.upload_rate(),
+                download: limits.download_rate(),
+            });
+            Some(follow_schedule(limits, schedule))
+        }
+        None => None,
+    };
+    let connection_manager = client.add_torrent(&torrent, download)?;
+    connection_manager.set_bind(bind.clone());
+    if let Some(bytes) = flag_value(&args, "--cache")? {
+        connection_manager.set_cache_size(bytes.parse()?);
+    }
+    if let Some(dir) = flag_value(&args, "--move-to")? {
+        connection_manager.set_completed_dir(dir.into());
+    }
+    if args.iter().any(|arg| arg == "--mmap") {
+        connection_manager.set_file_access(FileAccess::Mapped);
+    }
+    if let Some(files) = flag_value(&args, "--skip-files")? {
+        for file in files.split(',') {
+            connection_manager.set_file_priority(file.trim().parse()?, FilePriority::Skip)?;
+        }
+    }
+    let restored = match flag_value(&args, "--resume")? {
+        Some(path) => connection_manager.set_resume_file(path.into())?,
+        None => 0,
+    };
+    if restored > 0 {
+        println!("Resumed {} pieces", restored);
+    } else {
+        // Files left by an earlier run are checked before the swarm hears from us
+        let valid = connection_manager
+            .recheck(DEFAULT_RECHECK_WORKERS, |progress| {
+                print!("\rChecked {}/{} pieces", progress.checked, progress.total);
+                let _ = std::io::stdout().flush();
+            })
+            .await?;
+        if valid > 0 {
+            println!("\n{} pieces already downloaded", valid);
+        }
+    }
+    let allocation = if args.iter().any(|arg| arg == "--preallocate") {
+        Allocation::Full
+    } else {
+        Allocation::Sparse
+    };
+    if let Err(error) = connection_manager.check_free_space() {
+        if !args.iter().any(|arg| arg == "--ignore-free-space") {
+            return Err(error);
+        }
+        warn!("{}", error);
+    }
+    connection_manager.allocate_files(allocation)?;
+    let left = connection_manager.left();
+    let tracker_response = request_tracker(&torrent, &peer_id, bind.as_ref(), port, left).await?;
+    if args.iter().any(|arg| arg == "--sequential") {
+        connection_manager.set_pick_order(PickOrder::Sequential);
+    }
+    if args.iter().any(|arg| arg == "--prefer-lan") {
+        connection_manager.prefer_lan(IpFilter::default());
+    }
+    if let Some(port) = flag_value(&args, "--metrics")? {
+        let metrics = Metrics::shared();
+        connection_manager.report_metrics(metrics.clone())?;
+        let listener = TcpListener::bind(("0.0.0.0", port.parse::<u16>()?)).await?;
+        tokio::spawn(serve(listener, metrics));
+    }
+    if list_peers {
+        let mut peers = connection_manager.watch_peers();
+        tokio::spawn(async move {
+            while peers.changed().await.is_ok() {
+                println!("{}", render_table(&peers.borrow_and_update()));
+                sleep(PEER_LIST_INTERVAL).await;
+            }
+        });
+    } else {
+        let mut progress = connection_manager.watch_progress();
+        tokio::spawn(async move {
+            while progress.changed().await.is_ok() {
+                print!("\r{}", progress.borrow_and_update().render_line());
+                let _ = std::io::stdout().flush();
+            }
+        });
+    }
+    connection_manager.add_peers(tracker_response.peers);
+    connection_manager.add_peers(tracker_response.peers6);
+
+    // Routers that forward ports on request make us connectable without manual setup
+    let mapping = match PortMapper::discover().await {
+        Ok(mapper) => Some((keep_mapped(mapper.clone(), Protocol::Tcp, port), mapper)),
+        Err(error) => {
+            debug!("No port mapping: {}", error);
+            None
+        }
+    };
+    let result = tokio::select! {
+        result = client.run() => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    client.shutdown().await?;
+    if let Some(schedule) = schedule {
+        schedule.abort();
+    }
+    if let Some((renewal, mapper)) = mapping {
+        renewal.abort();
+        let _ = mapper.delete_port_mapping(Protocol::Tcp, port).await;
+    }
+    result?;
 
     Ok(())
 }
+
+/// Hash-checks the content of `torrent_file` under `content_dir` without contacting anyone,
+/// printing how each file fared. Fails if any piece doesn't match
+async fn verify(torrent_file: &str, content_dir: &str) -> Result<()> {
+    let torrent = parse_torrent(torrent_file);
+    let content_dir = Path::new(content_dir);
+    let storage = Arc::new(FileStorage::new(&torrent, content_dir));
+    let valid = recheck(&torrent, storage, DEFAULT_RECHECK_WORKERS, |progress| {
+        print!("\rChecked {}/{} pieces", progress.checked, progress.total);
+        let _ = std::io::stdout().flush();
+    })
+    .await?;
+    println!();
+    for file in check_files(&torrent, content_dir, &valid) {
+        println!(
+            "{} {} ({}/{} pieces)",
+            if file.is_complete() { "OK " } else { "BAD" },
+            file.path.display(),
+            file.valid,
+            file.pieces.len()
+        );
+    }
+    let bad: Vec<usize> = (0..valid.len())
+        .filter(|piece| !valid.has(*piece))
+        .collect();
+    if bad.is_empty() {
+        println!("All {} pieces match", valid.len());
+        return Ok(());
+    }
+    println!("Pieces that don't match: {}", piece_ranges(&bad));
+    Err(anyhow!(
+        "{} of {} pieces don't match",
+        bad.len(),
+        valid.len()
+    ))
+}
+
+/// Sorted pieces as `1, 4-7, 9`
+fn piece_ranges(pieces: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &piece in pieces {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == piece => *last = piece,
+            _ => ranges.push((piece, piece)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(first, last)| {
+            if first == last {
+                first.to_string()
+            } else {
+                format!("{}-{}", first, last)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The argument following `flag`, if it was passed
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Result<Option<&'a str>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(position) => args
+            .get(position + 1)
+            .map(|value| Some(value.as_str()))
+            .ok_or_else(|| anyhow!("{} needs a value", flag)),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use md5::{Digest, Md5};
+
+use crate::storage::Storage;
+
+/// Bytes hashed per read when hashing a file from storage
+const READ_BYTES: usize = 1 << 20;
+
+/// The MD5 of the `length` bytes at `offset` of the content in `storage`, read a chunk at
+/// a time. Only used to check the optional `md5sum` of files. Blocking
+pub fn storage_md5(storage: &dyn Storage, offset: u64, length: u64) -> Result<[u8; 16]> {
+    let mut hasher = Md5::new();
+    let mut read = 0;
+    while read < length {
+        let chunk = (length - read).min(READ_BYTES as u64) as usize;
+        hasher.update(storage.read_block(0, offset + read, chunk)?);
+        read += chunk as u64;
+    }
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse_torrent::TorrentFile, storage::MemoryStorage};
+
+    #[test]
+    fn it_hashes_a_span_of_the_content() {
+        let mut bencode = b"d4:infod6:lengthi5e4:name1:a12:piece lengthi8e6:pieces20:".to_vec();
+        bencode.extend_from_slice(&[0; 20]);
+        bencode.extend_from_slice(b"ee");
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencode).unwrap();
+        let storage = MemoryStorage::new(&torrent);
+        storage.write_block(0, 0, b"xabcx").unwrap();
+        assert_eq!(
+            "900150983cd24fb0d6963f7d28e17f72",
+            hex::encode(storage_md5(&storage, 1, 3).unwrap())
+        );
+        assert_eq!(
+            "d41d8cd98f00b204e9800998ecf8427e",
+            hex::encode(storage_md5(&storage, 0, 0).unwrap())
+        );
+    }
+}
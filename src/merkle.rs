@@ -0,0 +1,89 @@
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+pub type Hash = [u8; 32];
+
+/// Leaves of v2 Merkle trees are the SHA-256 of 16 KiB blocks
+pub const MERKLE_BLOCK_SIZE: usize = 16384;
+
+pub fn hash_block(block: &[u8]) -> Hash {
+    Sha256::digest(block).into()
+}
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a tree whose layer has the given nodes, padded up to `width` nodes
+/// (a power of two) with `padding` hashes
+pub fn root(nodes: &[Hash], width: usize, padding: Hash) -> Hash {
+    let mut layer: Vec<Hash> = nodes.to_vec();
+    layer.resize(width.max(1), padding);
+    let mut padding = padding;
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&padding)))
+            .collect();
+        padding = hash_pair(&padding, &padding);
+    }
+    layer[0]
+}
+
+/// Checks `hashes`, a run of nodes starting at `index` in some layer of a tree, against
+/// `expected_root` by hashing them together and walking up the `proof` uncle hashes
+pub fn verify_proof(
+    hashes: &[Hash],
+    index: u32,
+    proof: &[Hash],
+    expected_root: &Hash,
+) -> Result<()> {
+    if hashes.is_empty() || !hashes.len().is_power_of_two() {
+        return Err(anyhow!(
+            "Expected a power of two hashes, got {}",
+            hashes.len()
+        ));
+    }
+    if !(index as usize).is_multiple_of(hashes.len()) {
+        return Err(anyhow!(
+            "Index {} isn't aligned to {} hashes",
+            index,
+            hashes.len()
+        ));
+    }
+    let mut node = root(hashes, hashes.len(), [0; 32]);
+    let mut position = index as usize / hashes.len();
+    for uncle in proof {
+        node = if position.is_multiple_of(2) {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        position /= 2;
+    }
+    if position != 0 || node != *expected_root {
+        return Err(anyhow!("Hashes don't match the expected root"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_verifies_a_proof_for_a_subtree() {
+        let leaves: Vec<Hash> = (0..8_u8).map(|i| hash_block(&[i; 4])).collect();
+        let tree_root = root(&leaves, 8, [0; 32]);
+        let proof = [
+            hash_pair(&leaves[0], &leaves[1]),
+            root(&leaves[4..], 4, [0; 32]),
+        ];
+        assert!(verify_proof(&leaves[2..4], 2, &proof, &tree_root).is_ok());
+        assert!(verify_proof(&leaves[2..4], 0, &proof, &tree_root).is_err());
+        assert!(verify_proof(&leaves[3..5], 2, &proof, &tree_root).is_err());
+    }
+}
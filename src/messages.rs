@@ -1,8 +1,40 @@
-use crate::{download::Download, parse_torrent::{TorrentFile, bitfield_size}};
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-pub struct Message {}
+use crate::download::Download;
+use crate::merkle::Hash;
 
-pub const BLOCK_BYTES: u8 = 2 ^ 14;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bytes),
+    Request(BlockRequest),
+    Piece(Block),
+    Cancel(BlockRequest),
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(BlockRequest),
+    AllowedFast(u32),
+    Extended(u8, Bytes),
+    HashRequest(HashRequest),
+    /// The requested hashes followed by the uncle hashes proving them
+    Hashes(HashRequest, Vec<Hash>),
+    HashReject(HashRequest),
+    /// Messages of extensions we don't implement, kept so they can be skipped
+    Unknown(u8, Bytes),
+}
+
+/// The block size every client accepts, larger requests are commonly refused
+pub const BLOCK_BYTES: u32 = 16384;
+/// Longest block we serve, clients commonly refuse requests above this
+pub const MAX_REQUEST_BYTES: u32 = 128 * 1024;
 
 #[repr(u8)]
 pub enum MessageType {
@@ -16,83 +48,584 @@ pub enum MessageType {
     Piece,
     Cancel,
     Port,
+    SuggestPiece = 13,
+    HaveAll,
+    HaveNone,
+    RejectRequest,
+    AllowedFast,
+    Extended = 20,
+    HashRequest,
+    Hashes,
+    HashReject,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    pub fn from_payload(payload: &[u8]) -> Option<Self> {
+        if payload.len() != 12 {
+            return None;
+        }
+        Some(Self {
+            index: u32::from_be_bytes(payload[0..4].try_into().ok()?),
+            begin: u32::from_be_bytes(payload[4..8].try_into().ok()?),
+            length: u32::from_be_bytes(payload[8..12].try_into().ok()?),
+        })
+    }
+
+    /// Requests covering a whole piece in blocks of `block_size` bytes, the last one
+    /// being shorter if the piece length isn't a multiple of the block size
+    pub fn for_piece(index: u32, piece_length: u32, block_size: u32) -> Vec<Self> {
+        (0..piece_length)
+            .step_by(block_size as usize)
+            .map(|begin| Self {
+                index,
+                begin,
+                length: block_size.min(piece_length - begin),
+            })
+            .collect()
+    }
+}
+
+/// Asks for `length` hashes starting at `index` of the layer `base_layer` levels above
+/// the 16 KiB leaves of the file tree identified by `pieces_root` (BEP 52)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashRequest {
+    pub pieces_root: Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    pub const LENGTH: usize = 48;
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self> {
+        if payload.len() < Self::LENGTH {
+            return Err(anyhow!("Hash request of {} bytes", payload.len()));
+        }
+        let field =
+            |offset: usize| u32::from_be_bytes(payload[offset..offset + 4].try_into().unwrap());
+        Ok(Self {
+            pieces_root: payload[..32].try_into()?,
+            base_layer: field(32),
+            index: field(36),
+            length: field(40),
+            proof_layers: field(44),
+        })
+    }
+
+    fn put(&self, message: &mut BytesMut) {
+        message.extend_from_slice(&self.pieces_root);
+        message.put_u32(self.base_layer);
+        message.put_u32(self.index);
+        message.put_u32(self.length);
+        message.put_u32(self.proof_layers);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub data: Bytes,
+}
+
+impl Block {
+    /// Parses the payload of a piece message, i.e. everything after the message id.
+    /// The block data keeps pointing into the received frame instead of being copied.
+    pub fn from_payload(payload: Bytes) -> Result<Self> {
+        if payload.len() < 8 {
+            return Err(anyhow!("Piece message too short: {} bytes", payload.len()));
+        }
+        Ok(Self {
+            index: u32::from_be_bytes(payload[0..4].try_into()?),
+            begin: u32::from_be_bytes(payload[4..8].try_into()?),
+            data: payload.slice(8..),
+        })
+    }
+
+    /// Splits a whole piece into blocks of at most `block_size` bytes
+    pub fn split(index: u32, piece: &Bytes, block_size: usize) -> Vec<Self> {
+        (0..piece.len())
+            .step_by(block_size)
+            .map(|begin| Self {
+                index,
+                begin: begin as u32,
+                data: piece.slice(begin..piece.len().min(begin + block_size)),
+            })
+            .collect()
+    }
+
+    pub fn to_message(&self) -> Bytes {
+        Message::piece(self.index, self.begin, &self.data)
+    }
 }
 
 impl Message {
-    pub fn choke() -> Vec<u8> {
-        let len = 0_u32.to_be_bytes();
-        let mut message = Vec::from(len);
-        message.push(MessageType::Choke as u8);
-        message
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::KeepAlive => "keep-alive",
+            Message::Choke => "choke",
+            Message::Unchoke => "unchoke",
+            Message::Interested => "interested",
+            Message::NotInterested => "not-interested",
+            Message::Have(_) => "have",
+            Message::Bitfield(_) => "bitfield",
+            Message::Request(_) => "request",
+            Message::Piece(_) => "piece",
+            Message::Cancel(_) => "cancel",
+            Message::Port(_) => "port",
+            Message::SuggestPiece(_) => "suggest-piece",
+            Message::HaveAll => "have-all",
+            Message::HaveNone => "have-none",
+            Message::RejectRequest(_) => "reject-request",
+            Message::AllowedFast(_) => "allowed-fast",
+            Message::Extended(_, _) => "extended",
+            Message::HashRequest(_) => "hash-request",
+            Message::Hashes(_, _) => "hashes",
+            Message::HashReject(_) => "hash-reject",
+            Message::Unknown(_, _) => "unknown",
+        }
     }
 
-    pub fn unchoke() -> Vec<u8> {
-        let len = 1_u32.to_be_bytes();
-        let mut message = Vec::from(len);
-        message.push(MessageType::Unchoke as u8);
-        message
+    /// Parses a complete message including its length prefix, rejecting
+    /// messages whose declared length doesn't match the bytes that follow
+    pub fn decode(mut message: Bytes) -> Result<Self> {
+        if message.len() < 4 {
+            return Err(anyhow!("Truncated message: {} bytes", message.len()));
+        }
+        let declared = message.get_u32() as usize;
+        if declared != message.len() {
+            return Err(anyhow!(
+                "Declared length {} doesn't match payload of {} bytes",
+                declared,
+                message.len()
+            ));
+        }
+        if declared == 0 {
+            return Ok(Message::KeepAlive);
+        }
+        let id = message.get_u8();
+        Self::from_frame(id, message)
     }
 
-    pub fn interested() -> Vec<u8> {
-        let len = 1_u32.to_be_bytes();
-        let mut message = Vec::from(len);
-        message.push(MessageType::Interested as u8);
-        message
+    /// Builds a message from the id and payload of a frame whose length was already checked
+    pub fn from_frame(id: u8, payload: Bytes) -> Result<Self> {
+        let expected_len = match id {
+            id if id == MessageType::Have as u8
+                || id == MessageType::SuggestPiece as u8
+                || id == MessageType::AllowedFast as u8 =>
+            {
+                Some(4)
+            }
+            id if id == MessageType::Request as u8
+                || id == MessageType::Cancel as u8
+                || id == MessageType::RejectRequest as u8 =>
+            {
+                Some(12)
+            }
+            id if id == MessageType::Port as u8 => Some(2),
+            id if id == MessageType::HashRequest as u8 || id == MessageType::HashReject as u8 => {
+                Some(HashRequest::LENGTH)
+            }
+            id if id <= MessageType::NotInterested as u8
+                || id == MessageType::HaveAll as u8
+                || id == MessageType::HaveNone as u8 =>
+            {
+                Some(0)
+            }
+            _ => None,
+        };
+        if let Some(len) = expected_len.filter(|len| *len != payload.len()) {
+            return Err(anyhow!(
+                "Message {} with {} bytes of payload instead of {}",
+                id,
+                payload.len(),
+                len
+            ));
+        }
+        let piece_index = || u32::from_be_bytes(payload[..4].try_into().unwrap());
+        let block_request = || BlockRequest::from_payload(&payload).unwrap();
+        let message = match id {
+            id if id == MessageType::Choke as u8 => Message::Choke,
+            id if id == MessageType::Unchoke as u8 => Message::Unchoke,
+            id if id == MessageType::Interested as u8 => Message::Interested,
+            id if id == MessageType::NotInterested as u8 => Message::NotInterested,
+            id if id == MessageType::Have as u8 => Message::Have(piece_index()),
+            id if id == MessageType::Bitfield as u8 => Message::Bitfield(payload),
+            id if id == MessageType::Request as u8 => Message::Request(block_request()),
+            id if id == MessageType::Piece as u8 => Message::Piece(Block::from_payload(payload)?),
+            id if id == MessageType::Cancel as u8 => Message::Cancel(block_request()),
+            id if id == MessageType::Port as u8 => {
+                Message::Port(u16::from_be_bytes(payload[..2].try_into().unwrap()))
+            }
+            id if id == MessageType::SuggestPiece as u8 => Message::SuggestPiece(piece_index()),
+            id if id == MessageType::HaveAll as u8 => Message::HaveAll,
+            id if id == MessageType::HaveNone as u8 => Message::HaveNone,
+            id if id == MessageType::RejectRequest as u8 => Message::RejectRequest(block_request()),
+            id if id == MessageType::AllowedFast as u8 => Message::AllowedFast(piece_index()),
+            id if id == MessageType::Extended as u8 => {
+                if payload.is_empty() {
+                    return Err(anyhow!("Extended message without extended id"));
+                }
+                Message::Extended(payload[0], payload.slice(1..))
+            }
+            id if id == MessageType::HashRequest as u8 => {
+                Message::HashRequest(HashRequest::from_payload(&payload)?)
+            }
+            id if id == MessageType::Hashes as u8 => {
+                let request = HashRequest::from_payload(&payload)?;
+                let hashes = &payload[HashRequest::LENGTH..];
+                if !hashes.len().is_multiple_of(32) {
+                    return Err(anyhow!(
+                        "Hashes message with {} trailing bytes",
+                        hashes.len()
+                    ));
+                }
+                let hashes = hashes
+                    .chunks(32)
+                    .map(|hash| hash.try_into().unwrap())
+                    .collect();
+                Message::Hashes(request, hashes)
+            }
+            id if id == MessageType::HashReject as u8 => {
+                Message::HashReject(HashRequest::from_payload(&payload)?)
+            }
+            id => Message::Unknown(id, payload),
+        };
+        Ok(message)
     }
 
-    pub fn not_interested() -> Vec<u8> {
-        let len = 1_u32.to_be_bytes();
-        let mut message = Vec::from(len);
-        message.push(MessageType::NotInterested as u8);
-        message
+    pub fn encode(&self) -> Bytes {
+        let mut message = BytesMut::new();
+        match self {
+            Message::KeepAlive => message.put_u32(0),
+            Message::Choke => Self::put_header(&mut message, MessageType::Choke as u8, 0),
+            Message::Unchoke => Self::put_header(&mut message, MessageType::Unchoke as u8, 0),
+            Message::Interested => Self::put_header(&mut message, MessageType::Interested as u8, 0),
+            Message::NotInterested => {
+                Self::put_header(&mut message, MessageType::NotInterested as u8, 0)
+            }
+            Message::Have(index) => {
+                Self::put_header(&mut message, MessageType::Have as u8, 4);
+                message.put_u32(*index);
+            }
+            Message::Bitfield(bitfield) => {
+                Self::put_header(&mut message, MessageType::Bitfield as u8, bitfield.len());
+                message.extend_from_slice(bitfield);
+            }
+            Message::Request(request) => {
+                Self::put_request(&mut message, MessageType::Request as u8, request)
+            }
+            Message::Piece(block) => {
+                Self::put_header(&mut message, MessageType::Piece as u8, block.data.len() + 8);
+                message.put_u32(block.index);
+                message.put_u32(block.begin);
+                message.extend_from_slice(&block.data);
+            }
+            Message::Cancel(request) => {
+                Self::put_request(&mut message, MessageType::Cancel as u8, request)
+            }
+            Message::Port(port) => {
+                Self::put_header(&mut message, MessageType::Port as u8, 2);
+                message.put_u16(*port);
+            }
+            Message::SuggestPiece(index) => {
+                Self::put_header(&mut message, MessageType::SuggestPiece as u8, 4);
+                message.put_u32(*index);
+            }
+            Message::HaveAll => Self::put_header(&mut message, MessageType::HaveAll as u8, 0),
+            Message::HaveNone => Self::put_header(&mut message, MessageType::HaveNone as u8, 0),
+            Message::RejectRequest(request) => {
+                Self::put_request(&mut message, MessageType::RejectRequest as u8, request)
+            }
+            Message::AllowedFast(index) => {
+                Self::put_header(&mut message, MessageType::AllowedFast as u8, 4);
+                message.put_u32(*index);
+            }
+            Message::Extended(extended_id, payload) => {
+                Self::put_header(&mut message, MessageType::Extended as u8, payload.len() + 1);
+                message.put_u8(*extended_id);
+                message.extend_from_slice(payload);
+            }
+            Message::HashRequest(request) => {
+                Self::put_header(
+                    &mut message,
+                    MessageType::HashRequest as u8,
+                    HashRequest::LENGTH,
+                );
+                request.put(&mut message);
+            }
+            Message::Hashes(request, hashes) => {
+                let len = HashRequest::LENGTH + hashes.len() * 32;
+                Self::put_header(&mut message, MessageType::Hashes as u8, len);
+                request.put(&mut message);
+                for hash in hashes {
+                    message.extend_from_slice(hash);
+                }
+            }
+            Message::HashReject(request) => {
+                Self::put_header(
+                    &mut message,
+                    MessageType::HashReject as u8,
+                    HashRequest::LENGTH,
+                );
+                request.put(&mut message);
+            }
+            Message::Unknown(id, payload) => {
+                Self::put_header(&mut message, *id, payload.len());
+                message.extend_from_slice(payload);
+            }
+        }
+        message.freeze()
     }
 
-    pub fn bitfield(torrent: &TorrentFile, download: &Download) -> Vec<u8> {
-        let bitfield_size = bitfield_size(&torrent);
+    fn put_header(message: &mut BytesMut, id: u8, payload_len: usize) {
+        message.reserve(5 + payload_len);
+        message.put_u32(payload_len as u32 + 1);
+        message.put_u8(id);
+    }
 
-        let len = bitfield_size as u32 + 1;
-        let mut message = Vec::from(len.to_be_bytes());
-        message.push(MessageType::Bitfield as u8);
-        message.extend_from_slice(&vec![0_u8; len as usize * 4]);
-        message
+    fn put_request(message: &mut BytesMut, id: u8, request: &BlockRequest) {
+        Self::put_header(message, id, 12);
+        message.put_u32(request.index);
+        message.put_u32(request.begin);
+        message.put_u32(request.length);
     }
 
-    pub fn request(piece_index: u8, piece_offset: u8) -> Vec<u8> {
-        let len = 13_u32.to_be_bytes();
-        let mut message = Vec::from(len);
-        message.push(MessageType::Request as u8);
-        message.push(piece_index);
-        message.push(piece_offset * BLOCK_BYTES);
-        message.push(BLOCK_BYTES);
-        message
+    pub fn choke() -> Bytes {
+        Message::Choke.encode()
     }
 
-    pub fn piece(piece_index: u8, piece_offset: u8, block: Vec<u8>) {
-        todo!();
+    pub fn unchoke() -> Bytes {
+        Message::Unchoke.encode()
     }
 
-    pub fn cancel(piece_index: u8, piece_offset: u8) {
-        todo!();
+    pub fn interested() -> Bytes {
+        Message::Interested.encode()
     }
 
-    pub fn port(port: u8) {
-        let len = 3_u32.to_be_bytes();
-        let mut message = Vec::from(len);
-        message.push(9_u8);
-        todo!();
+    pub fn not_interested() -> Bytes {
+        Message::NotInterested.encode()
     }
-}
 
+    pub fn have(piece_index: u32) -> Bytes {
+        Message::Have(piece_index).encode()
+    }
+
+    pub fn bitfield(download: &Download) -> Bytes {
+        Message::Bitfield(Bytes::copy_from_slice(download.have.as_bytes())).encode()
+    }
+
+    pub fn request(piece_index: u32, begin: u32, length: u32) -> Bytes {
+        Message::Request(BlockRequest {
+            index: piece_index,
+            begin,
+            length,
+        })
+        .encode()
+    }
+
+    pub fn piece(piece_index: u32, piece_offset: u32, block: &[u8]) -> Bytes {
+        Message::Piece(Block {
+            index: piece_index,
+            begin: piece_offset,
+            data: Bytes::copy_from_slice(block),
+        })
+        .encode()
+    }
+
+    pub fn cancel(request: &BlockRequest) -> Bytes {
+        Message::Cancel(*request).encode()
+    }
+
+    pub fn port(port: u16) -> Bytes {
+        Message::Port(port).encode()
+    }
+
+    pub fn suggest_piece(piece_index: u32) -> Bytes {
+        Message::SuggestPiece(piece_index).encode()
+    }
+
+    pub fn have_all() -> Bytes {
+        Message::HaveAll.encode()
+    }
+
+    pub fn have_none() -> Bytes {
+        Message::HaveNone.encode()
+    }
+
+    pub fn reject_request(request: &BlockRequest) -> Bytes {
+        Message::RejectRequest(*request).encode()
+    }
+
+    pub fn allowed_fast(piece_index: u32) -> Bytes {
+        Message::AllowedFast(piece_index).encode()
+    }
+
+    pub fn extended(extended_id: u8, payload: &[u8]) -> Bytes {
+        Message::Extended(extended_id, Bytes::copy_from_slice(payload)).encode()
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use super::Message;
+    use super::{Block, BlockRequest, HashRequest, Message, BLOCK_BYTES};
+    use crate::download::Download;
+    use bytes::Bytes;
+
+    fn request(index: u32, begin: u32, length: u32) -> BlockRequest {
+        BlockRequest {
+            index,
+            begin,
+            length,
+        }
+    }
+
+    fn hash_request() -> HashRequest {
+        HashRequest {
+            pieces_root: [0xAA; 32],
+            base_layer: 2,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        }
+    }
+
+    fn hash_request_bytes() -> Vec<u8> {
+        [
+            &[0xAA; 32][..],
+            &[0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 3],
+        ]
+        .concat()
+    }
+
+    /// Byte layouts taken from BEP 3, BEP 5, BEP 6, BEP 10 and BEP 52
+    fn vectors() -> Vec<(Message, Vec<u8>)> {
+        vec![
+            (Message::KeepAlive, vec![0, 0, 0, 0]),
+            (Message::Choke, vec![0, 0, 0, 1, 0]),
+            (Message::Unchoke, vec![0, 0, 0, 1, 1]),
+            (Message::Interested, vec![0, 0, 0, 1, 2]),
+            (Message::NotInterested, vec![0, 0, 0, 1, 3]),
+            (Message::Have(0x0102), vec![0, 0, 0, 5, 4, 0, 0, 1, 2]),
+            (
+                Message::Bitfield(Bytes::from_static(&[0b1010_0000, 0x01])),
+                vec![0, 0, 0, 3, 5, 0b1010_0000, 0x01],
+            ),
+            (
+                Message::Request(request(0, 0, 16384)),
+                vec![0, 0, 0, 13, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x40, 0],
+            ),
+            (
+                Message::Piece(Block {
+                    index: 1,
+                    begin: 2,
+                    data: Bytes::from_static(&[0xAB, 0xCD]),
+                }),
+                vec![0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 2, 0xAB, 0xCD],
+            ),
+            (
+                Message::Cancel(request(2, 16384, 16384)),
+                vec![0, 0, 0, 13, 8, 0, 0, 0, 2, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (Message::Port(6881), vec![0, 0, 0, 3, 9, 0x1A, 0xE1]),
+            (Message::SuggestPiece(3), vec![0, 0, 0, 5, 0x0D, 0, 0, 0, 3]),
+            (Message::HaveAll, vec![0, 0, 0, 1, 0x0E]),
+            (Message::HaveNone, vec![0, 0, 0, 1, 0x0F]),
+            (
+                Message::RejectRequest(request(1, 16384, 16384)),
+                vec![0, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (Message::AllowedFast(7), vec![0, 0, 0, 5, 0x11, 0, 0, 0, 7]),
+            (
+                Message::Extended(1, Bytes::from_static(b"de")),
+                vec![0, 0, 0, 4, 0x14, 1, b'd', b'e'],
+            ),
+            (
+                Message::HashRequest(hash_request()),
+                [&[0, 0, 0, 49, 0x15][..], &hash_request_bytes()].concat(),
+            ),
+            (
+                Message::Hashes(hash_request(), vec![[0x11; 32], [0x22; 32]]),
+                [
+                    &[0, 0, 0, 113, 0x16][..],
+                    &hash_request_bytes(),
+                    &[0x11; 32],
+                    &[0x22; 32],
+                ]
+                .concat(),
+            ),
+            (
+                Message::HashReject(hash_request()),
+                [&[0, 0, 0, 49, 0x17][..], &hash_request_bytes()].concat(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn messages_match_spec_vectors() {
+        for (message, bytes) in vectors() {
+            assert_eq!(message.encode(), bytes, "encoding {:?}", message);
+            assert_eq!(
+                Message::decode(Bytes::from(bytes)).unwrap(),
+                message,
+                "decoding {:?}",
+                message
+            );
+        }
+    }
 
     #[test]
-    fn request_message() {
+    fn it_rejects_malformed_messages() {
+        // Declared length longer than the payload
+        assert!(Message::decode(Bytes::from_static(&[0, 0, 0, 5, 4, 0, 0])).is_err());
+        // Have with a 2 byte index
+        assert!(Message::decode(Bytes::from_static(&[0, 0, 0, 3, 4, 0, 0])).is_err());
+        // Choke with a payload
+        assert!(Message::decode(Bytes::from_static(&[0, 0, 0, 2, 0, 0])).is_err());
         assert_eq!(
-            Message::request(0, 0),
-            vec![0x00, 0x00, 0x00, 0x0D, 0x06, 0x00, 0x00, 0x0C]
+            Message::decode(Bytes::from_static(&[0, 0, 0, 2, 0x63, 1])).unwrap(),
+            Message::Unknown(0x63, Bytes::from_static(&[1]))
+        );
+    }
+
+    #[test]
+    fn bitfield_message() {
+        let torrent = crate::parse_torrent::parse_torrent(
+            "./data/ubuntu-22.04.3-live-server-amd64.iso.torrent",
         );
+        let mut download = Download::from(&torrent);
+        download.mark_have(1);
+        let message = Message::bitfield(&download);
+        let bytes = download.pieces.len().div_ceil(8);
+        assert_eq!(&message[..4], &(bytes as u32 + 1).to_be_bytes());
+        assert_eq!(message[4], 0x05);
+        assert_eq!(message[5], 0b0100_0000);
+        assert_eq!(message.len(), 5 + bytes);
+    }
+
+    #[test]
+    fn piece_split_into_blocks() {
+        let blocks = Block::split(3, &Bytes::from_static(&[1, 2, 3, 4, 5]), 2);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2].begin, 4);
+        assert_eq!(blocks[2].data, vec![5]);
+        let reassembled: Vec<u8> = blocks
+            .iter()
+            .flat_map(|block| block.data.to_vec())
+            .collect();
+        assert_eq!(reassembled, vec![1, 2, 3, 4, 5]);
+
+        let requests = BlockRequest::for_piece(3, BLOCK_BYTES * 2 + 100, BLOCK_BYTES);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2], request(3, BLOCK_BYTES * 2, 100));
     }
 }
@@ -0,0 +1,192 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::disconnect::DisconnectReason;
+
+/// Peer connection metrics of every torrent reporting into it, rendered in the
+/// Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// By hex encoded info hash
+    torrents: Mutex<BTreeMap<String, TorrentMetrics>>,
+}
+
+pub type SharedMetrics = Arc<Metrics>;
+
+/// A snapshot of one torrent's connections, apart from the dial failures which only
+/// ever get counted up
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TorrentMetrics {
+    pub connected_peers: u64,
+    /// Bytes sent and received over every connection so far, closed ones included
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub choked_by_us: u64,
+    pub choking_us: u64,
+    pub interested_in_us: u64,
+    pub dial_failures: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    pub fn shared() -> SharedMetrics {
+        Arc::new(Self::default())
+    }
+
+    /// Replaces the gauges and transfer totals of `torrent`
+    pub fn update(&self, torrent: &str, snapshot: TorrentMetrics) {
+        let mut torrents = self.torrents.lock().unwrap();
+        let metrics = torrents.entry(torrent.to_string()).or_default();
+        *metrics = TorrentMetrics {
+            dial_failures: std::mem::take(&mut metrics.dial_failures),
+            ..snapshot
+        };
+    }
+
+    pub fn record_dial_failure(&self, torrent: &str, reason: DisconnectReason) {
+        let mut torrents = self.torrents.lock().unwrap();
+        let metrics = torrents.entry(torrent.to_string()).or_default();
+        *metrics
+            .dial_failures
+            .entry(format!("{:?}", reason))
+            .or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let torrents = self.torrents.lock().unwrap();
+        let mut output = String::new();
+        let samples = |sample: fn(&TorrentMetrics) -> Vec<(String, u64)>| -> Vec<Sample> {
+            torrents
+                .iter()
+                .flat_map(|(torrent, metrics)| {
+                    sample(metrics).into_iter().map(move |(labels, value)| {
+                        (format!("torrent=\"{}\"{}", torrent, labels), value)
+                    })
+                })
+                .collect()
+        };
+        write_family(
+            &mut output,
+            "connected_peers",
+            "gauge",
+            "Peers connected to the torrent",
+            samples(|metrics| vec![(String::new(), metrics.connected_peers)]),
+        );
+        write_family(
+            &mut output,
+            "peer_bytes_total",
+            "counter",
+            "Bytes transferred with peers",
+            samples(|metrics| {
+                vec![
+                    (",direction=\"up\"".into(), metrics.uploaded),
+                    (",direction=\"down\"".into(), metrics.downloaded),
+                ]
+            }),
+        );
+        write_family(
+            &mut output,
+            "dial_failures_total",
+            "counter",
+            "Dials and inbound handshakes that failed, by reason",
+            samples(|metrics| {
+                metrics
+                    .dial_failures
+                    .iter()
+                    .map(|(reason, count)| (format!(",reason=\"{}\"", reason), *count))
+                    .collect()
+            }),
+        );
+        write_family(
+            &mut output,
+            "choke_state_peers",
+            "gauge",
+            "Connected peers by choke and interest state",
+            samples(|metrics| {
+                let connected = metrics.connected_peers;
+                [
+                    ("choked_by_us", metrics.choked_by_us),
+                    ("unchoked_by_us", connected - metrics.choked_by_us),
+                    ("choking_us", metrics.choking_us),
+                    ("unchoking_us", connected - metrics.choking_us),
+                    ("interested_in_us", metrics.interested_in_us),
+                ]
+                .into_iter()
+                .map(|(state, value)| (format!(",state=\"{}\"", state), value))
+                .collect()
+            }),
+        );
+        output
+    }
+}
+
+/// Labels and value of one sample
+type Sample = (String, u64);
+
+fn write_family(output: &mut String, name: &str, kind: &str, help: &str, samples: Vec<Sample>) {
+    let _ = writeln!(output, "# HELP furia_{} {}", name, help);
+    let _ = writeln!(output, "# TYPE furia_{} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(output, "furia_{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Answers every HTTP request on `listener` with the current metrics, whatever its path
+pub async fn serve(listener: TcpListener, metrics: SharedMetrics) -> Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // Scrapers send short GET requests, their headers are of no interest
+            let mut request = [0; 1024];
+            if stream.read(&mut request).await.is_err() {
+                return;
+            }
+            let body = metrics.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_renders_the_exposition_format() {
+        let metrics = Metrics::default();
+        metrics.record_dial_failure("ab", DisconnectReason::HandshakeTimeout);
+        metrics.update(
+            "ab",
+            TorrentMetrics {
+                connected_peers: 3,
+                uploaded: 10,
+                choked_by_us: 2,
+                ..Default::default()
+            },
+        );
+        metrics.record_dial_failure("ab", DisconnectReason::HandshakeTimeout);
+        let rendered = metrics.render();
+        assert!(rendered.contains("# TYPE furia_connected_peers gauge\n"));
+        assert!(rendered.contains("furia_connected_peers{torrent=\"ab\"} 3\n"));
+        assert!(rendered.contains("furia_peer_bytes_total{torrent=\"ab\",direction=\"up\"} 10\n"));
+        assert!(rendered
+            .contains("furia_dial_failures_total{torrent=\"ab\",reason=\"HandshakeTimeout\"} 2\n"));
+        assert!(rendered
+            .contains("furia_choke_state_peers{torrent=\"ab\",state=\"unchoked_by_us\"} 1\n"));
+    }
+}
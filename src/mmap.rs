@@ -0,0 +1,91 @@
+use std::{fs::File, io, os::fd::AsRawFd, ptr};
+
+/// A whole file mapped shared into memory, reads and writes going through the page cache
+/// without a syscall each. The file has to be at least `length` long beforehand, touching
+/// a mapping past its end faults
+#[derive(Debug)]
+pub struct Mapping {
+    pointer: *mut u8,
+    length: usize,
+}
+
+// The mapping is only ever accessed through copies in and out of it, callers keep
+// concurrent writes to separate ranges like they would with positioned file writes
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    pub fn new(file: &File, length: usize) -> io::Result<Self> {
+        if length == 0 || file.metadata()?.len() < length as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "File is shorter than the mapping",
+            ));
+        }
+        let pointer = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                length,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            pointer: pointer.cast(),
+            length,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    fn check(&self, offset: usize, length: usize) {
+        assert!(
+            offset
+                .checked_add(length)
+                .is_some_and(|end| end <= self.length),
+            "{} bytes at {} are outside a mapping of {}",
+            length,
+            offset,
+            self.length
+        );
+    }
+
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) {
+        self.check(offset, buffer.len());
+        unsafe {
+            ptr::copy_nonoverlapping(self.pointer.add(offset), buffer.as_mut_ptr(), buffer.len())
+        }
+    }
+
+    pub fn write(&self, offset: usize, data: &[u8]) {
+        self.check(offset, data.len());
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.pointer.add(offset), data.len()) }
+    }
+
+    /// Waits until what was written reached the file
+    pub fn flush(&self) -> io::Result<()> {
+        if unsafe { libc::msync(self.pointer.cast(), self.length, libc::MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.pointer.cast(), self.length);
+        }
+    }
+}
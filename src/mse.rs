@@ -0,0 +1,510 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::{anyhow, Result};
+use num_bigint::BigUint;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::transport::{PeerTransport, Transport};
+
+/// 768 bit safe prime shared by every MSE implementation
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LENGTH: usize = 96;
+const MAX_PADDING: usize = 512;
+const VERIFICATION_CONSTANT: [u8; 8] = [0; 8];
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Plain BitTorrent handshakes only
+    #[default]
+    Disabled,
+    /// Try the encrypted handshake first and fall back to plaintext
+    Prefer,
+    /// Only talk to peers that complete an RC4 encrypted handshake
+    Require,
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(&self) -> u32 {
+        match self {
+            EncryptionPolicy::Require => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+
+    fn crypto_select(&self, provided: u32) -> Option<u32> {
+        if provided & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if provided & CRYPTO_PLAINTEXT != 0 && *self != EncryptionPolicy::Require {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0_u8; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// RC4 as used by MSE, with the first 1024 bytes of keystream thrown away
+    fn for_mse(key: &[u8]) -> Self {
+        let mut rc4 = Self::new(key);
+        rc4.process(&mut [0; 1024]);
+        rc4
+    }
+
+    pub fn process(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PADDING);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: Vec<u8>,
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let prime = BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+        let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(2_u8).modpow(&private, &prime);
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8]) -> Vec<u8> {
+        let prime = BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+        let remote = BigUint::from_bytes_be(remote_public);
+        to_key_bytes(&remote.modpow(&self.private, &prime))
+    }
+}
+
+fn to_key_bytes(number: &BigUint) -> Vec<u8> {
+    let bytes = number.to_bytes_be();
+    let mut key = vec![0; KEY_LENGTH - bytes.len()];
+    key.extend_from_slice(&bytes);
+    key
+}
+
+/// Reads one byte at a time until `pattern` shows up, giving up after `limit` bytes
+async fn synchronize<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pattern: &[u8],
+    limit: usize,
+) -> Result<()> {
+    let mut window = Vec::with_capacity(limit + pattern.len());
+    while window.len() < limit + pattern.len() {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("Unable to synchronize encrypted handshake"))
+}
+
+/// Transport wrapper that encrypts and decrypts with RC4 once the MSE handshake
+/// negotiated it, and passes bytes through untouched otherwise
+pub struct MseStream<S> {
+    inner: S,
+    encryptor: Option<Rc4>,
+    decryptor: Option<Rc4>,
+    /// Already decrypted bytes received during the handshake, handed out before reading again
+    prefix: Vec<u8>,
+    /// Encrypted bytes accepted from the writer but not yet written to the socket
+    pending: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    pub fn plaintext(inner: S) -> Self {
+        Self {
+            inner,
+            encryptor: None,
+            decryptor: None,
+            prefix: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: PeerTransport> PeerTransport for MseStream<S> {
+    fn kind(&self) -> Transport {
+        self.inner.kind()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MseStream<S> {
+    /// Runs the initiating side of the handshake for the torrent identified by `info_hash`
+    pub async fn connect(mut inner: S, info_hash: &[u8], policy: EncryptionPolicy) -> Result<Self> {
+        if policy == EncryptionPolicy::Disabled {
+            return Ok(Self::plaintext(inner));
+        }
+        let keys = KeyPair::generate();
+        inner.write_all(&keys.public).await?;
+        inner.write_all(&random_padding()).await?;
+
+        let mut remote_public = [0; KEY_LENGTH];
+        inner.read_exact(&mut remote_public).await?;
+        let secret = keys.shared_secret(&remote_public);
+        let mut encryptor = Rc4::for_mse(&hash(&[b"keyA", &secret, info_hash]));
+        let mut decryptor = Rc4::for_mse(&hash(&[b"keyB", &secret, info_hash]));
+
+        let mut message = hash(&[b"req1", &secret]);
+        message.extend(xor(
+            &hash(&[b"req2", info_hash]),
+            &hash(&[b"req3", &secret]),
+        ));
+        let padding = random_padding();
+        let mut encrypted = VERIFICATION_CONSTANT.to_vec();
+        encrypted.extend_from_slice(&policy.crypto_provide().to_be_bytes());
+        encrypted.extend_from_slice(&(padding.len() as u16).to_be_bytes());
+        encrypted.extend_from_slice(&padding);
+        // No initial payload, the BitTorrent handshake follows on the negotiated stream
+        encrypted.extend_from_slice(&0_u16.to_be_bytes());
+        encryptor.process(&mut encrypted);
+        message.extend(encrypted);
+        inner.write_all(&message).await?;
+
+        let mut verification = VERIFICATION_CONSTANT;
+        decryptor.process(&mut verification);
+        synchronize(&mut inner, &verification, MAX_PADDING).await?;
+        let mut header = [0; 6];
+        inner.read_exact(&mut header).await?;
+        decryptor.process(&mut header);
+        let crypto_select = u32::from_be_bytes(header[..4].try_into()?);
+        let padding_length = u16::from_be_bytes(header[4..].try_into()?) as usize;
+        if padding_length > MAX_PADDING {
+            return Err(anyhow!("Padding of {} bytes", padding_length));
+        }
+        let mut padding = vec![0; padding_length];
+        inner.read_exact(&mut padding).await?;
+        decryptor.process(&mut padding);
+
+        Self::with_selected_crypto(
+            inner,
+            crypto_select,
+            policy,
+            encryptor,
+            decryptor,
+            Vec::new(),
+        )
+    }
+
+    /// Runs the receiving side of the handshake. Returns the stream along with the
+    /// info hash the initiator asked for, which must be one of `info_hashes`.
+    /// Plaintext BitTorrent handshakes are let through unless the policy requires encryption.
+    pub async fn accept(
+        mut inner: S,
+        info_hashes: &[Vec<u8>],
+        policy: EncryptionPolicy,
+    ) -> Result<(Self, Option<Vec<u8>>)> {
+        let mut remote_public = [0; KEY_LENGTH];
+        inner.read_exact(&mut remote_public[..20]).await?;
+        if remote_public[0] == 19 && &remote_public[1..20] == b"BitTorrent protocol" {
+            if policy == EncryptionPolicy::Require {
+                return Err(anyhow!("Plaintext connection refused by encryption policy"));
+            }
+            let mut stream = Self::plaintext(inner);
+            stream.prefix = remote_public[..20].to_vec();
+            return Ok((stream, None));
+        }
+        if policy == EncryptionPolicy::Disabled {
+            return Err(anyhow!("Encrypted connection refused by encryption policy"));
+        }
+        inner.read_exact(&mut remote_public[20..]).await?;
+        let keys = KeyPair::generate();
+        inner.write_all(&keys.public).await?;
+        inner.write_all(&random_padding()).await?;
+        let secret = keys.shared_secret(&remote_public);
+
+        synchronize(&mut inner, &hash(&[b"req1", &secret]), MAX_PADDING).await?;
+        let mut obfuscated = [0; 20];
+        inner.read_exact(&mut obfuscated).await?;
+        let req3 = hash(&[b"req3", &secret]);
+        let info_hash = info_hashes
+            .iter()
+            .find(|info_hash| xor(&hash(&[b"req2", info_hash]), &req3) == obfuscated)
+            .ok_or_else(|| anyhow!("Encrypted handshake for an unknown torrent"))?
+            .clone();
+        let encryptor = Rc4::for_mse(&hash(&[b"keyB", &secret, &info_hash]));
+        let mut decryptor = Rc4::for_mse(&hash(&[b"keyA", &secret, &info_hash]));
+
+        let mut header = [0; 14];
+        inner.read_exact(&mut header).await?;
+        decryptor.process(&mut header);
+        if header[..8] != VERIFICATION_CONSTANT {
+            return Err(anyhow!("Invalid verification constant"));
+        }
+        let crypto_provide = u32::from_be_bytes(header[8..12].try_into()?);
+        let padding_length = u16::from_be_bytes(header[12..].try_into()?) as usize;
+        if padding_length > MAX_PADDING {
+            return Err(anyhow!("Padding of {} bytes", padding_length));
+        }
+        let mut padding = vec![0; padding_length + 2];
+        inner.read_exact(&mut padding).await?;
+        decryptor.process(&mut padding);
+        let initial_payload_length =
+            u16::from_be_bytes(padding[padding_length..].try_into()?) as usize;
+        let mut initial_payload = vec![0; initial_payload_length];
+        inner.read_exact(&mut initial_payload).await?;
+        decryptor.process(&mut initial_payload);
+
+        let crypto_select = policy
+            .crypto_select(crypto_provide)
+            .ok_or_else(|| anyhow!("No acceptable crypto method in {:#x}", crypto_provide))?;
+        let mut encryptor = encryptor;
+        let padding = random_padding();
+        let mut reply = VERIFICATION_CONSTANT.to_vec();
+        reply.extend_from_slice(&crypto_select.to_be_bytes());
+        reply.extend_from_slice(&(padding.len() as u16).to_be_bytes());
+        reply.extend_from_slice(&padding);
+        encryptor.process(&mut reply);
+        inner.write_all(&reply).await?;
+
+        let stream = Self::with_selected_crypto(
+            inner,
+            crypto_select,
+            policy,
+            encryptor,
+            decryptor,
+            initial_payload,
+        )?;
+        Ok((stream, Some(info_hash)))
+    }
+
+    fn with_selected_crypto(
+        inner: S,
+        crypto_select: u32,
+        policy: EncryptionPolicy,
+        encryptor: Rc4,
+        decryptor: Rc4,
+        prefix: Vec<u8>,
+    ) -> Result<Self> {
+        let mut stream = Self::plaintext(inner);
+        stream.prefix = prefix;
+        match crypto_select {
+            CRYPTO_RC4 => {
+                stream.encryptor = Some(encryptor);
+                stream.decryptor = Some(decryptor);
+            }
+            CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Require => {}
+            method => return Err(anyhow!("Unacceptable crypto method {:#x}", method)),
+        }
+        Ok(stream)
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let len = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..len]);
+            this.prefix.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(decryptor) = &mut this.decryptor {
+            decryptor.process(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        let Some(encryptor) = &mut this.encryptor else {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        };
+        let mut encrypted = data.to_vec();
+        encryptor.process(&mut encrypted);
+        this.pending = encrypted;
+        // The data is accepted either way, whatever isn't written now goes out on flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[test]
+    fn rc4_matches_reference_vector() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").process(&mut data);
+        assert_eq!(hex::encode(data), "bbf316e8d940af0ad3");
+    }
+
+    async fn handshake(
+        initiator: EncryptionPolicy,
+        receiver: EncryptionPolicy,
+    ) -> Result<(
+        MseStream<tokio::io::DuplexStream>,
+        MseStream<tokio::io::DuplexStream>,
+    )> {
+        let (a, b) = duplex(4096);
+        let info_hash = vec![0xAA; 20];
+        let known = vec![vec![0xBB; 20], info_hash.clone()];
+        let (outgoing, incoming) = tokio::join!(
+            MseStream::connect(a, &info_hash, initiator),
+            MseStream::accept(b, &known, receiver)
+        );
+        let (incoming, negotiated) = incoming?;
+        assert_eq!(negotiated, Some(info_hash));
+        Ok((outgoing?, incoming))
+    }
+
+    #[tokio::test]
+    async fn encrypted_handshake_and_payload() {
+        let (mut outgoing, mut incoming) =
+            handshake(EncryptionPolicy::Require, EncryptionPolicy::Prefer)
+                .await
+                .unwrap();
+        assert!(outgoing.is_encrypted() && incoming.is_encrypted());
+
+        outgoing
+            .write_all(b"\x13BitTorrent protocol")
+            .await
+            .unwrap();
+        outgoing.flush().await.unwrap();
+        let mut received = [0; 20];
+        incoming.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"\x13BitTorrent protocol");
+
+        incoming.write_all(b"pong").await.unwrap();
+        incoming.flush().await.unwrap();
+        let mut received = [0; 4];
+        outgoing.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+    }
+
+    #[tokio::test]
+    async fn plaintext_handshakes_follow_the_policy() {
+        let handshake = b"\x13BitTorrent protocol\0\0\0\0\0\0\0\0";
+        let (mut a, b) = duplex(4096);
+        a.write_all(handshake).await.unwrap();
+        let (mut incoming, info_hash) = MseStream::accept(b, &[], EncryptionPolicy::Prefer)
+            .await
+            .unwrap();
+        assert!(info_hash.is_none() && !incoming.is_encrypted());
+        let mut received = [0; 28];
+        incoming.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, handshake);
+
+        let (mut a, b) = duplex(4096);
+        a.write_all(handshake).await.unwrap();
+        assert!(MseStream::accept(b, &[], EncryptionPolicy::Require)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::{
+    portmap::{Protocol, LEASE_DURATION},
+    upnp::local_ip_towards,
+};
+
+/// Port NAT-PMP and PCP servers listen on, on the default gateway
+const SERVER_PORT: u16 = 5351;
+/// Wait for the first answer, doubled on every retry as RFC 6886 asks
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const ATTEMPTS: u32 = 4;
+
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_ANNOUNCE: u8 = 0;
+const PCP_MAP: u8 = 1;
+/// Set in the opcode of PCP responses, NAT-PMP adds 128 to its opcodes the same way
+const RESPONSE: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// RFC 6886, IPv4 only and the predecessor of PCP
+    NatPmp,
+    /// RFC 6887
+    Pcp,
+}
+
+/// A gateway speaking NAT-PMP or PCP, both plain UDP request/response protocols
+#[derive(Debug, Clone)]
+pub struct NatPmp {
+    gateway: SocketAddr,
+    local_ip: Ipv4Addr,
+    version: Version,
+    /// Identifies our PCP mappings, so they can be renewed and deleted
+    nonce: [u8; 12],
+}
+
+impl NatPmp {
+    /// Checks that the default gateway answers requests of `version`
+    pub async fn discover(version: Version) -> Result<Self> {
+        let gateway = SocketAddr::new(IpAddr::V4(default_gateway()?), SERVER_PORT);
+        let this = Self {
+            gateway,
+            local_ip: local_ip_towards(gateway).await?,
+            version,
+            nonce: rand::random(),
+        };
+        let probe = match version {
+            // The external address request is the smallest request NAT-PMP has
+            Version::NatPmp => vec![NAT_PMP_VERSION, 0],
+            Version::Pcp => this.pcp_header(PCP_ANNOUNCE, 0),
+        };
+        this.request(&probe).await?;
+        Ok(this)
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub async fn add_port_mapping(&self, protocol: Protocol, port: u16) -> Result<()> {
+        self.map(protocol, port, LEASE_DURATION.as_secs() as u32)
+            .await
+    }
+
+    /// A mapping requested with a lifetime of 0 is deleted
+    pub async fn delete_port_mapping(&self, protocol: Protocol, port: u16) -> Result<()> {
+        self.map(protocol, port, 0).await
+    }
+
+    async fn map(&self, protocol: Protocol, port: u16, lifetime: u32) -> Result<()> {
+        let request = match self.version {
+            Version::NatPmp => {
+                let opcode = match protocol {
+                    Protocol::Udp => 1,
+                    Protocol::Tcp => 2,
+                };
+                let mut request = vec![NAT_PMP_VERSION, opcode, 0, 0];
+                request.extend_from_slice(&port.to_be_bytes());
+                request.extend_from_slice(&port.to_be_bytes());
+                request.extend_from_slice(&lifetime.to_be_bytes());
+                request
+            }
+            Version::Pcp => {
+                let mut request = self.pcp_header(PCP_MAP, lifetime);
+                request.extend_from_slice(&self.nonce);
+                request.push(match protocol {
+                    Protocol::Tcp => 6,
+                    Protocol::Udp => 17,
+                });
+                request.extend_from_slice(&[0; 3]);
+                request.extend_from_slice(&port.to_be_bytes());
+                request.extend_from_slice(&port.to_be_bytes());
+                // No preference for the external address
+                request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+                request
+            }
+        };
+        self.request(&request).await
+    }
+
+    fn pcp_header(&self, opcode: u8, lifetime: u32) -> Vec<u8> {
+        let mut header = vec![PCP_VERSION, opcode, 0, 0];
+        header.extend_from_slice(&lifetime.to_be_bytes());
+        header.extend_from_slice(&self.local_ip.to_ipv6_mapped().octets());
+        header
+    }
+
+    /// Sends `request` until the gateway answers it, failing on error result codes
+    async fn request(&self, request: &[u8]) -> Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(self.gateway).await?;
+        let mut wait = INITIAL_TIMEOUT;
+        let mut response = [0; 1100];
+        for _ in 0..ATTEMPTS {
+            socket.send(request).await?;
+            if let Ok(received) = timeout(wait, socket.recv(&mut response)).await {
+                return parse_result(&response[..received?], request[1]);
+            }
+            wait *= 2;
+        }
+        Err(anyhow!(
+            "{:?} gateway at {} didn't answer",
+            self.version,
+            self.gateway
+        ))
+    }
+}
+
+/// Both protocols answer with the request's opcode plus 128, NAT-PMP puts a 16 bit
+/// result code after it while PCP puts an 8 bit one after a reserved byte
+fn parse_result(response: &[u8], opcode: u8) -> Result<()> {
+    if response.len() < 4 || response[1] != opcode | RESPONSE {
+        return Err(anyhow!("Unexpected response {:02x?}", response));
+    }
+    let result = match response[0] {
+        NAT_PMP_VERSION => u16::from_be_bytes([response[2], response[3]]),
+        PCP_VERSION => response[3] as u16,
+        version => return Err(anyhow!("Response of unknown version {}", version)),
+    };
+    if result != 0 {
+        return Err(anyhow!("Request refused with result code {}", result));
+    }
+    Ok(())
+}
+
+/// Reads the default route from `/proc/net/route`, elsewhere assumes the router
+/// is the first address of our /24
+fn default_gateway() -> Result<Ipv4Addr> {
+    if let Ok(routes) = std::fs::read_to_string("/proc/net/route") {
+        let gateway = routes.lines().skip(1).find_map(|route| {
+            let fields: Vec<&str> = route.split_whitespace().collect();
+            let destination = fields.get(1)?;
+            let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+            // Addresses are in host byte order, little endian on every platform we run on
+            (*destination == "00000000").then(|| Ipv4Addr::from(gateway.swap_bytes()))
+        });
+        if let Some(gateway) = gateway {
+            return Ok(gateway);
+        }
+    }
+    let probe = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    probe.connect("8.8.8.8:53")?;
+    match probe.local_addr()?.ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Ok(Ipv4Addr::new(a, b, c, 1))
+        }
+        IpAddr::V6(_) => Err(anyhow!("No IPv4 default gateway")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_reads_result_codes_of_both_versions() {
+        assert!(parse_result(&[0, 130, 0, 0, 0, 0, 0, 1], 2).is_ok());
+        assert!(parse_result(&[0, 130, 0, 3], 2).is_err());
+        assert!(parse_result(&[2, 0x81, 0, 0], PCP_MAP).is_ok());
+        assert!(parse_result(&[2, 0x81, 0, 8], PCP_MAP).is_err());
+        assert!(parse_result(&[2, 0x80, 0, 0], PCP_MAP).is_err());
+    }
+}
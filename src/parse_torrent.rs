@@ -1,15 +1,21 @@
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bencode::value::Value;
 use serde_bytes::ByteBuf;
 
+use crate::merkle::Hash;
+
 #[derive(Debug, Deserialize, Serialize)]
 struct Node(String, i64);
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct File {
-    path: Vec<String>,
-    length: i64,
+    pub path: Vec<String>,
+    pub length: i64,
     #[serde(default)]
-    md5sum: Option<String>,
+    pub md5sum: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,6 +37,14 @@ pub struct Info {
     #[serde(default)]
     #[serde(rename = "root hash")]
     pub root_hash: Option<String>,
+    /// 2 for v2 and hybrid torrents (BEP 52)
+    #[serde(default)]
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<u8>,
+    /// Nested directories of v2 torrents, see `v2_files`
+    #[serde(default)]
+    #[serde(rename = "file tree")]
+    pub file_tree: Option<Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -44,6 +58,10 @@ pub struct TorrentFile {
     encoding: Option<String>,
     #[serde(default)]
     httpseeds: Option<Vec<String>>,
+    /// BEP 19 web seeds, either a single url or a list of them
+    #[serde(default)]
+    #[serde(rename = "url-list", deserialize_with = "one_or_many")]
+    pub url_list: Vec<String>,
     #[serde(default)]
     #[serde(rename = "announce-list")]
     announce_list: Option<Vec<Vec<String>>>,
@@ -55,6 +73,164 @@ pub struct TorrentFile {
     #[serde(default)]
     #[serde(rename = "created by")]
     created_by: Option<String>,
+    /// Piece layer hashes of v2 files longer than a piece, by pieces root
+    #[serde(default)]
+    #[serde(rename = "piece layers")]
+    pub piece_layers: Option<HashMap<ByteBuf, ByteBuf>>,
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) if url.is_empty() => Vec::new(),
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    })
+}
+
+/// Where a file lives in the concatenated content of the torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    /// Path of the file relative to the torrent name, empty for single file torrents
+    pub path: Vec<String>,
+    pub offset: u64,
+    pub length: u64,
+    /// Hex MD5 of the whole file, which few torrents bother with
+    pub md5sum: Option<String>,
+}
+
+impl FileSpan {
+    /// BEP 47 padding files only exist to align pieces, they are never written
+    pub fn is_padding(&self) -> bool {
+        self.path.first().is_some_and(|dir| dir == ".pad")
+    }
+}
+
+pub fn file_spans(torrent: &TorrentFile) -> Vec<FileSpan> {
+    let Some(files) = &torrent.info.files else {
+        return vec![FileSpan {
+            path: Vec::new(),
+            offset: 0,
+            length: total_length(torrent) as u64,
+            md5sum: torrent.info.md5sum.clone(),
+        }];
+    };
+    let mut offset = 0;
+    files
+        .iter()
+        .map(|file| {
+            let span = FileSpan {
+                path: file.path.clone(),
+                offset,
+                length: file.length as u64,
+                md5sum: file.md5sum.clone(),
+            };
+            offset += file.length as u64;
+            span
+        })
+        .collect()
+}
+
+/// Device names Windows reserves in every directory, whatever the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a name or path component of a torrent into a single file name that stays where
+/// it's put: separators, drive colons and control characters are replaced, `.`, `..` and
+/// empty components become `_`, and names Windows reserves get a leading `_`
+pub fn sanitize_component(component: &str) -> String {
+    let name: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if matches!(name.as_str(), "" | "." | "..") {
+        "_".into()
+    } else if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+/// Where the file of each span lives under `content_dir`, every component sanitized so
+/// that a malicious torrent can't write anywhere else
+pub fn content_paths(torrent: &TorrentFile, content_dir: &Path) -> Vec<PathBuf> {
+    let root = content_dir.join(sanitize_component(&torrent.info.name));
+    file_spans(torrent)
+        .into_iter()
+        .map(|span| {
+            span.path.iter().fold(root.clone(), |path, part| {
+                path.join(sanitize_component(part))
+            })
+        })
+        .collect()
+}
+
+/// A file of the v2 file tree, whose pieces start on a piece boundary
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Root of the Merkle tree over the 16 KiB blocks of the file, None when it's empty
+    pub pieces_root: Option<Hash>,
+}
+
+/// Files of the v2 file tree in the order their pieces come in, empty for v1 torrents
+pub fn v2_files(torrent: &TorrentFile) -> Vec<V2File> {
+    let mut files = Vec::new();
+    if let Some(tree) = &torrent.info.file_tree {
+        collect_v2_files(tree, &mut Vec::new(), &mut files);
+    }
+    files
+}
+
+fn collect_v2_files(node: &Value, path: &mut Vec<String>, files: &mut Vec<V2File>) {
+    let Value::Dict(entries) = node else {
+        return;
+    };
+    // Bencoded dictionaries are sorted by key, which is the order of the pieces too
+    let mut names: Vec<&Vec<u8>> = entries.keys().collect();
+    names.sort();
+    for name in names {
+        let entry = &entries[name];
+        if name.is_empty() {
+            let Value::Dict(file) = entry else {
+                continue;
+            };
+            let length = match file.get(b"length".as_slice()) {
+                Some(Value::Int(length)) => *length as u64,
+                _ => continue,
+            };
+            let pieces_root = match file.get(b"pieces root".as_slice()) {
+                Some(Value::Bytes(root)) => root.as_slice().try_into().ok(),
+                _ => None,
+            };
+            files.push(V2File {
+                path: path.clone(),
+                length,
+                pieces_root,
+            });
+        } else {
+            path.push(String::from_utf8_lossy(name).into_owned());
+            collect_v2_files(entry, path, files);
+            path.pop();
+        }
+    }
 }
 
 pub fn parse_torrent(file_path: &str) -> TorrentFile {
@@ -62,10 +238,20 @@ pub fn parse_torrent(file_path: &str) -> TorrentFile {
     serde_bencode::from_bytes(&torrent_file).expect("Unable to parse torrent file")
 }
 
+/// Size in bytes of the whole content, summing up every file of multi-file torrents
+pub fn total_length(torrent: &TorrentFile) -> i64 {
+    match (&torrent.info.length, &torrent.info.files) {
+        (Some(length), _) => *length,
+        (None, Some(files)) => files.iter().map(|file| file.length).sum(),
+        (None, None) => 0,
+    }
+}
+
+/// Bytes of a bitfield with a bit per piece, counting the shorter last piece
 pub fn bitfield_size(torrent: &TorrentFile) -> u32 {
-    let number_of_pieces = ((torrent.info.length.unwrap() + torrent.info.piece_length - 1)
-        / torrent.info.piece_length) as usize;
-    ((number_of_pieces + 7) / 8) as u32
+    let number_of_pieces =
+        (total_length(torrent) as u64).div_ceil(torrent.info.piece_length as u64);
+    number_of_pieces.div_ceil(8) as u32
 }
 
 #[cfg(test)]
@@ -75,12 +261,74 @@ mod test {
     #[test]
     fn it_parses_a_torrent_file() {
         let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
-        assert_eq!(
-            "https://torrent.ubuntu.com/announce",
-            torrent.announce
-        );
+        assert_eq!("https://torrent.ubuntu.com/announce", torrent.announce);
         assert_eq!(Some(1691692385), torrent.creation_date);
         assert_eq!("ubuntu-22.04.3-live-server-amd64.iso", torrent.info.name);
         assert_eq!(262144, torrent.info.piece_length);
+        assert!(torrent.url_list.is_empty());
+        assert_eq!(
+            total_length(&torrent) as u64,
+            file_spans(&torrent)[0].length
+        );
+    }
+
+    #[test]
+    fn it_keeps_malicious_paths_in_the_content_dir() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod5:filesld6:lengthi1e4:pathl2:..2:..6:passwdeed6:lengthi1e4:pathl\
+              11:/etc/shadoweed6:lengthi1e4:pathl2:C:1:.7:nul.txteed6:lengthi1e4:pathl\
+              0:4:a\\b\x01eee4:name5:../..12:piece lengthi4e6:pieces0:ee",
+        )
+        .unwrap();
+        let content_dir = Path::new("/downloads");
+        let paths = content_paths(&torrent, content_dir);
+        let expected = [
+            "/downloads/.._../_/_/passwd",
+            "/downloads/.._../_etc_shadow",
+            "/downloads/.._../C_/_/_nul.txt",
+            "/downloads/.._../_/a_b_",
+        ];
+        assert_eq!(expected.map(PathBuf::from).to_vec(), paths);
+        assert_eq!("ubuntu", sanitize_component("ubuntu"));
+        assert_eq!("console", sanitize_component("console"));
+        assert_eq!("_Aux .tar.gz", sanitize_component("Aux .tar.gz"));
+    }
+
+    #[test]
+    fn it_parses_web_seeds() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod6:lengthi3e4:name1:a12:piece lengthi16384e6:pieces0:e8:url-list14:http://a.b/c/de",
+        )
+        .unwrap();
+        assert_eq!(vec!["http://a.b/c/d".to_string()], torrent.url_list);
+    }
+
+    #[test]
+    fn it_lists_v2_files_in_piece_order() {
+        let root = [7_u8; 32];
+        let mut bencoded = b"d4:infod9:file treed1:bd0:d6:lengthi5e11:pieces root32:".to_vec();
+        bencoded.extend_from_slice(&root);
+        bencoded.extend_from_slice(
+            b"ee1:ad1:cd0:d6:lengthi0eeeee12:meta versioni2e4:name1:x12:piece lengthi16384e\
+              6:pieces0:e12:piece layersdee",
+        );
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencoded).unwrap();
+        assert_eq!(Some(2), torrent.info.meta_version);
+        assert_eq!(
+            vec![
+                V2File {
+                    path: vec!["a".into(), "c".into()],
+                    length: 0,
+                    pieces_root: None,
+                },
+                V2File {
+                    path: vec!["b".into()],
+                    length: 5,
+                    pieces_root: Some(root),
+                },
+            ],
+            v2_files(&torrent)
+        );
+        assert!(torrent.piece_layers.unwrap().is_empty());
     }
 }
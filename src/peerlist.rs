@@ -0,0 +1,102 @@
+use std::{fmt::Write, net::SocketAddr};
+
+use crate::{fingerprint::ClientFingerprint, session::PeerState, transport::Transport};
+
+/// What a connected peer looks like right now, for finding out why a download is slow
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    pub client: Option<ClientFingerprint>,
+    /// Share of the torrent the peer has, between 0 and 1
+    pub progress: f64,
+    /// Bytes per second
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub state: PeerState,
+    pub snubbed: bool,
+    pub encrypted: bool,
+    pub outbound: bool,
+    pub transport: Transport,
+}
+
+impl PeerInfo {
+    /// Choke and interest flags in the style of other clients: `d`/`D` we want something
+    /// and are choked/unchoked, `u`/`U` they want something and are choked/unchoked,
+    /// `S` snubbed, `E` encrypted, `I` incoming
+    pub fn flags(&self) -> String {
+        let flags = [
+            (self.state.am_interested && self.state.peer_choking, 'd'),
+            (self.state.am_interested && !self.state.peer_choking, 'D'),
+            (self.state.peer_interested && self.state.am_choking, 'u'),
+            (self.state.peer_interested && !self.state.am_choking, 'U'),
+            (self.snubbed, 'S'),
+            (self.encrypted, 'E'),
+            (!self.outbound, 'I'),
+        ];
+        flags
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| flag)
+            .collect()
+    }
+}
+
+/// One line per peer under a header, fastest downloads first
+pub fn render_table(peers: &[PeerInfo]) -> String {
+    let mut peers: Vec<&PeerInfo> = peers.iter().collect();
+    peers.sort_by_key(|peer| std::cmp::Reverse(peer.download_rate));
+    let mut table = format!(
+        "{:<46} {:<24} {:>6} {:>10} {:>10} {}\n",
+        "ADDRESS", "CLIENT", "HAS", "DOWN KB/s", "UP KB/s", "FLAGS"
+    );
+    for peer in peers {
+        let client = peer
+            .client
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| "unknown".into());
+        let _ = writeln!(
+            table,
+            "{:<46} {:<24} {:>5.1}% {:>10.1} {:>10.1} {}",
+            peer.address.to_string(),
+            client,
+            peer.progress * 100.0,
+            peer.download_rate as f64 / 1024.0,
+            peer.upload_rate as f64 / 1024.0,
+            peer.flags()
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_lists_peers_fastest_first() {
+        let peer = |port, download_rate| PeerInfo {
+            address: SocketAddr::from(([10, 0, 0, 2], port)),
+            client: ClientFingerprint::from_peer_id(b"-qB4630-000000000000"),
+            progress: 0.5,
+            download_rate,
+            upload_rate: 0,
+            state: PeerState {
+                am_interested: true,
+                peer_choking: false,
+                ..Default::default()
+            },
+            snubbed: false,
+            encrypted: true,
+            outbound: true,
+            transport: Transport::Tcp,
+        };
+        let table = render_table(&[peer(1, 1024), peer(2, 4096)]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[1].starts_with("10.0.0.2:2 "));
+        assert!(lines[1].contains("qBittorrent 4.6.3"));
+        assert!(lines[1].contains(" 50.0% "));
+        assert!(lines[1].ends_with(" DE"));
+    }
+}
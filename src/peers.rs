@@ -1,135 +1,3630 @@
 use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures::{
+    future::{select_all, select_ok},
+    FutureExt, SinkExt, StreamExt,
+};
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{
+        broadcast,
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        watch, OwnedSemaphorePermit, Semaphore,
+    },
+    task::{spawn_blocking, JoinHandle, JoinSet},
+    time::{sleep, timeout},
 };
+use tokio_util::codec::Framed;
+use tracing::{debug, warn};
 
 use crate::{
-    messages::Message,
-    parse_torrent::{TorrentFile, bitfield_size},
-    tracker::{get_info_hash, Peer}, download::Download,
+    ban::{BanList, SharedBanList},
+    bind::Bind,
+    bitfield::Bitfield,
+    blame::BlockBlame,
+    cache::PieceCache,
+    capabilities::PeerCapabilities,
+    choker::{
+        choose_optimistic, choose_unchoked, ChokeCandidate, CHOKE_INTERVAL,
+        DEFAULT_SEED_UPLOAD_SLOTS, DEFAULT_UPLOAD_SLOTS, NEW_PEER_AGE, OPTIMISTIC_UNCHOKE_INTERVAL,
+    },
+    codec::PeerCodec,
+    dht::{announce_peer, get_peers, Dht, Lookup, LOOKUP_INTERVAL},
+    disconnect::{Disconnect, DisconnectReason, DEFAULT_HANDSHAKE_TIMEOUT},
+    disk::{DiskWriter, WriteDone},
+    download::{Download, FilePriority, PickOrder, PieceStatus},
+    events::{PeerEvent, EVENT_CAPACITY},
+    extension::{ExtendedHandshake, HANDSHAKE_ID, LT_DONTHAVE_ID, UT_HOLEPUNCH_ID, UT_PEX_ID},
+    fingerprint::ClientFingerprint,
+    handler::{dispatch, MessageHandler},
+    holepunch::{HolepunchError, HolepunchMessage, HolepunchType},
+    ipfilter::IpFilter,
+    lan::LanPreference,
+    md5::storage_md5,
+    merkle::Hash,
+    messages::{Block, BlockRequest, HashRequest, Message, BLOCK_BYTES},
+    metrics::{SharedMetrics, TorrentMetrics},
+    mse::{EncryptionPolicy, MseStream},
+    parse_torrent::{content_paths, file_spans, TorrentFile},
+    peerlist::PeerInfo,
+    pex::{PexMessage, PexState, FLAG_SEED, MAX_PEX_PEERS, PEX_INTERVAL},
+    ports::PortRange,
+    progress::DownloadProgress,
+    queue::QueuedTorrent,
+    ratelimit::{PeerRateCaps, RateLimiter, SharedBucket, TokenBucket},
+    recheck::{recheck, RecheckProgress},
+    resume::{ResumeData, Snapshot},
+    retry::DialRetry,
+    score::{rarity, PeerScore, MAX_REPLACEMENTS, UNTRIED_PEER_SCORE},
+    session::PeerState,
+    socket::SocketOptions,
+    stats::{pipeline_depth_for, PeerStats, RequestWindow},
+    storage::{
+        allocated_bytes, available_space, move_files, Allocation, FileAccess, FileStorage, Storage,
+    },
+    superseed::SuperSeed,
+    trace::{trace_frame, trace_message, Direction},
+    tracker::{announce, get_info_hash, Event, Peer, TrackerResponse, DEFAULT_PORT},
+    transport::{is_unreachable, BoxedTransport, PeerTransport, Transport},
+    webseed::WebSeed,
 };
 
-pub enum PeerStatus {
-    Chocked,
-    Interested,
-}
+/// How long a pass of the manager waits for a message from any peer
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long an encrypted handshake may take before falling back or giving up
+const ENCRYPTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections kept open to peers of the torrent
+pub const DEFAULT_MAX_CONNECTIONS: usize = 50;
+/// Connections kept open across every torrent sharing the same slots
+pub const DEFAULT_GLOBAL_MAX_CONNECTIONS: usize = 200;
+/// How often the lowest scoring peers may be dropped in favour of untried candidates
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+/// Peers transferring nothing for this long give up their slot to waiting candidates
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Dial attempts in flight at the same time
+pub const DEFAULT_CONCURRENT_DIALS: usize = 8;
+/// Connections being established at once across every torrent sharing the limit,
+/// more exhaust file descriptors and trip the SYN limits of some systems
+pub const DEFAULT_HALF_OPEN_LIMIT: usize = 30;
+/// New connection attempts per second, so joining a large swarm doesn't flood the network
+/// stack or look like a scan to the ISP
+pub const DEFAULT_DIAL_RATE: u64 = 10;
+/// How long establishing the TCP connection itself may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// A peer with outstanding requests that sent no block for this long is snubbing us
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+/// A requested block that didn't arrive in this long is asked from another peer
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Block requests kept outstanding at each peer unless its reqq asks for fewer
+pub const DEFAULT_PIPELINE_DEPTH: usize = 16;
+/// Peers waiting to be dialed beyond this are dropped, PEX can easily bring in thousands
+pub const MAX_CANDIDATES: usize = 1000;
+/// Blocks left to arrive, all of them requested already, below which every peer that can
+/// serve one is asked for it too, so one slow peer can't stall the last pieces
+pub const ENDGAME_BLOCKS: usize = 32;
+/// How often the metrics we report into are brought up to date
+const METRICS_INTERVAL: Duration = Duration::from_secs(1);
+/// Head start of a dual-stack peer's IPv6 address over its IPv4 one, per RFC 8305
+pub const FALLBACK_DELAY: Duration = Duration::from_millis(250);
+/// How often the resume file is rewritten while downloading
+pub const RESUME_INTERVAL: Duration = Duration::from_secs(30);
 
 pub struct ConnectionManager<'a> {
     connections: Vec<PeerConnection>,
     torrent: &'a TorrentFile,
     download: Download,
+    peer_id: String,
+    /// Peers learned from other peers that we are not connected to yet
+    candidates: Vec<Peer>,
+    dht: Option<Dht>,
+    /// Length of the block requests pieces are split into
+    block_size: u32,
+    pipeline_depth: usize,
+    super_seed: Option<SuperSeed>,
+    web_seeds: Vec<WebSeed>,
+    upload_slots: usize,
+    seed_upload_slots: usize,
+    last_choke: Option<Instant>,
+    /// Holder of the upload slot that rotates every `OPTIMISTIC_UNCHOKE_INTERVAL`
+    optimistic: Option<Peer>,
+    last_optimistic: Option<Instant>,
+    /// Set once only the last few blocks are missing
+    endgame: bool,
+    /// Where the files of the torrent live
+    content_dir: PathBuf,
+    /// Where the files go once the download is finished, the content directory being
+    /// only for incomplete ones then
+    completed_dir: Option<PathBuf>,
+    file_access: FileAccess,
+    disk: DiskWriter,
+    /// Verified pieces in memory the disk queue had no room for yet
+    unwritten: VecDeque<u32>,
+    cache: PieceCache,
+    resume_file: Option<PathBuf>,
+    last_resume_save: Option<Instant>,
+    /// Timed out requests waiting for a peer other than the one that failed them
+    orphaned_requests: VecDeque<(BlockRequest, Peer)>,
+    /// Peers a send failed to while another peer's message was handled, dropped once
+    /// every message of the pass is handled
+    broken: Vec<(Peer, DisconnectReason)>,
+    handshake_timeout: Duration,
+    disconnected: HashMap<Peer, DisconnectReason>,
+    max_connections: usize,
+    concurrent_dials: usize,
+    dialing: HashSet<Peer>,
+    /// Dial attempts and inbound connections report back through this channel
+    dialed: (UnboundedSender<Dialed>, UnboundedReceiver<Dialed>),
+    /// Requests of peers waiting for a span of storage being read, by span
+    reading: HashMap<BlockRequest, Vec<(Peer, BlockRequest)>>,
+    read: (UnboundedSender<StorageRead>, UnboundedReceiver<StorageRead>),
+    /// Each connection, or dial in flight, holds one of these, which may be shared
+    /// with the managers of other torrents to enforce a global limit
+    global_slots: Arc<Semaphore>,
+    /// Held by a dial until its connection is established, those beyond the limit queue for it
+    half_open: Arc<Semaphore>,
+    /// One token per dial, may be shared with other torrents
+    dial_rate: SharedBucket,
+    last_eviction: Option<Instant>,
+    idle_timeout: Duration,
+    /// While listening there is always someone who may still connect
+    listen_port: Option<u16>,
+    listener: Option<Listener>,
+    searching_dht: bool,
+    last_dht_lookup: Option<Instant>,
+    dht_lookups: (UnboundedSender<Lookup>, UnboundedReceiver<Lookup>),
+    /// Address or interface every peer and tracker connection has to go through
+    bind: Option<Bind>,
+    socket_options: SocketOptions,
+    /// Applies to every peer of this torrent unless overridden for its address
+    encryption: EncryptionPolicy,
+    peer_encryption: HashMap<IpAddr, EncryptionPolicy>,
+    /// IPv4 addresses of dual-stack candidates, tried if their IPv6 one is slow to connect
+    fallbacks: HashMap<Peer, SocketAddr>,
+    /// Peers that sent each block of the pieces not verified yet
+    blame: BlockBlame,
+    /// Completed pieces and whether they matched their hash, settled once no connection
+    /// is being read so the peers to blame can be dropped
+    checked_pieces: Vec<(u32, bool)>,
+    bans: SharedBanList,
+    /// Failed dials of candidates waiting for another attempt, or given up on
+    retries: HashMap<SocketAddr, DialRetry>,
+    /// Our own addresses as peers report them in extended handshakes
+    external_ips: HashSet<IpAddr>,
+    /// Addresses that led back to ourselves, never dialed again
+    self_addresses: HashSet<SocketAddr>,
+    /// Connected peer that told us of each candidate over PEX, so likely connected to it
+    introducers: HashMap<SocketAddr, Peer>,
+    /// Unreachable candidates a relay was asked to introduce us to, with why their dial
+    /// failed should the relay fail too
+    holepunches: HashMap<SocketAddr, (Peer, DisconnectReason)>,
+    /// Every address a rendezvous was requested for, which is only tried once
+    holepunched: HashSet<SocketAddr>,
+    ip_filter: Arc<IpFilter>,
+    /// Buckets every connection of the torrent is charged to, shared with other torrents
+    /// for a global budget
+    upload_limiter: RateLimiter,
+    download_limiter: RateLimiter,
+    /// Ceilings for the connections with some addresses alone
+    peer_rate_caps: HashMap<IpAddr, PeerRateCaps>,
+    lan: LanPreference,
+    /// Peers refused by the IP filter, dialed or inbound
+    filtered: Arc<AtomicUsize>,
+    /// Dials and DHT lookups running in the background
+    tasks: Vec<JoinHandle<()>>,
+    events: broadcast::Sender<PeerEvent>,
+    /// Where to report, along with our info hash as the torrent label
+    metrics: Option<(SharedMetrics, String)>,
+    last_metrics: Option<Instant>,
+    /// Refreshed along with the metrics while anyone watches it
+    peer_list: watch::Sender<Vec<PeerInfo>>,
+    progress: watch::Sender<DownloadProgress>,
+    created_at: Instant,
+    /// Bytes sent and received over connections that are closed by now
+    closed_uploaded: u64,
+    closed_downloaded: u64,
+    /// Flipped from outside to pause or resume the torrent while it runs
+    pause_switch: Arc<AtomicBool>,
+    /// Whether every wanted piece is there, for those watching from outside
+    finished: Arc<AtomicBool>,
 }
 
 impl<'a> ConnectionManager<'a> {
-    pub fn new(torrent: &'a TorrentFile, download: Download) -> Self {
+    pub fn new(torrent: &'a TorrentFile, download: Download, peer_id: String) -> Self {
         Self {
+            finished: Arc::new(AtomicBool::new(download.is_finished())),
             connections: Vec::new(),
             torrent,
             download,
+            peer_id,
+            candidates: Vec::new(),
+            dht: None,
+            block_size: BLOCK_BYTES,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            super_seed: None,
+            web_seeds: torrent.url_list.iter().cloned().map(WebSeed::new).collect(),
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            seed_upload_slots: DEFAULT_SEED_UPLOAD_SLOTS,
+            last_choke: None,
+            optimistic: None,
+            last_optimistic: None,
+            endgame: false,
+            content_dir: PathBuf::from("."),
+            completed_dir: None,
+            file_access: FileAccess::default(),
+            disk: DiskWriter::new(Arc::new(FileStorage::new(torrent, Path::new(".")))),
+            unwritten: VecDeque::new(),
+            cache: PieceCache::default(),
+            resume_file: None,
+            last_resume_save: None,
+            orphaned_requests: VecDeque::new(),
+            broken: Vec::new(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            disconnected: HashMap::new(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            concurrent_dials: DEFAULT_CONCURRENT_DIALS,
+            dialing: HashSet::new(),
+            dialed: unbounded_channel(),
+            reading: HashMap::new(),
+            read: unbounded_channel(),
+            global_slots: Arc::new(Semaphore::new(DEFAULT_GLOBAL_MAX_CONNECTIONS)),
+            half_open: Arc::new(Semaphore::new(DEFAULT_HALF_OPEN_LIMIT)),
+            dial_rate: TokenBucket::shared(DEFAULT_DIAL_RATE),
+            last_eviction: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            listen_port: None,
+            listener: None,
+            searching_dht: false,
+            last_dht_lookup: None,
+            dht_lookups: unbounded_channel(),
+            bind: None,
+            socket_options: SocketOptions::default(),
+            encryption: EncryptionPolicy::default(),
+            peer_encryption: HashMap::new(),
+            fallbacks: HashMap::new(),
+            blame: BlockBlame::default(),
+            checked_pieces: Vec::new(),
+            bans: BanList::shared(),
+            retries: HashMap::new(),
+            external_ips: HashSet::new(),
+            self_addresses: HashSet::new(),
+            introducers: HashMap::new(),
+            holepunches: HashMap::new(),
+            holepunched: HashSet::new(),
+            ip_filter: Arc::new(IpFilter::default()),
+            upload_limiter: RateLimiter::default(),
+            download_limiter: RateLimiter::default(),
+            peer_rate_caps: HashMap::new(),
+            lan: LanPreference::default(),
+            filtered: Arc::new(AtomicUsize::new(0)),
+            tasks: Vec::new(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            metrics: None,
+            last_metrics: None,
+            peer_list: watch::channel(Vec::new()).0,
+            progress: watch::channel(DownloadProgress::default()).0,
+            created_at: Instant::now(),
+            closed_uploaded: 0,
+            closed_downloaded: 0,
+            pause_switch: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn add_peer(&mut self, peer: Peer) -> Result<()> {
-        let connection = PeerConnection::new(peer)?;
-        self.connections.push(connection);
-        Ok(())
+    /// Size of the blocks requested from peers, for pieces not started yet
+    pub fn set_block_size(&mut self, block_size: u32) {
+        self.block_size = block_size.max(1);
+        self.download.set_block_size(self.block_size);
+    }
+
+    /// Peers unchoked at once while downloading, 0 uploads to no one
+    pub fn set_upload_slots(&mut self, upload_slots: usize) {
+        self.upload_slots = upload_slots;
+    }
+
+    /// Peers unchoked at once once the download is complete
+    pub fn set_seed_upload_slots(&mut self, seed_upload_slots: usize) {
+        self.seed_upload_slots = seed_upload_slots;
+    }
+
+    /// The slots the choker currently fills
+    pub fn upload_slots(&self) -> usize {
+        if self.download.is_finished() {
+            self.seed_upload_slots
+        } else {
+            self.upload_slots
+        }
     }
 
-    pub fn connect_to_peers(&mut self) -> Result<()> {
+    /// Requests kept outstanding at peers until their rate and latency are known
+    pub fn set_pipeline_depth(&mut self, pipeline_depth: usize) {
+        self.pipeline_depth = pipeline_depth.max(1);
         for connection in &mut self.connections {
-            connection.handshake(&self.torrent)?;
-            connection.bitfield(&self.torrent, &self.download)?;
-            connection.interested()?;
+            connection.pipeline_depth = self.pipeline_depth;
+        }
+    }
+
+    /// Advertises DHT support in handshakes and collects the nodes peers announce via PORT
+    pub fn enable_dht(&mut self, port: u16) {
+        self.dht = Some(Dht::new(port));
+    }
+
+    pub fn dht(&self) -> Option<&Dht> {
+        self.dht.as_ref()
+    }
+
+    /// Only possible once we have every piece
+    pub fn enable_super_seeding(&mut self) -> Result<()> {
+        if !self.download.have.is_complete() {
+            return Err(anyhow!("Super-seeding requires the complete torrent"));
         }
+        self.super_seed = Some(SuperSeed::default());
         Ok(())
     }
-}
 
-pub struct PeerConnection {
-    peer: Peer,
-    am_status: Option<PeerStatus>,
-    peer_status: Option<PeerStatus>,
-    connection: TcpStream,
-    bitfield: Vec<u8>,
-}
+    /// Queues peers, e.g. from a tracker response, to be dialed as connection slots free up
+    pub fn add_peers(&mut self, peers: impl IntoIterator<Item = Peer>) {
+        for peer in peers {
+            self.add_candidate(peer);
+        }
+    }
 
-impl PeerConnection {
-    fn new(peer: Peer) -> Result<Self> {
-        dbg!("Connectiong to peer: {:?}", &peer);
-        let connection = TcpStream::connect(format!("{}:{}", peer.ip, peer.port))?;
-        Ok(Self {
-            peer,
-            connection,
-            am_status: None,
-            peer_status: None,
-            bitfield: Vec::new(),
-        })
+    /// Keeps `metrics`, which may be shared with other torrents, up to date with ours
+    pub fn report_metrics(&mut self, metrics: SharedMetrics) -> Result<()> {
+        self.metrics = Some((metrics, hex::encode(self.info_hash()?)));
+        Ok(())
     }
 
-    fn handshake(&mut self, torrent: &TorrentFile) -> Result<()> {
-        let info_hash = get_info_hash(&torrent.info)?;
-        let mut concatenated_bytes = Vec::new();
-        concatenated_bytes
-            .write_all(&19_u8.to_be_bytes())
-            .expect("Failed to write number of bytes");
-        concatenated_bytes.extend_from_slice("BitTorrent protocol00000000".as_bytes());
-        concatenated_bytes.extend_from_slice(&info_hash);
-        self.connection.write_all(&concatenated_bytes)?;
-        let mut len = [0; 1];
-        self.connection.read_exact(&mut len)?;
-        let total_length = len[0] + 8 + 20 + 20;
-        let mut response = vec![0; total_length as usize];
-        self.connection.read_exact(&mut response)?;
-        if &response[0..19] != "BitTorrent protocol".as_bytes() {
-            return Err(anyhow!("Invalid protocol"));
-        }
-        if &response[27..47] != info_hash.as_slice() {
+    /// Every connected peer as it is at the moment
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.connections.iter().map(PeerConnection::info).collect()
+    }
+
+    /// The connected peers, kept up to date while the manager runs
+    pub fn watch_peers(&self) -> watch::Receiver<Vec<PeerInfo>> {
+        self.peer_list.subscribe()
+    }
+
+    /// How far the download got and how fast it's going. Rates and the average count
+    /// since the manager was created
+    pub fn progress(&self) -> DownloadProgress {
+        let rates = self
+            .connections
+            .iter()
+            .fold((0, 0), |(down, up), connection| {
+                (
+                    down + connection.stats.download_rate.per_second(),
+                    up + connection.stats.upload_rate.per_second(),
+                )
+            });
+        let downloaded = self.closed_downloaded
+            + self
+                .connections
+                .iter()
+                .map(|connection| connection.stats.downloaded)
+                .sum::<u64>();
+        DownloadProgress::of(&self.download, rates, downloaded, self.created_at.elapsed())
+    }
+
+    /// `progress`, kept up to date while the manager runs
+    pub fn watch_progress(&self) -> watch::Receiver<DownloadProgress> {
+        self.progress.subscribe()
+    }
+
+    pub fn set_content_dir(&mut self, content_dir: PathBuf) {
+        self.content_dir = content_dir;
+        self.reopen_storage();
+    }
+
+    /// Moves the files from the content directory to `completed_dir` once every wanted
+    /// piece is on disk, and seeds from there
+    pub fn set_completed_dir(&mut self, completed_dir: PathBuf) {
+        self.completed_dir = Some(completed_dir);
+    }
+
+    /// Reads and writes through memory mapped files rather than a syscall per block
+    pub fn set_file_access(&mut self, access: FileAccess) {
+        self.file_access = access;
+        self.reopen_storage();
+    }
+
+    /// Keeps the content in `storage` rather than in files under the content directory,
+    /// until either is changed again. Meant for before the download starts, like the
+    /// other storage settings: writes still in flight are lost
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        self.disk = DiskWriter::new(storage);
+        self.cache.clear();
+    }
+
+    /// How many bytes of written and uploaded pieces are kept in memory, 0 for none
+    pub fn set_cache_size(&mut self, bytes: usize) {
+        self.cache.set_budget(bytes);
+    }
+
+    fn reopen_storage(&mut self) {
+        let storage = FileStorage::with_access(self.torrent, &self.content_dir, self.file_access);
+        self.set_storage(Arc::new(storage));
+    }
+
+    /// Keeps what was downloaded in `path` from now on, picking up from it first if it
+    /// exists. Returns how many pieces were restored
+    pub fn set_resume_file(&mut self, path: PathBuf) -> Result<usize> {
+        let restored = if path.exists() {
+            ResumeData::load(&path)?.restore(
+                self.torrent,
+                &mut self.download,
+                &self.content_dir,
+                self.disk.storage().as_ref(),
+            )?
+        } else {
+            0
+        };
+        self.resume_file = Some(path);
+        Ok(restored)
+    }
+
+    /// Hash-checks the files already in the content directory and marks the pieces that
+    /// match as had, for when there's no resume data. Best done before contacting the swarm
+    pub async fn recheck(
+        &mut self,
+        workers: usize,
+        progress: impl FnMut(RecheckProgress),
+    ) -> Result<usize> {
+        let storage = self.disk.storage().clone();
+        let valid = recheck(self.torrent, storage, workers, progress).await?;
+        for piece in valid.pieces() {
+            self.download.pieces[piece].status = PieceStatus::WrittenToDisk;
+            self.download.mark_have(piece);
+        }
+        Ok(valid.count())
+    }
+
+    /// Rewrites the resume file every `RESUME_INTERVAL`, or right away when `now`
+    async fn save_resume(&mut self, now: bool) -> Result<()> {
+        let Some(path) = self.resume_file.clone() else {
+            return Ok(());
+        };
+        let due = self
+            .last_resume_save
+            .is_none_or(|last| last.elapsed() >= RESUME_INTERVAL);
+        if !due && !now {
+            return Ok(());
+        }
+        self.last_resume_save = Some(Instant::now());
+        // The saved block maps have to point at data that is on disk
+        let snapshot = Snapshot::take(self.torrent, &self.download, &self.content_dir)?;
+        let storage = self.disk.storage().clone();
+        spawn_blocking(move || snapshot.store(storage.as_ref())?.save(&path))
+            .await
+            .unwrap_or_else(|error| Err(anyhow!("Resume saver failed: {}", error)))
+    }
+
+    fn publish_metrics(&mut self) {
+        if self
+            .last_metrics
+            .is_some_and(|last| last.elapsed() < METRICS_INTERVAL)
+        {
+            return;
+        }
+        self.last_metrics = Some(Instant::now());
+        if self.peer_list.receiver_count() > 0 {
+            self.peer_list.send_replace(self.peers());
+        }
+        if self.progress.receiver_count() > 0 {
+            self.progress.send_replace(self.progress());
+        }
+        let Some((metrics, torrent)) = &self.metrics else {
+            return;
+        };
+        let count = |predicate: fn(&PeerConnection) -> bool| {
+            self.connections
+                .iter()
+                .filter(|connection| predicate(connection))
+                .count() as u64
+        };
+        let snapshot = TorrentMetrics {
+            connected_peers: self.connections.len() as u64,
+            uploaded: self.closed_uploaded
+                + self
+                    .connections
+                    .iter()
+                    .map(|connection| connection.stats.uploaded)
+                    .sum::<u64>(),
+            downloaded: self.closed_downloaded
+                + self
+                    .connections
+                    .iter()
+                    .map(|connection| connection.stats.downloaded)
+                    .sum::<u64>(),
+            choked_by_us: count(|connection| connection.state.am_choking),
+            choking_us: count(|connection| connection.state.peer_choking),
+            interested_in_us: count(|connection| connection.state.peer_interested),
+            ..Default::default()
+        };
+        metrics.update(torrent, snapshot);
+    }
+
+    /// Events from now on, a subscriber lagging more than `EVENT_CAPACITY` events behind
+    /// misses the oldest
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: PeerEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    /// Remembers why we're done with `peer` and tells subscribers
+    fn record_disconnect(&mut self, peer: Peer, reason: DisconnectReason) {
+        self.emit(PeerEvent::Disconnected {
+            peer: peer.clone(),
+            reason,
+        });
+        self.disconnected.insert(peer, reason);
+    }
+
+    /// Creates the files of the torrent that aren't skipped at their full length, reserving
+    /// their space too when `allocation` is full
+    pub fn allocate_files(&self, allocation: Allocation) -> Result<()> {
+        for (index, priority) in self.download.file_priorities().iter().enumerate() {
+            if *priority != FilePriority::Skip {
+                self.disk.storage().allocate(index, allocation)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes the files we want still need on disk, which is their length minus whatever
+    /// an earlier run already wrote. Padding files take none
+    pub fn required_space(&self) -> u64 {
+        let paths = content_paths(self.torrent, &self.content_dir);
+        file_spans(self.torrent)
+            .iter()
+            .zip(paths)
+            .zip(self.download.file_priorities())
+            .filter(|((span, _), priority)| **priority != FilePriority::Skip && !span.is_padding())
+            .map(|((span, path), _)| span.length.saturating_sub(allocated_bytes(&path)))
+            .sum()
+    }
+
+    /// Fails when the content directory's filesystem is too full for `required_space`,
+    /// rather than halfway through the download. Passes when free space can't be told
+    pub fn check_free_space(&self) -> Result<()> {
+        let Ok(available) = available_space(&self.content_dir) else {
+            return Ok(());
+        };
+        let required = self.required_space();
+        if required > available {
             return Err(anyhow!(
-                "Invalid info hash {} {}",
-                hex::encode(&response[27..47]),
-                hex::encode(info_hash.as_slice())
+                "{} needs {} more bytes but only {} are free",
+                self.content_dir.display(),
+                required,
+                available
             ));
         }
-        self.am_status = Some(PeerStatus::Chocked);
         Ok(())
     }
 
-    fn bitfield(&mut self, torrent: &TorrentFile, download: &Download) -> Result<()> {
-        let message = Message::bitfield(&torrent, &download);
-        self.connection.write_all(&message)?;
+    /// How much the file at `index` is wanted, pieces of skipped files aren't requested
+    pub fn set_file_priority(&mut self, index: usize, priority: FilePriority) -> Result<()> {
+        self.download.set_file_priority(index, priority)
+    }
+
+    /// Fetches `piece` before anything else, to have it within `milliseconds` if the swarm
+    /// allows, e.g. for a player about to play it
+    pub fn set_piece_deadline(&mut self, piece: usize, milliseconds: u64) {
+        self.download.set_piece_deadline(piece, milliseconds);
+    }
+
+    pub fn reset_piece_deadline(&mut self, piece: usize) {
+        self.download.reset_piece_deadline(piece);
+    }
+
+    /// Bytes still missing of the files we want
+    pub fn left(&self) -> u64 {
+        self.download.left()
+    }
+
+    /// Whether pieces are fetched rarest first or in order for streaming, can change while
+    /// downloading
+    pub fn set_pick_order(&mut self, order: PickOrder) {
+        self.download.set_order(order);
+    }
+
+    /// Dials and unchokes peers on our subnets, or in `networks`, before others
+    pub fn prefer_lan(&mut self, networks: IpFilter) {
+        self.lan = LanPreference::new(networks);
+        self.lan.detect_local_ips();
+    }
+
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Makes this torrent draw from `slots` shared with others instead of its own
+    pub fn share_global_slots(&mut self, slots: Arc<Semaphore>) {
+        self.global_slots = slots;
+    }
+
+    /// Makes this torrent's dials queue with those of others for half-open connections
+    pub fn share_half_open_limit(&mut self, half_open: Arc<Semaphore>) {
+        self.half_open = half_open;
+    }
+
+    /// Dials started per second, 0 for no limit
+    pub fn set_dial_rate(&mut self, dials_per_second: u64) {
+        self.dial_rate.lock().unwrap().set_rate(dials_per_second);
+    }
+
+    pub fn share_dial_rate(&mut self, dial_rate: SharedBucket) {
+        self.dial_rate = dial_rate;
+    }
+
+    pub fn set_concurrent_dials(&mut self, concurrent_dials: usize) {
+        self.concurrent_dials = concurrent_dials.max(1);
+    }
+
+    pub fn set_bind(&mut self, bind: Option<Bind>) {
+        self.bind = bind;
+    }
+
+    /// Tuning of the sockets dialed from now on, and of the one listened on by `listen`
+    pub fn set_socket_options(&mut self, socket_options: SocketOptions) {
+        self.socket_options = socket_options;
+    }
+
+    pub fn set_encryption(&mut self, encryption: EncryptionPolicy) {
+        self.encryption = encryption;
+    }
+
+    /// Overrides the torrent's encryption policy for connections with `ip`
+    pub fn set_peer_encryption(&mut self, ip: IpAddr, encryption: EncryptionPolicy) {
+        self.peer_encryption.insert(ip, encryption);
+    }
+
+    pub fn encryption_for(&self, peer: &Peer) -> EncryptionPolicy {
+        self.peer_encryption
+            .get(&peer.addr.ip())
+            .copied()
+            .unwrap_or(self.encryption)
+    }
+
+    fn dial_options(&self) -> Result<DialOptions> {
+        Ok(DialOptions {
+            info_hash: get_info_hash(&self.torrent.info)?,
+            peer_id: self.peer_id.clone(),
+            private: self.is_private(),
+            dht: self.dht.is_some(),
+            seed: self.download.have.is_complete(),
+            encryption: self.encryption,
+            handshake_timeout: self.handshake_timeout,
+            half_open: self.half_open.clone(),
+            bind: self.bind.clone(),
+            socket: self.socket_options,
+            v2: self.download.is_v2(),
+            listen_port: self.announced_port(),
+        })
+    }
+
+    /// Starts dialing candidates in the background until the connection limit is reached,
+    /// with at most `concurrent_dials` attempts in flight
+    fn dial_candidates(&mut self) -> Result<()> {
+        while self.dialing.len() < self.concurrent_dials
+            && self.connections.len() + self.dialing.len() < self.max_connections
+            && !self.candidates.is_empty()
+        {
+            let Ok(slot) = self.global_slots.clone().try_acquire_owned() else {
+                break;
+            };
+            let now = Instant::now();
+            let is_due = |candidate: &Peer| {
+                self.retries
+                    .get(&candidate.addr)
+                    .is_none_or(|retry| retry.is_due(now))
+            };
+            let due = self
+                .candidates
+                .iter()
+                .position(|candidate| is_due(candidate) && self.lan.is_local(&candidate.addr))
+                .or_else(|| self.candidates.iter().position(is_due));
+            let Some(due) = due else {
+                break;
+            };
+            // Candidates left over are dialed on a later round, once tokens are back
+            if !self.dial_rate.lock().unwrap().try_consume(1) {
+                break;
+            }
+            let peer = self.candidates.remove(due);
+            let options = DialOptions {
+                encryption: self.encryption_for(&peer),
+                ..self.dial_options()?
+            };
+            let fallback = self.fallbacks.remove(&peer);
+            let dialed = self.dialed.0.clone();
+            self.dialing.insert(peer.clone());
+            self.tasks.push(tokio::spawn(async move {
+                let connection = PeerConnection::dial(peer.clone(), fallback, options)
+                    .await
+                    .map(|mut connection| {
+                        connection.global_slot = Some(slot);
+                        connection
+                    });
+                // The manager is gone if sending fails, nothing left to do then
+                let _ = dialed.send((peer, connection));
+            }));
+        }
+        Ok(())
+    }
+
+    /// With every slot taken and candidates waiting, drops up to `MAX_REPLACEMENTS` of the
+    /// lowest scoring peers every `EVICTION_INTERVAL`, if an untried candidate promises more.
+    /// Peers that just connected, and the optimistic unchoke, get time to prove themselves
+    fn evict_least_useful(&mut self) {
+        let due = self
+            .last_eviction
+            .is_none_or(|last| last.elapsed() >= EVICTION_INTERVAL);
+        if !self.is_full() || !due || self.candidates.is_empty() {
+            return;
+        }
+        self.last_eviction = Some(Instant::now());
+        let availability = self.availability();
+        let mut scored: Vec<(usize, f64)> = self
+            .connections
+            .iter()
+            .enumerate()
+            .filter(|(_, connection)| {
+                connection.connected_at.elapsed() >= NEW_PEER_AGE
+                    && self.optimistic.as_ref() != Some(&connection.peer)
+            })
+            .map(|(index, connection)| (index, self.score(connection, &availability)))
+            .filter(|(_, score)| *score < UNTRIED_PEER_SCORE)
+            .collect();
+        scored.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+        let mut evicted: Vec<usize> = scored
+            .into_iter()
+            .take(MAX_REPLACEMENTS.min(self.candidates.len()))
+            .map(|(index, _)| index)
+            .collect();
+        evicted.sort_unstable_by(|a, b| b.cmp(a));
+        for index in evicted {
+            self.disconnect(index, DisconnectReason::Evicted);
+        }
+    }
+
+    fn score(&self, connection: &PeerConnection, availability: &[usize]) -> f64 {
+        PeerScore {
+            rate: connection.stats.download_rate.per_second()
+                + connection.stats.upload_rate.per_second(),
+            pieces_contributed: connection.stats.pieces_contributed,
+            hash_failures: connection.stats.hash_failures,
+            snubbed: connection.is_snubbed(),
+            rarity: rarity(&connection.bitfield, &self.download.have, availability),
+        }
+        .value()
+    }
+
+    /// Connected peers holding each piece
+    fn availability(&self) -> Vec<usize> {
+        self.download.availability().to_vec()
+    }
+
+    /// Queues the blocks of the rarest piece the peer at `index` has for us, once the
+    /// peer has nothing queued any more. Pieces other peers are fetching are left to them,
+    /// except in endgame when the peer gets every outstanding block it can serve
+    async fn pick_piece(&mut self, index: usize) -> Result<()> {
+        let connection = &self.connections[index];
+        if self.super_seed.is_some()
+            || self.download.is_finished()
+            || !connection.state.am_interested
+            || !connection.queued_requests.is_empty()
+        {
+            return Ok(());
+        }
+        let outstanding = self.outstanding_requests();
+        let fetching: HashSet<u32> = outstanding.iter().map(|request| request.index).collect();
+        let picked = self.download.pick(&connection.bitfield, |piece| {
+            fetching.contains(&piece) || !connection.can_request(piece)
+        });
+        if let Some(piece) = picked {
+            let requests = self.download.missing_requests(piece);
+            let leaf_hashes = self.download.leaf_hash_request(piece as usize);
+            let connection = &mut self.connections[index];
+            connection.queued_requests.extend(requests);
+            if let Some(request) = leaf_hashes.filter(|_| connection.supports(PeerCapabilities::V2))
+            {
+                connection
+                    .send(Message::HashRequest(request).encode())
+                    .await?;
+            }
+            self.endgame = false;
+            return Ok(());
+        }
+        self.endgame = outstanding.len() <= ENDGAME_BLOCKS
+            && (0..self.download.pieces.len()).all(|piece| {
+                self.download.have.has(piece)
+                    || fetching.contains(&(piece as u32))
+                    || self.download.piece_priority(piece) == FilePriority::Skip
+            });
+        if self.endgame {
+            let duplicates: Vec<BlockRequest> = outstanding
+                .into_iter()
+                .filter(|request| {
+                    connection.bitfield.has(request.index as usize)
+                        && connection.can_request(request.index)
+                        && self.download.in_flight(request).is_none_or(|in_flight| {
+                            !in_flight.peers.contains(&connection.peer.addr)
+                        })
+                })
+                .collect();
+            self.connections[index].queued_requests.extend(duplicates);
+        }
+        Ok(())
+    }
+
+    /// Blocks queued or requested anywhere and not received yet
+    fn outstanding_requests(&self) -> HashSet<BlockRequest> {
+        let connections = self.connections.iter().flat_map(|connection| {
+            let queued = connection.queued_requests.iter();
+            let pending = connection
+                .pending_requests
+                .iter()
+                .map(|pending| &pending.request);
+            queued.chain(pending)
+        });
+        let web_seeds = self
+            .web_seeds
+            .iter()
+            .flat_map(|seed| seed.queued_requests.iter());
+        let orphaned = self.orphaned_requests.iter().map(|(request, _)| request);
+        connections
+            .chain(web_seeds)
+            .chain(orphaned)
+            .copied()
+            .collect()
+    }
+
+    /// With no slot left for waiting candidates, drops every peer that exchanged no block
+    /// for `idle_timeout`, up to one per candidate
+    fn prune_idle(&mut self) {
+        // Paused peers are idle on purpose
+        if !self.is_full() || self.candidates.is_empty() || self.download.is_paused() {
+            return;
+        }
+        let idle_timeout = self.idle_timeout;
+        let mut idle: Vec<usize> = self
+            .connections
+            .iter()
+            .enumerate()
+            .filter(|(_, connection)| {
+                let last_transfer = connection
+                    .stats
+                    .last_transfer
+                    .unwrap_or(connection.connected_at);
+                last_transfer.elapsed() >= idle_timeout
+            })
+            .map(|(index, _)| index)
+            .take(self.candidates.len())
+            .collect();
+        idle.reverse();
+        for index in idle {
+            self.disconnect(index, DisconnectReason::Idle);
+        }
+    }
+
+    /// Whether this torrent, or the torrents sharing its global slots, can't connect more peers
+    fn is_full(&self) -> bool {
+        self.connections.len() >= self.max_connections || self.global_slots.available_permits() == 0
+    }
+
+    /// Takes in the connections whose dial finished since the last call
+    async fn accept_dialed(&mut self) -> Result<()> {
+        while let Ok((peer, dialed)) = self.dialed.1.try_recv() {
+            let outbound = self.dialing.remove(&peer);
+            let connection = match dialed {
+                Ok(connection) => connection,
+                Err(error) => {
+                    debug!("Could not connect to {:?}: {:?}", &peer, &error);
+                    let reason = DisconnectReason::of(&error);
+                    if let Some((metrics, torrent)) = &self.metrics {
+                        metrics.record_dial_failure(torrent, reason);
+                    }
+                    self.emit(PeerEvent::HandshakeFailed {
+                        peer: peer.clone(),
+                        reason,
+                    });
+                    if outbound && is_unreachable(&error) && self.holepunch(&peer, reason).await {
+                        continue;
+                    }
+                    // Peers that are down or overloaded may take us later, not ones we disagree with
+                    let transient = matches!(
+                        reason,
+                        DisconnectReason::ConnectionClosed | DisconnectReason::HandshakeTimeout
+                    );
+                    let retry = self.retries.entry(peer.addr).or_default();
+                    if outbound && transient && retry.failed(Instant::now(), error.to_string()) {
+                        self.candidates.push(peer);
+                    } else {
+                        self.disconnected.insert(peer, reason);
+                    }
+                    continue;
+                }
+            };
+            self.retries.remove(&peer.addr);
+            if connection.remote_peer_id.as_ref().map(|id| &id[..]) == Some(self.peer_id.as_bytes())
+            {
+                self.refuse_self(peer, outbound);
+                continue;
+            }
+            // Inbound peers negotiated under the torrent policy before we knew who they were
+            if self.encryption_for(&peer) == EncryptionPolicy::Require && !connection.is_encrypted()
+            {
+                self.record_disconnect(peer, DisconnectReason::EncryptionRequired);
+                continue;
+            }
+            self.add_connection(connection).await?;
+        }
         Ok(())
     }
 
-    fn interested(&mut self) -> Result<()> {
-        let message = Message::interested();
-        self.connection.write_all(&message)?;
+    /// Only outbound addresses are worth remembering, the ports of inbound ones are ephemeral
+    fn refuse_self(&mut self, peer: Peer, outbound: bool) {
+        if outbound {
+            self.self_addresses.insert(peer.addr);
+        }
+        self.candidates
+            .retain(|candidate| candidate.addr != peer.addr);
+        self.record_disconnect(peer, DisconnectReason::SelfConnection);
+    }
+
+    /// Whether dialing `address` would reach ourselves, going by what peers told us
+    /// of our external address
+    fn is_self(&self, address: &SocketAddr) -> bool {
+        self.self_addresses.contains(address)
+            || (self.listen_port == Some(address.port())
+                && self.external_ips.contains(&address.ip()))
+    }
+
+    /// Accepts inbound peers on `port` in the background, they join once their handshake is done
+    pub async fn listen(&mut self, port: u16) -> Result<SocketAddr> {
+        let listener = Listener::bind(port, self.socket_options).await?;
+        self.attach(&listener)?;
+        Ok(listener.local_addr())
+    }
+
+    /// Takes the inbound peers of `listener` that handshake for this torrent, the listener
+    /// may be shared with other torrents and stops once none of them uses it any more
+    pub fn attach(&mut self, listener: &Listener) -> Result<()> {
+        let inbound = InboundTorrent {
+            options: self.dial_options()?,
+            accepted: self.dialed.0.clone(),
+            global_slots: self.global_slots.clone(),
+            bans: self.bans.clone(),
+            ip_filter: self.ip_filter.clone(),
+            filtered: self.filtered.clone(),
+        };
+        listener
+            .torrents
+            .lock()
+            .unwrap()
+            .insert(inbound.options.info_hash.clone(), inbound);
+        self.listen_port = Some(listener.local_addr().port());
+        self.listener = Some(listener.clone());
         Ok(())
     }
 
-    fn download_block(&mut self, index: u32) -> Result<()> {
-        let mut concatenated_bytes = Vec::new();
-        concatenated_bytes
-            .write_all(&13_u32.to_be_bytes())
-            .expect("Failed to write number of bytes");
-        concatenated_bytes
-            .write_all(&6_u8.to_be_bytes())
-            .expect("Failed to write number of bytes");
-        concatenated_bytes
-            .write_all(&index.to_be_bytes())
-            .expect("Failed to write number of bytes");
-        concatenated_bytes
-            .write_all(&0_u32.to_be_bytes())
-            .expect("Failed to write number of bytes");
-        concatenated_bytes
-            .write_all(&16384_u32.to_be_bytes())
-            .expect("Failed to write number of bytes");
-        self.connection.write_all(&concatenated_bytes)?;
-
-        let mut response = vec![0; 16];
-        self.connection.read_exact(&mut response)?;
-
-        dbg!(&response);
+    /// Where inbound peers reach us, if attached to a listener
+    pub fn listen_address(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().map(Listener::local_addr)
+    }
+
+    pub fn torrent(&self) -> &'a TorrentFile {
+        self.torrent
+    }
+
+    /// The port trackers are told we accept peers on
+    pub fn announced_port(&self) -> u16 {
+        self.listen_port.unwrap_or(DEFAULT_PORT)
+    }
+
+    fn detach(&mut self) -> Result<()> {
+        if let Some(listener) = self.listener.take() {
+            let info_hash = get_info_hash(&self.torrent.info)?;
+            listener.torrents.lock().unwrap().remove(&info_hash);
+        }
+        self.listen_port = None;
+        Ok(())
+    }
+
+    /// The same peer may be reached through several addresses, or dial us while we dial it.
+    /// Of two connections in opposite directions both sides keep the one opened by whoever
+    /// has the lower peer id, otherwise the connection that was there first stays.
+    fn keep_over_duplicate(&mut self, connection: &PeerConnection) -> bool {
+        let Some(remote_peer_id) = connection.remote_peer_id else {
+            return true;
+        };
+        let Some(existing) = self
+            .connections
+            .iter()
+            .position(|existing| existing.remote_peer_id == Some(remote_peer_id))
+        else {
+            return true;
+        };
+        let we_are_lower = self.peer_id.as_bytes() < &remote_peer_id[..];
+        let keep_new = connection.outbound != self.connections[existing].outbound
+            && connection.outbound == we_are_lower;
+        if keep_new {
+            self.disconnect(existing, DisconnectReason::Duplicate);
+        }
+        keep_new
+    }
+
+    async fn add_connection(&mut self, mut connection: PeerConnection) -> Result<()> {
+        // Inbound peers can show up while we're already full
+        if self.connections.len() >= self.max_connections {
+            return Ok(());
+        }
+        if !self.keep_over_duplicate(&connection) {
+            self.record_disconnect(connection.peer, DisconnectReason::Duplicate);
+            return Ok(());
+        }
+        self.introducers.remove(&connection.peer.addr);
+        if self.lan.is_enabled() {
+            if let Some(local) = connection.connection.get_ref().local_addr() {
+                self.lan.add_local_ip(local.ip());
+            }
+        }
+        connection.upload_limiter = self.upload_limiter.clone();
+        connection.download_limiter = self.download_limiter.clone();
+        if let Some(caps) = self.peer_rate_caps.get(&connection.peer.addr.ip()) {
+            connection.set_rate_caps(*caps);
+        }
+        let number_of_pieces = self.download.pieces.len();
+        connection.bitfield = Bitfield::new(number_of_pieces);
+        connection.pipeline_depth = self.pipeline_depth;
+        connection.block_size = self.block_size;
+        let dht_port = self.dht.as_ref().map(|dht| dht.port);
+        // Super-seeds start out claiming to have nothing
+        let started = match self.super_seed {
+            Some(_) => {
+                connection
+                    .start(dht_port, &Bitfield::new(number_of_pieces))
+                    .await
+            }
+            None => connection.start(dht_port, &self.download.have).await,
+        };
+        let peer = connection.peer.clone();
+        self.connections.push(connection);
+        let index = self.connections.len() - 1;
+        match started {
+            Ok(()) => {
+                self.emit(PeerEvent::Connected { peer });
+                if let Err(error) = self.reveal_piece(index).await {
+                    self.disconnect(index, DisconnectReason::of(&error));
+                }
+                Ok(())
+            }
+            Err(error) => {
+                debug!("Could not start session with {:?}: {:?}", &peer, &error);
+                self.disconnect(index, DisconnectReason::of(&error));
+                Ok(())
+            }
+        }
+    }
+
+    /// Cancels requests that went unanswered for `REQUEST_TIMEOUT` and hands them to other peers
+    async fn expire_requests(&mut self) {
+        let mut failed = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            let expired: Vec<BlockRequest> = connection
+                .pending_requests
+                .iter()
+                .filter(|pending| pending.sent_at.elapsed() >= REQUEST_TIMEOUT)
+                .map(|pending| pending.request)
+                .collect();
+            for request in expired {
+                let cancelled = connection.cancel(&request).await;
+                self.download.mark_cancelled(&request, connection.peer.addr);
+                connection.timeouts += 1;
+                connection.request_window.shrink();
+                self.orphaned_requests
+                    .push_back((request, connection.peer.clone()));
+                // The rest of its requests get orphaned as it's dropped
+                if let Err(error) = cancelled {
+                    failed.push((index, DisconnectReason::of(&error)));
+                    break;
+                }
+            }
+        }
+        for (index, reason) in failed.into_iter().rev() {
+            self.disconnect(index, reason);
+        }
+        self.reassign_orphaned_requests().await
+    }
+
+    /// Gives each orphaned request to the peer with the shortest queue that has the piece,
+    /// preferring anyone but the peer it timed out at
+    async fn reassign_orphaned_requests(&mut self) {
+        for _ in 0..self.orphaned_requests.len() {
+            let Some((request, failed_at)) = self.orphaned_requests.pop_front() else {
+                break;
+            };
+            let target = self
+                .connections
+                .iter()
+                .enumerate()
+                .filter(|(_, connection)| {
+                    connection.bitfield.has(request.index as usize) && !connection.is_snubbed()
+                })
+                .min_by_key(|(_, connection)| {
+                    (
+                        connection.peer == failed_at,
+                        connection.queued_requests.len(),
+                    )
+                })
+                .map(|(index, _)| index);
+            match target {
+                Some(index) => {
+                    self.connections[index].queued_requests.push_back(request);
+                    if let Err(error) = self.fill_pipeline(index).await {
+                        self.disconnect(index, DisconnectReason::of(&error));
+                    }
+                }
+                None => self.orphaned_requests.push_back((request, failed_at)),
+            }
+        }
+    }
+
+    /// A round of tit-for-tat: ranks peers by what they gave us over the last interval, or by
+    /// what we gave them once we're seeding, and only keeps the best ones unchoked
+    pub async fn rechoke(&mut self) -> Result<()> {
+        let seeding = self.download.is_finished();
+        let candidates: Vec<ChokeCandidate> = self
+            .connections
+            .iter()
+            .enumerate()
+            .map(|(index, connection)| ChokeCandidate {
+                index,
+                transferred: if seeding {
+                    connection.stats.upload_rate.per_second()
+                } else {
+                    connection.stats.download_rate.per_second()
+                },
+                interested: connection.is_interested(),
+                upload_only: connection.is_upload_only(),
+                newly_connected: connection.connected_at.elapsed() < NEW_PEER_AGE,
+                snubbed: connection.is_snubbed(),
+                local: self.lan.is_local(&connection.peer.addr),
+            })
+            .collect();
+        // One of the slots is kept for the optimistic unchoke
+        let slots = self.upload_slots();
+        let mut unchoked = choose_unchoked(&candidates, slots.saturating_sub(1));
+        if slots > 0 {
+            let current = self
+                .optimistic
+                .as_ref()
+                .and_then(|peer| self.connections.iter().position(|c| &c.peer == peer))
+                .filter(|index| candidates[*index].interested && !unchoked.contains(index));
+            let due = self
+                .last_optimistic
+                .is_none_or(|last| last.elapsed() >= OPTIMISTIC_UNCHOKE_INTERVAL);
+            let optimistic = match current {
+                Some(index) if !due => Some(index),
+                _ => {
+                    self.last_optimistic = Some(Instant::now());
+                    choose_optimistic(&candidates, &unchoked, &mut rand::thread_rng())
+                }
+            };
+            self.optimistic = optimistic.map(|index| self.connections[index].peer.clone());
+            unchoked.extend(optimistic);
+        }
+        let mut failed = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            if let Err(error) = connection.set_choking(!unchoked.contains(&index)).await {
+                failed.push((index, DisconnectReason::of(&error)));
+            }
+        }
+        for (index, reason) in failed.into_iter().rev() {
+            self.disconnect(index, reason);
+        }
+        self.last_choke = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Sends the peer a have for the piece super-seeding gives it, if super-seeding
+    async fn reveal_piece(&mut self, index: usize) -> Result<()> {
+        let Some(super_seed) = &mut self.super_seed else {
+            return Ok(());
+        };
+        let connection = &mut self.connections[index];
+        if let Some(piece) =
+            super_seed.next_piece(&connection.peer, &connection.bitfield, &self.download)
+        {
+            self.download
+                .mark_revealed(piece as usize, connection.peer.addr);
+            connection.send(Message::have(piece)).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues every block of a piece for the peer at `index`, they get requested as the
+    /// peer's pipeline has room
+    pub async fn request_piece(&mut self, index: usize, piece: u32) -> Result<()> {
+        let requests = self.piece_requests(piece)?;
+        self.connections[index].queued_requests.extend(requests);
+        self.fill_pipeline(index).await
+    }
+
+    /// Same as `request_piece`, for the web seed at `index`
+    pub fn request_piece_from_web_seed(&mut self, index: usize, piece: u32) -> Result<()> {
+        let requests = self.piece_requests(piece)?;
+        self.web_seeds[index].queued_requests.extend(requests);
+        Ok(())
+    }
+
+    fn piece_requests(&self, piece: u32) -> Result<Vec<BlockRequest>> {
+        self.download.block_requests(piece)
+    }
+
+    /// Sends what the peer at `index` has queued as far as its pipeline allows, keeping
+    /// track of who each block was asked from
+    async fn fill_pipeline(&mut self, index: usize) -> Result<()> {
+        if self.is_disk_backlogged() || self.download.is_paused() {
+            return Ok(());
+        }
+        let connection = &mut self.connections[index];
+        for request in connection.fill_pipeline(self.endgame).await? {
+            self.download.mark_requested(&request, connection.peer.addr);
+        }
+        Ok(())
+    }
+
+    pub fn web_seeds(&self) -> &[WebSeed] {
+        &self.web_seeds
+    }
+
+    /// Fetches the next queued block of every web seed, handing it on like a piece message.
+    /// Seeds that failed too often are skipped.
+    async fn poll_web_seeds(&mut self) -> Result<()> {
+        if self.download.is_paused() {
+            return Ok(());
+        }
+        for index in 0..self.web_seeds.len() {
+            let seed = &mut self.web_seeds[index];
+            if !seed.is_usable() {
+                continue;
+            }
+            let Some(request) = seed.queued_requests.pop_front() else {
+                continue;
+            };
+            match seed.fetch(self.torrent, request).await {
+                Ok(block) => self.receive_block(None, block).await?,
+                Err(error) => {
+                    debug!("Web seed failed: {:?}", error);
+                    seed.failures += 1;
+                    seed.queued_requests.push_front(request);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn has_web_seed_requests(&self) -> bool {
+        self.web_seeds
+            .iter()
+            .any(|seed| seed.is_usable() && !seed.queued_requests.is_empty())
+    }
+
+    pub fn candidates(&self) -> &[Peer] {
+        &self.candidates
+    }
+
+    fn is_private(&self) -> bool {
+        self.torrent.info.private == Some(1)
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        loop {
+            self.tasks.retain(|task| !task.is_finished());
+            self.follow_pause_switch().await?;
+            self.prune_idle();
+            self.evict_least_useful();
+            if !self.download.is_paused() {
+                self.dial_candidates()?;
+                self.search_dht()?;
+            }
+            self.accept_dialed().await?;
+            self.accept_dht_lookups()?;
+            let dialing = !self.dialing.is_empty() || !self.candidates.is_empty();
+            // Paused torrents wait to be resumed, whether anyone is connected or not
+            let waiting = dialing
+                || self.download.is_paused()
+                || self.searching_dht
+                || self.listen_port.is_some()
+                || self.has_web_seed_requests();
+            if self.connections.is_empty() && !waiting {
+                break;
+            }
+            let mut failed = Vec::new();
+            for (index, frame) in self.read_ready().await {
+                let handled = match self.connections[index].take_message(frame).await {
+                    Ok(Some(message)) => self.handle_message(index, message).await,
+                    Ok(None) => Ok(()),
+                    Err(error) => Err(error),
+                };
+                if let Err(error) = handled {
+                    debug!("Dropping {:?}: {:?}", &self.connections[index].peer, &error);
+                    failed.push((index, DisconnectReason::of(&error)));
+                }
+            }
+            for (index, reason) in failed.into_iter().rev() {
+                self.disconnect(index, reason);
+            }
+            self.disconnect_broken();
+            self.answer_reads().await;
+            self.poll_web_seeds().await?;
+            self.settle_checked_pieces().await?;
+            let completed = self.disk.completed();
+            self.settle_writes(completed);
+            self.finished
+                .store(self.download.is_finished(), Ordering::Relaxed);
+            self.expire_requests().await;
+            let due = self
+                .last_choke
+                .is_none_or(|last| last.elapsed() >= CHOKE_INTERVAL);
+            if due && !self.download.is_paused() {
+                self.rechoke().await?;
+            }
+            self.exchange_peers().await?;
+            self.publish_metrics();
+            if let Err(error) = self.save_resume(false).await {
+                warn!("Could not save resume data: {:?}", error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits up to `READ_TIMEOUT` for a frame from any peer, then picks up the frames every
+    /// other peer has ready without waiting on them, in connection order
+    async fn read_ready(&mut self) -> Vec<(usize, Option<Result<Message>>)> {
+        if self.connections.is_empty() {
+            // Nothing to read from, don't spin while dials are in flight
+            sleep(READ_TIMEOUT).await;
+            return Vec::new();
+        }
+        let next = self
+            .connections
+            .iter_mut()
+            .map(|connection| connection.connection.next());
+        // Frames stay buffered in the codec of the streams that lost the race
+        let Ok((frame, first, _)) = timeout(READ_TIMEOUT, select_all(next)).await else {
+            return Vec::new();
+        };
+        let mut first_frame = Some(frame);
+        let mut ready = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            let frame = if index == first {
+                first_frame.take()
+            } else {
+                connection.connection.next().now_or_never()
+            };
+            ready.extend(frame.map(|frame| (index, frame)));
+        }
+        ready
+    }
+
+    /// Stops downloading and uploading without dropping anyone: requests are cancelled,
+    /// every peer choked and told we're not interested, and the tracker hears we stopped.
+    /// Pieces in memory and on disk stay as they are for `resume`
+    pub async fn pause(&mut self) -> Result<()> {
+        if self.download.is_paused() {
+            return Ok(());
+        }
+        self.download.pause();
+        self.pause_switch.store(true, Ordering::Relaxed);
+        self.endgame = false;
+        self.orphaned_requests.clear();
+        for seed in &mut self.web_seeds {
+            seed.queued_requests.clear();
+        }
+        let mut failed = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            for pending in &connection.pending_requests {
+                self.download
+                    .mark_cancelled(&pending.request, connection.peer.addr);
+            }
+            if let Err(error) = connection.pause().await {
+                failed.push((index, DisconnectReason::of(&error)));
+            }
+        }
+        for (index, reason) in failed.into_iter().rev() {
+            self.disconnect(index, reason);
+        }
+        if let Err(error) = self.announce_event(Event::Stopped).await {
+            warn!("Could not announce pausing: {:?}", error);
+        }
+        if let Err(error) = self.save_resume(true).await {
+            warn!("Could not save resume data: {:?}", error);
+        }
+        Ok(())
+    }
+
+    /// Picks up after `pause` with the peers still connected: the tracker hears we started
+    /// again, interest is shown anew and chokes are worked out on the next round
+    pub async fn resume(&mut self) -> Result<()> {
+        if !self.download.is_paused() {
+            return Ok(());
+        }
+        self.download.resume();
+        self.pause_switch.store(false, Ordering::Relaxed);
+        match self.announce_event(Event::Started).await {
+            Ok(response) => {
+                self.add_peers(response.peers);
+                self.add_peers(response.peers6);
+            }
+            Err(error) => {
+                warn!("Could not announce resuming: {:?}", error);
+            }
+        }
+        let mut failed = Vec::new();
+        for index in 0..self.connections.len() {
+            if let Err(error) = self.resume_connection(index).await {
+                failed.push((index, DisconnectReason::of(&error)));
+            }
+        }
+        for (index, reason) in failed.into_iter().rev() {
+            self.disconnect(index, reason);
+        }
+        self.last_choke = None;
+        Ok(())
+    }
+
+    async fn resume_connection(&mut self, index: usize) -> Result<()> {
+        self.update_interest(index).await?;
+        self.pick_piece(index).await?;
+        self.fill_pipeline(index).await
+    }
+
+    /// Tells the peer at `index` we're interested once it has a piece we're missing
+    async fn update_interest(&mut self, index: usize) -> Result<()> {
+        let connection = &mut self.connections[index];
+        if !self.download.is_paused() && self.download.is_interested_in(&connection.bitfield) {
+            connection.interested().await?;
+        }
         Ok(())
     }
+
+    pub fn is_paused(&self) -> bool {
+        self.download.is_paused()
+    }
+
+    /// Set to true to pause the running torrent, to false to resume it
+    pub fn pause_switch(&self) -> Arc<AtomicBool> {
+        self.pause_switch.clone()
+    }
+
+    /// What the queue of a client needs to start and stop this torrent
+    pub fn queued(&self) -> QueuedTorrent {
+        QueuedTorrent {
+            finished: self.finished.clone(),
+            pause: self.pause_switch.clone(),
+        }
+    }
+
+    async fn follow_pause_switch(&mut self) -> Result<()> {
+        match (self.pause_switch.load(Ordering::Relaxed), self.is_paused()) {
+            (true, false) => self.pause().await,
+            (false, true) => self.resume().await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn announce_event(&self, event: Event) -> Result<TrackerResponse> {
+        announce(
+            self.torrent,
+            &self.peer_id,
+            Some(event),
+            self.bind.as_ref(),
+            self.announced_port(),
+            self.download.left(),
+        )
+        .await
+    }
+
+    fn info_hash(&self) -> Result<[u8; 20]> {
+        get_info_hash(&self.torrent.info)?
+            .try_into()
+            .map_err(|_| anyhow!("Info hash isn't 20 bytes long"))
+    }
+
+    /// Starts a background `get_peers` lookup every `LOOKUP_INTERVAL`, never for private torrents
+    fn search_dht(&mut self) -> Result<()> {
+        let due = self
+            .last_dht_lookup
+            .is_none_or(|last| last.elapsed() >= LOOKUP_INTERVAL);
+        let Some(dht) = &self.dht else {
+            return Ok(());
+        };
+        if self.is_private() || self.searching_dht || !due {
+            return Ok(());
+        }
+        let info_hash = self.info_hash()?;
+        let node_id = dht.node_id;
+        let known = dht.routing_table.nodes().to_vec();
+        let lookups = self.dht_lookups.0.clone();
+        self.searching_dht = true;
+        self.last_dht_lookup = Some(Instant::now());
+        self.tasks.push(tokio::spawn(async move {
+            let lookup = get_peers(node_id, info_hash, known).await;
+            let _ = lookups.send(lookup.unwrap_or_default());
+        }));
+        Ok(())
+    }
+
+    /// Merges finished lookups into the candidates and routing table, then announces
+    /// ourselves to the nodes that answered if we take inbound connections
+    fn accept_dht_lookups(&mut self) -> Result<()> {
+        while let Ok(lookup) = self.dht_lookups.1.try_recv() {
+            self.searching_dht = false;
+            let Some(dht) = &mut self.dht else {
+                continue;
+            };
+            for node in &lookup.nodes {
+                dht.routing_table.add_node(*node);
+            }
+            let node_id = dht.node_id;
+            self.add_peers(lookup.peers);
+            // Announcing only makes sense when peers can reach us
+            if let Some(port) = self.listen_port {
+                let info_hash = self.info_hash()?;
+                self.tasks.push(tokio::spawn(async move {
+                    let _ = announce_peer(node_id, info_hash, port, &lookup.tokens).await;
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// Tears everything down: stops dialing and listening, tells the tracker we're leaving,
+    /// closes every connection and waits for the background tasks to end.
+    /// Verified pieces still queued are written and the content synced before the resume
+    /// data is saved for the last time.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.candidates.clear();
+        let port = self.announced_port();
+        self.detach()?;
+        for task in &self.tasks {
+            task.abort();
+        }
+        for task in self.tasks.drain(..) {
+            // Aborted tasks report a cancellation, which is what we asked for
+            let _ = task.await;
+        }
+        self.dialing.clear();
+        while self.dialed.1.try_recv().is_ok() {}
+        // A paused torrent told the tracker already
+        if !self.download.is_paused() {
+            let stopped = announce(
+                self.torrent,
+                &self.peer_id,
+                Some(Event::Stopped),
+                self.bind.as_ref(),
+                port,
+                self.download.left(),
+            );
+            if let Err(error) = stopped.await {
+                warn!("Could not announce stopping: {:?}", error);
+            }
+        }
+        for mut connection in self.connections.drain(..) {
+            // Flushes what is still buffered and shuts the sending side down
+            let _ = SinkExt::<Bytes>::close(&mut connection.connection).await;
+        }
+        self.flush_writes().await;
+        if let Err(error) = self.disk.sync().await {
+            warn!("Could not flush the content to disk: {:?}", error);
+        }
+        self.save_resume(true).await
+    }
+
+    fn disconnect_broken(&mut self) {
+        for (peer, reason) in std::mem::take(&mut self.broken) {
+            if let Some(index) = self.connections.iter().position(|c| c.peer == peer) {
+                self.disconnect(index, reason);
+            }
+        }
+    }
+
+    fn disconnect(&mut self, index: usize, reason: DisconnectReason) {
+        let connection = self.connections.remove(index);
+        self.download.remove_available(&connection.bitfield);
+        self.closed_uploaded += connection.stats.uploaded;
+        self.closed_downloaded += connection.stats.downloaded;
+        if let Some(super_seed) = &mut self.super_seed {
+            super_seed.remove_peer(&connection.peer);
+        }
+        for pending in connection.pending_requests {
+            self.download
+                .mark_cancelled(&pending.request, connection.peer.addr);
+            self.orphaned_requests
+                .push_back((pending.request, connection.peer.clone()));
+        }
+        // Rendezvous it was relaying won't be answered any more
+        let abandoned: Vec<SocketAddr> = self
+            .holepunches
+            .keys()
+            .filter(|address| self.introducers.get(address) == Some(&connection.peer))
+            .copied()
+            .collect();
+        for address in abandoned {
+            if let Some((peer, reason)) = self.holepunches.remove(&address) {
+                self.disconnected.insert(peer, reason);
+            }
+        }
+        self.record_disconnect(connection.peer, reason);
+    }
+
+    /// Peers we dropped and why
+    pub fn disconnected(&self) -> &HashMap<Peer, DisconnectReason> {
+        &self.disconnected
+    }
+
+    /// Failure count, last error and next attempt of dials that failed
+    pub fn dial_retries(&self) -> &HashMap<SocketAddr, DialRetry> {
+        &self.retries
+    }
+
+    pub fn set_handshake_timeout(&mut self, handshake_timeout: Duration) {
+        self.handshake_timeout = handshake_timeout;
+    }
+
+    async fn handle_message(&mut self, index: usize, message: Message) -> Result<()> {
+        self.connections[index].state.receive(&message);
+        dispatch(
+            &mut PeerSession {
+                manager: self,
+                index,
+            },
+            message,
+        )
+        .await?;
+        self.pick_piece(index).await?;
+        self.fill_pipeline(index).await
+    }
+
+    /// Marks the pieces checked since the last call as done, or discards them to be picked
+    /// again when they didn't match their hash
+    async fn settle_checked_pieces(&mut self) -> Result<()> {
+        for (piece, valid) in std::mem::take(&mut self.checked_pieces) {
+            if valid {
+                self.piece_verified(piece).await?;
+            } else {
+                warn!("Piece {} failed its hash check", piece);
+                self.piece_failed(piece);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands verified pieces to the disk writer while its queue has room. They stay in memory,
+    /// and are served from there, until written
+    fn write_pieces(&mut self) {
+        while let Some(&piece) = self.unwritten.front() {
+            let Some(content) = &self.download.pieces[piece as usize].content else {
+                self.unwritten.pop_front();
+                continue;
+            };
+            if self.disk.submit(piece, content.clone()).is_err() {
+                break;
+            }
+            self.unwritten.pop_front();
+        }
+    }
+
+    /// Moves pieces the disk has to the cache. Pieces that failed to be written are kept
+    /// in memory to be served from
+    fn settle_writes(&mut self, completed: Vec<WriteDone>) {
+        for done in completed {
+            match done.result {
+                Ok(()) => {
+                    let written = &mut self.download.pieces[done.piece as usize];
+                    written.status = PieceStatus::WrittenToDisk;
+                    if let Some(content) = written.content.take() {
+                        self.cache.insert(done.piece, content);
+                    }
+                }
+                Err(error) => {
+                    warn!("Could not write piece {}: {:?}", done.piece, error);
+                }
+            }
+        }
+        self.write_pieces();
+    }
+
+    /// Waits until every verified piece is on disk or failed to get there
+    async fn flush_writes(&mut self) {
+        self.write_pieces();
+        while self.disk.is_writing() {
+            let completed = self.disk.flush().await;
+            self.settle_writes(completed);
+        }
+    }
+
+    /// Whether the disk is behind, in which case no new blocks are requested
+    fn is_disk_backlogged(&self) -> bool {
+        !self.unwritten.is_empty() || self.disk.is_full()
+    }
+
+    /// Records a piece that passed hash verification, queues it to be written to its files
+    /// and announces it to every peer lacking it
+    pub async fn piece_verified(&mut self, piece: u32) -> Result<()> {
+        let content = self.download.pieces.get(piece as usize);
+        let (contributors, culprits) = self
+            .blame
+            .passed(piece, content.and_then(|piece| piece.content.as_deref()));
+        for culprit in culprits {
+            self.punish(&HashSet::from([culprit]));
+        }
+        if let Some(verified) = self.download.pieces.get_mut(piece as usize) {
+            verified.status = PieceStatus::ShaVerified;
+            if verified.content.is_some() {
+                self.unwritten.push_back(piece);
+            }
+        }
+        self.write_pieces();
+        self.download.mark_have(piece as usize);
+        for contributor in contributors {
+            if let Some(stats) = self.stats_of(&contributor) {
+                stats.pieces_contributed += 1;
+            }
+        }
+        self.broadcast_have(piece).await;
+        if self.download.is_finished() {
+            self.start_seeding().await?;
+        }
+        Ok(())
+    }
+
+    /// With everything downloaded we keep serving: peers and the tracker learn we're a seed,
+    /// and the next rechoke spreads the seed upload slots over peers that still want pieces
+    async fn start_seeding(&mut self) -> Result<()> {
+        self.check_md5sums().await;
+        self.move_completed().await;
+        let mut failed = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            if let Err(error) = connection.not_interested().await {
+                failed.push((index, DisconnectReason::of(&error)));
+            }
+        }
+        for (index, reason) in failed.into_iter().rev() {
+            self.disconnect(index, reason);
+        }
+        let completed = announce(
+            self.torrent,
+            &self.peer_id,
+            Some(Event::Completed),
+            self.bind.as_ref(),
+            self.announced_port(),
+            self.download.left(),
+        );
+        if let Err(error) = completed.await {
+            warn!("Could not announce completion: {:?}", error);
+        }
+        self.last_choke = None;
+        Ok(())
+    }
+
+    /// Renames the finished files into the completed directory and reopens the storage
+    /// there. Should that fail they're seeded from where they are
+    async fn move_completed(&mut self) {
+        let Some(completed_dir) = self.completed_dir.clone() else {
+            return;
+        };
+        if completed_dir == self.content_dir {
+            return;
+        }
+        self.flush_writes().await;
+        if let Err(error) = self.disk.sync().await {
+            warn!("Could not flush the content to disk: {:?}", error);
+        }
+        let moves: Vec<(PathBuf, PathBuf)> = content_paths(self.torrent, &self.content_dir)
+            .into_iter()
+            .zip(content_paths(self.torrent, &completed_dir))
+            .collect();
+        let content_dir = self.content_dir.clone();
+        let moved = spawn_blocking(move || move_files(&moves, &content_dir))
+            .await
+            .unwrap_or_else(|error| Err(anyhow!("Mover failed: {}", error)));
+        match moved {
+            Ok(_) => {
+                self.set_content_dir(completed_dir.clone());
+                self.emit(PeerEvent::ContentMoved { to: completed_dir });
+            }
+            Err(error) => {
+                warn!("Could not move the finished files: {:?}", error);
+            }
+        }
+    }
+
+    /// Checks the finished files that come with an `md5sum` once they're on disk, emitting
+    /// `FileMismatch` for those that differ. Their pieces did match, so they're kept
+    async fn check_md5sums(&mut self) {
+        let files: Vec<_> = file_spans(self.torrent)
+            .into_iter()
+            .enumerate()
+            .filter(|(index, span)| {
+                span.md5sum.is_some()
+                    && self.download.file_priorities()[*index] != FilePriority::Skip
+            })
+            .collect();
+        if files.is_empty() {
+            return;
+        }
+        self.flush_writes().await;
+        let storage = self.disk.storage().clone();
+        let checked = spawn_blocking(move || {
+            files
+                .into_iter()
+                .map(|(index, span)| {
+                    let expected = span.md5sum.unwrap_or_default().to_ascii_lowercase();
+                    let actual = storage_md5(&*storage, span.offset, span.length);
+                    (index, actual.map(|digest| hex::encode(digest) == expected))
+                })
+                .collect::<Vec<_>>()
+        });
+        let Ok(checked) = checked.await else {
+            return;
+        };
+        for (file, matches) in checked {
+            match matches {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("File {} doesn't match its md5sum", file);
+                    self.emit(PeerEvent::FileMismatch { file });
+                }
+                Err(error) => {
+                    warn!("Could not check the md5sum of file {}: {:?}", file, error);
+                }
+            }
+        }
+    }
+
+    /// Throws away a piece that failed hash verification so it is downloaded again,
+    /// returning the peers that sent blocks of it. Those to blame may only be known once
+    /// the piece passes, see `BlockBlame`
+    pub fn piece_failed(&mut self, piece: u32) -> HashSet<Peer> {
+        let contributors = self.blame.contributors(piece);
+        let content = self.download.pieces.get(piece as usize);
+        let blamed = self
+            .blame
+            .failed(piece, content.and_then(|piece| piece.content.as_deref()));
+        self.download.discard(piece as usize);
+        self.punish(&blamed);
+        contributors
+    }
+
+    /// Splits the blame for one bad piece between `peers`, dropping those it gets banned
+    fn punish(&mut self, peers: &HashSet<Peer>) {
+        for peer in peers {
+            if let Some(stats) = self.stats_of(peer) {
+                stats.hash_failures += 1;
+            }
+        }
+        let ips = peers.iter().map(|peer| peer.addr.ip()).collect();
+        let banned = self.bans.lock().unwrap().blame(&ips);
+        for index in (0..self.connections.len()).rev() {
+            if banned.contains(&self.connections[index].peer.addr.ip()) {
+                self.disconnect(index, DisconnectReason::Banned);
+            }
+        }
+        self.candidates
+            .retain(|candidate| !banned.contains(&candidate.addr.ip()));
+    }
+
+    /// Charges what every connection sends to `bucket`, for connections made from now on
+    pub fn limit_upload(&mut self, bucket: SharedBucket) {
+        self.upload_limiter.add(bucket);
+    }
+
+    pub fn limit_download(&mut self, bucket: SharedBucket) {
+        self.download_limiter.add(bucket);
+    }
+
+    /// Caps transfers with `ip` on top of the torrent wide limits, connected or not yet
+    pub fn set_peer_rate_caps(&mut self, ip: IpAddr, caps: PeerRateCaps) {
+        for connection in &mut self.connections {
+            if connection.peer.addr.ip() == ip {
+                connection.set_rate_caps(caps);
+            }
+        }
+        self.peer_rate_caps.insert(ip, caps);
+    }
+
+    /// Makes this torrent refuse the addresses banned by others, and the other way around
+    pub fn share_ban_list(&mut self, bans: SharedBanList) {
+        self.bans = bans;
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.lock().unwrap().is_banned(ip)
+    }
+
+    /// Only applies to inbound peers if set before `listen`
+    pub fn set_ip_filter(&mut self, ip_filter: Arc<IpFilter>) {
+        self.ip_filter = ip_filter;
+    }
+
+    pub fn filtered_peers(&self) -> usize {
+        self.filtered.load(Ordering::Relaxed)
+    }
+
+    fn stats_of(&mut self, peer: &Peer) -> Option<&mut PeerStats> {
+        self.connections
+            .iter_mut()
+            .find(|connection| &connection.peer == peer)
+            .map(|connection| &mut connection.stats)
+    }
+
+    /// Transfer statistics of every connected peer
+    pub fn peer_stats(&self) -> impl Iterator<Item = (&Peer, &PeerStats)> {
+        self.connections
+            .iter()
+            .map(|connection| (&connection.peer, &connection.stats))
+    }
+
+    async fn broadcast_have(&mut self, piece: u32) {
+        // Super-seeding reveals pieces one peer at a time instead
+        if self.super_seed.is_some() {
+            return;
+        }
+        let mut failed = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            if connection.bitfield.has(piece as usize) {
+                continue;
+            }
+            if let Err(error) = connection.send(Message::have(piece)).await {
+                failed.push((index, DisconnectReason::of(&error)));
+            }
+        }
+        for (index, reason) in failed.into_iter().rev() {
+            self.disconnect(index, reason);
+        }
+    }
+
+    /// Drops a piece we advertised and tells every lt_donthave capable peer we no longer have it
+    pub async fn discard_piece(&mut self, index: u32) -> Result<()> {
+        self.download.discard(index as usize);
+        let mut failed = Vec::new();
+        for (position, connection) in self.connections.iter_mut().enumerate() {
+            if let Err(error) = connection.send_donthave(index).await {
+                failed.push((position, DisconnectReason::of(&error)));
+            }
+        }
+        for (position, reason) in failed.into_iter().rev() {
+            self.disconnect(position, reason);
+        }
+        Ok(())
+    }
+
+    /// Sends the block a peer asked for, or refuses it explicitly when the fast extension
+    /// lets us and we can't or won't serve it
+    async fn answer_request(
+        &mut self,
+        index: usize,
+        request: &BlockRequest,
+        data: Option<Bytes>,
+    ) -> Result<()> {
+        let connection = &mut self.connections[index];
+        match data {
+            Some(data) if !connection.am_choking() => {
+                connection
+                    .send_block(Block {
+                        index: request.index,
+                        begin: request.begin,
+                        data,
+                    })
+                    .await?;
+                connection.stats.last_transfer = Some(Instant::now());
+            }
+            _ if connection.supports(PeerCapabilities::FAST) => {
+                connection.send(Message::reject_request(request)).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Reads `span` of storage on a blocking thread for `request` of `peer`, answered by
+    /// `answer_reads` once it's back. Requests for a span already being read wait for it
+    fn read_for_upload(&mut self, peer: Peer, request: BlockRequest, span: BlockRequest) {
+        let waiting = self.reading.entry(span).or_default();
+        waiting.push((peer, request));
+        if waiting.len() > 1 {
+            return;
+        }
+        let storage = self.disk.storage().clone();
+        let read = self.read.0.clone();
+        self.tasks.push(spawn_blocking(move || {
+            let data =
+                storage.read_block(span.index as usize, span.begin as u64, span.length as usize);
+            // The manager is gone if sending fails, nothing left to do then
+            let _ = read.send((span, data));
+        }));
+    }
+
+    /// Answers the requests whose data came back from storage
+    async fn answer_reads(&mut self) {
+        while let Ok((span, data)) = self.read.1.try_recv() {
+            let piece = self.download.pieces.get(span.index as usize);
+            let whole =
+                span.begin == 0 && piece.is_some_and(|piece| piece.length == span.length as usize);
+            let data = data.ok().map(|data| {
+                if whole {
+                    self.cache.insert(span.index, data.clone());
+                }
+                Bytes::from(data)
+            });
+            for (peer, request) in self.reading.remove(&span).unwrap_or_default() {
+                let Some(index) = self.connections.iter().position(|c| c.peer == peer) else {
+                    continue;
+                };
+                let start = (request.begin - span.begin) as usize;
+                let block = data
+                    .as_ref()
+                    .filter(|data| start + request.length as usize <= data.len())
+                    .map(|data| data.slice(start..start + request.length as usize));
+                if let Err(error) = self.answer_request(index, &request, block).await {
+                    self.disconnect(index, DisconnectReason::of(&error));
+                }
+            }
+        }
+    }
+
+    /// Acting as the relay of BEP 55: introduces the requesting peer and the target
+    /// to each other so both can connect at the same time through their NATs
+    async fn relay_holepunch(&mut self, from: usize, target: SocketAddr) -> Result<()> {
+        let initiator = self.connections[from].peer.addr;
+        let target_index = self
+            .connections
+            .iter()
+            .position(|connection| connection.peer.addr == target);
+        let reply = match target_index {
+            _ if target == initiator => HolepunchMessage::error(target, HolepunchError::NoSelf),
+            None => HolepunchMessage::error(target, HolepunchError::NotConnected),
+            Some(target_index) if !self.connections[target_index].supports_holepunch() => {
+                HolepunchMessage::error(target, HolepunchError::NoSupport)
+            }
+            Some(target_index) => {
+                let target_connection = &mut self.connections[target_index];
+                let sent = target_connection
+                    .send_holepunch(HolepunchMessage::connect(initiator))
+                    .await;
+                match sent {
+                    Ok(()) => HolepunchMessage::connect(target),
+                    // The target is dropped after the pass, it's not the initiator's fault
+                    Err(error) => {
+                        let peer = target_connection.peer.clone();
+                        self.broken.push((peer, DisconnectReason::of(&error)));
+                        HolepunchMessage::error(target, HolepunchError::NotConnected)
+                    }
+                }
+            }
+        };
+        self.connections[from].send_holepunch(reply).await
+    }
+
+    /// Asks the peer that told us of `peer` to introduce us, as BEP 55 suggests for peers
+    /// behind a NAT. Whether a rendezvous was requested, only once per address
+    async fn holepunch(&mut self, peer: &Peer, reason: DisconnectReason) -> bool {
+        if self.holepunched.contains(&peer.addr) {
+            return false;
+        }
+        let Some(introducer) = self.introducers.get(&peer.addr) else {
+            return false;
+        };
+        let Some(relay) = self.connections.iter().position(|connection| {
+            &connection.peer == introducer && connection.supports_holepunch()
+        }) else {
+            return false;
+        };
+        if self.request_holepunch(relay, peer.addr).await.is_err() {
+            return false;
+        }
+        self.holepunched.insert(peer.addr);
+        self.holepunches.insert(peer.addr, (peer.clone(), reason));
+        true
+    }
+
+    /// Asks the peer at `relay` to put us in touch with `target`, a peer it is connected to
+    pub async fn request_holepunch(&mut self, relay: usize, target: SocketAddr) -> Result<()> {
+        self.connections[relay]
+            .send_holepunch(HolepunchMessage::rendezvous(target))
+            .await
+    }
+
+    /// Blocks from peers and web seeds alike end up here, `received_from` is None for web seeds
+    async fn receive_block(&mut self, received_from: Option<usize>, block: Block) -> Result<()> {
+        if self.download.has_block(&block) {
+            // A duplicate that crossed our cancel, possibly of a piece long written out
+            if let Some(index) = received_from {
+                self.connections[index].stats.redundant += block.data.len() as u64;
+            }
+            return Ok(());
+        }
+        if let Some(index) = received_from.filter(|_| !self.download.block_matches_hashes(&block)) {
+            return self.reject_block(index, &block);
+        }
+        let completed = self.download.add_block(&block)?;
+        if let Some(index) = received_from {
+            let peer = self.connections[index].peer.clone();
+            self.blame
+                .record(block.index, block.begin, block.data.len(), peer);
+        }
+        if completed {
+            self.emit(PeerEvent::PieceReceived {
+                peer: received_from.map(|index| self.connections[index].peer.clone()),
+                piece: block.index,
+            });
+            let valid = self.download.verify_piece(block.index as usize);
+            self.checked_pieces.push((block.index, valid));
+        }
+        self.cancel_duplicate_requests(received_from, &block).await
+    }
+
+    /// Drops a block that doesn't match its v2 leaf hash and asks someone else for it. The
+    /// peer that sent it is the only one to blame, as much as for a bad piece of its own
+    fn reject_block(&mut self, index: usize, block: &Block) -> Result<()> {
+        debug!(
+            "Block {} at {} failed its hash check",
+            block.index, block.begin
+        );
+        let connection = &mut self.connections[index];
+        connection.stats.hash_failures += 1;
+        let request = BlockRequest {
+            index: block.index,
+            begin: block.begin,
+            length: block.data.len() as u32,
+        };
+        self.download.mark_cancelled(&request, connection.peer.addr);
+        self.orphaned_requests
+            .push_back((request, connection.peer.clone()));
+        let ip = connection.peer.addr.ip();
+        if self
+            .bans
+            .lock()
+            .unwrap()
+            .blame(&HashSet::from([ip]))
+            .is_empty()
+        {
+            return Ok(());
+        }
+        Err(Disconnect::new(
+            DisconnectReason::Banned,
+            format!("Bad block {} at {}", block.index, block.begin),
+        )
+        .into())
+    }
+
+    /// Once a block arrives, any request for it still outstanding at other peers
+    /// (only possible in endgame) is cancelled so they don't waste bandwidth on it, and
+    /// the pipeline slot it took is given to their next request. Failing to send to those
+    /// peers isn't the fault of the one being read, their broken connections show up when
+    /// they're read next
+    async fn cancel_duplicate_requests(
+        &mut self,
+        received_from: Option<usize>,
+        block: &Block,
+    ) -> Result<()> {
+        let mut freed = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            if Some(index) == received_from {
+                continue;
+            }
+            let duplicates: Vec<BlockRequest> = connection
+                .pending_requests
+                .iter()
+                .map(|pending| pending.request)
+                .filter(|pending| (pending.index, pending.begin) == (block.index, block.begin))
+                .collect();
+            for request in &duplicates {
+                self.download.mark_cancelled(request, connection.peer.addr);
+                if let Err(error) = connection.cancel(request).await {
+                    debug!("Could not cancel at {:?}: {:?}", &connection.peer, error);
+                }
+            }
+            if !duplicates.is_empty() {
+                freed.push(index);
+            }
+            connection
+                .queued_requests
+                .retain(|queued| (queued.index, queued.begin) != (block.index, block.begin));
+        }
+        for index in freed {
+            if let Err(error) = self.fill_pipeline(index).await {
+                debug!(
+                    "Could not refill {:?}: {:?}",
+                    &self.connections[index].peer, error
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn add_candidate(&mut self, peer: Peer) {
+        if self.ip_filter.is_blocked(&peer.addr.ip()) {
+            self.filtered.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // Sources don't agree on peer ids, so peers are told apart by address
+        let known = self.candidates.iter().any(|known| known.addr == peer.addr)
+            || self.dialing.iter().any(|known| known.addr == peer.addr)
+            || self
+                .disconnected
+                .keys()
+                .any(|known| known.addr == peer.addr)
+            || self.is_banned(&peer.addr.ip())
+            || self.is_self(&peer.addr)
+            || self
+                .connections
+                .iter()
+                .any(|connection| connection.peer.addr == peer.addr);
+        if known || self.candidates.len() >= MAX_CANDIDATES {
+            return;
+        }
+        // The same peer id on the other address family is one dual-stack peer, dialed over
+        // IPv6 with its IPv4 address raced against it
+        let twin = self.candidates.iter().position(|candidate| {
+            peer.peer_id.is_some()
+                && candidate.peer_id == peer.peer_id
+                && candidate.is_ipv6() != peer.is_ipv6()
+        });
+        match twin {
+            Some(twin) if peer.is_ipv6() => {
+                let ipv4 = std::mem::replace(&mut self.candidates[twin], peer.clone());
+                self.fallbacks.insert(peer, ipv4.addr);
+            }
+            Some(twin) => {
+                self.fallbacks
+                    .insert(self.candidates[twin].clone(), peer.addr);
+            }
+            None => self.candidates.push(peer),
+        }
+    }
+
+    /// Sends every ut_pex capable peer the changes to our connected peer set.
+    /// Each peer is only messaged once per `PEX_INTERVAL`, and never for private torrents.
+    pub async fn exchange_peers(&mut self) -> Result<()> {
+        if self.is_private() {
+            return Ok(());
+        }
+        let connected: HashSet<Peer> = self
+            .connections
+            .iter()
+            .filter_map(PeerConnection::listen_peer)
+            .collect();
+        let mut failed = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            if let Err(error) = connection.send_pex(&connected).await {
+                failed.push((index, DisconnectReason::of(&error)));
+            }
+        }
+        for (index, reason) in failed.into_iter().rev() {
+            self.disconnect(index, reason);
+        }
+        Ok(())
+    }
+}
+
+/// A connection seen together with the state shared by every connection, which is what
+/// handling one peer's messages needs
+struct PeerSession<'m, 'a> {
+    manager: &'m mut ConnectionManager<'a>,
+    index: usize,
+}
+
+impl PeerSession<'_, '_> {
+    fn connection(&mut self) -> &mut PeerConnection {
+        &mut self.manager.connections[self.index]
+    }
+
+    fn number_of_pieces(&self) -> usize {
+        self.manager.download.pieces.len()
+    }
+
+    fn on_donthave(&mut self, payload: &[u8]) -> Result<()> {
+        let index: [u8; 4] = payload
+            .try_into()
+            .map_err(|_| anyhow!("Malformed lt_donthave message"))?;
+        let piece = u32::from_be_bytes(index) as usize;
+        if self.connection().bitfield.has(piece) {
+            self.connection().bitfield.unset(piece);
+            self.manager.download.remove_available_piece(piece);
+        }
+        Ok(())
+    }
+
+    /// Learned peers join the candidates, unless the peer floods us with messages
+    /// more often than `PEX_INTERVAL` allows
+    fn on_pex(&mut self, payload: &[u8]) -> Result<()> {
+        if self.manager.is_private() {
+            return Ok(());
+        }
+        let connection = self.connection();
+        let flooding = connection
+            .last_pex_received
+            .is_some_and(|last| last.elapsed() < Duration::from_secs(PEX_INTERVAL) / 2);
+        connection.last_pex_received = Some(Instant::now());
+        if flooding {
+            return Ok(());
+        }
+        let pex = PexMessage::from_bytes(payload)?;
+        let seeding = self.manager.download.have.is_complete();
+        for (peer, flags) in pex.added_peers().into_iter().take(MAX_PEX_PEERS) {
+            // Two seeds have nothing to exchange
+            if seeding && flags & FLAG_SEED != 0 {
+                continue;
+            }
+            if self.manager.introducers.len() < MAX_CANDIDATES {
+                let introducer = self.connection().peer.clone();
+                self.manager
+                    .introducers
+                    .entry(peer.addr)
+                    .or_insert(introducer);
+            }
+            self.manager.add_candidate(peer);
+        }
+        // Peers that left the swarm aren't worth a dial anymore
+        let dropped = pex.dropped_peers();
+        self.manager
+            .candidates
+            .retain(|candidate| !dropped.iter().any(|peer| peer.addr == candidate.addr));
+        for peer in dropped {
+            self.manager.introducers.remove(&peer.addr);
+        }
+        Ok(())
+    }
+
+    /// Swaps the pieces the peer has, keeping the availability of every piece in step
+    fn replace_bitfield(&mut self, bitfield: Bitfield) {
+        let manager = &mut *self.manager;
+        let connection = &mut manager.connections[self.index];
+        manager.download.remove_available(&connection.bitfield);
+        manager.download.add_available(&bitfield);
+        connection.bitfield = bitfield;
+    }
+
+    async fn on_holepunch(&mut self, payload: &[u8]) -> Result<()> {
+        let holepunch = HolepunchMessage::from_bytes(payload)?;
+        match holepunch.kind {
+            HolepunchType::Rendezvous => {
+                self.manager
+                    .relay_holepunch(self.index, holepunch.addr)
+                    .await?
+            }
+            // Both sides dial each other now, which opens the way through their NATs
+            HolepunchType::Connect => {
+                self.manager.holepunches.remove(&holepunch.addr);
+                self.manager
+                    .add_candidate(Peer::from_socket_addr(holepunch.addr))
+            }
+            HolepunchType::Error => {
+                debug!(
+                    "Holepunch to {:?} failed: {:?}",
+                    holepunch.addr, holepunch.error
+                );
+                if let Some((peer, reason)) = self.manager.holepunches.remove(&holepunch.addr) {
+                    self.manager.disconnected.insert(peer, reason);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageHandler for PeerSession<'_, '_> {
+    async fn on_choke(&mut self) -> Result<()> {
+        let peer = self.connection().peer.clone();
+        self.manager.emit(PeerEvent::Choked { peer });
+        let manager = &mut *self.manager;
+        let connection = &mut manager.connections[self.index];
+        // Without the fast extension a choke silently discards all of our requests,
+        // with it every pending request gets an explicit reject instead
+        if !connection.supports(PeerCapabilities::FAST) {
+            for pending in connection.pending_requests.drain(..).rev() {
+                manager
+                    .download
+                    .mark_cancelled(&pending.request, connection.peer.addr);
+                connection.queued_requests.push_front(pending.request);
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_bitfield(&mut self, bitfield: Bytes) -> Result<()> {
+        let number_of_pieces = self.number_of_pieces();
+        self.replace_bitfield(Bitfield::from_bytes(&bitfield, number_of_pieces)?);
+        self.manager.update_interest(self.index).await
+    }
+
+    async fn on_have(&mut self, piece: u32) -> Result<()> {
+        let connection = &mut self.manager.connections[self.index];
+        if (piece as usize) < connection.bitfield.len() && !connection.bitfield.has(piece as usize)
+        {
+            connection.bitfield.set(piece as usize);
+            self.manager.download.add_available_piece(piece as usize);
+        }
+        let manager = &mut *self.manager;
+        let addr = manager.connections[self.index].peer.addr;
+        manager.download.observe_have(piece as usize, addr);
+        if let Some(super_seed) = &mut manager.super_seed {
+            let due = super_seed.on_have(&manager.connections[self.index].peer, piece);
+            for peer in due {
+                if let Some(due) = manager.connections.iter().position(|c| c.peer == peer) {
+                    if let Err(error) = manager.reveal_piece(due).await {
+                        manager.broken.push((peer, DisconnectReason::of(&error)));
+                    }
+                }
+            }
+        }
+        manager.update_interest(self.index).await
+    }
+
+    async fn on_have_all(&mut self) -> Result<()> {
+        let number_of_pieces = self.number_of_pieces();
+        if self.connection().supports(PeerCapabilities::FAST) {
+            self.replace_bitfield(Bitfield::full(number_of_pieces));
+        }
+        self.manager.update_interest(self.index).await
+    }
+
+    async fn on_have_none(&mut self) -> Result<()> {
+        let number_of_pieces = self.number_of_pieces();
+        if self.connection().supports(PeerCapabilities::FAST) {
+            self.replace_bitfield(Bitfield::new(number_of_pieces));
+        }
+        Ok(())
+    }
+
+    async fn on_suggest_piece(&mut self, piece: u32) -> Result<()> {
+        let connection = self.connection();
+        if !connection.suggested_pieces.contains(&piece) {
+            connection.suggested_pieces.push_back(piece);
+        }
+        Ok(())
+    }
+
+    async fn on_allowed_fast(&mut self, piece: u32) -> Result<()> {
+        if (piece as usize) < self.number_of_pieces() {
+            self.connection().allowed_fast.insert(piece);
+        }
+        Ok(())
+    }
+
+    async fn on_reject_request(&mut self, request: BlockRequest) -> Result<()> {
+        let connection = self.connection();
+        connection
+            .pending_requests
+            .retain(|pending| pending.request != request);
+        // Requests rejected because of a choke are retried once we're unchoked
+        if connection.state.peer_choking {
+            connection.queued_requests.push_back(request);
+        }
+        Ok(())
+    }
+
+    async fn on_piece(&mut self, block: Block) -> Result<()> {
+        let connection = self.connection();
+        connection.stats.record_download(block.data.len() as u64);
+        connection.awaiting_block_since = Some(Instant::now());
+        connection.timeouts = connection.timeouts.saturating_sub(1);
+        let requested = connection.pending_requests.iter().position(|pending| {
+            (pending.request.index, pending.request.begin) == (block.index, block.begin)
+        });
+        match requested {
+            Some(position) => {
+                let pending = connection.pending_requests.remove(position);
+                connection.stats.record_latency(pending.sent_at.elapsed());
+                connection.request_window.grow();
+            }
+            // Blocks we cancelled may still be on their way
+            None if connection
+                .cancelled_requests
+                .remove(&(block.index, block.begin)) => {}
+            None => {
+                return Err(Disconnect::new(
+                    DisconnectReason::UnrequestedPiece,
+                    format!("Block {} at {}", block.index, block.begin),
+                )
+                .into())
+            }
+        }
+        self.manager.receive_block(Some(self.index), block).await
+    }
+
+    async fn on_port(&mut self, port: u16) -> Result<()> {
+        let manager = &mut *self.manager;
+        let ip = manager.connections[self.index].peer.addr.ip();
+        if let Some(dht) = &mut manager.dht {
+            dht.routing_table.add_node(SocketAddr::new(ip, port));
+        }
+        Ok(())
+    }
+
+    /// Serves blocks of verified pieces to peers we unchoked, refusing the rest explicitly
+    /// when the fast extension lets us
+    async fn on_request(&mut self, request: BlockRequest) -> Result<()> {
+        let manager = &mut *self.manager;
+        let (begin, length) = (request.begin as usize, request.length as usize);
+        let piece_length = manager
+            .download
+            .pieces
+            .get(request.index as usize)
+            .map_or(0, |piece| piece.length);
+        let peer = manager.connections[self.index].peer.clone();
+        // Reading on the runtime would hold up every peer, what isn't in memory is answered
+        // once read
+        let block = match manager.download.block(&request) {
+            Some(block) => Some(Bytes::copy_from_slice(block)),
+            None if !manager.download.can_serve(&request) => None,
+            // Peers tend to ask for the rest of a piece soon after, it's cached in one go
+            None if manager.cache.fits(piece_length) => match manager.cache.get(request.index) {
+                Some(piece) => piece.get(begin..begin + length).map(Bytes::copy_from_slice),
+                None => {
+                    let piece = BlockRequest {
+                        index: request.index,
+                        begin: 0,
+                        length: piece_length as u32,
+                    };
+                    manager.read_for_upload(peer, request, piece);
+                    return Ok(());
+                }
+            },
+            None => {
+                manager.read_for_upload(peer, request, request);
+                return Ok(());
+            }
+        };
+        manager.answer_request(self.index, &request, block).await
+    }
+
+    // We don't keep v2 hash trees to serve from yet
+    async fn on_hash_request(&mut self, request: HashRequest) -> Result<()> {
+        self.connection()
+            .send(Message::HashReject(request).encode())
+            .await
+    }
+
+    async fn on_hashes(&mut self, request: HashRequest, hashes: Vec<Hash>) -> Result<()> {
+        self.manager.download.add_hashes(&request, &hashes)
+    }
+
+    async fn on_extended(&mut self, id: u8, payload: Bytes) -> Result<()> {
+        match id {
+            HANDSHAKE_ID => {
+                let handshake = ExtendedHandshake::from_bytes(&payload)?;
+                if let Some(ip) = handshake.your_ip() {
+                    self.manager.external_ips.insert(ip);
+                }
+                self.connection().extensions = Some(handshake);
+            }
+            LT_DONTHAVE_ID => self.on_donthave(&payload)?,
+            UT_PEX_ID => self.on_pex(&payload)?,
+            UT_HOLEPUNCH_ID => self.on_holepunch(&payload).await?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a background dial
+type Dialed = (Peer, Result<PeerConnection>);
+
+/// What was read from storage off the runtime, for uploads
+type StorageRead = (BlockRequest, Result<Vec<u8>>);
+
+/// What the listener needs to hand an inbound peer over to the manager of its torrent
+#[derive(Clone)]
+struct InboundTorrent {
+    options: DialOptions,
+    accepted: UnboundedSender<Dialed>,
+    global_slots: Arc<Semaphore>,
+    bans: SharedBanList,
+    ip_filter: Arc<IpFilter>,
+    filtered: Arc<AtomicUsize>,
+}
+
+type InboundTorrents = Arc<Mutex<HashMap<Vec<u8>, InboundTorrent>>>;
+
+/// Accepts inbound peers for every torrent attached to it, routing each to the torrent
+/// whose info hash it handshakes with and refusing the others
+#[derive(Clone)]
+pub struct Listener {
+    address: SocketAddr,
+    torrents: InboundTorrents,
+    _task: Arc<ListenerTask>,
+}
+
+/// Aborts the accept loop, and the handshakes in flight, with the last `Listener` clone
+struct ListenerTask(JoinHandle<()>);
+
+impl Drop for ListenerTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Listener {
+    /// Accepted connections get `socket` applied, its buffer sizes already on the listener
+    pub async fn bind(port: u16, socket: SocketOptions) -> Result<Self> {
+        Self::from_listener(listen_tcp(port, &socket)?, socket)
+    }
+
+    /// Listens on the first free port of `range`, moving on to the next one while they
+    /// are taken
+    pub async fn bind_range(
+        range: &PortRange,
+        randomized: bool,
+        socket: SocketOptions,
+    ) -> Result<Self> {
+        let mut taken = None;
+        for port in range.ports(randomized) {
+            match listen_tcp(port, &socket) {
+                Ok(listener) => return Self::from_listener(listener, socket),
+                Err(error) if error.kind() == std::io::ErrorKind::AddrInUse => taken = Some(error),
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Err(anyhow!("Every port of {:?} is taken: {:?}", range, taken))
+    }
+
+    fn from_listener(listener: TcpListener, socket: SocketOptions) -> Result<Self> {
+        let address = listener.local_addr()?;
+        let torrents = InboundTorrents::default();
+        let routed = torrents.clone();
+        let task = tokio::spawn(async move {
+            // Dropped along with the listener task, which aborts the handshakes in flight
+            let mut handshakes = JoinSet::new();
+            loop {
+                let incoming = tokio::select! {
+                    incoming = listener.accept() => incoming,
+                    Some(_) = handshakes.join_next() => continue,
+                };
+                let (stream, remote) = match incoming {
+                    Ok(incoming) => incoming,
+                    Err(error) => {
+                        warn!("Listener stopped: {:?}", error);
+                        return;
+                    }
+                };
+                if let Err(error) = socket.apply(&stream) {
+                    debug!("Could not tune the socket of {}: {:?}", remote, error);
+                }
+                let torrents = routed.clone();
+                handshakes.spawn(async move {
+                    if let Err(error) = Self::route(stream, remote, &torrents).await {
+                        debug!("Refused inbound peer {}: {:?}", remote, error);
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            address,
+            torrents,
+            _task: Arc::new(ListenerTask(task)),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Handshakes far enough to learn which torrent the peer wants, then lets that torrent
+    /// decide whether to take it
+    async fn route(
+        stream: TcpStream,
+        remote: SocketAddr,
+        torrents: &InboundTorrents,
+    ) -> Result<()> {
+        let (mut connection, theirs, torrent) =
+            PeerConnection::accept(stream, remote, torrents).await?;
+        if torrent.ip_filter.is_blocked(&remote.ip()) {
+            torrent.filtered.fetch_add(1, Ordering::Relaxed);
+            return Err(anyhow!("Blocked by the IP filter"));
+        }
+        if torrent.bans.lock().unwrap().is_banned(&remote.ip()) {
+            return Err(Disconnect::new(DisconnectReason::Banned, remote.to_string()).into());
+        }
+        // Over the global limit the connection is dropped right away
+        let Ok(slot) = torrent.global_slots.clone().try_acquire_owned() else {
+            return Err(anyhow!("No connection slot left"));
+        };
+        let options = &torrent.options;
+        let handshake = async {
+            connection.write_handshake(options).await?;
+            connection.finish_handshake(options, theirs).await
+        };
+        let connection = PeerConnection::within_handshake_timeout(options, handshake)
+            .await
+            .map(|_| {
+                connection.global_slot = Some(slot);
+                connection
+            });
+        let _ = torrent
+            .accepted
+            .send((Peer::from_socket_addr(remote), connection));
+        Ok(())
+    }
+}
+
+/// What a background dial needs to know about the torrent and ourselves
+#[derive(Debug, Clone)]
+struct DialOptions {
+    info_hash: Vec<u8>,
+    peer_id: String,
+    private: bool,
+    dht: bool,
+    seed: bool,
+    encryption: EncryptionPolicy,
+    handshake_timeout: Duration,
+    half_open: Arc<Semaphore>,
+    bind: Option<Bind>,
+    socket: SocketOptions,
+    /// Whether the torrent has v2 hashes to exchange
+    v2: bool,
+    /// Sent as `p` in the extended handshake, how peers learn where we accept connections
+    listen_port: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingRequest {
+    request: BlockRequest,
+    sent_at: Instant,
+}
+
+pub struct PeerConnection {
+    peer: Peer,
+    /// The peer id the remote sent in its handshake
+    remote_peer_id: Option<[u8; 20]>,
+    /// Whether we dialed the peer rather than it dialing us
+    outbound: bool,
+    connection: Framed<MseStream<BoxedTransport>, PeerCodec>,
+    /// Choke and interest on both sides, driven by the messages exchanged
+    state: PeerState,
+    stats: PeerStats,
+    connected_at: Instant,
+    /// Since when we've been waiting for a block, i.e. the time of the last block received
+    /// or of the first request sent after the pipeline ran dry
+    awaiting_block_since: Option<Instant>,
+    /// Released when the connection is dropped
+    global_slot: Option<OwnedSemaphorePermit>,
+    /// Pieces the peer has announced through bitfield, have or fast extension messages
+    bitfield: Bitfield,
+    /// What both sides announced in the reserved bytes of their handshakes
+    capabilities: PeerCapabilities,
+    /// Pieces the peer lets us request even while choking us
+    allowed_fast: HashSet<u32>,
+    suggested_pieces: VecDeque<u32>,
+    pending_requests: Vec<PendingRequest>,
+    /// Index and offset of blocks cancelled while in flight, which may arrive regardless
+    cancelled_requests: HashSet<(u32, u32)>,
+    /// Requests that timed out recently, each one halves how many we keep outstanding
+    timeouts: u32,
+    request_window: RequestWindow,
+    /// Requests waiting for room in the pipeline
+    queued_requests: VecDeque<BlockRequest>,
+    pipeline_depth: usize,
+    block_size: u32,
+    upload_limiter: RateLimiter,
+    download_limiter: RateLimiter,
+    /// Buckets of this peer alone, once it got caps
+    rate_caps: Option<(SharedBucket, SharedBucket)>,
+    extensions: Option<ExtendedHandshake>,
+    pex: PexState,
+    last_pex: Option<Instant>,
+    last_pex_received: Option<Instant>,
+}
+
+impl PeerConnection {
+    /// Connects to `peer`, or its fallback, and negotiates encryption
+    async fn connect(
+        mut peer: Peer,
+        fallback: Option<SocketAddr>,
+        options: &DialOptions,
+    ) -> Result<Self> {
+        debug!("Connecting to peer: {:?}", &peer);
+        let encryption = options.encryption;
+        let stream = Self::open(peer.addr, fallback, options).await?;
+        // The fallback may have won the race
+        peer.addr = stream.peer_addr().unwrap_or(peer.addr);
+        let address = peer.addr;
+        let stream = match timeout(
+            ENCRYPTION_TIMEOUT,
+            MseStream::connect(stream, &options.info_hash, encryption),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            // Peers that don't speak MSE usually just drop the connection, so start over in plaintext
+            _ if encryption == EncryptionPolicy::Prefer => {
+                MseStream::plaintext(Self::open(address, None, options).await?)
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(anyhow!("Encrypted handshake with {} timed out", address)),
+        };
+        Ok(Self::from_stream(peer, stream))
+    }
+
+    /// The bare stream to `address`, or to its fallback if that one connects first
+    async fn open(
+        address: SocketAddr,
+        fallback: Option<SocketAddr>,
+        options: &DialOptions,
+    ) -> Result<BoxedTransport> {
+        let bind = options.bind.as_ref();
+        let connecting = connect_either(address, fallback, bind, &options.socket);
+        let stream = timeout(CONNECT_TIMEOUT, connecting).await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("Connecting to {} timed out", address),
+            )
+        })??;
+        Ok(Box::new(stream))
+    }
+
+    /// A connection to `peer` over an in-memory pipe, with the other end handed back to
+    /// play the peer in tests. Nothing is exchanged yet, not even the handshake
+    pub fn in_memory(peer: Peer) -> (Self, DuplexStream) {
+        let (ours, theirs) = duplex(64 * 1024);
+        let stream = MseStream::plaintext(Box::new(ours) as BoxedTransport);
+        (Self::from_stream(peer, stream), theirs)
+    }
+
+    fn from_stream(peer: Peer, stream: MseStream<BoxedTransport>) -> Self {
+        let connection = Framed::new(stream, PeerCodec::default());
+        Self {
+            peer,
+            remote_peer_id: None,
+            outbound: false,
+            connection,
+            state: PeerState::default(),
+            stats: PeerStats::default(),
+            connected_at: Instant::now(),
+            awaiting_block_since: None,
+            global_slot: None,
+            bitfield: Bitfield::default(),
+            capabilities: PeerCapabilities::empty(),
+            allowed_fast: HashSet::new(),
+            suggested_pieces: VecDeque::new(),
+            pending_requests: Vec::new(),
+            cancelled_requests: HashSet::new(),
+            timeouts: 0,
+            request_window: RequestWindow::default(),
+            queued_requests: VecDeque::new(),
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            block_size: BLOCK_BYTES,
+            upload_limiter: RateLimiter::default(),
+            download_limiter: RateLimiter::default(),
+            rate_caps: None,
+            extensions: None,
+            pex: PexState::default(),
+            last_pex: None,
+            last_pex_received: None,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.connection.get_ref().is_encrypted()
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.connection.get_ref().kind()
+    }
+
+    pub fn state(&self) -> &PeerState {
+        &self.state
+    }
+
+    pub fn is_choking(&self) -> bool {
+        self.state.peer_choking
+    }
+
+    pub fn available_pieces(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        !self.pending_requests.is_empty()
+            && self
+                .awaiting_block_since
+                .is_some_and(|since| since.elapsed() >= SNUB_TIMEOUT)
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.state.am_choking
+    }
+
+    async fn set_choking(&mut self, choking: bool) -> Result<()> {
+        if self.state.set_am_choking(choking) {
+            let message = if choking {
+                Message::choke()
+            } else {
+                Message::unchoke()
+            };
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    pub fn is_interested(&self) -> bool {
+        self.state.peer_interested
+    }
+
+    /// Whether a block of the given piece may be requested right now
+    pub fn can_request(&self, piece_index: u32) -> bool {
+        !self.state.peer_choking
+            || (self.supports(PeerCapabilities::FAST) && self.allowed_fast.contains(&piece_index))
+    }
+
+    pub fn next_suggested_piece(&mut self) -> Option<u32> {
+        self.suggested_pieces.pop_front()
+    }
+
+    /// Connects and handshakes: everything needed before the manager takes it over
+    async fn dial(peer: Peer, fallback: Option<SocketAddr>, options: DialOptions) -> Result<Self> {
+        let half_open = options.half_open.clone().acquire_owned().await?;
+        let mut connection = Self::connect(peer, fallback, &options).await?;
+        drop(half_open);
+        connection.outbound = true;
+        let handshake = async {
+            connection.write_handshake(&options).await?;
+            connection.read_handshake(&options).await
+        };
+        Self::within_handshake_timeout(&options, handshake).await?;
+        Ok(connection)
+    }
+
+    /// The inbound side of `dial`: the remote speaks first and we look up the torrent it asks
+    /// for among `torrents`, whose handshake completes the connection
+    async fn accept(
+        stream: TcpStream,
+        address: SocketAddr,
+        torrents: &InboundTorrents,
+    ) -> Result<(Self, PeerCapabilities, InboundTorrent)> {
+        let (info_hashes, encryption) = {
+            let torrents = torrents.lock().unwrap();
+            let mut policies = torrents.values().map(|torrent| torrent.options.encryption);
+            let first = policies.next().unwrap_or_default();
+            // With several policies each torrent checks its own once the peer picked one
+            let encryption = if policies.all(|policy| policy == first) {
+                first
+            } else {
+                EncryptionPolicy::Prefer
+            };
+            (torrents.keys().cloned().collect::<Vec<_>>(), encryption)
+        };
+        let stream: BoxedTransport = Box::new(stream);
+        let (stream, obfuscated) = timeout(
+            ENCRYPTION_TIMEOUT,
+            MseStream::accept(stream, &info_hashes, encryption),
+        )
+        .await
+        .map_err(|_| anyhow!("Encrypted handshake with {} timed out", address))??;
+        let mut connection = Self::from_stream(Peer::from_socket_addr(address), stream);
+        let (info_hash, theirs) =
+            timeout(DEFAULT_HANDSHAKE_TIMEOUT, connection.read_handshake_only())
+                .await
+                .map_err(|_| {
+                    Disconnect::new(DisconnectReason::HandshakeTimeout, address.to_string())
+                })??;
+        let torrent = torrents.lock().unwrap().get(&info_hash[..]).cloned();
+        let torrent = match torrent {
+            // The encrypted handshake already named the torrent, both have to agree
+            Some(torrent) if obfuscated.as_ref().is_none_or(|hash| *hash == info_hash) => torrent,
+            _ => {
+                return Err(Disconnect::new(
+                    DisconnectReason::InvalidHandshake,
+                    format!("Unknown info hash {}", hex::encode(info_hash)),
+                )
+                .into())
+            }
+        };
+        if torrent.options.encryption == EncryptionPolicy::Disabled && connection.is_encrypted() {
+            return Err(anyhow!("Encrypted connection refused by encryption policy"));
+        }
+        Ok((connection, theirs, torrent))
+    }
+
+    async fn within_handshake_timeout(
+        options: &DialOptions,
+        handshake: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        match timeout(options.handshake_timeout, handshake).await {
+            Ok(handshake) => handshake,
+            Err(_) => Err(Disconnect::new(
+                DisconnectReason::HandshakeTimeout,
+                format!("No handshake within {:?}", options.handshake_timeout),
+            )
+            .into()),
+        }
+    }
+
+    fn our_capabilities(options: &DialOptions) -> PeerCapabilities {
+        let mut ours = PeerCapabilities::EXTENDED | PeerCapabilities::FAST;
+        ours.set(PeerCapabilities::DHT, options.dht);
+        ours.set(PeerCapabilities::V2, options.v2);
+        ours
+    }
+
+    /// The handshake isn't length-prefixed, so it goes straight to the socket before
+    /// any frame is read through the codec
+    async fn write_handshake(&mut self, options: &DialOptions) -> Result<()> {
+        let mut concatenated_bytes = Vec::new();
+        concatenated_bytes.extend_from_slice(&19_u8.to_be_bytes());
+        concatenated_bytes.extend_from_slice("BitTorrent protocol".as_bytes());
+        concatenated_bytes.extend_from_slice(&Self::our_capabilities(options).to_reserved());
+        concatenated_bytes.extend_from_slice(&options.info_hash);
+        concatenated_bytes.extend_from_slice(options.peer_id.as_bytes());
+        self.connection
+            .get_mut()
+            .write_all(&concatenated_bytes)
+            .await?;
+        Ok(())
+    }
+
+    async fn read_handshake(&mut self, options: &DialOptions) -> Result<()> {
+        let info_hash = &options.info_hash;
+        let (theirs_hash, theirs) = self.read_handshake_only().await?;
+        if theirs_hash[..] != info_hash[..] {
+            return Err(Disconnect::new(
+                DisconnectReason::InvalidHandshake,
+                format!(
+                    "Invalid info hash {} {}",
+                    hex::encode(theirs_hash),
+                    hex::encode(info_hash.as_slice())
+                ),
+            )
+            .into());
+        }
+        self.finish_handshake(options, theirs).await
+    }
+
+    /// Reads the remote handshake, returning the info hash and capabilities it announced
+    async fn read_handshake_only(&mut self) -> Result<([u8; 20], PeerCapabilities)> {
+        let stream = self.connection.get_mut();
+        let mut len = [0; 1];
+        stream.read_exact(&mut len).await?;
+        let total_length = len[0] as usize + 8 + 20 + 20;
+        let mut response = vec![0; total_length];
+        stream.read_exact(&mut response).await?;
+        if len[0] != 19 || &response[0..19] != "BitTorrent protocol".as_bytes() {
+            return Err(
+                Disconnect::new(DisconnectReason::InvalidHandshake, "Invalid protocol").into(),
+            );
+        }
+        self.remote_peer_id = response[47..67].try_into().ok();
+        Ok((
+            response[27..47].try_into()?,
+            PeerCapabilities::from_reserved(response[19..27].try_into()?),
+        ))
+    }
+
+    async fn finish_handshake(
+        &mut self,
+        options: &DialOptions,
+        theirs: PeerCapabilities,
+    ) -> Result<()> {
+        self.capabilities = Self::our_capabilities(options) & theirs;
+        if self.supports(PeerCapabilities::EXTENDED) {
+            let handshake =
+                ExtendedHandshake::new(options.listen_port, options.private, options.seed);
+            let message = Message::extended(HANDSHAKE_ID, &handshake.to_bytes()?);
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// What follows a successful handshake: our DHT port and what we have. Interest waits
+    /// for the peer to tell what it has
+    async fn start(&mut self, dht_port: Option<u16>, have: &Bitfield) -> Result<()> {
+        if let (Some(port), true) = (dht_port, self.supports(PeerCapabilities::DHT)) {
+            self.send(Message::port(port)).await?;
+        }
+        self.bitfield(have).await
+    }
+
+    async fn send(&mut self, message: Bytes) -> Result<()> {
+        self.upload_limiter.throttle(message.len()).await;
+        trace_frame(&self.peer, Direction::Outgoing, &message);
+        self.stats.record_upload(message.len() as u64);
+        self.connection.send(message).await
+    }
+
+    /// Sends a piece message, the codec writing the block data straight into the socket
+    /// buffer instead of into a message first
+    async fn send_block(&mut self, block: Block) -> Result<()> {
+        // Length prefix, id, index and offset
+        let length = 13 + block.data.len();
+        self.upload_limiter.throttle(length).await;
+        trace_message(
+            &self.peer,
+            Direction::Outgoing,
+            &Message::Piece(block.clone()),
+        );
+        self.stats.record_upload(length as u64);
+        self.connection.send(block).await
+    }
+
+    /// Subjects uploads to this peer to `bucket`, on top of any bucket already applied
+    pub fn limit_upload(&mut self, bucket: SharedBucket) {
+        self.upload_limiter.add(bucket);
+    }
+
+    pub fn limit_download(&mut self, bucket: SharedBucket) {
+        self.download_limiter.add(bucket);
+    }
+
+    /// Caps transfers with this peer alone, replacing the caps set before
+    pub fn set_rate_caps(&mut self, caps: PeerRateCaps) {
+        match &self.rate_caps {
+            Some((upload, download)) => {
+                upload.lock().unwrap().set_rate(caps.upload);
+                download.lock().unwrap().set_rate(caps.download);
+            }
+            None => {
+                let upload = TokenBucket::shared(caps.upload);
+                let download = TokenBucket::shared(caps.download);
+                self.limit_upload(upload.clone());
+                self.limit_download(download.clone());
+                self.rate_caps = Some((upload, download));
+            }
+        }
+    }
+
+    async fn bitfield(&mut self, have: &Bitfield) -> Result<()> {
+        let message = match (self.supports(PeerCapabilities::FAST), have.count()) {
+            (true, 0) => Message::have_none(),
+            (true, count) if count == have.len() => Message::have_all(),
+            // Peers without the fast extension expect no bitfield at all when we have nothing
+            (false, 0) => return Ok(()),
+            _ => Message::Bitfield(Bytes::copy_from_slice(have.as_bytes())).encode(),
+        };
+        self.send(message).await
+    }
+
+    async fn interested(&mut self) -> Result<()> {
+        if self.state.set_am_interested(true) {
+            self.send(Message::interested()).await?;
+        }
+        Ok(())
+    }
+
+    async fn not_interested(&mut self) -> Result<()> {
+        if self.state.set_am_interested(false) {
+            self.send(Message::not_interested()).await?;
+        }
+        Ok(())
+    }
+
+    /// Makes sense of what was read off the socket, returning `None` on keep-alives.
+    /// The end of the stream means the peer closed the connection
+    async fn take_message(&mut self, frame: Option<Result<Message>>) -> Result<Option<Message>> {
+        match frame {
+            None => Err(Disconnect::new(
+                DisconnectReason::ConnectionClosed,
+                format!("Connection closed by {:?}", self.peer),
+            )
+            .into()),
+            Some(message) => {
+                // Bytes are charged once they're read, waiting out the debt delays the next read
+                let read = self.connection.codec_mut().take_bytes_read();
+                self.download_limiter.throttle(read).await;
+                let message = message?;
+                trace_message(&self.peer, Direction::Incoming, &message);
+                match message {
+                    Message::KeepAlive => Ok(None),
+                    message => Ok(Some(message)),
+                }
+            }
+        }
+    }
+
+    /// Where the peer accepts connections: the address we dialed, or for a peer that dialed
+    /// us the listen port of its extended handshake, its own port being ephemeral
+    fn listen_peer(&self) -> Option<Peer> {
+        let addr = if self.outbound {
+            self.peer.addr
+        } else {
+            let port = self.extensions.as_ref()?.p.filter(|port| *port != 0)?;
+            SocketAddr::new(self.peer.addr.ip(), port)
+        };
+        Some(Peer {
+            peer_id: self.peer.peer_id.clone(),
+            addr,
+        })
+    }
+
+    async fn send_pex(&mut self, connected: &HashSet<Peer>) -> Result<()> {
+        let Some(id) = self.extension_id("ut_pex") else {
+            return Ok(());
+        };
+        if self
+            .last_pex
+            .is_some_and(|last| last.elapsed() < Duration::from_secs(PEX_INTERVAL))
+        {
+            return Ok(());
+        }
+        let mut others = connected.clone();
+        if let Some(us) = self.listen_peer() {
+            others.remove(&us);
+        }
+        let pex = self.pex.next_message(&others);
+        self.last_pex = Some(Instant::now());
+        if !pex.is_empty() {
+            self.send(Message::extended(id, &pex.to_bytes()?)).await?;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+
+    fn info(&self) -> PeerInfo {
+        PeerInfo {
+            address: self.peer.addr,
+            client: self.client(),
+            progress: self.bitfield.count() as f64 / self.bitfield.len().max(1) as f64,
+            download_rate: self.stats.download_rate.per_second(),
+            upload_rate: self.stats.upload_rate.per_second(),
+            state: self.state,
+            snubbed: self.is_snubbed(),
+            encrypted: self.is_encrypted(),
+            outbound: self.outbound,
+            transport: self.transport(),
+        }
+    }
+
+    /// The client the peer runs, as told by its extended handshake or peer id
+    pub fn client(&self) -> Option<ClientFingerprint> {
+        let v = self
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.v.as_deref());
+        ClientFingerprint::identify(self.remote_peer_id.as_ref()?, v)
+    }
+
+    /// Seeds announce upload_only, unchoking them is a wasted slot since they never download
+    pub fn is_upload_only(&self) -> bool {
+        self.extensions
+            .as_ref()
+            .is_some_and(|extensions| extensions.is_upload_only())
+    }
+
+    pub fn supports(&self, capability: PeerCapabilities) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// The id to send an extension message with, only known if both sides negotiated
+    /// the extension protocol and the peer listed the extension in its handshake
+    fn extension_id(&self, name: &str) -> Option<u8> {
+        if !self.supports(PeerCapabilities::EXTENDED) {
+            return None;
+        }
+        self.extensions
+            .as_ref()
+            .and_then(|extensions| extensions.extension_id(name))
+    }
+
+    fn supports_holepunch(&self) -> bool {
+        self.extension_id("ut_holepunch").is_some()
+    }
+
+    async fn send_holepunch(&mut self, holepunch: HolepunchMessage) -> Result<()> {
+        let Some(id) = self.extension_id("ut_holepunch") else {
+            return Ok(());
+        };
+        self.send(Message::extended(id, &holepunch.to_bytes()))
+            .await
+    }
+
+    async fn send_donthave(&mut self, index: u32) -> Result<()> {
+        let Some(id) = self.extension_id("lt_donthave") else {
+            return Ok(());
+        };
+        self.send(Message::extended(id, &index.to_be_bytes())).await
+    }
+
+    /// Drops what is queued, cancels what is requested and chokes the peer, keeping the
+    /// connection itself
+    async fn pause(&mut self) -> Result<()> {
+        self.queued_requests.clear();
+        let pending: Vec<BlockRequest> = self
+            .pending_requests
+            .iter()
+            .map(|pending| pending.request)
+            .collect();
+        for request in pending {
+            self.cancel(&request).await?;
+        }
+        self.set_choking(true).await?;
+        self.not_interested().await
+    }
+
+    /// Takes the request out of the pipeline even if the cancel can't be sent, since the
+    /// connection is as good as gone then
+    async fn cancel(&mut self, request: &BlockRequest) -> Result<()> {
+        self.pending_requests
+            .retain(|pending| pending.request != *request);
+        self.cancelled_requests
+            .insert((request.index, request.begin));
+        self.send(Message::cancel(request)).await
+    }
+
+    /// How many requests may be outstanding: enough to cover the peer's bandwidth-delay
+    /// product once it is known, honouring the reqq from the peer's extended handshake
+    /// and never more than the slow start window
+    fn max_outstanding_requests(&self) -> usize {
+        let reqq = self
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.reqq);
+        let rate = self.stats.download_rate.per_second();
+        let depth = match self.stats.latency {
+            Some(latency) if rate > 0 => pipeline_depth_for(rate, latency, self.block_size),
+            _ => self.pipeline_depth,
+        }
+        .min(self.request_window.size());
+        let depth = match reqq {
+            Some(reqq) => depth.min(reqq.max(1) as usize),
+            None => depth,
+        };
+        (depth >> self.timeouts.min(usize::BITS - 1)).max(1)
+    }
+
+    /// Sends queued requests until the pipeline is full or the peer won't serve the next one,
+    /// returning the ones sent. Snubbing peers only get more requests in endgame, when every
+    /// peer is worth a try
+    async fn fill_pipeline(&mut self, endgame: bool) -> Result<Vec<BlockRequest>> {
+        if self.pending_requests.is_empty() {
+            self.awaiting_block_since = None;
+        }
+        let mut sent = Vec::new();
+        if self.is_snubbed() && !endgame {
+            return Ok(sent);
+        }
+        while self.pending_requests.len() < self.max_outstanding_requests() {
+            match self.queued_requests.front() {
+                Some(request) if self.can_request(request.index) => {
+                    let request = self.queued_requests.pop_front().unwrap();
+                    self.download_block(request).await?;
+                    sent.push(request);
+                }
+                _ => break,
+            }
+        }
+        Ok(sent)
+    }
+
+    pub async fn download_block(&mut self, request: BlockRequest) -> Result<()> {
+        if !self.can_request(request.index) {
+            return Err(anyhow!(
+                "Peer is choking us and piece {} is not allowed fast",
+                request.index
+            ));
+        }
+        self.send(Message::request(
+            request.index,
+            request.begin,
+            request.length,
+        ))
+        .await?;
+        self.pending_requests.push(PendingRequest {
+            request,
+            sent_at: Instant::now(),
+        });
+        self.awaiting_block_since.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+}
+
+/// Happy eyeballs: connects to `primary`, racing `fallback` against it once the primary
+/// had `FALLBACK_DELAY` to answer, and keeps whichever connects first
+async fn connect_either(
+    primary: SocketAddr,
+    fallback: Option<SocketAddr>,
+    bind: Option<&Bind>,
+    socket: &SocketOptions,
+) -> Result<TcpStream> {
+    let Some(fallback) = fallback else {
+        return Ok(connect_tcp(primary, bind, socket).await?);
+    };
+    let delayed = async move {
+        sleep(FALLBACK_DELAY).await;
+        connect_tcp(fallback, bind, socket).await
+    };
+    let primary = connect_tcp(primary, bind, socket);
+    let (stream, _) = select_ok([primary.boxed(), delayed.boxed()]).await?;
+    Ok(stream)
+}
+
+async fn connect_tcp(
+    remote: SocketAddr,
+    bind: Option<&Bind>,
+    options: &SocketOptions,
+) -> std::io::Result<TcpStream> {
+    let socket = if remote.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    options.prepare(&socket)?;
+    if let Some(bind) = bind {
+        bind.bind_socket(&socket, remote)?;
+    }
+    let stream = socket.connect(remote).await?;
+    options.apply(&stream)?;
+    Ok(stream)
+}
+
+/// Like `TcpListener::bind` on every IPv4 address, with the buffer sizes of `options`
+fn listen_tcp(port: u16, options: &SocketOptions) -> std::io::Result<TcpListener> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    options.prepare(&socket)?;
+    socket.bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+    socket.listen(1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        fake_peer::{FakePeer, Step},
+        parse_torrent::parse_torrent,
+        storage::MemoryStorage,
+    };
+
+    #[tokio::test]
+    async fn it_follows_the_choke_state_of_a_scripted_peer() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let info_hash = get_info_hash(&torrent.info).unwrap();
+        let mut manager = ConnectionManager::new(
+            &torrent,
+            Download::from(&torrent),
+            "-FU0001-000000000000".into(),
+        );
+        let mut events = manager.subscribe();
+        let options = manager.dial_options().unwrap();
+        let peer = Peer::from_socket_addr("10.0.0.2:6881".parse().unwrap());
+        let (mut connection, stream) = PeerConnection::in_memory(peer.clone());
+        let mut fake = FakePeer::new(stream);
+        let (ours, theirs) = tokio::join!(
+            async {
+                connection.write_handshake(&options).await?;
+                connection.read_handshake(&options).await
+            },
+            fake.handshake(&info_hash, b"-XX0001-000000000000")
+        );
+        ours.unwrap();
+        assert_eq!(&info_hash[..], &theirs.unwrap()[28..48]);
+        manager.add_connection(connection).await.unwrap();
+
+        let pieces = manager.download.pieces.len();
+        let mut everything = Bitfield::new(pieces);
+        (0..pieces).for_each(|piece| everything.set(piece));
+        fake.play(vec![
+            Step::Send(Message::Bitfield(Bytes::copy_from_slice(everything.as_bytes())).encode()),
+            Step::Send(Message::unchoke()),
+        ])
+        .await
+        .unwrap();
+        for _ in 0..2 {
+            let (_, frame) = manager.read_ready().await.pop().unwrap();
+            let message = manager.connections[0]
+                .take_message(frame)
+                .await
+                .unwrap()
+                .unwrap();
+            manager.handle_message(0, message).await.unwrap();
+        }
+        assert!(!manager.connections[0].is_choking());
+        assert_eq!(pieces, manager.connections[0].available_pieces().count());
+        // Interest only follows the bitfield showing pieces we're missing
+        fake.play(vec![Step::Expect(Message::Interested)])
+            .await
+            .unwrap();
+
+        fake.send(Message::choke()).await.unwrap();
+        let (_, frame) = manager.read_ready().await.pop().unwrap();
+        let message = manager.connections[0]
+            .take_message(frame)
+            .await
+            .unwrap()
+            .unwrap();
+        manager.handle_message(0, message).await.unwrap();
+        assert!(manager.connections[0].is_choking());
+        assert_eq!(
+            PeerEvent::Connected { peer: peer.clone() },
+            events.recv().await.unwrap()
+        );
+        assert_eq!(PeerEvent::Choked { peer }, events.recv().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn it_reports_finished_files_that_dont_match_their_md5sum() {
+        let mut bencode = b"d4:infod5:filesl".to_vec();
+        bencode.extend_from_slice(b"d6:lengthi4e6:md5sum32:e2fc714c4727ee9395f324cd2e7f331f");
+        bencode.extend_from_slice(b"4:pathl1:aee");
+        bencode.extend_from_slice(b"d6:lengthi4e6:md5sum32:00000000000000000000000000000000");
+        bencode.extend_from_slice(b"4:pathl1:bee");
+        bencode.extend_from_slice(b"e4:name1:t12:piece lengthi8e6:pieces20:");
+        bencode.extend_from_slice(&[0; 20]);
+        bencode.extend_from_slice(b"ee");
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencode).unwrap();
+        let storage = Arc::new(MemoryStorage::new(&torrent));
+        storage.write_block(0, 0, b"abcdefgh").unwrap();
+        let mut manager = ConnectionManager::new(
+            &torrent,
+            Download::from(&torrent),
+            "-FU0001-000000000000".into(),
+        );
+        manager.set_storage(storage);
+        let mut events = manager.subscribe();
+        manager.check_md5sums().await;
+        assert_eq!(
+            PeerEvent::FileMismatch { file: 1 },
+            events.recv().await.unwrap()
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn it_answers_inbound_handshakes_for_our_torrent() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let info_hash = get_info_hash(&torrent.info).unwrap();
+        let mut manager = ConnectionManager::new(
+            &torrent,
+            Download::from(&torrent),
+            "-FU0001-000000000000".into(),
+        );
+        let address = manager.listen(0).await.unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", address.port()))
+            .await
+            .unwrap();
+        let mut handshake = vec![19];
+        handshake.extend_from_slice(b"BitTorrent protocol");
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(b"-XX0001-000000000000");
+        stream.write_all(&handshake).await.unwrap();
+        let mut response = [0; 68];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(&info_hash[..], &response[28..48]);
+        assert_eq!(b"-FU0001-000000000000", &response[48..68]);
+        manager.accept_dialed().await.unwrap_or_default();
+
+        // Torrents nobody attached to the listener are refused without an answer
+        let mut stream = TcpStream::connect(("127.0.0.1", address.port()))
+            .await
+            .unwrap();
+        handshake[28..48].copy_from_slice(&[0xff; 20]);
+        stream.write_all(&handshake).await.unwrap();
+        assert_eq!(0, stream.read(&mut response).await.unwrap_or_default());
+    }
 }
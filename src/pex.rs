@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::tracker::{
+    encode_compact_peer, encode_compact_peer6, parse_compact_peers, parse_compact_peers6, Peer,
+};
+
+/// Seconds between two peer exchange messages sent to the same peer, as recommended by BEP 11
+pub const PEX_INTERVAL: u64 = 60;
+/// Upper bound of added and dropped peers in a single message
+pub const MAX_PEX_PEERS: usize = 50;
+
+pub const FLAG_PREFERS_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_SUPPORTS_UTP: u8 = 0x04;
+pub const FLAG_SUPPORTS_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(default)]
+    #[serde(rename = "added.f")]
+    pub added_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(default)]
+    #[serde(rename = "added6.f")]
+    pub added6_flags: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+impl PexMessage {
+    pub fn new(added: &[(Peer, u8)], dropped: &[Peer]) -> Self {
+        let mut message = Self::default();
+        for (peer, flags) in added.iter().take(MAX_PEX_PEERS) {
+            if let Some(compact) = encode_compact_peer(peer) {
+                message.added.extend_from_slice(&compact);
+                message.added_flags.push(*flags);
+            } else if let Some(compact) = encode_compact_peer6(peer) {
+                message.added6.extend_from_slice(&compact);
+                message.added6_flags.push(*flags);
+            }
+        }
+        for peer in dropped.iter().take(MAX_PEX_PEERS) {
+            if let Some(compact) = encode_compact_peer(peer) {
+                message.dropped.extend_from_slice(&compact);
+            } else if let Some(compact) = encode_compact_peer6(peer) {
+                message.dropped6.extend_from_slice(&compact);
+            }
+        }
+        message
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(serde_bencode::from_bytes(bytes)?)
+    }
+
+    /// Added peers paired with their flags; missing flags are reported as 0
+    pub fn added_peers(&self) -> Vec<(Peer, u8)> {
+        let with_flags = |peers: Vec<Peer>, flags: &ByteBuf| {
+            peers
+                .into_iter()
+                .enumerate()
+                .map(|(i, peer)| (peer, flags.get(i).copied().unwrap_or(0)))
+                .collect::<Vec<_>>()
+        };
+        let mut added = with_flags(parse_compact_peers(&self.added), &self.added_flags);
+        added.extend(with_flags(
+            parse_compact_peers6(&self.added6),
+            &self.added6_flags,
+        ));
+        added
+    }
+
+    pub fn dropped_peers(&self) -> Vec<Peer> {
+        let mut dropped = parse_compact_peers(&self.dropped);
+        dropped.extend(parse_compact_peers6(&self.dropped6));
+        dropped
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.dropped.is_empty()
+            && self.added6.is_empty()
+            && self.dropped6.is_empty()
+    }
+}
+
+/// Remembers which peers were already advertised to a connection, so that each
+/// message only carries the difference since the previous one
+#[derive(Debug, Default)]
+pub struct PexState {
+    advertised: HashSet<Peer>,
+}
+
+impl PexState {
+    pub fn next_message(&mut self, connected: &HashSet<Peer>) -> PexMessage {
+        let added: Vec<(Peer, u8)> = connected
+            .difference(&self.advertised)
+            .take(MAX_PEX_PEERS)
+            .map(|peer| (peer.clone(), 0))
+            .collect();
+        let dropped: Vec<Peer> = self
+            .advertised
+            .difference(connected)
+            .take(MAX_PEX_PEERS)
+            .cloned()
+            .collect();
+        for (peer, _) in &added {
+            self.advertised.insert(peer.clone());
+        }
+        for peer in &dropped {
+            self.advertised.remove(peer);
+        }
+        PexMessage::new(&added, &dropped)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn peer(ip: &str, port: u16) -> Peer {
+        Peer::from_socket_addr(SocketAddr::new(ip.parse().unwrap(), port))
+    }
+
+    #[test]
+    fn pex_message_round_trip() {
+        let message = PexMessage::new(
+            &[(peer("10.0.0.1", 6881), FLAG_SEED | FLAG_REACHABLE)],
+            &[peer("192.168.1.2", 51413)],
+        );
+        assert_eq!(&message.added[..], &[10, 0, 0, 1, 0x1A, 0xE1]);
+        let decoded = PexMessage::from_bytes(&message.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            decoded.added_peers(),
+            vec![(peer("10.0.0.1", 6881), FLAG_SEED | FLAG_REACHABLE)]
+        );
+        assert_eq!(decoded.dropped_peers(), vec![peer("192.168.1.2", 51413)]);
+    }
+
+    #[test]
+    fn pex_message_carries_ipv6_peers() {
+        let message = PexMessage::new(&[(peer("::1", 6881), FLAG_SEED)], &[]);
+        assert!(message.added.is_empty());
+        assert_eq!(message.added6.len(), 18);
+        let decoded = PexMessage::from_bytes(&message.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.added_peers(), vec![(peer("::1", 6881), FLAG_SEED)]);
+    }
+
+    #[test]
+    fn pex_state_sends_only_differences() {
+        let mut state = PexState::default();
+        let mut connected = HashSet::from([peer("10.0.0.1", 6881)]);
+        assert_eq!(state.next_message(&connected).added_peers().len(), 1);
+        assert!(state.next_message(&connected).is_empty());
+
+        connected.clear();
+        connected.insert(peer("10.0.0.2", 6881));
+        let message = state.next_message(&connected);
+        assert_eq!(message.added_peers(), vec![(peer("10.0.0.2", 6881), 0)]);
+        assert_eq!(message.dropped_peers(), vec![peer("10.0.0.1", 6881)]);
+    }
+}
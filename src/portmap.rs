@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::{task::JoinHandle, time::sleep};
+use tracing::warn;
+
+use crate::{
+    natpmp::{NatPmp, Version},
+    upnp::Gateway,
+};
+
+/// Lifetime requested for each mapping, renewed at half of it while the mapping is needed
+pub const LEASE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Whichever way the router lets us forward ports to ourselves
+#[derive(Debug, Clone)]
+pub enum PortMapper {
+    NatPmp(NatPmp),
+    Upnp(Gateway),
+}
+
+impl PortMapper {
+    /// Tries PCP, then NAT-PMP and last UPnP, whose discovery is the slowest
+    pub async fn discover() -> Result<Self> {
+        for version in [Version::Pcp, Version::NatPmp] {
+            if let Ok(gateway) = NatPmp::discover(version).await {
+                return Ok(Self::NatPmp(gateway));
+            }
+        }
+        Gateway::discover()
+            .await
+            .map(Self::Upnp)
+            .map_err(|error| anyhow!("No PCP, NAT-PMP or UPnP gateway found: {}", error))
+    }
+
+    pub async fn add_port_mapping(&self, protocol: Protocol, port: u16) -> Result<()> {
+        match self {
+            Self::NatPmp(gateway) => gateway.add_port_mapping(protocol, port).await,
+            Self::Upnp(gateway) => gateway.add_port_mapping(protocol, port).await,
+        }
+    }
+
+    pub async fn delete_port_mapping(&self, protocol: Protocol, port: u16) -> Result<()> {
+        match self {
+            Self::NatPmp(gateway) => gateway.delete_port_mapping(protocol, port).await,
+            Self::Upnp(gateway) => gateway.delete_port_mapping(protocol, port).await,
+        }
+    }
+}
+
+/// Maps `port` and renews the mapping every half lease until the returned task is aborted
+pub fn keep_mapped(mapper: PortMapper, protocol: Protocol, port: u16) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            if let Err(error) = mapper.add_port_mapping(protocol, port).await {
+                warn!("Could not map port {}: {:?}", port, error);
+            }
+            sleep(LEASE_DURATION / 2).await;
+        }
+    })
+}
@@ -0,0 +1,77 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+
+use crate::tracker::DEFAULT_PORT;
+
+/// Ports we're willing to listen on, tried in turn until one is free
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    first: u16,
+    last: u16,
+}
+
+impl Default for PortRange {
+    /// The range BitTorrent clients traditionally listen on
+    fn default() -> Self {
+        Self {
+            first: DEFAULT_PORT,
+            last: DEFAULT_PORT + 8,
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    /// Either a single port like `6881` or an inclusive range like `6881-6889`
+    fn from_str(range: &str) -> Result<Self> {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        Self::new(first.trim().parse()?, last.trim().parse()?)
+    }
+}
+
+impl PortRange {
+    pub fn new(first: u16, last: u16) -> Result<Self> {
+        if first > last {
+            return Err(anyhow!("Port range {}-{} is empty", first, last));
+        }
+        Ok(Self { first, last })
+    }
+
+    /// Every port of the range, from the first one or, when `randomized`, from a random one
+    /// wrapping around, so each launch is reachable on a different port
+    pub fn ports(&self, randomized: bool) -> impl Iterator<Item = u16> {
+        let length = (self.last - self.first) as u32 + 1;
+        let start = if randomized {
+            rand::thread_rng().gen_range(0..length)
+        } else {
+            0
+        };
+        let first = self.first;
+        (0..length).map(move |offset| first + ((start + offset) % length) as u16)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_tries_every_port_of_the_range_once() {
+        let range: PortRange = "6881-6883".parse().unwrap();
+        assert_eq!(
+            vec![6881, 6882, 6883],
+            range.ports(false).collect::<Vec<_>>()
+        );
+        let mut randomized: Vec<_> = range.ports(true).collect();
+        randomized.sort();
+        assert_eq!(vec![6881, 6882, 6883], randomized);
+        assert_eq!(
+            PortRange::new(51413, 51413).unwrap(),
+            "51413".parse().unwrap()
+        );
+        assert!("6889-6881".parse::<PortRange>().is_err());
+    }
+}
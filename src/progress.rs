@@ -0,0 +1,135 @@
+use std::time::Duration;
+
+use crate::download::{Download, FilePriority, SwarmAvailability};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProgress {
+    /// Verified bytes
+    pub done: u64,
+    pub length: u64,
+    pub priority: FilePriority,
+}
+
+/// Where a download stands, cheap enough to take every time a progress bar redraws
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DownloadProgress {
+    /// Verified bytes of the whole torrent
+    pub done: u64,
+    pub total: u64,
+    /// Bytes of the wanted pieces still missing
+    pub left: u64,
+    pub verified_pieces: usize,
+    pub pieces: usize,
+    pub files: Vec<FileProgress>,
+    /// Bytes per second over the last few seconds
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    /// Bytes per second since the download started
+    pub average_download_rate: u64,
+    /// Time until the wanted files are done at the current rate, or the average one when
+    /// nothing is coming in right now. None when there's no rate to go by
+    pub eta: Option<Duration>,
+    pub availability: SwarmAvailability,
+}
+
+impl DownloadProgress {
+    /// A snapshot of `download`, with `downloaded` bytes received in the `elapsed` time
+    /// since it started
+    pub fn of(download: &Download, rates: (u64, u64), downloaded: u64, elapsed: Duration) -> Self {
+        let (download_rate, upload_rate) = rates;
+        let files: Vec<FileProgress> = download
+            .file_progress()
+            .into_iter()
+            .zip(download.files())
+            .zip(download.file_priorities())
+            .map(|((done, span), priority)| FileProgress {
+                done,
+                length: span.length,
+                priority: *priority,
+            })
+            .collect();
+        let average_download_rate = match elapsed.as_secs() {
+            0 => 0,
+            seconds => downloaded / seconds,
+        };
+        let left = download.left();
+        let rate = match download_rate {
+            0 => average_download_rate,
+            rate => rate,
+        };
+        let eta = match (left, rate) {
+            (0, _) => Some(Duration::ZERO),
+            (_, 0) => None,
+            (left, rate) => Some(Duration::from_secs(left.div_ceil(rate))),
+        };
+        Self {
+            done: files.iter().map(|file| file.done).sum(),
+            total: files.iter().map(|file| file.length).sum(),
+            left,
+            verified_pieces: download.have.count(),
+            pieces: download.pieces.len(),
+            files,
+            download_rate,
+            upload_rate,
+            average_download_rate,
+            eta,
+            availability: download.swarm_availability(),
+        }
+    }
+
+    /// Share of the torrent verified, between 0 and 1
+    pub fn fraction(&self) -> f64 {
+        match self.total {
+            0 => 1.0,
+            total => self.done as f64 / total as f64,
+        }
+    }
+
+    /// One line for a terminal, e.g.
+    /// `42.0% 120/285 pieces 1024.0 KB/s down 12.0 KB/s up 3.25 copies ETA 3m20s`
+    pub fn render_line(&self) -> String {
+        let eta = match self.eta {
+            Some(eta) if eta.as_secs() >= 3600 => {
+                format!("{}h{:02}m", eta.as_secs() / 3600, eta.as_secs() % 3600 / 60)
+            }
+            Some(eta) => format!("{}m{:02}s", eta.as_secs() / 60, eta.as_secs() % 60),
+            None => "unknown".into(),
+        };
+        format!(
+            "{:.1}% {}/{} pieces {:.1} KB/s down {:.1} KB/s up {:.2} copies ETA {}",
+            self.fraction() * 100.0,
+            self.verified_pieces,
+            self.pieces,
+            self.download_rate as f64 / 1024.0,
+            self.upload_rate as f64 / 1024.0,
+            self.availability.distributed_copies,
+            eta
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_torrent::parse_torrent;
+
+    #[test]
+    fn it_sums_up_verified_pieces() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        let piece_length = download.pieces[0].length as u64;
+        download.mark_have(0);
+        download.mark_have(1);
+        let progress =
+            DownloadProgress::of(&download, (0, 0), 2 * piece_length, Duration::from_secs(2));
+        assert_eq!(2 * piece_length, progress.done);
+        assert_eq!(vec![2 * piece_length], vec![progress.files[0].done]);
+        assert_eq!(piece_length, progress.average_download_rate);
+        assert_eq!(
+            Some(Duration::from_secs(progress.left.div_ceil(piece_length))),
+            progress.eta
+        );
+        assert!(progress.render_line().contains("% 2/"));
+        assert!(progress.render_line().contains(" 0.0 KB/s down "));
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::time::sleep;
+
+/// How often the queue looks for torrents that finished
+pub const QUEUE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many torrents may download and seed at once, None for no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub downloads: Option<usize>,
+    pub seeds: Option<usize>,
+}
+
+impl QueueLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.downloads.is_none() && self.seeds.is_none()
+    }
+}
+
+/// The switches of one torrent the queue goes by
+#[derive(Debug, Clone)]
+pub struct QueuedTorrent {
+    pub finished: Arc<AtomicBool>,
+    pub pause: Arc<AtomicBool>,
+}
+
+/// Which torrents may be active given whether each is finished, in queue order: the first
+/// `downloads` unfinished ones and the first `seeds` finished ones
+pub fn active_torrents(finished: &[bool], limits: QueueLimits) -> Vec<bool> {
+    let (mut downloading, mut seeding) = (0, 0);
+    finished
+        .iter()
+        .map(|finished| {
+            let (active, limit) = if *finished {
+                (&mut seeding, limits.seeds)
+            } else {
+                (&mut downloading, limits.downloads)
+            };
+            *active += 1;
+            limit.is_none_or(|limit| *active <= limit)
+        })
+        .collect()
+}
+
+/// Pauses and resumes `torrents` so `limits` hold, the next queued download starting as
+/// soon as one finishes. Never returns, and does nothing without limits so torrents
+/// paused by hand stay paused
+pub async fn keep_queued(torrents: Vec<QueuedTorrent>, limits: QueueLimits) {
+    if limits.is_unlimited() {
+        return std::future::pending().await;
+    }
+    loop {
+        let finished: Vec<bool> = torrents
+            .iter()
+            .map(|torrent| torrent.finished.load(Ordering::Relaxed))
+            .collect();
+        for (torrent, active) in torrents.iter().zip(active_torrents(&finished, limits)) {
+            torrent.pause.store(!active, Ordering::Relaxed);
+        }
+        sleep(QUEUE_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_activates_torrents_in_queue_order() {
+        let limits = QueueLimits {
+            downloads: Some(2),
+            seeds: Some(1),
+        };
+        let finished = [false, true, false, true, false];
+        assert_eq!(
+            vec![true, true, true, false, false],
+            active_torrents(&finished, limits)
+        );
+        // The first download finishing lets the last one start, and waits to seed
+        let finished = [true, true, false, true, false];
+        assert_eq!(
+            vec![true, false, true, false, true],
+            active_torrents(&finished, limits)
+        );
+        assert!(active_torrents(&finished, QueueLimits::default())
+            .into_iter()
+            .all(|active| active));
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Bandwidth cap in bytes per second that allows bursts of up to one second worth of bytes.
+/// Consuming more than is available puts the bucket in debt, which callers wait out.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second, 0 means unlimited
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn shared(rate: u64) -> SharedBucket {
+        Arc::new(Mutex::new(Self::new(rate)))
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: u64) {
+        self.refill();
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = now;
+    }
+
+    /// Takes `tokens` out of the bucket only if it has them, for callers that would rather
+    /// do something else than wait
+    pub fn try_consume(&mut self, tokens: usize) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        self.refill();
+        if self.tokens < tokens as f64 {
+            return false;
+        }
+        self.tokens -= tokens as f64;
+        true
+    }
+
+    /// Takes `bytes` out of the bucket, returning how long to wait before they may hit the socket
+    pub fn consume(&mut self, bytes: usize) -> Duration {
+        if self.rate == 0 {
+            return Duration::ZERO;
+        }
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// A bucket that can be shared by many connections, e.g. for a global cap
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+/// Session wide buckets every connection of every torrent is charged to. Clones share
+/// the buckets, so the rates can be changed while the torrents run
+#[derive(Debug, Clone)]
+pub struct GlobalRateLimits {
+    pub upload: SharedBucket,
+    pub download: SharedBucket,
+}
+
+impl Default for GlobalRateLimits {
+    fn default() -> Self {
+        Self {
+            upload: TokenBucket::shared(0),
+            download: TokenBucket::shared(0),
+        }
+    }
+}
+
+impl GlobalRateLimits {
+    /// Bytes per second sent to all peers together, 0 for no limit
+    pub fn set_upload_rate(&self, rate: u64) {
+        self.upload.lock().unwrap().set_rate(rate);
+    }
+
+    pub fn set_download_rate(&self, rate: u64) {
+        self.download.lock().unwrap().set_rate(rate);
+    }
+
+    pub fn upload_rate(&self) -> u64 {
+        self.upload.lock().unwrap().rate()
+    }
+
+    pub fn download_rate(&self) -> u64 {
+        self.download.lock().unwrap().rate()
+    }
+}
+
+/// Ceilings in bytes per second for transfers with a single peer, 0 for none
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerRateCaps {
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// Every bucket one direction of a connection is subject to, typically its own and the global one
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    buckets: Vec<SharedBucket>,
+}
+
+impl RateLimiter {
+    pub fn add(&mut self, bucket: SharedBucket) {
+        self.buckets.push(bucket);
+    }
+
+    pub fn is_limited(&self) -> bool {
+        !self.buckets.is_empty()
+    }
+
+    /// Charges `bytes` to every bucket and waits as long as the most depleted one requires
+    pub async fn throttle(&self, bytes: usize) {
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.lock().unwrap().consume(bytes))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_goes_into_debt() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(Duration::ZERO, bucket.consume(1000));
+        let wait = bucket.consume(500);
+        assert!(wait > Duration::from_millis(490) && wait <= Duration::from_millis(500));
+        assert_eq!(Duration::ZERO, TokenBucket::new(0).consume(usize::MAX));
+    }
+
+    #[test]
+    fn global_limits_change_for_every_clone() {
+        let limits = GlobalRateLimits::default();
+        let mut limiter = RateLimiter::default();
+        limiter.add(limits.upload.clone());
+        limits.clone().set_upload_rate(1000);
+        assert_eq!(1000, limits.upload_rate());
+        assert_eq!(0, limits.download_rate());
+        assert!(!limits.upload.lock().unwrap().try_consume(500));
+    }
+
+    #[test]
+    fn bucket_refuses_what_it_lacks() {
+        let mut bucket = TokenBucket::new(10);
+        assert!(bucket.try_consume(10));
+        assert!(!bucket.try_consume(1));
+        assert!(TokenBucket::new(0).try_consume(usize::MAX));
+    }
+}
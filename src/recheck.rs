@@ -0,0 +1,195 @@
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use futures::future::try_join_all;
+use tokio::{sync::mpsc, task::spawn_blocking};
+
+use crate::{
+    bitfield::Bitfield,
+    parse_torrent::{content_paths, file_spans, total_length, TorrentFile},
+    storage::Storage,
+};
+
+/// Threads hashing pieces at once unless told otherwise
+pub const DEFAULT_RECHECK_WORKERS: usize = 4;
+
+/// How far a re-check got, reported after every piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecheckProgress {
+    pub checked: usize,
+    pub total: usize,
+    /// Pieces that matched their hash so far
+    pub valid: usize,
+}
+
+/// How the pieces of one file fared in a re-check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCheck {
+    pub path: PathBuf,
+    pub pieces: Range<usize>,
+    /// Pieces of the file that matched their hash
+    pub valid: usize,
+}
+
+impl FileCheck {
+    pub fn is_complete(&self) -> bool {
+        self.valid == self.pieces.len()
+    }
+}
+
+/// What the workers share: where the content is and the expected hashes
+struct Pieces {
+    storage: Arc<dyn Storage>,
+    piece_length: usize,
+    total_length: usize,
+    hashes: Vec<u8>,
+}
+
+impl Pieces {
+    fn number_of_pieces(&self) -> usize {
+        self.hashes.len() / 20
+    }
+
+    fn is_valid(&self, piece: usize) -> Result<bool> {
+        let start = piece * self.piece_length;
+        let length = self.piece_length.min(self.total_length - start);
+        let expected = &self.hashes[piece * 20..piece * 20 + 20];
+        self.storage.verify(piece, length, expected)
+    }
+}
+
+/// Hashes the content of `torrent` already in `storage` piece by piece on `workers`
+/// blocking threads, returning the pieces that match
+pub async fn recheck(
+    torrent: &TorrentFile,
+    storage: Arc<dyn Storage>,
+    workers: usize,
+    mut progress: impl FnMut(RecheckProgress),
+) -> Result<Bitfield> {
+    let pieces = Arc::new(Pieces {
+        storage,
+        piece_length: torrent.info.piece_length as usize,
+        total_length: total_length(torrent) as usize,
+        hashes: torrent.info.pieces.to_vec(),
+    });
+    let total = pieces.number_of_pieces();
+    let mut valid = Bitfield::new(total);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (checked, mut results) = mpsc::unbounded_channel();
+    let workers: Vec<_> = (0..workers.max(1))
+        .map(|_| {
+            let (pieces, next, checked) = (pieces.clone(), next.clone(), checked.clone());
+            spawn_blocking(move || -> Result<()> {
+                loop {
+                    let piece = next.fetch_add(1, Ordering::Relaxed);
+                    if piece >= pieces.number_of_pieces() {
+                        return Ok(());
+                    }
+                    let _ = checked.send((piece, pieces.is_valid(piece)?));
+                }
+            })
+        })
+        .collect();
+    drop(checked);
+    let mut done = RecheckProgress {
+        checked: 0,
+        total,
+        valid: 0,
+    };
+    while let Some((piece, matches)) = results.recv().await {
+        done.checked += 1;
+        if matches {
+            valid.set(piece);
+            done.valid += 1;
+        }
+        progress(done);
+    }
+    for worker in try_join_all(workers).await? {
+        worker?;
+    }
+    Ok(valid)
+}
+
+/// The results of a re-check that found `valid`, file by file under `content_dir`. Pieces
+/// spanning two files count for both
+pub fn check_files(torrent: &TorrentFile, content_dir: &Path, valid: &Bitfield) -> Vec<FileCheck> {
+    let piece_length = torrent.info.piece_length as u64;
+    file_spans(torrent)
+        .into_iter()
+        .zip(content_paths(torrent, content_dir))
+        .map(|(span, path)| {
+            let pieces = if span.length == 0 {
+                0..0
+            } else {
+                let first = span.offset / piece_length;
+                let last = (span.offset + span.length - 1) / piece_length;
+                first as usize..last as usize + 1
+            };
+            FileCheck {
+                path,
+                valid: pieces.clone().filter(|piece| valid.has(*piece)).count(),
+                pieces,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::FileStorage;
+    use sha1::{Digest, Sha1};
+
+    #[tokio::test]
+    async fn it_keeps_the_pieces_that_match() {
+        let content: Vec<u8> = (0..20000_u32).map(|byte| byte as u8).collect();
+        let mut bencoded =
+            b"d4:infod6:lengthi20000e4:name10:recheck.rs12:piece lengthi16384e6:pieces40:".to_vec();
+        bencoded.extend_from_slice(&Sha1::digest(&content[..16384]));
+        bencoded.extend_from_slice(&Sha1::digest(b"something else"));
+        bencoded.extend_from_slice(b"ee");
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencoded).unwrap();
+        let content_dir = std::env::temp_dir().join("furia-recheck-test");
+        std::fs::create_dir_all(&content_dir).unwrap();
+        std::fs::write(content_dir.join("recheck.rs"), &content).unwrap();
+
+        let storage = Arc::new(FileStorage::new(&torrent, &content_dir));
+        let mut reports = Vec::new();
+        let valid = recheck(&torrent, storage.clone(), 2, |progress| {
+            reports.push(progress)
+        })
+        .await
+        .unwrap();
+        assert!(valid.has(0) && !valid.has(1));
+        let files = check_files(&torrent, &content_dir, &valid);
+        assert_eq!(
+            vec![FileCheck {
+                path: content_dir.join("recheck.rs"),
+                pieces: 0..2,
+                valid: 1,
+            }],
+            files
+        );
+        assert!(!files[0].is_complete());
+        assert_eq!(
+            Some(&RecheckProgress {
+                checked: 2,
+                total: 2,
+                valid: 1
+            }),
+            reports.last()
+        );
+        std::fs::remove_dir_all(&content_dir).unwrap();
+        assert_eq!(
+            0,
+            recheck(&torrent, storage, 2, |_| {}).await.unwrap().count()
+        );
+    }
+}
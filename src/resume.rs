@@ -0,0 +1,324 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    bitfield::Bitfield,
+    download::{Download, Piece},
+    parse_torrent::{content_paths, file_spans, TorrentFile},
+    storage::Storage,
+    tracker::get_info_hash,
+};
+
+/// Blocks of a piece that arrived before we stopped, stored with `store_partial`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub piece: u32,
+    #[serde(rename = "block size")]
+    pub block_size: u32,
+    pub blocks: ByteBuf,
+}
+
+/// What a torrent had downloaded when it was last saved, so restarting it doesn't mean
+/// downloading or checking everything again. Bencoded like the torrent itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info hash")]
+    pub info_hash: ByteBuf,
+    /// Bitfield of the verified pieces
+    pub pieces: ByteBuf,
+    pub partial: Vec<PartialPiece>,
+    /// Modification time of every file of the torrent in seconds, 0 for missing files.
+    /// Pieces of a file changed since are checked again
+    #[serde(rename = "file mtimes")]
+    pub file_mtimes: Vec<i64>,
+}
+
+/// The state of a download taken on the runtime, for `store` to write from a blocking thread
+pub struct Snapshot {
+    data: ResumeData,
+    blocks: Vec<(usize, u64, Vec<u8>)>,
+    files: Vec<PathBuf>,
+}
+
+impl Snapshot {
+    /// The state of `download`, with its files under `content_dir`
+    pub fn take(torrent: &TorrentFile, download: &Download, content_dir: &Path) -> Result<Self> {
+        let mut partial = Vec::new();
+        let mut blocks = Vec::new();
+        for (index, piece) in partial_pieces(download) {
+            partial.push(PartialPiece {
+                piece: index as u32,
+                block_size: piece.block_size(),
+                blocks: ByteBuf::from(piece.received_blocks().as_bytes().to_vec()),
+            });
+            for (begin, data) in piece.received_data() {
+                blocks.push((index, begin as u64, data.to_vec()));
+            }
+        }
+        let data = ResumeData {
+            info_hash: ByteBuf::from(get_info_hash(&torrent.info)?),
+            pieces: ByteBuf::from(download.have.as_bytes().to_vec()),
+            partial,
+            file_mtimes: Vec::new(),
+        };
+        Ok(Self {
+            data,
+            blocks,
+            files: content_paths(torrent, content_dir),
+        })
+    }
+
+    /// Writes the blocks of partial pieces to `storage` and syncs it, for `restore` to read
+    /// them back, then stamps the files as they are after. Only verified pieces are written
+    /// to their files otherwise
+    pub fn store(self, storage: &dyn Storage) -> Result<ResumeData> {
+        for (piece, begin, data) in &self.blocks {
+            storage.write_block(*piece, *begin, data)?;
+        }
+        if !self.blocks.is_empty() {
+            storage.flush()?;
+        }
+        Ok(ResumeData {
+            file_mtimes: file_mtimes(&self.files),
+            ..self.data
+        })
+    }
+}
+
+impl ResumeData {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_bencode::from_bytes(&fs::read(path)?)?)
+    }
+
+    /// Writes next to `path` first and renames, so a crash never leaves half a file behind
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        fs::write(&partial, serde_bencode::to_bytes(self)?)?;
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// Marks the saved pieces as had and restores partial ones from `storage`. Those of files
+    /// modified since the save are only had if they still match their hash, and never
+    /// partial. Returns how many pieces were restored
+    pub fn restore(
+        &self,
+        torrent: &TorrentFile,
+        download: &mut Download,
+        content_dir: &Path,
+        storage: &dyn Storage,
+    ) -> Result<usize> {
+        if self.info_hash.as_slice() != get_info_hash(&torrent.info)? {
+            return Err(anyhow!("Resume data belongs to another torrent"));
+        }
+        let number_of_pieces = download.pieces.len();
+        let have = Bitfield::from_bytes(&self.pieces, number_of_pieces)?;
+        let piece_length = torrent.info.piece_length as u64;
+        let mut changed = Bitfield::new(number_of_pieces);
+        let mtimes = file_mtimes(&content_paths(torrent, content_dir));
+        for (span, (saved, current)) in file_spans(torrent)
+            .iter()
+            .zip(self.file_mtimes.iter().zip(&mtimes))
+        {
+            if saved == current || span.length == 0 {
+                continue;
+            }
+            let first = span.offset / piece_length;
+            let last = (span.offset + span.length - 1) / piece_length;
+            for piece in first..=last {
+                changed.set(piece as usize);
+            }
+        }
+        let mut restored = 0;
+        for piece in have.pieces() {
+            let original = &download.pieces[piece];
+            if changed.has(piece)
+                && !storage.verify(piece, original.length, &original.original_sha1)?
+            {
+                continue;
+            }
+            download.mark_have(piece);
+            restored += 1;
+        }
+        for partial in &self.partial {
+            let piece = partial.piece as usize;
+            if piece >= number_of_pieces || changed.has(piece) || download.have.has(piece) {
+                continue;
+            }
+            let blocks = download.pieces[piece]
+                .length
+                .div_ceil(partial.block_size.max(1) as usize);
+            let blocks = Bitfield::from_bytes(&partial.blocks, blocks)?;
+            let load = |begin, length| storage.read_block(piece, begin, length);
+            if download.restore_partial(piece, partial.block_size, blocks, load) {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+}
+
+/// Pieces with some but not all blocks in memory, which `Snapshot` saves the block map of.
+/// Complete ones are being checked and get downloaded again should we stop first
+fn partial_pieces(download: &Download) -> impl Iterator<Item = (usize, &Piece)> {
+    download.pieces.iter().enumerate().filter(|(index, piece)| {
+        let received = piece.received_bytes();
+        !download.have.has(*index)
+            && piece.content.is_some()
+            && received > 0
+            && received < piece.length
+    })
+}
+
+fn file_mtimes(paths: &[PathBuf]) -> Vec<i64> {
+    paths
+        .iter()
+        .map(|path| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs() as i64)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        messages::Block,
+        parse_torrent::parse_torrent,
+        storage::{FileStorage, MemoryStorage},
+    };
+    use bytes::Bytes;
+    use sha1::{Digest, Sha1};
+
+    #[test]
+    fn it_restores_verified_and_partial_pieces() {
+        let torrent = parse_torrent("./data/ubuntu-22.04.3-live-server-amd64.iso.torrent");
+        let mut download = Download::from(&torrent);
+        download.mark_have(0);
+        download.mark_have(7);
+        let block = Block {
+            index: 3,
+            begin: 16384,
+            data: Bytes::from(vec![1; 16384]),
+        };
+        download.add_block(&block).unwrap();
+        let content_dir = std::env::temp_dir().join("furia-resume-test");
+        let path = content_dir.with_extension("resume");
+        let storage = FileStorage::new(&torrent, &content_dir);
+        Snapshot::take(&torrent, &download, &content_dir)
+            .unwrap()
+            .store(&storage)
+            .unwrap()
+            .save(&path)
+            .unwrap();
+
+        let mut resumed = Download::from(&torrent);
+        let data = ResumeData::load(&path).unwrap();
+        assert_eq!(
+            3,
+            data.restore(&torrent, &mut resumed, &content_dir, &storage)
+                .unwrap()
+        );
+        assert!(resumed.have.has(0) && resumed.have.has(7) && !resumed.have.has(3));
+        assert_eq!(16384, resumed.pieces[3].received_bytes());
+        assert!(resumed.pieces[3].received_blocks().has(1));
+        fs::remove_file(path).unwrap();
+        fs::remove_dir_all(content_dir).unwrap();
+    }
+
+    #[test]
+    fn it_verifies_a_restored_piece_once_complete() {
+        let content = [7_u8; 40000];
+        let mut bencode =
+            b"d4:infod6:lengthi40000e4:name1:a12:piece lengthi32768e6:pieces40:".to_vec();
+        bencode.extend_from_slice(&Sha1::digest(&content[..32768]));
+        bencode.extend_from_slice(&Sha1::digest(&content[32768..]));
+        bencode.extend_from_slice(b"ee");
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencode).unwrap();
+        let block = |begin: usize| Block {
+            index: 0,
+            begin: begin as u32,
+            data: Bytes::copy_from_slice(&content[begin..begin + 16384]),
+        };
+        let mut download = Download::from(&torrent);
+        download.add_block(&block(0)).unwrap();
+        let storage = MemoryStorage::new(&torrent);
+        let content_dir = std::env::temp_dir().join("furia-resume-partial-test");
+        let data = Snapshot::take(&torrent, &download, &content_dir)
+            .unwrap()
+            .store(&storage)
+            .unwrap();
+
+        let mut resumed = Download::from(&torrent);
+        assert_eq!(
+            1,
+            data.restore(&torrent, &mut resumed, &content_dir, &storage)
+                .unwrap()
+        );
+        assert!(resumed.add_block(&block(16384)).unwrap());
+        assert!(resumed.verify_piece(0));
+        // Blocks missing from storage aren't restored
+        let mut missing = Download::from(&torrent);
+        let storage = FileStorage::new(&torrent, &content_dir);
+        assert_eq!(
+            0,
+            data.restore(&torrent, &mut missing, &content_dir, &storage)
+                .unwrap()
+        );
+        assert_eq!(0, missing.pieces[0].received_bytes());
+    }
+
+    #[test]
+    fn it_checks_the_pieces_of_changed_files_again() {
+        let content = [3_u8; 40000];
+        let mut bencode =
+            b"d4:infod6:lengthi40000e4:name1:b12:piece lengthi32768e6:pieces40:".to_vec();
+        bencode.extend_from_slice(&Sha1::digest(&content[..32768]));
+        bencode.extend_from_slice(&Sha1::digest(&content[32768..]));
+        bencode.extend_from_slice(b"ee");
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencode).unwrap();
+        let content_dir = std::env::temp_dir().join("furia-resume-changed-test");
+        let storage = FileStorage::new(&torrent, &content_dir);
+        storage.write_block(0, 0, &content).unwrap();
+        storage.flush().unwrap();
+        let mut download = Download::from(&torrent);
+        download.mark_have(0);
+        download.mark_have(1);
+        let data = Snapshot::take(&torrent, &download, &content_dir)
+            .unwrap()
+            .store(&storage)
+            .unwrap();
+
+        // The second piece gets corrupted and the file touched
+        storage.write_block(1, 0, &[0; 100]).unwrap();
+        storage.flush().unwrap();
+        let path = &content_paths(&torrent, &content_dir)[0];
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH)
+            .unwrap();
+        let mut resumed = Download::from(&torrent);
+        assert_eq!(
+            1,
+            data.restore(&torrent, &mut resumed, &content_dir, &storage)
+                .unwrap()
+        );
+        assert!(resumed.have.has(0) && !resumed.have.has(1));
+        fs::remove_dir_all(content_dir).unwrap();
+    }
+}
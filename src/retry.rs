@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+/// Wait before the first retry of a failed dial, doubled after every further failure
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(15);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+/// Failed dials after which an address is given up on
+pub const MAX_DIAL_ATTEMPTS: u32 = 5;
+
+/// Why and how often dialing a candidate failed, and when to try again
+#[derive(Debug, Clone)]
+pub struct DialRetry {
+    pub failures: u32,
+    pub next_attempt: Instant,
+    pub last_error: String,
+}
+
+impl DialRetry {
+    pub fn new() -> Self {
+        Self {
+            failures: 0,
+            next_attempt: Instant::now(),
+            last_error: String::new(),
+        }
+    }
+
+    /// Records a failure at `now`, returning false once the address isn't worth retrying
+    pub fn failed(&mut self, now: Instant, error: String) -> bool {
+        self.failures += 1;
+        self.last_error = error;
+        self.next_attempt = now + Self::backoff(self.failures);
+        self.failures < MAX_DIAL_ATTEMPTS
+    }
+
+    pub fn backoff(failures: u32) -> Duration {
+        INITIAL_BACKOFF
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(MAX_BACKOFF)
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_attempt
+    }
+}
+
+impl Default for DialRetry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_backs_off_exponentially_then_gives_up() {
+        let mut retry = DialRetry::new();
+        let now = Instant::now();
+        assert!(retry.is_due(now));
+        assert!(retry.failed(now, "refused".into()));
+        assert!(!retry.is_due(now + Duration::from_secs(14)));
+        assert!(retry.is_due(now + INITIAL_BACKOFF));
+        assert_eq!(INITIAL_BACKOFF * 4, DialRetry::backoff(3));
+        assert_eq!(MAX_BACKOFF, DialRetry::backoff(30));
+        for _ in 1..MAX_DIAL_ATTEMPTS - 1 {
+            assert!(retry.failed(now, "refused".into()));
+        }
+        assert!(!retry.failed(now, "refused".into()));
+    }
+}
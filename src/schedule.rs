@@ -0,0 +1,198 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use tokio::{task::JoinHandle, time::sleep};
+
+use crate::ratelimit::GlobalRateLimits;
+
+/// How often the schedule is looked at again, well under a minute so windows start on time
+pub const SCHEDULE_INTERVAL: Duration = Duration::from_secs(15);
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Session wide rates in bytes per second, 0 for no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rates {
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// Minutes since midnight, written `HH:MM`. `24:00` is accepted as the end of a day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(u16);
+
+impl FromStr for TimeOfDay {
+    type Err = anyhow::Error;
+
+    fn from_str(time: &str) -> Result<Self> {
+        let (hours, minutes) = time
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| anyhow!("{} isn't HH:MM", time))?;
+        let (hours, minutes): (u16, u16) = (hours.parse()?, minutes.parse()?);
+        if hours > 24 || minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+            return Err(anyhow!("{} isn't a time of day", time));
+        }
+        Ok(Self(hours * 60 + minutes))
+    }
+}
+
+impl TimeOfDay {
+    pub fn minute(&self) -> u16 {
+        self.0 % MINUTES_PER_DAY
+    }
+}
+
+/// Rates that apply from `from` until `until`, past midnight when `until` comes first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleRule {
+    pub from: TimeOfDay,
+    pub until: TimeOfDay,
+    pub rates: Rates,
+}
+
+impl FromStr for ScheduleRule {
+    type Err = anyhow::Error;
+
+    /// `08:00-23:00=1048576` caps both directions, `08:00-23:00=1048576/262144` sets the
+    /// download and upload rates apart
+    fn from_str(rule: &str) -> Result<Self> {
+        let (window, rates) = rule
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Schedule rule {} has no rate", rule))?;
+        let (from, until) = window
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Schedule rule {} has no end", rule))?;
+        let (download, upload) = rates.split_once('/').unwrap_or((rates, rates));
+        Ok(Self {
+            from: from.parse()?,
+            until: until.parse()?,
+            rates: Rates {
+                upload: upload.trim().parse()?,
+                download: download.trim().parse()?,
+            },
+        })
+    }
+}
+
+impl ScheduleRule {
+    /// A window starting and ending at the same time covers the whole day
+    pub fn contains(&self, minute: u16) -> bool {
+        let (from, until) = (self.from.minute(), self.until.minute());
+        if from < until {
+            (from..until).contains(&minute)
+        } else {
+            minute >= from || minute < until
+        }
+    }
+}
+
+/// Rates depending on the time of day, the first rule covering the current time wins and
+/// `otherwise` applies outside of every rule
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BandwidthSchedule {
+    rules: Vec<ScheduleRule>,
+    otherwise: Rates,
+}
+
+impl FromStr for BandwidthSchedule {
+    type Err = anyhow::Error;
+
+    /// Comma separated rules, unlimited outside of them
+    fn from_str(rules: &str) -> Result<Self> {
+        let rules = rules
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<ScheduleRule>>>()?;
+        Ok(Self {
+            rules,
+            otherwise: Rates::default(),
+        })
+    }
+}
+
+impl BandwidthSchedule {
+    pub fn new(rules: Vec<ScheduleRule>, otherwise: Rates) -> Self {
+        Self { rules, otherwise }
+    }
+
+    pub fn set_otherwise(&mut self, otherwise: Rates) {
+        self.otherwise = otherwise;
+    }
+
+    pub fn rates_at(&self, minute: u16) -> Rates {
+        self.rules
+            .iter()
+            .find(|rule| rule.contains(minute))
+            .map_or(self.otherwise, |rule| rule.rates)
+    }
+}
+
+/// Minutes since local midnight
+#[cfg(unix)]
+pub fn local_minute_of_day() -> u16 {
+    let mut local: libc::tm = unsafe { std::mem::zeroed() };
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    if unsafe { libc::localtime_r(&now, &mut local) }.is_null() {
+        return utc_minute_of_day();
+    }
+    (local.tm_hour * 60 + local.tm_min) as u16
+}
+
+#[cfg(not(unix))]
+pub fn local_minute_of_day() -> u16 {
+    utc_minute_of_day()
+}
+
+fn utc_minute_of_day() -> u16 {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_epoch.as_secs() / 60 % MINUTES_PER_DAY as u64) as u16
+}
+
+/// Sets the session rates `schedule` calls for now and whenever they change, until the
+/// returned task is aborted
+pub fn follow_schedule(limits: GlobalRateLimits, schedule: BandwidthSchedule) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut current = None;
+        loop {
+            let rates = schedule.rates_at(local_minute_of_day());
+            if current != Some(rates) {
+                limits.set_upload_rate(rates.upload);
+                limits.set_download_rate(rates.download);
+                current = Some(rates);
+            }
+            sleep(SCHEDULE_INTERVAL).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_picks_the_rates_of_the_time_of_day() {
+        let mut schedule: BandwidthSchedule = "08:00-23:00=1048576, 23:30-06:00=2000/1000"
+            .parse()
+            .unwrap();
+        let capped = Rates {
+            upload: 1048576,
+            download: 1048576,
+        };
+        assert_eq!(capped, schedule.rates_at(8 * 60));
+        assert_eq!(capped, schedule.rates_at(23 * 60 - 1));
+        assert_eq!(Rates::default(), schedule.rates_at(23 * 60));
+        let night = Rates {
+            upload: 1000,
+            download: 2000,
+        };
+        assert_eq!(night, schedule.rates_at(0));
+        assert_eq!(night, schedule.rates_at(23 * 60 + 45));
+        schedule.set_otherwise(night);
+        assert_eq!(night, schedule.rates_at(7 * 60));
+        assert!("25:00-26:00=1".parse::<BandwidthSchedule>().is_err());
+        assert!("08:00=1".parse::<BandwidthSchedule>().is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::bitfield::Bitfield;
+
+/// Transfer rate at which a peer earns half of the rate part of its score
+const HALF_SCORE_RATE: f64 = 16.0 * 1024.0;
+const RATE_WEIGHT: f64 = 0.5;
+const RELIABILITY_WEIGHT: f64 = 0.3;
+const RARITY_WEIGHT: f64 = 0.2;
+/// What we expect of a peer we never talked to: reliable, not fast as far as we know,
+/// and likely to have some of what we miss
+pub const UNTRIED_PEER_SCORE: f64 = RELIABILITY_WEIGHT + RARITY_WEIGHT / 2.0;
+/// Peers replaced at most each round, so the swarm isn't churned all at once
+pub const MAX_REPLACEMENTS: usize = 2;
+
+/// What a connected peer is worth to us, between 0 and 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerScore {
+    /// Bytes per second transferred either way
+    pub rate: u64,
+    pub pieces_contributed: u32,
+    pub hash_failures: u32,
+    /// Sent nothing for the requests it has outstanding
+    pub snubbed: bool,
+    /// From `rarity`
+    pub rarity: f64,
+}
+
+impl PeerScore {
+    pub fn value(&self) -> f64 {
+        let rate = self.rate as f64 / (self.rate as f64 + HALF_SCORE_RATE);
+        RATE_WEIGHT * rate + RELIABILITY_WEIGHT * self.reliability() + RARITY_WEIGHT * self.rarity
+    }
+
+    /// Share of the pieces it helped with that verified, benefit of the doubt until one did
+    fn reliability(&self) -> f64 {
+        if self.snubbed {
+            return 0.0;
+        }
+        let verified = self.pieces_contributed + self.hash_failures;
+        if verified == 0 {
+            1.0
+        } else {
+            self.pieces_contributed as f64 / verified as f64
+        }
+    }
+}
+
+/// How hard the pieces `bitfield` has and we miss are to find elsewhere: the inverse of
+/// the number of connected peers holding the rarest of them, 0 if it has nothing we want
+pub fn rarity(bitfield: &Bitfield, have: &Bitfield, availability: &[usize]) -> f64 {
+    bitfield
+        .pieces()
+        .filter(|piece| !have.has(*piece))
+        .map(|piece| 1.0 / availability.get(piece).copied().unwrap_or(1).max(1) as f64)
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn score(rate: u64, hash_failures: u32, rarity: f64) -> f64 {
+        PeerScore {
+            rate,
+            pieces_contributed: 4,
+            hash_failures,
+            snubbed: false,
+            rarity,
+        }
+        .value()
+    }
+
+    #[test]
+    fn it_ranks_fast_reliable_peers_with_rare_pieces_first() {
+        assert!(score(100_000, 0, 0.0) > score(1_000, 0, 0.0));
+        assert!(score(1_000, 0, 0.0) > score(1_000, 4, 0.0));
+        assert!(score(1_000, 0, 1.0) > score(1_000, 0, 0.5));
+        assert!(score(0, 4, 0.0) < UNTRIED_PEER_SCORE);
+
+        let mut bitfield = Bitfield::new(3);
+        bitfield.set(0);
+        bitfield.set(2);
+        let mut have = Bitfield::new(3);
+        have.set(0);
+        assert_eq!(0.5, rarity(&bitfield, &have, &[1, 3, 2]));
+        assert_eq!(0.0, rarity(&have, &have, &[1, 3, 2]));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::messages::Message;
+
+/// The choke and interest flags each side of a connection keeps about the other,
+/// both sides start out choking and not interested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+impl PeerState {
+    /// Applies a message received from the peer, returning whether it changed anything
+    pub fn receive(&mut self, message: &Message) -> bool {
+        let previous = *self;
+        match message {
+            Message::Choke => self.peer_choking = true,
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+        previous != *self
+    }
+
+    /// Returns whether a choke or unchoke has to be sent to reach `choking`
+    pub fn set_am_choking(&mut self, choking: bool) -> bool {
+        std::mem::replace(&mut self.am_choking, choking) != choking
+    }
+
+    /// Returns whether an interested or not interested has to be sent to reach `interested`
+    pub fn set_am_interested(&mut self, interested: bool) -> bool {
+        std::mem::replace(&mut self.am_interested, interested) != interested
+    }
+
+    /// Blocks can flow from the peer to us
+    pub fn can_download(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+
+    /// Blocks can flow from us to the peer
+    pub fn can_upload(&self) -> bool {
+        self.peer_interested && !self.am_choking
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_follows_choke_and_interest_messages() {
+        let mut state = PeerState::default();
+        assert!(!state.can_download());
+        assert!(state.set_am_interested(true));
+        assert!(!state.set_am_interested(true));
+        assert!(state.receive(&Message::Unchoke));
+        assert!(state.can_download());
+        assert!(!state.receive(&Message::KeepAlive));
+
+        assert!(state.receive(&Message::Interested));
+        assert!(!state.can_upload());
+        assert!(state.set_am_choking(false));
+        assert!(state.can_upload());
+        state.receive(&Message::Choke);
+        assert!(!state.can_download());
+    }
+}
@@ -0,0 +1,79 @@
+use std::{io, time::Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// TCP tuning for peer connections, dialed and accepted alike. The OS defaults suit
+/// neither links with a large bandwidth-delay product nor our small protocol messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Sends small messages like requests and haves right away instead of coalescing them
+    pub nodelay: bool,
+    /// Bytes, the OS default when unset
+    pub send_buffer: Option<u32>,
+    pub recv_buffer: Option<u32>,
+    /// Idle time before the OS probes whether the peer is still there, no probes when unset
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            send_buffer: None,
+            recv_buffer: None,
+            keepalive: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Sets what has to be in place before connecting or listening, buffers sizes decide
+    /// the window scale negotiated in the TCP handshake. Accepted sockets inherit them
+    /// from the listening one
+    pub fn prepare(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the rest on a connected stream
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn it_tunes_connected_sockets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = SocketOptions {
+            nodelay: false,
+            send_buffer: Some(256 * 1024),
+            recv_buffer: Some(256 * 1024),
+            keepalive: Some(Duration::from_secs(60)),
+        };
+        let socket = TcpSocket::new_v4().unwrap();
+        options.prepare(&socket).unwrap();
+        assert!(socket.send_buffer_size().unwrap() >= 256 * 1024);
+        let stream = socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        options.apply(&stream).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+    }
+}
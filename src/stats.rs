@@ -0,0 +1,181 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Span over which transfer rates are averaged
+pub const RATE_WINDOW: Duration = Duration::from_secs(20);
+/// Bounds of the adaptive request pipeline
+pub const MIN_PIPELINE_DEPTH: usize = 2;
+pub const MAX_PIPELINE_DEPTH: usize = 256;
+/// Requests a new peer may have outstanding before it delivered anything
+pub const INITIAL_REQUEST_WINDOW: usize = MIN_PIPELINE_DEPTH;
+
+/// Bytes per second over the last `RATE_WINDOW`, from timestamped samples
+#[derive(Debug, Default, Clone)]
+pub struct RollingRate {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RollingRate {
+    pub fn add(&mut self, bytes: u64) {
+        self.add_at(Instant::now(), bytes);
+    }
+
+    pub fn add_at(&mut self, now: Instant, bytes: u64) {
+        self.expire(now);
+        match self.samples.back_mut() {
+            // Samples within the same second are merged to keep the queue short
+            Some((at, total)) if now.duration_since(*at) < Duration::from_secs(1) => {
+                *total += bytes
+            }
+            _ => self.samples.push_back((now, bytes)),
+        }
+    }
+
+    pub fn per_second(&self) -> u64 {
+        self.per_second_at(Instant::now())
+    }
+
+    pub fn per_second_at(&self, now: Instant) -> u64 {
+        let total: u64 = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) < RATE_WINDOW)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        total / RATE_WINDOW.as_secs()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// What a peer did for us over the whole connection, shared by the choker, banning and the UI
+#[derive(Debug, Default, Clone)]
+pub struct PeerStats {
+    /// Bytes sent to the peer, messages of any kind included
+    pub uploaded: u64,
+    /// Block bytes received from the peer
+    pub downloaded: u64,
+    pub upload_rate: RollingRate,
+    pub download_rate: RollingRate,
+    /// Block bytes that arrived after someone else had sent them, the cost of endgame
+    pub redundant: u64,
+    /// Pieces with a block from this peer that passed hash verification
+    pub pieces_contributed: u32,
+    /// Pieces with a block from this peer that failed hash verification
+    pub hash_failures: u32,
+    /// Moving average of the time between requesting a block and receiving it
+    pub latency: Option<Duration>,
+    /// When a block last went either way
+    pub last_transfer: Option<Instant>,
+}
+
+impl PeerStats {
+    pub fn record_upload(&mut self, bytes: u64) {
+        self.uploaded += bytes;
+        self.upload_rate.add(bytes);
+    }
+
+    pub fn record_download(&mut self, bytes: u64) {
+        self.downloaded += bytes;
+        self.download_rate.add(bytes);
+        self.last_transfer = Some(Instant::now());
+    }
+
+    pub fn record_latency(&mut self, sample: Duration) {
+        self.latency = Some(match self.latency {
+            Some(latency) => (latency * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+}
+
+/// Requests to keep outstanding so the peer never idles: what it delivers at `rate`
+/// during one request round trip, plus one to cover the next
+pub fn pipeline_depth_for(rate: u64, latency: Duration, block_size: u32) -> usize {
+    let in_flight = rate as f64 * latency.as_secs_f64() / block_size.max(1) as f64;
+    (in_flight.ceil() as usize + 1).clamp(MIN_PIPELINE_DEPTH, MAX_PIPELINE_DEPTH)
+}
+
+/// Slow start for requests: a new peer gets a small window, one more request for each
+/// block it delivers in time, so it doubles every round trip until the pipeline depth caps
+/// it. Timeouts halve it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestWindow {
+    size: usize,
+}
+
+impl Default for RequestWindow {
+    fn default() -> Self {
+        Self {
+            size: INITIAL_REQUEST_WINDOW,
+        }
+    }
+}
+
+impl RequestWindow {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn grow(&mut self) {
+        self.size = (self.size + 1).min(MAX_PIPELINE_DEPTH);
+    }
+
+    pub fn shrink(&mut self) {
+        self.size = (self.size / 2).max(INITIAL_REQUEST_WINDOW);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_averages_over_the_window() {
+        let start = Instant::now();
+        let mut rate = RollingRate::default();
+        rate.add_at(start, 20_000);
+        rate.add_at(start + Duration::from_millis(500), 20_000);
+        assert_eq!(2000, rate.per_second_at(start + Duration::from_secs(1)));
+        rate.add_at(start + RATE_WINDOW, 40_000);
+        assert_eq!(2000, rate.per_second_at(start + RATE_WINDOW));
+        assert_eq!(0, rate.per_second_at(start + RATE_WINDOW * 2));
+    }
+
+    #[test]
+    fn it_sizes_the_pipeline_to_the_bandwidth_delay_product() {
+        let latency = Duration::from_millis(500);
+        assert_eq!(MIN_PIPELINE_DEPTH, pipeline_depth_for(1000, latency, 16384));
+        assert_eq!(33, pipeline_depth_for(1024 * 1024, latency, 16384));
+        assert_eq!(
+            MAX_PIPELINE_DEPTH,
+            pipeline_depth_for(u32::MAX.into(), latency, 16384)
+        );
+    }
+
+    #[test]
+    fn it_grows_the_request_window_until_a_timeout() {
+        let mut window = RequestWindow::default();
+        assert_eq!(INITIAL_REQUEST_WINDOW, window.size());
+        for _ in 0..10 {
+            window.grow();
+        }
+        assert_eq!(INITIAL_REQUEST_WINDOW + 10, window.size());
+        window.shrink();
+        assert_eq!((INITIAL_REQUEST_WINDOW + 10) / 2, window.size());
+        for _ in 0..1000 {
+            window.grow();
+        }
+        assert_eq!(MAX_PIPELINE_DEPTH, window.size());
+    }
+}
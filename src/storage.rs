@@ -0,0 +1,573 @@
+#[cfg(unix)]
+use std::sync::Arc;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+
+#[cfg(unix)]
+use crate::mmap::Mapping;
+use crate::parse_torrent::{content_paths, file_spans, total_length, FileSpan, TorrentFile};
+
+/// Zeros written at once when space has to be reserved by hand
+const ZEROS_BYTES: usize = 1 << 20;
+
+/// How the files of a torrent take up disk space before their pieces arrive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Allocation {
+    /// Files get their length right away but blocks are only used as pieces are written
+    #[default]
+    Sparse,
+    /// Every byte is reserved up front, so files don't fragment and a full disk or quota
+    /// shows before downloading rather than halfway through
+    Full,
+}
+
+/// How pieces get to and from the files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileAccess {
+    /// A positioned read or write per file a block touches
+    #[default]
+    Syscalls,
+    /// Each file is mapped into memory once and blocks are copied in and out of it, much
+    /// cheaper when seeding at high rates. Unix only, elsewhere the same as syscalls.
+    /// Files must not be truncated behind our back while mapped
+    Mapped,
+}
+
+/// Where the content of a torrent is kept, addressed by piece like the wire protocol does.
+/// Files on disk unless another backend is plugged in, e.g. `MemoryStorage` for tests.
+/// Blocking: once a torrent runs it's only called from blocking threads, never on the runtime
+pub trait Storage: Send + Sync {
+    /// `length` bytes at `begin` of `piece`
+    fn read_block(&self, piece: usize, begin: u64, length: usize) -> Result<Vec<u8>>;
+
+    fn write_block(&self, piece: usize, begin: u64, data: &[u8]) -> Result<()>;
+
+    /// Makes what was written so far survive a crash
+    fn flush(&self) -> Result<()>;
+
+    /// Whether the `length` bytes of `piece` stored match its SHA-1, false when they are
+    /// missing altogether
+    fn verify(&self, piece: usize, length: usize, expected: &[u8]) -> Result<bool> {
+        match self.read_block(piece, 0, length) {
+            Ok(content) => Ok(Sha1::digest(content).as_slice() == expected),
+            Err(error) if is_missing(&error) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Reserves room for the file at `index` in the order of the torrent, for backends
+    /// that have files
+    fn allocate(&self, _index: usize, _allocation: Allocation) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether reading failed because there's nothing stored there yet
+fn is_missing(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<io::Error>().is_some_and(|error| {
+        matches!(
+            error.kind(),
+            io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+        )
+    })
+}
+
+/// The whole content in memory, unwritten bytes reading as zeros
+#[derive(Debug)]
+pub struct MemoryStorage {
+    piece_length: u64,
+    content: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new(torrent: &TorrentFile) -> Self {
+        Self {
+            piece_length: torrent.info.piece_length as u64,
+            content: Mutex::new(vec![0; total_length(torrent) as usize]),
+        }
+    }
+
+    fn range(&self, piece: usize, begin: u64, length: usize, total: usize) -> Result<Range<usize>> {
+        let start = (piece as u64 * self.piece_length + begin) as usize;
+        match start.checked_add(length) {
+            Some(end) if end <= total => Ok(start..end),
+            _ => Err(anyhow!(
+                "{} bytes at {} of piece {} are past the end of the content",
+                length,
+                begin,
+                piece
+            )),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read_block(&self, piece: usize, begin: u64, length: usize) -> Result<Vec<u8>> {
+        let content = self.content.lock().unwrap();
+        Ok(content[self.range(piece, begin, length, content.len())?].to_vec())
+    }
+
+    fn write_block(&self, piece: usize, begin: u64, data: &[u8]) -> Result<()> {
+        let mut content = self.content.lock().unwrap();
+        let range = self.range(piece, begin, data.len(), content.len())?;
+        content[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The part of one file a range of the content maps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    /// Index of the file in the torrent
+    pub file: usize,
+    /// Where the slice starts in the file
+    pub offset: u64,
+    /// Where the slice starts in the range
+    pub position: usize,
+    pub length: usize,
+}
+
+/// The files of a torrent under a content directory, pieces being read and written across
+/// file boundaries. Single file torrents are one file named after the torrent, multi-file
+/// ones a directory of that name
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    spans: Vec<FileSpan>,
+    paths: Vec<PathBuf>,
+    piece_length: u64,
+    total_length: u64,
+    access: FileAccess,
+    /// Mapped files by index, mapped on first use and shared by clones
+    #[cfg(unix)]
+    mappings: Arc<Vec<Mutex<Option<Arc<Mapping>>>>>,
+}
+
+impl FileStorage {
+    pub fn new(torrent: &TorrentFile, content_dir: &Path) -> Self {
+        Self::with_access(torrent, content_dir, FileAccess::default())
+    }
+
+    pub fn with_access(torrent: &TorrentFile, content_dir: &Path, access: FileAccess) -> Self {
+        let spans = file_spans(torrent);
+        Self {
+            #[cfg(unix)]
+            mappings: Arc::new(spans.iter().map(|_| Mutex::new(None)).collect()),
+            spans,
+            paths: content_paths(torrent, content_dir),
+            piece_length: torrent.info.piece_length as u64,
+            total_length: total_length(torrent) as u64,
+            access,
+        }
+    }
+
+    pub fn access(&self) -> FileAccess {
+        self.access
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// The slices of files `length` bytes at `begin` of `piece` span, in order
+    pub fn slices(&self, piece: usize, begin: u64, length: usize) -> Result<Vec<FileSlice>> {
+        let start = piece as u64 * self.piece_length + begin;
+        let end = start + length as u64;
+        if end > self.total_length {
+            return Err(anyhow!(
+                "{} bytes at {} of piece {} are past the end of the content",
+                length,
+                begin,
+                piece
+            ));
+        }
+        Ok(self
+            .spans
+            .iter()
+            .enumerate()
+            .filter_map(|(file, span)| {
+                let from = start.max(span.offset);
+                let to = end.min(span.offset + span.length);
+                (from < to).then(|| FileSlice {
+                    file,
+                    offset: from - span.offset,
+                    position: (from - start) as usize,
+                    length: (to - from) as usize,
+                })
+            })
+            .collect())
+    }
+
+    fn is_padding(&self, file: usize) -> bool {
+        self.spans[file].is_padding()
+    }
+
+    fn open(&self, index: usize) -> Result<File> {
+        let path = &self.paths[index];
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?)
+    }
+
+    /// The mapping of the file at `index`, which is created and grown to its full length
+    /// first when `create`
+    #[cfg(unix)]
+    fn mapping(&self, index: usize, create: bool) -> Result<Arc<Mapping>> {
+        let mut mapping = self.mappings[index].lock().unwrap();
+        if let Some(mapping) = &*mapping {
+            return Ok(mapping.clone());
+        }
+        let length = self.spans[index].length;
+        let file = if create {
+            let file = self.open(index)?;
+            if file.metadata()?.len() < length {
+                file.set_len(length)?;
+            }
+            file
+        } else {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&self.paths[index])?
+        };
+        let mapped = Arc::new(Mapping::new(&file, length as usize)?);
+        *mapping = Some(mapped.clone());
+        Ok(mapped)
+    }
+
+    fn is_mapped(&self) -> bool {
+        cfg!(unix) && self.access == FileAccess::Mapped
+    }
+}
+
+impl Storage for FileStorage {
+    /// Writes `data` at `begin` of `piece`, creating the files and their directories
+    fn write_block(&self, piece: usize, begin: u64, data: &[u8]) -> Result<()> {
+        for slice in self.slices(piece, begin, data.len())? {
+            if self.is_padding(slice.file) {
+                continue;
+            }
+            let data = &data[slice.position..slice.position + slice.length];
+            #[cfg(unix)]
+            if self.is_mapped() {
+                self.mapping(slice.file, true)?
+                    .write(slice.offset as usize, data);
+                continue;
+            }
+            let mut file = self.open(slice.file)?;
+            file.seek(SeekFrom::Start(slice.offset))?;
+            file.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    /// Reads `length` bytes at `begin` of `piece`, padding files read as zeros
+    fn read_block(&self, piece: usize, begin: u64, length: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        for slice in self.slices(piece, begin, length)? {
+            if self.is_padding(slice.file) {
+                continue;
+            }
+            let buffer = &mut data[slice.position..slice.position + slice.length];
+            #[cfg(unix)]
+            if self.is_mapped() {
+                self.mapping(slice.file, false)?
+                    .read(slice.offset as usize, buffer);
+                continue;
+            }
+            let mut file = File::open(&self.paths[slice.file])?;
+            file.seek(SeekFrom::Start(slice.offset))?;
+            file.read_exact(buffer)?;
+        }
+        Ok(data)
+    }
+
+    /// Creates the file at `index` with its full length, keeping what it already holds
+    fn allocate(&self, index: usize, allocation: Allocation) -> Result<()> {
+        if self.is_padding(index) {
+            return Ok(());
+        }
+        let file = self.open(index)?;
+        let (length, target) = (file.metadata()?.len(), self.spans[index].length);
+        if length >= target {
+            return Ok(());
+        }
+        match allocation {
+            Allocation::Sparse => file.set_len(target)?,
+            Allocation::Full => reserve(file, length, target)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        for (index, path) in self.paths.iter().enumerate() {
+            #[cfg(unix)]
+            if let Some(mapping) = &*self.mappings[index].lock().unwrap() {
+                mapping.flush()?;
+                continue;
+            }
+            if !self.is_padding(index) && path.exists() {
+                File::open(path)?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Has the filesystem reserve the blocks between `from` and `to`, writing zeros where it can't
+#[cfg(unix)]
+fn reserve(file: File, from: u64, to: u64) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let result = unsafe {
+        libc::posix_fallocate(
+            file.as_raw_fd(),
+            from as libc::off_t,
+            (to - from) as libc::off_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        libc::EINVAL | libc::EOPNOTSUPP => write_zeros(file, from, to),
+        errno => Err(std::io::Error::from_raw_os_error(errno).into()),
+    }
+}
+
+#[cfg(not(unix))]
+fn reserve(file: File, from: u64, to: u64) -> Result<()> {
+    write_zeros(file, from, to)
+}
+
+/// Moves every existing file of `moves` from the first path to the second, returning how
+/// many were moved. Directories under `from_dir` that end up empty are removed
+pub fn move_files(moves: &[(PathBuf, PathBuf)], from_dir: &Path) -> Result<usize> {
+    let mut moved = 0;
+    for (from, to) in moves {
+        if !from.exists() {
+            continue;
+        }
+        move_file(from, to)?;
+        moved += 1;
+        let emptied = from.ancestors().skip(1);
+        for dir in emptied.take_while(|dir| dir.starts_with(from_dir) && *dir != from_dir) {
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(moved)
+}
+
+/// Renames `from` to `to`, creating the directories it goes to. Across filesystems the
+/// file is copied next to `to` first, so `to` only ever shows up whole
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+            let mut partial = to.as_os_str().to_owned();
+            partial.push(".part");
+            fs::copy(from, &partial)?;
+            fs::rename(&partial, to)?;
+            fs::remove_file(from)?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Bytes we may still use on the filesystem holding `dir`, or the closest of its parents
+/// that exists
+#[cfg(unix)]
+pub fn available_space(dir: &Path) -> Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let existing = dir
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    let path = CString::new(existing.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_dir: &Path) -> Result<u64> {
+    Err(anyhow!("Free space can't be told on this platform"))
+}
+
+/// Bytes of `path` that take up space on disk, fewer than its length while it's sparse
+pub fn allocated_bytes(path: &Path) -> u64 {
+    let Ok(metadata) = fs::metadata(path) else {
+        return 0;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        (metadata.blocks() * 512).min(metadata.len())
+    }
+    #[cfg(not(unix))]
+    metadata.len()
+}
+
+fn write_zeros(mut file: File, from: u64, to: u64) -> Result<()> {
+    let zeros = vec![0; ZEROS_BYTES];
+    file.seek(SeekFrom::Start(from))?;
+    let mut left = to - from;
+    while left > 0 {
+        let length = left.min(ZEROS_BYTES as u64) as usize;
+        file.write_all(&zeros[..length])?;
+        left -= length as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_writes_pieces_across_files() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi6e4:pathl3:sub1:beee\
+              4:name7:storage12:piece lengthi4e6:pieces0:ee",
+        )
+        .unwrap();
+        let content_dir = std::env::temp_dir().join("furia-storage-test");
+        let storage = FileStorage::new(&torrent, &content_dir);
+        assert_eq!(
+            vec![
+                FileSlice {
+                    file: 0,
+                    offset: 0,
+                    position: 0,
+                    length: 3
+                },
+                FileSlice {
+                    file: 1,
+                    offset: 0,
+                    position: 3,
+                    length: 1
+                },
+            ],
+            storage.slices(0, 0, 4).unwrap()
+        );
+        storage.write_block(1, 0, b"efgh").unwrap();
+        storage.write_block(0, 0, b"abcd").unwrap();
+        storage.write_block(2, 0, b"i").unwrap();
+        assert!(storage.write_block(2, 0, b"ij").is_err());
+        let root = content_dir.join("storage");
+        assert_eq!(b"abc", &fs::read(root.join("a")).unwrap()[..]);
+        assert_eq!(
+            b"defghi",
+            &fs::read(root.join("sub").join("b")).unwrap()[..]
+        );
+        assert_eq!(b"cdef", &storage.read_block(0, 2, 4).unwrap()[..]);
+        assert!(storage.verify(0, 4, &Sha1::digest(b"abcd")).unwrap());
+        fs::remove_dir_all(&content_dir).unwrap();
+        assert!(!storage.verify(0, 4, &Sha1::digest(b"abcd")).unwrap());
+    }
+
+    #[test]
+    fn it_moves_finished_files() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi2e4:pathl3:sub1:beee\
+              4:name4:move12:piece lengthi4e6:pieces0:ee",
+        )
+        .unwrap();
+        let incomplete = std::env::temp_dir()
+            .join("furia-move-test")
+            .join("incomplete");
+        let complete = std::env::temp_dir()
+            .join("furia-move-test")
+            .join("complete");
+        FileStorage::new(&torrent, &incomplete)
+            .write_block(0, 0, b"abcd")
+            .unwrap();
+        let moves: Vec<_> = content_paths(&torrent, &incomplete)
+            .into_iter()
+            .zip(content_paths(&torrent, &complete))
+            .collect();
+        assert_eq!(2, move_files(&moves, &incomplete).unwrap());
+        assert!(!incomplete.join("move").exists() && incomplete.exists());
+        let root = complete.join("move");
+        assert_eq!(b"abc", &fs::read(root.join("a")).unwrap()[..]);
+        assert_eq!(b"d", &fs::read(root.join("sub").join("b")).unwrap()[..]);
+        assert_eq!(0, move_files(&moves, &incomplete).unwrap());
+        fs::remove_dir_all(complete.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_tells_space_used_and_available() {
+        let missing = std::env::temp_dir()
+            .join("furia-space-test")
+            .join("missing");
+        assert!(available_space(&missing).unwrap() > 0);
+        assert_eq!(0, allocated_bytes(&missing));
+        let path = std::env::temp_dir().join("furia-space-test.bin");
+        File::create(&path).unwrap().set_len(1 << 20).unwrap();
+        assert!(allocated_bytes(&path) <= 1 << 20);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_allocates_files_to_their_length() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi6000e4:pathl1:beee\
+              4:name8:allocate12:piece lengthi4e6:pieces0:ee",
+        )
+        .unwrap();
+        let content_dir = std::env::temp_dir().join("furia-allocate-test");
+        let storage = FileStorage::new(&torrent, &content_dir);
+        storage.write_block(0, 0, b"abcd").unwrap();
+        storage.allocate(0, Allocation::Full).unwrap();
+        storage.allocate(1, Allocation::Full).unwrap();
+        let root = content_dir.join("allocate");
+        assert_eq!(b"abc", &fs::read(root.join("a")).unwrap()[..]);
+        let b = fs::read(root.join("b")).unwrap();
+        assert_eq!(6000, b.len());
+        assert_eq!(b"d\0", &b[..2]);
+        fs::remove_dir_all(&content_dir).unwrap();
+    }
+
+    #[test]
+    fn it_maps_files_into_memory() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi6e4:pathl1:beee\
+              4:name6:mapped12:piece lengthi4e6:pieces0:ee",
+        )
+        .unwrap();
+        let content_dir = std::env::temp_dir().join("furia-mmap-test");
+        let storage = FileStorage::with_access(&torrent, &content_dir, FileAccess::Mapped);
+        assert!(storage.read_block(0, 0, 4).is_err());
+        storage.write_block(1, 0, b"efgh").unwrap();
+        storage.write_block(0, 0, b"abcd").unwrap();
+        assert_eq!(b"cdefgh", &storage.read_block(0, 2, 6).unwrap()[..]);
+        drop(storage);
+        let root = content_dir.join("mapped");
+        assert_eq!(b"abc", &fs::read(root.join("a")).unwrap()[..]);
+        assert_eq!(b"defgh\0", &fs::read(root.join("b")).unwrap()[..]);
+        fs::remove_dir_all(&content_dir).unwrap();
+    }
+}
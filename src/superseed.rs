@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use crate::{bitfield::Bitfield, download::Download, tracker::Peer};
+
+/// Super-seeding (BEP 16): we pretend to have nothing and reveal a single piece to each peer,
+/// moving on to the next one only once the piece was seen at some other peer, i.e. once the
+/// peer we gave it to passed it on instead of us uploading it twice. What was revealed to
+/// whom and what spread is kept by the `Download`, this only tracks each peer's turn
+#[derive(Debug, Default)]
+pub struct SuperSeed {
+    /// The piece currently revealed to each peer
+    revealed: HashMap<Peer, u32>,
+}
+
+impl SuperSeed {
+    /// Picks the piece the peer lacks that was revealed the fewest times, rarest first.
+    /// Pieces that already spread come last, the swarm takes care of them
+    pub fn next_piece(
+        &mut self,
+        peer: &Peer,
+        peer_has: &Bitfield,
+        download: &Download,
+    ) -> Option<u32> {
+        if let Some(piece) = self.revealed.get(peer) {
+            return Some(*piece);
+        }
+        let availability = download.availability();
+        let piece = (0..availability.len())
+            .filter(|piece| !peer_has.has(*piece))
+            .min_by_key(|piece| {
+                (
+                    download.has_spread(*piece),
+                    download.times_revealed(*piece),
+                    availability[*piece],
+                )
+            })? as u32;
+        self.revealed.insert(peer.clone(), piece);
+        Some(piece)
+    }
+
+    /// Records that `from` announced `piece`, returning the peers that the piece was revealed
+    /// to and that are now due for a new one
+    pub fn on_have(&mut self, from: &Peer, piece: u32) -> Vec<Peer> {
+        let done: Vec<Peer> = self
+            .revealed
+            .iter()
+            .filter(|(peer, revealed)| **revealed == piece && *peer != from)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in &done {
+            self.revealed.remove(peer);
+        }
+        done
+    }
+
+    pub fn remove_peer(&mut self, peer: &Peer) {
+        self.revealed.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_torrent::TorrentFile;
+
+    fn peer(port: u16) -> Peer {
+        Peer::from_socket_addr(([10, 0, 0, 1], port).into())
+    }
+
+    /// Reveals what `super_seed` picks like the manager does
+    fn reveal(
+        super_seed: &mut SuperSeed,
+        download: &mut Download,
+        to: &Peer,
+        has: &Bitfield,
+    ) -> Option<u32> {
+        let piece = super_seed.next_piece(to, has, download)?;
+        download.mark_revealed(piece as usize, to.addr);
+        Some(piece)
+    }
+
+    #[test]
+    fn it_reveals_the_next_piece_after_an_echo() {
+        let mut bencoded = b"d4:infod6:lengthi3e4:name1:a12:piece lengthi1e6:pieces60:".to_vec();
+        bencoded.extend_from_slice(&[0; 60]);
+        bencoded.extend_from_slice(b"ee");
+        let torrent: TorrentFile = serde_bencode::from_bytes(&bencoded).unwrap();
+        let mut download = Download::from(&torrent);
+        for piece in [0, 0, 2] {
+            download.add_available_piece(piece);
+        }
+        let mut super_seed = SuperSeed::default();
+        let nothing = Bitfield::new(3);
+        let seed = &mut super_seed;
+        assert_eq!(Some(1), reveal(seed, &mut download, &peer(1), &nothing));
+        assert_eq!(Some(2), reveal(seed, &mut download, &peer(2), &nothing));
+        // The peer got the piece but nobody else has it yet
+        assert!(!download.observe_have(1, peer(1).addr));
+        assert!(seed.on_have(&peer(1), 1).is_empty());
+        assert_eq!(Some(1), reveal(seed, &mut download, &peer(1), &nothing));
+        assert!(download.observe_have(1, peer(2).addr));
+        assert_eq!(vec![peer(1)], seed.on_have(&peer(2), 1));
+        assert_eq!(1, download.spread_pieces());
+        download.add_available_piece(1);
+        download.add_available_piece(1);
+        let mut has_one = Bitfield::new(3);
+        has_one.set(1);
+        assert_eq!(Some(0), reveal(seed, &mut download, &peer(1), &has_one));
+    }
+}
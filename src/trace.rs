@@ -0,0 +1,69 @@
+use bytes::Bytes;
+use tracing::{debug, enabled, trace, Level};
+
+use crate::{messages::Message, tracker::Peer};
+
+/// Enable with e.g. `RUST_LOG=furia::wire=debug`, or `=trace` to get hex dumps of every frame
+pub const WIRE_TARGET: &str = "furia::wire";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+impl Direction {
+    fn arrow(self) -> &'static str {
+        match self {
+            Direction::Incoming => "<-",
+            Direction::Outgoing => "->",
+        }
+    }
+}
+
+/// Logs a message exchanged with `peer` along with the block coordinates it carries
+pub fn trace_message(peer: &Peer, direction: Direction, message: &Message) {
+    if !enabled!(target: WIRE_TARGET, Level::DEBUG) {
+        return;
+    }
+    let peer = peer.addr.to_string();
+    let (index, begin, length) = match message {
+        Message::Have(index) | Message::SuggestPiece(index) | Message::AllowedFast(index) => {
+            (Some(*index), None, None)
+        }
+        Message::Request(request) | Message::Cancel(request) | Message::RejectRequest(request) => (
+            Some(request.index),
+            Some(request.begin),
+            Some(request.length),
+        ),
+        Message::Piece(block) => (
+            Some(block.index),
+            Some(block.begin),
+            Some(block.data.len() as u32),
+        ),
+        Message::Bitfield(bitfield) => (None, None, Some(bitfield.len() as u32)),
+        Message::Extended(id, payload) => (Some(*id as u32), None, Some(payload.len() as u32)),
+        _ => (None, None, None),
+    };
+    debug!(
+        target: WIRE_TARGET,
+        peer,
+        direction = direction.arrow(),
+        message = message.name(),
+        index,
+        begin,
+        length,
+    );
+}
+
+/// Logs an encoded frame, decoding it only if wire tracing is enabled
+pub fn trace_frame(peer: &Peer, direction: Direction, frame: &Bytes) {
+    if !enabled!(target: WIRE_TARGET, Level::DEBUG) {
+        return;
+    }
+    match Message::decode(frame.clone()) {
+        Ok(message) => trace_message(peer, direction, &message),
+        Err(error) => debug!(target: WIRE_TARGET, %error, "undecodable frame"),
+    }
+    trace!(target: WIRE_TARGET, frame = hex::encode(frame));
+}
@@ -1,15 +1,23 @@
-use anyhow::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Result};
 use percent_encoding::percent_encode_byte;
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use serde_bytes::ByteArray;
 use sha1::{Digest, Sha1};
+use tokio::net::lookup_host;
 use url::Url;
 
-use crate::parse_torrent::{Info, TorrentFile};
+use crate::{
+    bind::Bind,
+    parse_torrent::{Info, TorrentFile},
+};
+
+pub const DEFAULT_PORT: u16 = 6881;
 
 #[derive(Debug, Serialize, Deserialize)]
-enum Event {
+#[serde(rename_all = "lowercase")]
+pub enum Event {
     Started,
     Stopped,
     Completed,
@@ -24,57 +32,133 @@ struct TrackerRequest {
     left: usize,
     compact: bool,
     no_peer_id: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<Event>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct Peer {
     #[serde(rename = "peer id")]
     pub peer_id: Option<String>,
-    pub ip: String,
-    pub port: i64,
+    pub addr: SocketAddr,
+}
+
+impl Peer {
+    pub fn from_socket_addr(addr: SocketAddr) -> Self {
+        Self {
+            peer_id: None,
+            addr,
+        }
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TrackerResponse {
     #[serde(rename = "failure reason")]
-    failure_reason: Option<bool>,
+    pub failure_reason: Option<bool>,
     #[serde(rename = "warning message")]
-    warning_message: Option<bool>,
+    pub warning_message: Option<bool>,
     /// Interval in seconds that the client should wait between sending regular requests to the tracker
-    interval: u32,
+    pub interval: u32,
     #[serde(rename = "tracker id")]
-    tracker_id: Option<String>,
-    complete: u32,
-    incomplete: u32,
+    pub tracker_id: Option<String>,
+    pub complete: u32,
+    pub incomplete: u32,
     #[serde(with = "peer_list")]
     pub peers: Vec<Peer>,
+    /// IPv6 peers, which BEP 7 trackers return separately
+    #[serde(default, with = "peer6_list")]
+    pub peers6: Vec<Peer>,
 }
 
 mod peer_list {
-    use super::Peer;
+    use super::{parse_compact_peers, Peer};
     use serde::{Deserialize, Deserializer};
-    use serde_bytes::ByteArray;
+    use serde_bytes::ByteBuf;
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Peer>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let bytes: ByteArray<6> = Deserialize::deserialize(deserializer)?;
-        let mut peers = Vec::new();
-        for chunk in bytes.chunks(6) {
-            if chunk.len() == 6 {
-                let ip = format!("{}.{}.{}.{}", chunk[0], chunk[1], chunk[2], chunk[3]);
-                let port = ((chunk[4] as i64) << 8) | chunk[5] as i64;
-                peers.push(Peer {
-                    peer_id: None,
-                    ip,
-                    port,
-                });
-            }
-        }
-        Ok(peers)
+        let bytes: ByteBuf = Deserialize::deserialize(deserializer)?;
+        Ok(parse_compact_peers(&bytes))
+    }
+}
+
+mod peer6_list {
+    use super::{parse_compact_peers6, Peer};
+    use serde::{Deserialize, Deserializer};
+    use serde_bytes::ByteBuf;
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Peer>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bytes: ByteBuf = Deserialize::deserialize(deserializer)?;
+        Ok(parse_compact_peers6(&bytes))
     }
 }
+
+/// Decodes the compact IPv4 peer format: 4 bytes of address followed by 2 bytes of port
+pub fn parse_compact_peers(bytes: &[u8]) -> Vec<Peer> {
+    bytes
+        .chunks_exact(6)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            Peer::from_socket_addr(SocketAddr::new(IpAddr::V4(ip), port))
+        })
+        .collect()
+}
+
+/// Decodes the compact IPv6 peer format: 16 bytes of address followed by 2 bytes of port
+pub fn parse_compact_peers6(bytes: &[u8]) -> Vec<Peer> {
+    bytes
+        .chunks_exact(18)
+        .map(|chunk| {
+            let octets: [u8; 16] = chunk[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            Peer::from_socket_addr(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        })
+        .collect()
+}
+
+/// Only IPv4 peers have a 6 byte compact form, IPv6 ones go through `encode_compact_peer6`
+pub fn encode_compact_peer(peer: &Peer) -> Option<[u8; 6]> {
+    let SocketAddr::V4(addr) = peer.addr else {
+        return None;
+    };
+    let mut compact = [0; 6];
+    compact[..4].copy_from_slice(&addr.ip().octets());
+    compact[4..].copy_from_slice(&addr.port().to_be_bytes());
+    Some(compact)
+}
+
+pub fn encode_compact_peer6(peer: &Peer) -> Option<[u8; 18]> {
+    let SocketAddr::V6(addr) = peer.addr else {
+        return None;
+    };
+    let mut compact = [0; 18];
+    compact[..16].copy_from_slice(&addr.ip().octets());
+    compact[16..].copy_from_slice(&addr.port().to_be_bytes());
+    Some(compact)
+}
+
+pub fn generate_peer_id() -> String {
+    format!(
+        "-FU0001-{}",
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect::<String>()
+    )
+}
+
 pub fn get_info_hash(info: &Info) -> Result<Vec<u8>> {
     let mut hasher = Sha1::new();
     let info_hash = serde_bencode::to_bytes(info)?;
@@ -85,7 +169,7 @@ pub fn get_info_hash(info: &Info) -> Result<Vec<u8>> {
 }
 
 pub fn get_encoded_info_hash(info: &Info) -> Result<String> {
-    let info_hash = get_info_hash(&info)?; // Vec<u8>
+    let info_hash = get_info_hash(info)?; // Vec<u8>
     let info_hash = info_hash
         .into_iter()
         .map(percent_encode_byte)
@@ -93,29 +177,59 @@ pub fn get_encoded_info_hash(info: &Info) -> Result<String> {
     Ok(info_hash)
 }
 
-pub async fn request_tracker(torrent: &TorrentFile) -> Result<TrackerResponse> {
+pub async fn request_tracker(
+    torrent: &TorrentFile,
+    peer_id: &str,
+    bind: Option<&Bind>,
+    port: u16,
+    left: u64,
+) -> Result<TrackerResponse> {
+    announce(torrent, peer_id, Some(Event::Started), bind, port, left).await
+}
+
+/// Regular announces carry no event, only starting, stopping and completing do.
+/// `port` is where we accept peers, so the tracker hands it out to others, `left` the
+/// bytes of the files we want still missing
+pub async fn announce(
+    torrent: &TorrentFile,
+    peer_id: &str,
+    event: Option<Event>,
+    bind: Option<&Bind>,
+    port: u16,
+    left: u64,
+) -> Result<TrackerResponse> {
     let info_hash = get_encoded_info_hash(&torrent.info)?;
 
     let tracker_request = TrackerRequest {
-        peer_id: format!(
-            "-FU0001-{}",
-            rand::thread_rng()
-                .sample_iter(&Alphanumeric)
-                .take(12)
-                .map(char::from)
-                .collect::<String>()
-        ),
-        port: 6881,
+        peer_id: peer_id.to_string(),
+        port: port as isize,
         uploaded: 0,
         downloaded: 0,
-        left: 0,
+        left: left as usize,
         compact: true,
         no_peer_id: true,
+        event,
     };
     let url = Url::parse(&torrent.announce)?;
     let url = url.join(&format!("?info_hash={}", &info_hash)).unwrap();
 
-    let client = reqwest::Client::new();
+    let client = match bind {
+        // The client can only bind to an address, which has to be found for interfaces
+        Some(bind) => {
+            let host = url
+                .host_str()
+                .ok_or_else(|| anyhow!("Tracker url has no host"))?;
+            let port = url.port_or_known_default().unwrap_or(80);
+            let remote = lookup_host((host, port))
+                .await?
+                .next()
+                .ok_or_else(|| anyhow!("Could not resolve {}", host))?;
+            reqwest::Client::builder()
+                .local_address(bind.local_ip(remote).await?)
+                .build()?
+        }
+        None => reqwest::Client::new(),
+    };
     let response = client.get(url).query(&tracker_request).send().await?;
     let body = response.bytes().await?;
     let response: TrackerResponse = serde_bencode::from_bytes::<TrackerResponse>(&body)?;
@@ -124,10 +238,19 @@ pub async fn request_tracker(torrent: &TorrentFile) -> Result<TrackerResponse> {
 
 #[cfg(test)]
 mod test {
-    use super::get_encoded_info_hash;
+    use super::{encode_compact_peer6, get_encoded_info_hash, parse_compact_peers6};
     use crate::parse_torrent::Info;
     use serde_bytes::ByteBuf;
 
+    #[test]
+    fn compact_ipv6_peers_round_trip() {
+        let mut compact = vec![0; 15];
+        compact.extend_from_slice(&[1, 0x1A, 0xE1]);
+        let peers = parse_compact_peers6(&compact);
+        assert_eq!("[::1]:6881", peers[0].addr.to_string());
+        assert_eq!(&compact[..], &encode_compact_peer6(&peers[0]).unwrap()[..]);
+    }
+
     #[test]
     fn calculate_info_hash() {
         let info = Info {
@@ -139,6 +262,8 @@ mod test {
             private: None,
             path: None,
             root_hash: None,
+            meta_version: None,
+            file_tree: None,
             files: None,
         };
         let info_hash = get_encoded_info_hash(&info);
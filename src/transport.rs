@@ -0,0 +1,114 @@
+use std::{io::ErrorKind, net::SocketAddr};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    net::TcpStream,
+};
+
+/// How bytes reach a peer, one of the transports BitTorrent runs over. Only TCP for now,
+/// uTP (BEP 29) isn't implemented
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Transport {
+    #[default]
+    Tcp,
+}
+
+/// Bytes to and from a peer along with what carries them, so sessions are written once for
+/// every transport, encrypted or not
+pub trait PeerTransport: AsyncRead + AsyncWrite + Unpin + Send {
+    fn kind(&self) -> Transport;
+    fn peer_addr(&self) -> Option<SocketAddr>;
+    fn local_addr(&self) -> Option<SocketAddr>;
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+}
+
+pub type BoxedTransport = Box<dyn PeerTransport>;
+
+impl<T: PeerTransport + ?Sized> PeerTransport for Box<T> {
+    fn kind(&self) -> Transport {
+        (**self).kind()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn is_encrypted(&self) -> bool {
+        (**self).is_encrypted()
+    }
+}
+
+impl PeerTransport for TcpStream {
+    fn kind(&self) -> Transport {
+        Transport::Tcp
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+/// In-memory pipes stand in for TCP in tests, with no address on either end
+impl PeerTransport for DuplexStream {
+    fn kind(&self) -> Transport {
+        Transport::Tcp
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Dials that failed before any byte was exchanged, because the peer refused the
+/// connection or something on the way dropped it, are worth another way in
+pub fn is_unreachable(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<std::io::Error>().is_some_and(|error| {
+        matches!(
+            error.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::TimedOut
+                | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn it_falls_back_only_when_unreachable() {
+        let refused = anyhow::Error::new(std::io::Error::from(ErrorKind::ConnectionRefused));
+        assert!(is_unreachable(&refused));
+        let reset = anyhow::Error::new(std::io::Error::from(ErrorKind::ConnectionReset));
+        assert!(!is_unreachable(&reset));
+        assert!(!is_unreachable(&anyhow!("Invalid handshake")));
+    }
+
+    #[tokio::test]
+    async fn it_describes_the_stream_it_wraps() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let stream: BoxedTransport = Box::new(TcpStream::connect(address).await.unwrap());
+        let stream = crate::mse::MseStream::plaintext(stream);
+        assert_eq!(Transport::Tcp, stream.kind());
+        assert_eq!(Some(address), PeerTransport::peer_addr(&stream));
+        assert!(!PeerTransport::is_encrypted(&stream));
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+use tracing::debug;
+use url::Url;
+
+use crate::portmap::{Protocol, LEASE_DURATION};
+
+/// Multicast address routers listen on for SSDP discovery
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+/// How long routers get to answer the discovery
+pub const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Services able to map ports, the newest preferred
+const WAN_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "TCP",
+        Protocol::Udp => "UDP",
+    }
+}
+
+/// An Internet Gateway Device found through SSDP, whose WAN connection service
+/// forwards ports to us
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    control_url: Url,
+    service_type: &'static str,
+    /// Our address on the router's network, which mappings point to
+    local_ip: Ipv4Addr,
+    client: reqwest::Client,
+}
+
+impl Gateway {
+    pub async fn discover() -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+             ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+            SSDP_ADDR
+        );
+        socket.send_to(search.as_bytes(), SSDP_ADDR).await?;
+        let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+        let mut buffer = [0; 2048];
+        loop {
+            let (length, from) = timeout_at(deadline, socket.recv_from(&mut buffer))
+                .await
+                .map_err(|_| anyhow!("No UPnP gateway answered"))??;
+            let response = String::from_utf8_lossy(&buffer[..length]);
+            let Some(location) = parse_location(&response) else {
+                continue;
+            };
+            match Self::from_description(&location, from).await {
+                Ok(gateway) => return Ok(gateway),
+                Err(error) => {
+                    debug!("Skipping UPnP device at {}: {:?}", location, error);
+                }
+            }
+        }
+    }
+
+    async fn from_description(location: &str, from: SocketAddr) -> Result<Self> {
+        let client = reqwest::Client::new();
+        let location = Url::parse(location)?;
+        let description = client
+            .get(location.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let (service_type, control_url) = find_control_url(&description, &location)
+            .ok_or_else(|| anyhow!("Device has no WAN connection service"))?;
+        Ok(Self {
+            control_url,
+            service_type,
+            local_ip: local_ip_towards(from).await?,
+            client,
+        })
+    }
+
+    /// Forwards `port` of the router to the same port on this host for `LEASE_DURATION`
+    pub async fn add_port_mapping(&self, protocol: Protocol, port: u16) -> Result<()> {
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{port}</NewExternalPort>\
+             <NewProtocol>{}</NewProtocol>\
+             <NewInternalPort>{port}</NewInternalPort>\
+             <NewInternalClient>{}</NewInternalClient>\
+             <NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>furia</NewPortMappingDescription>\
+             <NewLeaseDuration>{}</NewLeaseDuration>",
+            protocol_name(protocol),
+            self.local_ip,
+            LEASE_DURATION.as_secs()
+        );
+        self.call("AddPortMapping", &arguments).await
+    }
+
+    pub async fn delete_port_mapping(&self, protocol: Protocol, port: u16) -> Result<()> {
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{port}</NewExternalPort>\
+             <NewProtocol>{}</NewProtocol>",
+            protocol_name(protocol)
+        );
+        self.call("DeletePortMapping", &arguments).await
+    }
+
+    async fn call(&self, action: &str, arguments: &str) -> Result<()> {
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{}\">{arguments}</u:{action}></s:Body></s:Envelope>",
+            self.service_type
+        );
+        let response = self
+            .client
+            .post(self.control_url.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header(
+                "SOAPAction",
+                format!("\"{}#{}\"", self.service_type, action),
+            )
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(anyhow!("{} failed with {}", action, response.status()));
+        }
+        Ok(())
+    }
+}
+
+/// The LOCATION header of an SSDP response, pointing to the device description
+fn parse_location(response: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("location")
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// The first WAN connection service of the description, along with its control url
+/// resolved against the description's location
+fn find_control_url(description: &str, location: &Url) -> Option<(&'static str, Url)> {
+    WAN_SERVICES.iter().find_map(|service_type| {
+        let service = &description[description.find(service_type)?..];
+        let start = service.find("<controlURL>")? + "<controlURL>".len();
+        let end = service[start..].find("</controlURL>")? + start;
+        Some((
+            *service_type,
+            location.join(service[start..end].trim()).ok()?,
+        ))
+    })
+}
+
+/// Connecting a UDP socket sends nothing, it only makes the OS pick the outgoing interface
+pub async fn local_ip_towards(remote: SocketAddr) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(remote).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(ip) => Err(anyhow!("Local address {} isn't IPv4", ip)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_finds_the_wan_service() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                        Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        let location = Url::parse(&parse_location(response).unwrap()).unwrap();
+        let description = "<service>\
+             <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+             <controlURL>/ctl/L3F</controlURL></service><service>\
+             <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+             <controlURL>/ctl/IPConn</controlURL></service>";
+        let (service_type, control_url) = find_control_url(description, &location).unwrap();
+        assert_eq!(
+            "urn:schemas-upnp-org:service:WANIPConnection:1",
+            service_type
+        );
+        assert_eq!("http://192.168.1.1:5000/ctl/IPConn", control_url.as_str());
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header::RANGE, StatusCode};
+
+use crate::{
+    messages::{Block, BlockRequest},
+    parse_torrent::{file_spans, TorrentFile},
+};
+
+/// Everything but the unreserved characters of RFC 3986 gets escaped in url path segments
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Failed fetches after which a web seed is no longer used
+pub const MAX_WEB_SEED_FAILURES: u32 = 5;
+
+/// An HTTP server holding the torrent content (BEP 19), fed block requests like any peer
+/// and answering them with ranged GETs turned into blocks
+pub struct WebSeed {
+    url: String,
+    client: reqwest::Client,
+    pub queued_requests: VecDeque<BlockRequest>,
+    pub failures: u32,
+}
+
+impl WebSeed {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+            queued_requests: VecDeque::new(),
+            failures: 0,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn is_usable(&self) -> bool {
+        self.failures < MAX_WEB_SEED_FAILURES
+    }
+
+    /// Urls ending with a slash are directories the torrent name (and file path) is appended to
+    fn file_url(&self, torrent: &TorrentFile, path: &[String]) -> String {
+        if !self.url.ends_with('/') && torrent.info.files.is_none() {
+            return self.url.clone();
+        }
+        let mut url = self.url.trim_end_matches('/').to_string();
+        for segment in std::iter::once(&torrent.info.name).chain(path) {
+            url.push('/');
+            url.extend(utf8_percent_encode(segment, PATH_SEGMENT));
+        }
+        url
+    }
+
+    /// Downloads the bytes of a block, which for multi-file torrents may span several files
+    pub async fn fetch(&self, torrent: &TorrentFile, request: BlockRequest) -> Result<Block> {
+        let start = request.index as u64 * torrent.info.piece_length as u64 + request.begin as u64;
+        let end = start + request.length as u64;
+        let mut data = BytesMut::with_capacity(request.length as usize);
+        for span in file_spans(torrent) {
+            let from = start.max(span.offset);
+            let to = end.min(span.offset + span.length);
+            if from >= to {
+                continue;
+            }
+            let (from, to) = (from - span.offset, to - span.offset);
+            // Padding files are all zeros and web seeds don't host them
+            if span.is_padding() {
+                data.resize(data.len() + (to - from) as usize, 0);
+                continue;
+            }
+            let response = self
+                .client
+                .get(self.file_url(torrent, &span.path))
+                .header(RANGE, format!("bytes={}-{}", from, to - 1))
+                .send()
+                .await?;
+            let bytes = match response.status() {
+                StatusCode::PARTIAL_CONTENT => response.bytes().await?,
+                // Servers ignoring the range send the whole file
+                StatusCode::OK => {
+                    let file = response.bytes().await?;
+                    if (file.len() as u64) < to {
+                        return Err(anyhow!(
+                            "Web seed {} sent {} bytes of a file of {}",
+                            self.url,
+                            file.len(),
+                            span.length
+                        ));
+                    }
+                    file.slice(from as usize..to as usize)
+                }
+                status => return Err(anyhow!("Web seed {} answered {}", self.url, status)),
+            };
+            data.extend_from_slice(&bytes);
+        }
+        if data.len() != request.length as usize {
+            return Err(anyhow!(
+                "Web seed {} sent {} bytes instead of {}",
+                self.url,
+                data.len(),
+                request.length
+            ));
+        }
+        Ok(Block {
+            index: request.index,
+            begin: request.begin,
+            data: data.freeze(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_builds_file_urls() {
+        let torrent: TorrentFile = serde_bencode::from_bytes(
+            b"d4:infod5:filesld6:lengthi3e4:pathl3:dir5:a b.ceee4:name4:root12:piece lengthi16384e6:pieces0:ee",
+        )
+        .unwrap();
+        let path = vec!["dir".to_string(), "a b.c".to_string()];
+        let seed = WebSeed::new("http://seed/files/".to_string());
+        assert_eq!(
+            "http://seed/files/root/dir/a%20b.c",
+            seed.file_url(&torrent, &path)
+        );
+        let seed = WebSeed::new("http://seed/files".to_string());
+        assert_eq!(
+            "http://seed/files/root/dir/a%20b.c",
+            seed.file_url(&torrent, &path)
+        );
+    }
+}